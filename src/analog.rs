@@ -0,0 +1,343 @@
+//! analog samples analog inputs and writes analog outputs, scaling raw values to engineering units
+
+// Default interval at which analog inputs are sampled, in ms
+pub const SAMPLE_INTERVAL: u64 = 1000;
+// Decimals kept in published values
+const PRECISION: f64 = 1000.0;
+
+/// Linear mapping from raw values to engineering units, e.g. 0-10000 mV to 0-100 %
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale {
+    pub raw_min: f64,
+    pub raw_max: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Scale {
+    /// Map a raw value to engineering units
+    pub fn to_value(&self, raw: f64) -> f64 {
+        self.min + (raw - self.raw_min) * (self.max - self.min) / (self.raw_max - self.raw_min)
+    }
+
+    /// Map a value in engineering units back to a raw value
+    pub fn to_raw(&self, value: f64) -> f64 {
+        self.raw_min + (value - self.min) * (self.raw_max - self.raw_min) / (self.max - self.min)
+    }
+}
+
+/// Settings for a single analog device
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalogSettings {
+    // Time in between samples of an input
+    pub interval: std::time::Duration,
+    // Minimal change in engineering units before a new value is published
+    pub deadband: f64,
+    // Raw values are published as is without a scale
+    pub scale: Option<Scale>,
+    pub unit: Option<String>,
+}
+
+impl Default for AnalogSettings {
+    fn default() -> Self {
+        AnalogSettings {
+            interval: std::time::Duration::from_millis(SAMPLE_INTERVAL),
+            deadband: 0.0,
+            scale: None,
+            unit: None,
+        }
+    }
+}
+
+impl AnalogSettings {
+    /// Map a raw value to engineering units
+    pub fn to_value(&self, raw: f64) -> f64 {
+        match &self.scale {
+            Some(scale) => scale.to_value(raw),
+            None => raw,
+        }
+    }
+
+    /// Range of values in engineering units
+    pub fn range(&self) -> (f64, f64) {
+        match &self.scale {
+            Some(scale) => (scale.min.min(scale.max), scale.min.max(scale.max)),
+            // Raw values of the analog outputs are in mV
+            None => (0.0, 10000.0),
+        }
+    }
+
+    /// Map a value in engineering units to a raw value for an output
+    pub fn to_raw(&self, value: f64) -> Result<u32, String> {
+        let (min, max) = self.range();
+        if !(min..=max).contains(&value) {
+            return Err(format!(
+                "Value {} is out of range: expected {} to {}",
+                value, min, max
+            ));
+        }
+        let raw = match &self.scale {
+            Some(scale) => scale.to_raw(value),
+            None => value,
+        };
+        Ok(raw.round().max(0.0) as u32)
+    }
+}
+
+/// Parse a command payload for an analog output: a number in engineering units
+pub fn parse_value(payload: &str) -> Result<f64, String> {
+    match payload.trim().parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(format!("Invalid payload {:?}: expected a number", payload)),
+    }
+}
+
+/// Payload for an analog value, rounded to a fixed number of decimals
+pub fn value_payload(value: f64) -> String {
+    format!("{}", (value * PRECISION).round() / PRECISION)
+}
+
+/// Analog value in engineering units by device ID
+pub type AnalogEvent = (u8, f64);
+
+// A single input being sampled
+#[derive(Debug)]
+struct SampledInput {
+    device: crate::device::Device,
+    settings: AnalogSettings,
+    next_sample: std::time::Instant,
+    last_value: Option<f64>,
+}
+
+/// AnalogSampler keeps track of when to sample every analog input and what was published last
+#[derive(Debug, Default)]
+pub struct AnalogSampler {
+    inputs: std::vec::Vec<SampledInput>,
+}
+
+impl AnalogSampler {
+    /// Set up the inputs, all of them to be sampled right away
+    pub fn new(
+        inputs: std::vec::Vec<(crate::device::Device, AnalogSettings)>,
+        now: std::time::Instant,
+    ) -> Self {
+        AnalogSampler {
+            inputs: inputs
+                .into_iter()
+                .map(|(device, settings)| SampledInput {
+                    device,
+                    settings,
+                    next_sample: now,
+                    last_value: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Indices and devices of the inputs due for a sample at `now`
+    pub fn due(&self, now: std::time::Instant) -> std::vec::Vec<(usize, &crate::device::Device)> {
+        self.inputs
+            .iter()
+            .enumerate()
+            .filter(|(_, input)| input.next_sample <= now)
+            .map(|(index, input)| (index, &input.device))
+            .collect()
+    }
+
+    /// Handle a raw sample taken at `now`, returning the value to publish if it moved past the
+    /// deadband
+    pub fn feed(
+        &mut self,
+        index: usize,
+        raw: Option<f64>,
+        now: std::time::Instant,
+    ) -> Option<AnalogEvent> {
+        let input = &mut self.inputs[index];
+        input.next_sample = now + input.settings.interval;
+        let value = input.settings.to_value(raw?);
+        match input.last_value {
+            Some(last_value) if (value - last_value).abs() < input.settings.deadband => None,
+            Some(last_value) if value == last_value => None,
+            _ => {
+                input.last_value = Some(value);
+                Some((input.device.id, value))
+            }
+        }
+    }
+
+    /// The next moment an input needs to be sampled
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        self.inputs.iter().map(|input| input.next_sample).min()
+    }
+}
+
+/// Read the raw value of an analog device
+pub fn read_raw(path: &str) -> Result<f64, crate::errors::MausError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| crate::errors::MausError::new(format!("Could not read {}: {}", path, e)))?;
+    contents.trim().parse().map_err(|_| {
+        crate::errors::MausError::new(format!(
+            "Invalid contents {:?} in {}",
+            contents.trim(),
+            path
+        ))
+    })
+}
+
+/// Sample all analog inputs and publish the values that changed
+pub fn run_analog_inputs(
+    mut sampler: AnalogSampler,
+    io: crate::backend::Io,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+) {
+    while let Some(deadline) = sampler.next_deadline() {
+        std::thread::sleep(deadline.saturating_duration_since(std::time::Instant::now()));
+        let now = std::time::Instant::now();
+        let samples: std::vec::Vec<(usize, Option<f64>)> = sampler
+            .due(now)
+            .into_iter()
+            .map(|(index, device)| match io.read_analog(device) {
+                Ok(raw) => (index, Some(raw)),
+                Err(e) => {
+                    log::debug!("{}", e);
+                    (index, None)
+                }
+            })
+            .collect();
+        for (index, raw) in samples {
+            if let Some(event) = sampler.feed(index, raw, now) {
+                if mqtt_publish_tx
+                    .send(crate::mqtt::MQTTMessage::Analog(event))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Write a value in engineering units to an analog output, returning the value written
+fn write_output(
+    io: &crate::backend::Io,
+    device: &crate::device::Device,
+    settings: &AnalogSettings,
+    value: f64,
+) -> Result<f64, String> {
+    let raw = settings.to_raw(value)?;
+    log::debug!("Writing {} to analog output #{}", raw, device.id);
+    io.write_analog(device, raw).map_err(|e| e.to_string())?;
+    Ok(settings.to_value(raw as f64))
+}
+
+/// Write every command to its analog output and publish the new value
+///
+/// Values out of range and failed writes are published as an error for the output, as are commands
+/// for outputs not written here, like the output of a dimmable light.
+pub fn run_analog_outputs(
+    rx: std::sync::mpsc::Receiver<AnalogEvent>,
+    outputs: std::collections::HashMap<u8, (crate::device::Device, AnalogSettings)>,
+    io: crate::backend::Io,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+) {
+    for (device_id, value) in rx {
+        let (device, settings) = match outputs.get(&device_id) {
+            Some(output) => output,
+            // Outputs driven by a dimmer are not written here
+            None => {
+                mqtt_publish_tx
+                    .send(crate::mqtt::MQTTMessage::Error(
+                        device_id,
+                        "Analog output is not commanded directly".to_string(),
+                    ))
+                    .unwrap();
+                continue;
+            }
+        };
+        let message = match write_output(&io, device, settings, value) {
+            Ok(value) => crate::mqtt::MQTTMessage::Analog((device_id, value)),
+            Err(e) => crate::mqtt::MQTTMessage::Error(device_id, e),
+        };
+        mqtt_publish_tx.send(message).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn percent() -> AnalogSettings {
+        AnalogSettings {
+            deadband: 1.0,
+            scale: Some(Scale {
+                raw_min: 0.0,
+                raw_max: 10000.0,
+                min: 0.0,
+                max: 100.0,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn device(id: u8) -> crate::device::Device {
+        crate::device::Device {
+            id,
+            path: "/foo/bar".to_string(),
+            module_name: String::from("foo"),
+            device_type: crate::device::DeviceType::AnalogInput,
+            io_group: 1,
+            number: id as i8,
+            settings: crate::device::DeviceSettings::default(),
+        }
+    }
+
+    #[test]
+    fn test_scale() {
+        let settings = percent();
+        assert_eq!(settings.to_value(2500.0), 25.0);
+        assert_eq!(settings.to_raw(42.5), Ok(4250));
+        assert!(settings.to_raw(101.0).is_err());
+        assert_eq!(parse_value(" 42.5\n"), Ok(42.5));
+        assert!(parse_value("half").is_err());
+        assert!(parse_value("NaN").is_err());
+        assert_eq!(value_payload(100.0 / 3.0), "33.333");
+        assert_eq!(value_payload(12.0), "12");
+    }
+
+    #[test]
+    fn test_sampler_deadband_and_interval() {
+        let start = std::time::Instant::now();
+        let mut sampler = AnalogSampler::new(vec![(device(1), percent())], start);
+        assert_eq!(sampler.due(start).len(), 1);
+        assert_eq!(sampler.feed(0, Some(5000.0), start), Some((1, 50.0)));
+
+        let next = start + std::time::Duration::from_millis(SAMPLE_INTERVAL);
+        assert_eq!(sampler.next_deadline(), Some(next));
+        assert!(sampler.due(start).is_empty());
+        // Changes within the deadband are not published
+        assert_eq!(sampler.feed(0, Some(5050.0), next), None);
+        assert_eq!(sampler.feed(0, Some(5100.0), next), Some((1, 51.0)));
+        // Failed reads only move on to the next sample
+        assert_eq!(sampler.feed(0, None, next), None);
+    }
+
+    #[test]
+    fn test_output_not_written_here_is_an_error() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        tx.send((3, 50.0)).unwrap();
+        drop(tx);
+        run_analog_outputs(
+            rx,
+            std::collections::HashMap::new(),
+            crate::backend::Io::default(),
+            publish_tx,
+        );
+
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [crate::mqtt::MQTTMessage::Error(3, _)]
+        ));
+    }
+}
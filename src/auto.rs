@@ -1,28 +1,494 @@
 /// auto contains the main functions related to automation, and links the different parts together
 use crate::sysfs::FileEvent;
 
+/// Local bindings of push buttons to lights, handled without going through the broker
+pub struct Bindings {
+    pub lights: std::vec::Vec<crate::models::Light>,
+    // Input ID -> indices of the dimmable lights it drives
+    pub dimmers: std::collections::HashMap<u8, std::vec::Vec<usize>>,
+    pub output_tx: std::sync::mpsc::Sender<crate::output::OutputEvent>,
+    pub dimmer_tx: std::sync::mpsc::Sender<crate::models::DimmerEvent>,
+    // Reads the current state of the lights
+    pub io: crate::backend::Io,
+}
+
+impl Bindings {
+    // Toggle the lights bound to a pressed button
+    fn feed(&self, event: FileEvent) {
+        for light in &self.lights {
+            if let Some((device_id, state)) = light.feed(event, &self.io) {
+                log::debug!("Button #{} toggles device #{}", event.0, device_id);
+                self.output_tx
+                    .send((device_id, crate::output::OutputCommand::Set(state)))
+                    .unwrap();
+            }
+        }
+    }
+
+    // Pass gestures on to the dimmable lights bound to the button
+    fn gesture(&self, (device_id, gesture): crate::gesture::GestureEvent) {
+        let command = match crate::models::DimmableLight::gesture_command(gesture) {
+            Some(command) => command,
+            None => return,
+        };
+        for &index in self.dimmers.get(&device_id).into_iter().flatten() {
+            self.dimmer_tx.send((index, command)).unwrap();
+        }
+    }
+}
+
+/// What makes a rule fire
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    // Input changed, optionally to a given state only
+    Input(u8, Option<bool>),
+    Gesture(u8, crate::gesture::Gesture),
+    // Message on a topic, optionally with a given payload only
+    Message(String, Option<String>),
+    // Named timer expired
+    Timer(String),
+}
+
+/// What needs to hold for a rule to fire
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    // Device is in the given state, as read from the hardware
+    State(crate::device::Device, bool),
+    // Local time of day in minutes since midnight is in [after, before); wraps around midnight
+    // when after is later than before
+    Time(u32, u32),
+}
+
+/// What a rule does when it fires
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Set(u8, bool),
+    // Toggle based on the state read from the hardware
+    Toggle(crate::device::Device),
+    // Switch on for the given time, restarting a running timer
+    Timed(u8, std::time::Duration),
+    // Switch on once for the given time
+    Pulse(u8, std::time::Duration),
+    // Topic, payload and retain flag
+    Publish(String, String, bool),
+    // (Re)start a named timer
+    StartTimer(String, std::time::Duration),
+}
+
+/// A single automation rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub trigger: Trigger,
+    pub conditions: std::vec::Vec<Condition>,
+    pub actions: std::vec::Vec<Action>,
+}
+
+/// Event evaluated against the rule triggers
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleEvent {
+    Input(FileEvent),
+    Gesture(crate::gesture::GestureEvent),
+    Message(String, String),
+}
+
+/// Effect of a fired rule
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleOutput {
+    Command(crate::output::OutputEvent),
+    Publish(String, String, bool),
+}
+
+/// RuleEngine evaluates all rules against incoming events and keeps track of their timers
+#[derive(Debug, Default)]
+pub struct RuleEngine {
+    rules: std::vec::Vec<Rule>,
+    timers: std::collections::HashMap<String, std::time::Instant>,
+    // Reads the devices in conditions and toggle actions
+    io: crate::backend::Io,
+}
+
+// Local time of day in minutes since midnight
+fn minute_of_day() -> u32 {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return 0;
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+impl Condition {
+    fn holds(&self, minute_of_day: u32, io: &crate::backend::Io) -> bool {
+        match self {
+            Condition::State(device, state) => io
+                .read_states(std::slice::from_ref(device))
+                .first()
+                .is_some_and(|&(_, actual)| actual == *state),
+            Condition::Time(after, before) if after <= before => {
+                (*after..*before).contains(&minute_of_day)
+            }
+            Condition::Time(after, before) => minute_of_day >= *after || minute_of_day < *before,
+        }
+    }
+}
+
+impl Trigger {
+    fn matches(&self, event: &RuleEvent) -> bool {
+        match (self, event) {
+            (Trigger::Input(device_id, state), RuleEvent::Input((id, value, _))) => {
+                device_id == id && state.is_none_or(|state| state == *value)
+            }
+            (Trigger::Gesture(device_id, gesture), RuleEvent::Gesture((id, value))) => {
+                device_id == id && gesture == value
+            }
+            (Trigger::Message(topic, payload), RuleEvent::Message(t, p)) => {
+                topic == t && payload.as_ref().is_none_or(|payload| payload == p)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl RuleEngine {
+    pub fn new(rules: std::vec::Vec<Rule>, io: crate::backend::Io) -> Self {
+        RuleEngine {
+            rules,
+            io,
+            ..Default::default()
+        }
+    }
+
+    /// Fire all rules triggered by an event at `now`
+    pub fn feed(
+        &mut self,
+        event: &RuleEvent,
+        now: std::time::Instant,
+        minute_of_day: u32,
+    ) -> std::vec::Vec<RuleOutput> {
+        let fired: std::vec::Vec<usize> = (0..self.rules.len())
+            .filter(|&index| self.rules[index].trigger.matches(event))
+            .collect();
+        self.fire(&fired, now, minute_of_day)
+    }
+
+    /// Fire the rules for timers expired at `now`
+    pub fn poll(
+        &mut self,
+        now: std::time::Instant,
+        minute_of_day: u32,
+    ) -> std::vec::Vec<RuleOutput> {
+        let mut outputs = std::vec::Vec::new();
+        let mut expired: std::vec::Vec<String> = self
+            .timers
+            .iter()
+            .filter(|(_, &until)| until <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        expired.sort();
+        for name in expired {
+            self.timers.remove(&name);
+            log::debug!("Timer {} expired", name);
+            let fired: std::vec::Vec<usize> = (0..self.rules.len())
+                .filter(|&index| self.rules[index].trigger == Trigger::Timer(name.clone()))
+                .collect();
+            outputs.extend(self.fire(&fired, now, minute_of_day));
+        }
+        outputs
+    }
+
+    /// The next moment a timer expires
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        self.timers.values().min().copied()
+    }
+
+    // Run the actions of the given rules for which all conditions hold
+    fn fire(
+        &mut self,
+        indices: &[usize],
+        now: std::time::Instant,
+        minute_of_day: u32,
+    ) -> std::vec::Vec<RuleOutput> {
+        let mut outputs = std::vec::Vec::new();
+        for &index in indices {
+            let rule = &self.rules[index];
+            if !rule
+                .conditions
+                .iter()
+                .all(|condition| condition.holds(minute_of_day, &self.io))
+            {
+                continue;
+            }
+            log::debug!("Rule {} fired", rule.name);
+            for action in &rule.actions {
+                match action {
+                    Action::Set(device_id, state) => outputs.push(RuleOutput::Command((
+                        *device_id,
+                        crate::output::OutputCommand::Set(*state),
+                    ))),
+                    Action::Toggle(device) => {
+                        let state = self.io.state(device);
+                        outputs.push(RuleOutput::Command((
+                            device.id,
+                            crate::output::OutputCommand::Set(!state),
+                        )));
+                    }
+                    Action::Timed(device_id, duration) => outputs.push(RuleOutput::Command((
+                        *device_id,
+                        crate::output::OutputCommand::Timed(*duration),
+                    ))),
+                    Action::Pulse(device_id, duration) => outputs.push(RuleOutput::Command((
+                        *device_id,
+                        crate::output::OutputCommand::Pulse(*duration),
+                    ))),
+                    Action::Publish(topic, payload, retain) => {
+                        outputs.push(RuleOutput::Publish(topic.clone(), payload.clone(), *retain));
+                    }
+                    Action::StartTimer(name, duration) => {
+                        self.timers.insert(name.clone(), now + *duration);
+                    }
+                }
+            }
+        }
+        outputs
+    }
+}
+
 /// Connect channels from sysfs read -> mqtt publish
+///
+/// Push button gestures are recognized along the way and published as actions. Local bindings
+/// switch lights right away, whether the broker is reachable or not. States and actions are
+/// streamed to the WebSocket clients as well.
 pub fn run_sysfs_to_mqtt(
     file_read_rx: std::sync::mpsc::Receiver<FileEvent>,
     log_write_tx: std::sync::mpsc::Sender<FileEvent>,
-    mqtt_publish_tx: std::sync::mpsc::Sender<FileEvent>,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+    mut gesture_recognizer: crate::gesture::GestureRecognizer,
+    bindings: Bindings,
+    rule_tx: std::sync::mpsc::Sender<RuleEvent>,
+    stream: crate::stream::Broadcast,
 ) {
-    // Simple pass-through, for now
-    for event in file_read_rx {
-        // Connect to log write
-        log_write_tx.send(event).unwrap();
+    loop {
+        let received = match gesture_recognizer.next_deadline() {
+            Some(deadline) => file_read_rx
+                .recv_timeout(deadline.saturating_duration_since(std::time::Instant::now())),
+            None => file_read_rx
+                .recv()
+                .map_err(|_| std::sync::mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        let mut gestures = std::vec::Vec::new();
+        match received {
+            Ok(event) => {
+                // Connect to log write
+                log_write_tx.send(event).unwrap();
+
+                bindings.feed(event);
+                rule_tx.send(RuleEvent::Input(event)).unwrap();
 
-        // Connect to MQTT publish
-        mqtt_publish_tx.send(event).unwrap();
+                // Connect to MQTT publish
+                mqtt_publish_tx
+                    .send(crate::mqtt::MQTTMessage::State(event))
+                    .unwrap();
+                stream.send(crate::stream::StreamEvent::State(event));
+
+                gestures.extend(gesture_recognizer.feed(event, std::time::Instant::now()));
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        gestures.extend(gesture_recognizer.poll(std::time::Instant::now()));
+
+        for gesture in gestures {
+            log::debug!("Gesture recognized {:?}", gesture);
+            bindings.gesture(gesture);
+            rule_tx.send(RuleEvent::Gesture(gesture)).unwrap();
+            mqtt_publish_tx
+                .send(crate::mqtt::MQTTMessage::Action(gesture))
+                .unwrap();
+            stream.send(crate::stream::StreamEvent::Action(gesture));
+        }
     }
 }
 
-pub fn run_mqtt_to_sysfs(
-    mqtt_subscribe_rx: std::sync::mpsc::Receiver<crate::mqtt::MQTTEvent>,
-    file_write_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTEvent>,
+/// Run the rule engine on the input events, gestures and MQTT messages it receives
+pub fn run_rules(
+    rx: std::sync::mpsc::Receiver<RuleEvent>,
+    output_tx: std::sync::mpsc::Sender<crate::output::OutputEvent>,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+    mut rule_engine: RuleEngine,
 ) {
-    for msg in mqtt_subscribe_rx {
-        log::debug!("Message received {:?}", msg);
-        file_write_tx.send(msg).unwrap();
+    loop {
+        let received = match rule_engine.next_deadline() {
+            Some(deadline) => {
+                rx.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+            }
+            None => rx
+                .recv()
+                .map_err(|_| std::sync::mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        let now = std::time::Instant::now();
+        let minute_of_day = minute_of_day();
+        let mut outputs = std::vec::Vec::new();
+        match received {
+            Ok(event) => outputs.extend(rule_engine.feed(&event, now, minute_of_day)),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        outputs.extend(rule_engine.poll(now, minute_of_day));
+
+        for output in outputs {
+            match output {
+                RuleOutput::Command(command) => output_tx.send(command).unwrap(),
+                RuleOutput::Publish(topic, payload, retain) => mqtt_publish_tx
+                    .send(crate::mqtt::MQTTMessage::Publish(topic, payload, retain))
+                    .unwrap(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> std::time::Duration {
+        std::time::Duration::from_millis(millis)
+    }
+
+    fn input(device_id: u8, state: bool) -> RuleEvent {
+        RuleEvent::Input((device_id, state, ms(0)))
+    }
+
+    fn rule(trigger: Trigger, conditions: Vec<Condition>, actions: Vec<Action>) -> Rule {
+        Rule {
+            name: "test".to_string(),
+            trigger,
+            conditions,
+            actions,
+        }
+    }
+
+    // Output device backed by a temporary file with the given contents
+    fn output(tmp_dir: &tempdir::TempDir, id: u8, contents: &str) -> crate::device::Device {
+        let path = tmp_dir.path().join(format!("ro_{}", id));
+        std::fs::write(&path, contents).expect("Could not write contents to temp file");
+        crate::device::Device {
+            id,
+            path: path.to_str().unwrap().to_string(),
+            module_name: String::from("foo"),
+            device_type: crate::device::DeviceType::RelayOutput,
+            io_group: 2,
+            number: id as i8,
+            settings: crate::device::DeviceSettings::default(),
+        }
+    }
+
+    #[test]
+    fn test_input_trigger_with_state() {
+        let mut rule_engine = RuleEngine::new(
+            vec![rule(
+                Trigger::Input(1, Some(true)),
+                vec![],
+                vec![Action::Set(7, true)],
+            )],
+            crate::backend::Io::default(),
+        );
+        let now = std::time::Instant::now();
+        assert_eq!(
+            rule_engine.feed(&input(1, true), now, 0),
+            vec![RuleOutput::Command((
+                7,
+                crate::output::OutputCommand::Set(true)
+            ))]
+        );
+        assert_eq!(rule_engine.feed(&input(1, false), now, 0), vec![]);
+        assert_eq!(rule_engine.feed(&input(2, true), now, 0), vec![]);
+    }
+
+    #[test]
+    fn test_time_condition_wraps_around_midnight() {
+        let mut rule_engine = RuleEngine::new(
+            vec![rule(
+                Trigger::Gesture(1, crate::gesture::Gesture::Double),
+                vec![Condition::Time(22 * 60, 6 * 60)],
+                vec![Action::Publish(
+                    "foo/night".to_string(),
+                    "1".to_string(),
+                    false,
+                )],
+            )],
+            crate::backend::Io::default(),
+        );
+        let now = std::time::Instant::now();
+        let event = RuleEvent::Gesture((1, crate::gesture::Gesture::Double));
+        assert_eq!(rule_engine.feed(&event, now, 23 * 60).len(), 1);
+        assert_eq!(rule_engine.feed(&event, now, 5 * 60).len(), 1);
+        assert_eq!(rule_engine.feed(&event, now, 12 * 60).len(), 0);
+    }
+
+    #[test]
+    fn test_device_state_condition_and_toggle() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let lamp = output(&tmp_dir, 7, "0\n");
+        let fan = output(&tmp_dir, 8, "1\n");
+        let mut rule_engine = RuleEngine::new(
+            vec![rule(
+                Trigger::Message("foo/toggle".to_string(), None),
+                vec![Condition::State(fan, true)],
+                vec![Action::Toggle(lamp.clone())],
+            )],
+            crate::backend::Io::default(),
+        );
+        let now = std::time::Instant::now();
+        let event = RuleEvent::Message("foo/toggle".to_string(), "x".to_string());
+        assert_eq!(
+            rule_engine.feed(&event, now, 0),
+            vec![RuleOutput::Command((
+                7,
+                crate::output::OutputCommand::Set(true)
+            ))]
+        );
+
+        std::fs::write(tmp_dir.path().join("ro_8"), "0\n").unwrap();
+        assert_eq!(rule_engine.feed(&event, now, 0), vec![]);
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_timer_chain_and_pulse() {
+        let mut rule_engine = RuleEngine::new(
+            vec![
+                rule(
+                    Trigger::Input(1, Some(true)),
+                    vec![],
+                    vec![Action::StartTimer("delay".to_string(), ms(1000))],
+                ),
+                rule(
+                    Trigger::Timer("delay".to_string()),
+                    vec![],
+                    vec![Action::Pulse(7, ms(500))],
+                ),
+            ],
+            crate::backend::Io::default(),
+        );
+        let start = std::time::Instant::now();
+        assert_eq!(rule_engine.feed(&input(1, true), start, 0), vec![]);
+        // Retriggering restarts the timer
+        rule_engine.feed(&input(1, true), start + ms(500), 0);
+        assert_eq!(rule_engine.next_deadline(), Some(start + ms(1500)));
+        assert_eq!(rule_engine.poll(start + ms(1000), 0), vec![]);
+
+        assert_eq!(
+            rule_engine.poll(start + ms(1500), 0),
+            vec![RuleOutput::Command((
+                7,
+                crate::output::OutputCommand::Pulse(ms(500))
+            ))]
+        );
+        assert_eq!(rule_engine.next_deadline(), None);
     }
 }
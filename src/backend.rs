@@ -0,0 +1,380 @@
+//! backend abstracts how digital devices are found, read, written and watched
+//!
+//! Every configured board is driven by a backend: the UniPi sysfs layout, a Linux GPIO character
+//! device or a Modbus TCP server. Counters are UniPi only and are read through their path.
+pub mod gpio;
+pub mod modbus;
+pub mod unipi;
+
+// Number of times the state is read back before concluding the hardware did not follow
+const READ_BACK_ATTEMPTS: u32 = 3;
+// Time to wait in between read back attempts
+const READ_BACK_DELAY: u64 = 20;
+
+/// IoBackend drives the digital devices of a single board
+///
+/// Values are the raw values on the hardware; inverting them is left to `Io`, except for the
+/// events sent out while watching.
+pub trait IoBackend: Send + Sync + std::fmt::Debug {
+    /// Add all devices found, with IDs following the devices already in the list
+    fn discover(
+        &self,
+        module_name: &str,
+        devices: &mut std::vec::Vec<crate::device::Device>,
+    ) -> Result<(), crate::errors::MausError>;
+
+    /// Read the raw value of a digital device
+    fn read(&self, device: &crate::device::Device) -> Result<bool, crate::errors::MausError>;
+
+    /// Write the raw value of a digital output
+    fn write(
+        &self,
+        device: &crate::device::Device,
+        value: bool,
+    ) -> Result<(), crate::errors::MausError>;
+
+    /// Read the raw value of an analog device
+    fn read_analog(&self, device: &crate::device::Device) -> Result<f64, crate::errors::MausError> {
+        Err(crate::errors::MausError::new(format!(
+            "Device #{} has no analog value",
+            device.id
+        )))
+    }
+
+    /// Write the raw value of an analog output
+    fn write_analog(
+        &self,
+        device: &crate::device::Device,
+        _value: u32,
+    ) -> Result<(), crate::errors::MausError> {
+        Err(crate::errors::MausError::new(format!(
+            "Device #{} has no analog value",
+            device.id
+        )))
+    }
+
+    /// Block, sending out an event for every toggle of the given devices, inverted as configured
+    ///
+    /// Only returns when the receiving end of the channel is dropped or on an unrecoverable error.
+    fn watch(
+        &self,
+        devices: std::vec::Vec<crate::device::Device>,
+        tx: std::sync::mpsc::Sender<crate::sysfs::FileEvent>,
+    ) -> Result<(), crate::errors::MausError>;
+}
+
+/// Io routes reads and writes of every device to the backend of its board
+///
+/// Cloning is cheap, so every thread reading or writing devices gets its own copy. Devices that
+/// were not discovered through `Io::discover` belong to the first backend.
+#[derive(Debug, Clone)]
+pub struct Io {
+    backends: std::sync::Arc<std::vec::Vec<std::sync::Arc<dyn IoBackend>>>,
+    // Device ID -> index of its backend
+    boards: std::sync::Arc<std::collections::HashMap<u8, usize>>,
+}
+
+// The UniPi sysfs backend for all devices
+impl Default for Io {
+    fn default() -> Self {
+        Io::new(vec![std::sync::Arc::new(unipi::UniPi::default())])
+    }
+}
+
+impl Io {
+    /// Set up the backends without any devices discovered
+    pub fn new(backends: std::vec::Vec<std::sync::Arc<dyn IoBackend>>) -> Self {
+        Io {
+            backends: std::sync::Arc::new(backends),
+            boards: std::sync::Arc::new(std::collections::HashMap::new()),
+        }
+    }
+
+    /// Discover the devices of all backends, in order
+    ///
+    /// Devices are identified by their type and hardware coordinates, so boards can not share
+    /// them.
+    pub fn discover(
+        backends: std::vec::Vec<std::sync::Arc<dyn IoBackend>>,
+        module_name: &str,
+    ) -> Result<(Self, std::vec::Vec<crate::device::Device>), crate::errors::MausError> {
+        let mut devices: std::vec::Vec<crate::device::Device> = std::vec::Vec::new();
+        let mut boards = std::collections::HashMap::new();
+        for (index, backend) in backends.iter().enumerate() {
+            let first = devices.len();
+            backend.discover(module_name, &mut devices)?;
+            if devices.len() > crate::device::MAX_DEVICES {
+                return Err(crate::errors::MausError::new(format!(
+                    "Found {} devices, at most {} are supported",
+                    devices.len(),
+                    crate::device::MAX_DEVICES
+                )));
+            }
+            for device in &devices[first..] {
+                if devices[..first].iter().any(|other| {
+                    other.device_type == device.device_type
+                        && other.io_group == device.io_group
+                        && other.number == device.number
+                }) {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Device {} {}_{:02} is found on more than one board",
+                        device.device_type.as_str(),
+                        device.io_group,
+                        device.number
+                    )));
+                }
+                boards.insert(device.id, index);
+            }
+        }
+        let io = Io {
+            backends: std::sync::Arc::new(backends),
+            boards: std::sync::Arc::new(boards),
+        };
+        Ok((io, devices))
+    }
+
+    // Backend of the board a device is on
+    fn backend(&self, device_id: u8) -> &std::sync::Arc<dyn IoBackend> {
+        &self.backends[self.boards.get(&device_id).copied().unwrap_or(0)]
+    }
+
+    /// Read the current value of all devices, skipping the ones that can not be read
+    pub fn read_states(&self, devices: &[crate::device::Device]) -> std::vec::Vec<(u8, bool)> {
+        devices
+            .iter()
+            .filter_map(|device| match self.backend(device.id).read(device) {
+                Ok(value) => Some((device.id, value != device.settings.invert)),
+                Err(e) => {
+                    log::debug!("{}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Current state of a single device, off when it can not be read
+    pub fn state(&self, device: &crate::device::Device) -> bool {
+        self.read_states(std::slice::from_ref(device))
+            .first()
+            .is_some_and(|&(_, state)| state)
+    }
+
+    /// Write the new state of an output and read it back until it matches
+    pub fn write_state(
+        &self,
+        device: &crate::device::Device,
+        state: bool,
+    ) -> Result<bool, crate::errors::MausError> {
+        let backend = self.backend(device.id);
+        let invert = device.settings.invert;
+        backend.write(device, state != invert)?;
+
+        let mut value = backend.read(device)? != invert;
+        for _ in 1..READ_BACK_ATTEMPTS {
+            if value == state {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(READ_BACK_DELAY));
+            value = backend.read(device)? != invert;
+        }
+        Ok(value)
+    }
+
+    /// Read the raw value of an analog device
+    pub fn read_analog(
+        &self,
+        device: &crate::device::Device,
+    ) -> Result<f64, crate::errors::MausError> {
+        self.backend(device.id).read_analog(device)
+    }
+
+    /// Write the raw value of an analog output
+    pub fn write_analog(
+        &self,
+        device: &crate::device::Device,
+        value: u32,
+    ) -> Result<(), crate::errors::MausError> {
+        self.backend(device.id).write_analog(device, value)
+    }
+
+    /// Watch the given devices, with a thread per backend
+    pub fn watch(
+        &self,
+        devices: std::vec::Vec<crate::device::Device>,
+        tx: std::sync::mpsc::Sender<crate::sysfs::FileEvent>,
+    ) -> std::vec::Vec<std::thread::JoinHandle<()>> {
+        let mut by_backend: std::collections::BTreeMap<
+            usize,
+            std::vec::Vec<crate::device::Device>,
+        > = std::collections::BTreeMap::new();
+        for device in devices {
+            let index = self.boards.get(&device.id).copied().unwrap_or(0);
+            by_backend.entry(index).or_default().push(device);
+        }
+        by_backend
+            .into_iter()
+            .map(|(index, devices)| {
+                let backend = self.backends[index].clone();
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = backend.watch(devices, tx) {
+                        log::error!("{}", e);
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Backend keeping all values in memory, with a fixed list of devices
+    #[derive(Debug, Default)]
+    pub struct MockBackend {
+        pub devices: std::vec::Vec<(crate::device::DeviceType, i8, i8)>,
+        pub values: std::sync::Mutex<std::collections::HashMap<u8, bool>>,
+        // Outputs that do not follow writes
+        pub stuck: std::collections::HashSet<u8>,
+        // Devices that can be written but not read
+        pub unreadable: std::collections::HashSet<u8>,
+        // Raw values written to analog outputs
+        pub analog: std::sync::Mutex<std::collections::HashMap<u8, u32>>,
+    }
+
+    impl IoBackend for MockBackend {
+        fn discover(
+            &self,
+            module_name: &str,
+            devices: &mut std::vec::Vec<crate::device::Device>,
+        ) -> Result<(), crate::errors::MausError> {
+            for (device_type, io_group, number) in &self.devices {
+                devices.push(crate::device::Device {
+                    id: crate::device::device_id(devices.len())?,
+                    module_name: module_name.to_string(),
+                    device_type: device_type.clone(),
+                    io_group: *io_group,
+                    number: *number,
+                    path: format!("mock:{}", number),
+                    settings: crate::device::DeviceSettings::default(),
+                });
+            }
+            Ok(())
+        }
+
+        fn read(&self, device: &crate::device::Device) -> Result<bool, crate::errors::MausError> {
+            if self.unreadable.contains(&device.id) {
+                return Err(crate::errors::MausError::new(format!(
+                    "Could not read device #{}",
+                    device.id
+                )));
+            }
+            Ok(self
+                .values
+                .lock()
+                .unwrap()
+                .get(&device.id)
+                .copied()
+                .unwrap_or(false))
+        }
+
+        fn write(
+            &self,
+            device: &crate::device::Device,
+            value: bool,
+        ) -> Result<(), crate::errors::MausError> {
+            if !self.stuck.contains(&device.id) {
+                self.values.lock().unwrap().insert(device.id, value);
+            }
+            Ok(())
+        }
+
+        fn write_analog(
+            &self,
+            device: &crate::device::Device,
+            value: u32,
+        ) -> Result<(), crate::errors::MausError> {
+            self.analog.lock().unwrap().insert(device.id, value);
+            Ok(())
+        }
+
+        fn watch(
+            &self,
+            _devices: std::vec::Vec<crate::device::Device>,
+            _tx: std::sync::mpsc::Sender<crate::sysfs::FileEvent>,
+        ) -> Result<(), crate::errors::MausError> {
+            Ok(())
+        }
+    }
+
+    fn relays(numbers: &[i8]) -> MockBackend {
+        MockBackend {
+            devices: numbers
+                .iter()
+                .map(|&number| (crate::device::DeviceType::RelayOutput, 2, number))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_discover_and_route() {
+        let first = std::sync::Arc::new(relays(&[1, 2]));
+        let second = std::sync::Arc::new(relays(&[3]));
+        let (io, mut devices) = Io::discover(vec![first.clone(), second.clone()], "foo").unwrap();
+        assert_eq!(devices.len(), 3);
+        assert_eq!(devices[2].id, 2);
+
+        devices[2].settings.invert = true;
+        assert!(io.write_state(&devices[2], true).unwrap());
+        assert_eq!(second.values.lock().unwrap().get(&2), Some(&false));
+        assert!(first.values.lock().unwrap().is_empty());
+        assert_eq!(
+            io.read_states(&devices),
+            vec![(0, false), (1, false), (2, true)]
+        );
+    }
+
+    #[test]
+    fn test_discover_too_many_devices() {
+        let numbers: std::vec::Vec<i8> = (0..100).collect();
+        let boards: std::vec::Vec<std::sync::Arc<dyn IoBackend>> = (1..=3)
+            .map(|io_group| {
+                let mut board = relays(&numbers);
+                for device in &mut board.devices {
+                    device.1 = io_group;
+                }
+                std::sync::Arc::new(board) as std::sync::Arc<dyn IoBackend>
+            })
+            .collect();
+        let error = Io::discover(boards, "foo").unwrap_err();
+        assert!(error.to_string().contains("at most 256"));
+    }
+
+    #[test]
+    fn test_discover_overlapping_boards() {
+        let error = Io::discover(
+            vec![
+                std::sync::Arc::new(relays(&[1, 2])),
+                std::sync::Arc::new(relays(&[2])),
+            ],
+            "foo",
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "MausError: Device relay 2_02 is found on more than one board"
+        );
+    }
+
+    #[test]
+    fn test_write_state_reads_back() {
+        let backend = std::sync::Arc::new(MockBackend {
+            stuck: std::collections::HashSet::from([0]),
+            ..relays(&[1])
+        });
+        let (io, devices) = Io::discover(vec![backend], "foo").unwrap();
+        assert!(!io.write_state(&devices[0], true).unwrap());
+    }
+}
@@ -0,0 +1,282 @@
+//! gpio drives lines of a Linux GPIO character device (`/dev/gpiochipN`) as inputs and outputs
+//!
+//! Lines are numbered in the order they are configured, so the second input line of a board with
+//! io_group 1 is input 1_02.
+
+// Consumer label shown for the requested lines, e.g. by gpioinfo
+const CONSUMER: &str = "hausmaus";
+
+// A requested line, held for as long as hausmaus runs
+#[derive(Debug)]
+enum Line {
+    Input(gpio_cdev::LineEventHandle),
+    Output(gpio_cdev::LineHandle),
+}
+
+/// Board with inputs and outputs on the lines of a GPIO chip
+#[derive(Debug)]
+pub struct Gpio {
+    chip: String,
+    io_group: i8,
+    // Line offsets of the inputs and outputs
+    inputs: std::vec::Vec<u32>,
+    outputs: std::vec::Vec<u32>,
+    // Requested lines by device ID
+    lines: std::sync::Mutex<std::collections::HashMap<u8, Line>>,
+}
+
+// Map a GPIO error for a line to a MausError
+fn line_error(chip: &str, offset: u32, e: gpio_cdev::Error) -> crate::errors::MausError {
+    crate::errors::MausError::new(format!("GPIO line {} of {}: {}", offset, chip, e))
+}
+
+impl Gpio {
+    pub fn new(chip: &str, io_group: i8, inputs: &[u32], outputs: &[u32]) -> Self {
+        Gpio {
+            chip: chip.to_string(),
+            io_group,
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+            lines: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+
+    /// Devices for all configured lines along with their offsets, with IDs from `first_id`
+    pub fn layout(
+        &self,
+        module_name: &str,
+        first_id: usize,
+    ) -> Result<std::vec::Vec<(crate::device::Device, u32)>, crate::errors::MausError> {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|&offset| (crate::device::DeviceType::DigitalInput, offset));
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|&offset| (crate::device::DeviceType::DigitalOutput, offset));
+        let mut numbers: std::collections::HashMap<crate::device::DeviceType, i8> =
+            std::collections::HashMap::new();
+        inputs
+            .chain(outputs)
+            .enumerate()
+            .map(|(index, (device_type, offset))| {
+                let number = numbers.entry(device_type.clone()).or_default();
+                *number += 1;
+                let device = crate::device::Device {
+                    id: crate::device::device_id(first_id + index)?,
+                    module_name: module_name.to_string(),
+                    device_type,
+                    io_group: self.io_group,
+                    number: *number,
+                    path: format!("{}:{}", self.chip, offset),
+                    settings: crate::device::DeviceSettings::default(),
+                };
+                Ok((device, offset))
+            })
+            .collect()
+    }
+
+    // Request a line, keeping the current value of outputs
+    fn request(
+        &self,
+        chip: &mut gpio_cdev::Chip,
+        device_type: &crate::device::DeviceType,
+        offset: u32,
+    ) -> Result<Line, gpio_cdev::Error> {
+        let line = chip.get_line(offset)?;
+        if *device_type == crate::device::DeviceType::DigitalInput {
+            return Ok(Line::Input(line.events(
+                gpio_cdev::LineRequestFlags::INPUT,
+                gpio_cdev::EventRequestFlags::BOTH_EDGES,
+                CONSUMER,
+            )?));
+        }
+        let value = line
+            .request(gpio_cdev::LineRequestFlags::INPUT, 0, CONSUMER)?
+            .get_value()?;
+        Ok(Line::Output(line.request(
+            gpio_cdev::LineRequestFlags::OUTPUT,
+            value,
+            CONSUMER,
+        )?))
+    }
+
+    fn lines(&self) -> std::sync::MutexGuard<'_, std::collections::HashMap<u8, Line>> {
+        self.lines.lock().unwrap()
+    }
+}
+
+impl crate::backend::IoBackend for Gpio {
+    fn discover(
+        &self,
+        module_name: &str,
+        devices: &mut std::vec::Vec<crate::device::Device>,
+    ) -> Result<(), crate::errors::MausError> {
+        let mut chip = gpio_cdev::Chip::new(&self.chip).map_err(|e| {
+            crate::errors::MausError::new(format!("Could not open {}: {}", self.chip, e))
+        })?;
+        let mut lines = self.lines();
+        for (device, offset) in self.layout(module_name, devices.len())? {
+            let line = self
+                .request(&mut chip, &device.device_type, offset)
+                .map_err(|e| line_error(&self.chip, offset, e))?;
+            log::debug!(
+                "Requested line {} of {} as device #{}",
+                offset,
+                self.chip,
+                device.id
+            );
+            lines.insert(device.id, line);
+            devices.push(device);
+        }
+        Ok(())
+    }
+
+    fn read(&self, device: &crate::device::Device) -> Result<bool, crate::errors::MausError> {
+        let value = match self.lines().get(&device.id) {
+            Some(Line::Input(handle)) => handle.get_value(),
+            Some(Line::Output(handle)) => handle.get_value(),
+            None => {
+                return Err(crate::errors::MausError::new(format!(
+                    "No GPIO line for device #{}",
+                    device.id
+                )))
+            }
+        };
+        value.map(|value| value != 0).map_err(|e| {
+            crate::errors::MausError::new(format!("Could not read {}: {}", device.path, e))
+        })
+    }
+
+    fn write(
+        &self,
+        device: &crate::device::Device,
+        value: bool,
+    ) -> Result<(), crate::errors::MausError> {
+        match self.lines().get(&device.id) {
+            Some(Line::Output(handle)) => handle.set_value(value as u8).map_err(|e| {
+                crate::errors::MausError::new(format!("Could not write to {}: {}", device.path, e))
+            }),
+            _ => Err(crate::errors::MausError::new(format!(
+                "Device #{} is not a GPIO output",
+                device.id
+            ))),
+        }
+    }
+
+    fn watch(
+        &self,
+        devices: std::vec::Vec<crate::device::Device>,
+        tx: std::sync::mpsc::Sender<crate::sysfs::FileEvent>,
+    ) -> Result<(), crate::errors::MausError> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut pollfds = std::vec::Vec::with_capacity(devices.len());
+        // Last value and toggle time per device, in the same order
+        let mut last = std::vec::Vec::with_capacity(devices.len());
+        for device in &devices {
+            let fd = match self.lines().get(&device.id) {
+                Some(Line::Input(handle)) => handle.as_raw_fd(),
+                // Output lines are held by this process, so only its own writes change them
+                _ => continue,
+            };
+            pollfds.push(libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            let value = self.read(device)? != device.settings.invert;
+            last.push((device, value, std::time::Instant::now()));
+        }
+
+        loop {
+            let n = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) };
+            if n < 0 {
+                let error = std::io::Error::last_os_error();
+                if error.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(crate::errors::MausError::new(format!(
+                    "Could not poll {}: {}",
+                    self.chip, error
+                )));
+            }
+            for (pollfd, (device, last_value, last_toggle_time)) in pollfds.iter().zip(&mut last) {
+                if pollfd.revents & libc::POLLIN == 0 {
+                    continue;
+                }
+                let event = match self.lines().get_mut(&device.id) {
+                    Some(Line::Input(handle)) => handle.get_event(),
+                    _ => continue,
+                };
+                let value = match event {
+                    Ok(event) => {
+                        (event.event_type() == gpio_cdev::EventType::RisingEdge)
+                            != device.settings.invert
+                    }
+                    Err(e) => {
+                        log::debug!("Could not read event of {}: {}", device.path, e);
+                        continue;
+                    }
+                };
+                if value == *last_value {
+                    continue;
+                }
+                let toggle_time = last_toggle_time.elapsed();
+                log::debug!(
+                    "Toggled for device #{} line {:?} ! {:?} / {:?}",
+                    device.id,
+                    device.path,
+                    value,
+                    toggle_time
+                );
+                if tx.send((device.id, value, toggle_time)).is_err() {
+                    return Ok(());
+                }
+                *last_value = value;
+                *last_toggle_time = std::time::Instant::now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let gpio = Gpio::new("/dev/gpiochip0", 3, &[17, 27], &[22]);
+        let layout = gpio.layout("pi", 4).unwrap();
+        let devices: std::vec::Vec<(u8, &str, i8, i8, u32)> = layout
+            .iter()
+            .map(|(device, offset)| {
+                (
+                    device.id,
+                    device.device_type.as_str(),
+                    device.io_group,
+                    device.number,
+                    *offset,
+                )
+            })
+            .collect();
+        assert_eq!(
+            devices,
+            vec![
+                (4, "input", 3, 1, 17),
+                (5, "input", 3, 2, 27),
+                (6, "output", 3, 1, 22),
+            ]
+        );
+        assert_eq!(layout[2].0.path, "/dev/gpiochip0:22");
+    }
+
+    #[test]
+    fn test_discover_missing_chip() {
+        let gpio = Gpio::new("/dev/gpiochip99", 1, &[1], &[]);
+        let error = crate::backend::IoBackend::discover(&gpio, "pi", &mut std::vec::Vec::new())
+            .unwrap_err();
+        assert!(error.to_string().contains("Could not open /dev/gpiochip99"));
+    }
+}
@@ -0,0 +1,498 @@
+//! modbus drives the coils, discrete inputs and registers of a Modbus TCP server as devices
+//!
+//! Coils are outputs, discrete inputs are inputs, and holding and input registers are analog
+//! outputs and inputs. Devices are numbered in the order their addresses are configured, so the
+//! second discrete input of a board with io_group 2 is input 2_02. Modbus has no way to report
+//! changes, so discrete inputs and coils are polled.
+
+// Number of failed polls in a row before the error is logged again
+const POLL_ERROR_LOG_INTERVAL: u32 = 100;
+
+/// Board with devices on a single unit of a Modbus TCP server
+#[derive(Debug)]
+pub struct Modbus {
+    // Host and port of the server
+    address: String,
+    unit_id: u8,
+    io_group: i8,
+    poll_interval: std::time::Duration,
+    // Addresses of the devices, per table
+    coils: std::vec::Vec<u16>,
+    discrete_inputs: std::vec::Vec<u16>,
+    holding_registers: std::vec::Vec<u16>,
+    input_registers: std::vec::Vec<u16>,
+    // Connection shared by all reads, writes and polls
+    client: std::sync::Mutex<crate::modbus::Client>,
+    // Table and address by device ID
+    registers: std::sync::Mutex<std::collections::HashMap<u8, (crate::modbus::Table, u16)>>,
+}
+
+/// Connection and device settings of a Modbus board
+#[derive(Debug, Clone, Default)]
+pub struct ModbusSettings {
+    pub unit_id: u8,
+    pub io_group: i8,
+    pub poll_interval: std::time::Duration,
+    pub timeout: std::time::Duration,
+    pub coils: std::vec::Vec<u16>,
+    pub discrete_inputs: std::vec::Vec<u16>,
+    pub holding_registers: std::vec::Vec<u16>,
+    pub input_registers: std::vec::Vec<u16>,
+}
+
+impl Modbus {
+    pub fn new(address: &str, settings: ModbusSettings) -> Self {
+        Modbus {
+            address: address.to_string(),
+            unit_id: settings.unit_id,
+            io_group: settings.io_group,
+            poll_interval: settings.poll_interval,
+            coils: settings.coils,
+            discrete_inputs: settings.discrete_inputs,
+            holding_registers: settings.holding_registers,
+            input_registers: settings.input_registers,
+            client: std::sync::Mutex::new(crate::modbus::Client::new(
+                address,
+                settings.unit_id,
+                settings.timeout,
+            )),
+            registers: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+
+    /// Devices for all configured addresses along with their table and address, with IDs from
+    /// `first_id`
+    pub fn layout(
+        &self,
+        module_name: &str,
+        first_id: usize,
+    ) -> Result<
+        std::vec::Vec<(crate::device::Device, crate::modbus::Table, u16)>,
+        crate::errors::MausError,
+    > {
+        let tables = [
+            (
+                crate::device::DeviceType::DigitalInput,
+                crate::modbus::Table::DiscreteInputs,
+                &self.discrete_inputs,
+            ),
+            (
+                crate::device::DeviceType::DigitalOutput,
+                crate::modbus::Table::Coils,
+                &self.coils,
+            ),
+            (
+                crate::device::DeviceType::AnalogInput,
+                crate::modbus::Table::InputRegisters,
+                &self.input_registers,
+            ),
+            (
+                crate::device::DeviceType::AnalogOutput,
+                crate::modbus::Table::HoldingRegisters,
+                &self.holding_registers,
+            ),
+        ];
+        tables
+            .into_iter()
+            .flat_map(|(device_type, table, addresses)| {
+                addresses.iter().enumerate().map(move |(index, &address)| {
+                    (device_type.clone(), table, index as i8 + 1, address)
+                })
+            })
+            .enumerate()
+            .map(|(index, (device_type, table, number, address))| {
+                let device = crate::device::Device {
+                    id: crate::device::device_id(first_id + index)?,
+                    module_name: module_name.to_string(),
+                    device_type,
+                    io_group: self.io_group,
+                    number,
+                    path: format!(
+                        "modbus://{}/{}/{}/{}",
+                        self.address,
+                        self.unit_id,
+                        table.as_str(),
+                        address
+                    ),
+                    settings: crate::device::DeviceSettings::default(),
+                };
+                Ok((device, table, address))
+            })
+            .collect()
+    }
+
+    fn client(&self) -> std::sync::MutexGuard<'_, crate::modbus::Client> {
+        self.client.lock().unwrap()
+    }
+
+    // Table and address of a device
+    fn register(
+        &self,
+        device: &crate::device::Device,
+    ) -> Result<(crate::modbus::Table, u16), crate::errors::MausError> {
+        self.registers
+            .lock()
+            .unwrap()
+            .get(&device.id)
+            .copied()
+            .ok_or_else(|| {
+                crate::errors::MausError::new(format!(
+                    "No Modbus address for device #{}",
+                    device.id
+                ))
+            })
+    }
+}
+
+impl crate::backend::IoBackend for Modbus {
+    fn discover(
+        &self,
+        module_name: &str,
+        devices: &mut std::vec::Vec<crate::device::Device>,
+    ) -> Result<(), crate::errors::MausError> {
+        // Devices come from the configuration: the server may well come up later than hausmaus
+        let mut registers = self.registers.lock().unwrap();
+        for (device, table, address) in self.layout(module_name, devices.len())? {
+            log::debug!(
+                "Mapped {} {} of {} as device #{}",
+                table.as_str(),
+                address,
+                self.address,
+                device.id
+            );
+            registers.insert(device.id, (table, address));
+            devices.push(device);
+        }
+        Ok(())
+    }
+
+    fn read(&self, device: &crate::device::Device) -> Result<bool, crate::errors::MausError> {
+        let (table, address) = self.register(device)?;
+        let bits = self.client().read_bits(table, address, 1)?;
+        bits.first().copied().ok_or_else(|| {
+            crate::errors::MausError::new(format!("No value read for {}", device.path))
+        })
+    }
+
+    fn write(
+        &self,
+        device: &crate::device::Device,
+        value: bool,
+    ) -> Result<(), crate::errors::MausError> {
+        match self.register(device)? {
+            (crate::modbus::Table::Coils, address) => self
+                .client()
+                .request(&crate::modbus::Request::WriteCoil(address, value))
+                .map(|_| ()),
+            _ => Err(crate::errors::MausError::new(format!(
+                "Device #{} is not a Modbus coil",
+                device.id
+            ))),
+        }
+    }
+
+    fn read_analog(&self, device: &crate::device::Device) -> Result<f64, crate::errors::MausError> {
+        let (table, address) = self.register(device)?;
+        let registers = self.client().read_registers(table, address, 1)?;
+        registers.first().map(|&value| value as f64).ok_or_else(|| {
+            crate::errors::MausError::new(format!("No value read for {}", device.path))
+        })
+    }
+
+    fn write_analog(
+        &self,
+        device: &crate::device::Device,
+        value: u32,
+    ) -> Result<(), crate::errors::MausError> {
+        let value: u16 = value.try_into().map_err(|_| {
+            crate::errors::MausError::new(format!(
+                "Value {} does not fit in register {}",
+                value, device.path
+            ))
+        })?;
+        match self.register(device)? {
+            (crate::modbus::Table::HoldingRegisters, address) => self
+                .client()
+                .request(&crate::modbus::Request::WriteRegister(address, value))
+                .map(|_| ()),
+            _ => Err(crate::errors::MausError::new(format!(
+                "Device #{} is not a Modbus holding register",
+                device.id
+            ))),
+        }
+    }
+
+    fn watch(
+        &self,
+        devices: std::vec::Vec<crate::device::Device>,
+        tx: std::sync::mpsc::Sender<crate::sysfs::FileEvent>,
+    ) -> Result<(), crate::errors::MausError> {
+        let registers: std::vec::Vec<(crate::device::Device, crate::modbus::Table, u16)> = devices
+            .into_iter()
+            .filter_map(|device| match self.register(&device) {
+                Ok((table, address)) if table.is_bits() => Some((device, table, address)),
+                _ => None,
+            })
+            .collect();
+        let requests = poll_requests(
+            &registers
+                .iter()
+                .map(|&(_, table, address)| (table, address))
+                .collect::<std::vec::Vec<_>>(),
+        );
+        // Devices with their request, offset in its response, last value and toggle time; the
+        // value is unknown until the first poll
+        let mut watched: std::vec::Vec<(
+            crate::device::Device,
+            usize,
+            usize,
+            Option<bool>,
+            std::time::Instant,
+        )> = registers
+            .into_iter()
+            .filter_map(|(device, table, address)| {
+                let index = requests.iter().position(|&(other, first, count)| {
+                    other == table && address >= first && address - first < count
+                })?;
+                let offset = (address - requests[index].1) as usize;
+                Some((device, index, offset, None, std::time::Instant::now()))
+            })
+            .collect();
+        if watched.is_empty() {
+            return Ok(());
+        }
+        let mut failed_polls = 0;
+
+        loop {
+            let responses: Result<std::vec::Vec<std::vec::Vec<bool>>, crate::errors::MausError> =
+                requests
+                    .iter()
+                    .map(|&(table, first, count)| self.client().read_bits(table, first, count))
+                    .collect();
+            let responses = match responses {
+                Ok(responses) => {
+                    if failed_polls > 0 {
+                        log::info!("Polling {} again", self.address);
+                    }
+                    failed_polls = 0;
+                    responses
+                }
+                Err(e) => {
+                    if failed_polls % POLL_ERROR_LOG_INTERVAL == 0 {
+                        log::error!("Could not poll inputs: {}", e);
+                    }
+                    failed_polls += 1;
+                    std::thread::sleep(self.poll_interval);
+                    continue;
+                }
+            };
+            for (device, index, offset, last_value, last_toggle_time) in &mut watched {
+                let value = responses[*index][*offset] != device.settings.invert;
+                if *last_value == Some(value) {
+                    continue;
+                }
+                // The first poll only tells the current state
+                if last_value.replace(value).is_none() {
+                    continue;
+                }
+                let toggle_time = last_toggle_time.elapsed();
+                log::debug!(
+                    "Toggled for device #{} at {:?} ! {:?} / {:?}",
+                    device.id,
+                    device.path,
+                    value,
+                    toggle_time
+                );
+                if tx.send((device.id, value, toggle_time)).is_err() {
+                    return Ok(());
+                }
+                *last_toggle_time = std::time::Instant::now();
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+// Table, first address and count of the reads covering all given bits, each spanning no more
+// than fits in a single response
+fn poll_requests(
+    addresses: &[(crate::modbus::Table, u16)],
+) -> std::vec::Vec<(crate::modbus::Table, u16, u16)> {
+    let mut requests: std::vec::Vec<(crate::modbus::Table, u16, u16)> = std::vec::Vec::new();
+    for table in [
+        crate::modbus::Table::DiscreteInputs,
+        crate::modbus::Table::Coils,
+    ] {
+        let mut sorted: std::vec::Vec<u16> = addresses
+            .iter()
+            .filter(|&&(other, _)| other == table)
+            .map(|&(_, address)| address)
+            .collect();
+        sorted.sort();
+        sorted.dedup();
+        for address in sorted {
+            match requests.last_mut() {
+                Some((other, first, count))
+                    if *other == table && address - *first < crate::modbus::MAX_BITS =>
+                {
+                    *count = address - *first + 1;
+                }
+                _ => requests.push((table, address, 1)),
+            }
+        }
+    }
+    requests
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::IoBackend;
+
+    fn board(address: &str) -> Modbus {
+        Modbus::new(
+            address,
+            ModbusSettings {
+                unit_id: 1,
+                io_group: 2,
+                poll_interval: std::time::Duration::from_millis(10),
+                timeout: std::time::Duration::from_secs(1),
+                coils: vec![0, 1],
+                discrete_inputs: vec![8, 10],
+                holding_registers: vec![100],
+                input_registers: vec![],
+            },
+        )
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = board("127.0.0.1:502").layout("plc", 3).unwrap();
+        let devices: std::vec::Vec<(u8, &str, i8, i8, u16)> = layout
+            .iter()
+            .map(|(device, _, address)| {
+                (
+                    device.id,
+                    device.device_type.as_str(),
+                    device.io_group,
+                    device.number,
+                    *address,
+                )
+            })
+            .collect();
+        assert_eq!(
+            devices,
+            vec![
+                (3, "input", 2, 1, 8),
+                (4, "input", 2, 2, 10),
+                (5, "output", 2, 1, 0),
+                (6, "output", 2, 2, 1),
+                (7, "analog_output", 2, 1, 100),
+            ]
+        );
+        assert_eq!(layout[3].0.path, "modbus://127.0.0.1:502/1/coil/1");
+    }
+
+    #[test]
+    fn test_layout_too_many_devices() {
+        let error = board("127.0.0.1:502").layout("plc", 254).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "MausError: Too many devices: at most 256 are supported"
+        );
+    }
+
+    #[test]
+    fn test_poll_requests() {
+        let requests = poll_requests(&[
+            (crate::modbus::Table::Coils, 4),
+            (crate::modbus::Table::DiscreteInputs, 3000),
+            (crate::modbus::Table::DiscreteInputs, 1999),
+            (crate::modbus::Table::DiscreteInputs, 0),
+            (crate::modbus::Table::Coils, 2),
+        ]);
+        assert_eq!(
+            requests,
+            vec![
+                (crate::modbus::Table::DiscreteInputs, 0, 2000),
+                (crate::modbus::Table::DiscreteInputs, 3000, 1),
+                (crate::modbus::Table::Coils, 2, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_watch_sparse_addresses() {
+        let simulator = std::sync::Arc::new(crate::modbus::tests::Simulator::default());
+        let address = simulator.start();
+        let backend = std::sync::Arc::new(Modbus::new(
+            &address,
+            ModbusSettings {
+                unit_id: 1,
+                io_group: 2,
+                poll_interval: std::time::Duration::from_millis(10),
+                timeout: std::time::Duration::from_secs(1),
+                coils: vec![5],
+                discrete_inputs: vec![0, 3000],
+                ..Default::default()
+            },
+        ));
+        let (_, devices) = crate::backend::Io::discover(vec![backend.clone()], "plc").unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || backend.watch(devices, tx));
+        // Let the first poll pass before toggling the input and the coil
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        simulator
+            .tables
+            .lock()
+            .unwrap()
+            .insert((crate::modbus::Table::DiscreteInputs, 3000), 1);
+        let (device_id, state, _) = rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!((device_id, state), (1, true));
+
+        // Coils switched by another client show up as well
+        simulator
+            .tables
+            .lock()
+            .unwrap()
+            .insert((crate::modbus::Table::Coils, 5), 1);
+        let (device_id, state, _) = rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!((device_id, state), (2, true));
+    }
+
+    #[test]
+    fn test_against_simulator() {
+        let simulator = std::sync::Arc::new(crate::modbus::tests::Simulator::default());
+        let address = simulator.start();
+        let backend = std::sync::Arc::new(board(&address));
+        let (io, devices) = crate::backend::Io::discover(vec![backend.clone()], "plc").unwrap();
+        assert_eq!(devices.len(), 5);
+
+        assert!(io.write_state(&devices[3], true).unwrap());
+        assert_eq!(
+            simulator
+                .tables
+                .lock()
+                .unwrap()
+                .get(&(crate::modbus::Table::Coils, 1)),
+            Some(&1)
+        );
+        io.write_analog(&devices[4], 4711).unwrap();
+        assert_eq!(io.read_analog(&devices[4]).unwrap(), 4711.0);
+        assert!(io.write_analog(&devices[4], 70000).is_err());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let inputs = devices[..2].to_vec();
+        std::thread::spawn(move || backend.watch(inputs, tx));
+        // Let the first poll pass before toggling the input
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        simulator
+            .tables
+            .lock()
+            .unwrap()
+            .insert((crate::modbus::Table::DiscreteInputs, 10), 1);
+        let (device_id, state, _) = rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!((device_id, state), (1, true));
+    }
+}
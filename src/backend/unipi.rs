@@ -0,0 +1,87 @@
+//! unipi drives the devices of a UniPi board through its sysfs layout
+use std::io::Write;
+
+/// UniPi board with its devices below a sysfs path
+#[derive(Debug, Clone)]
+pub struct UniPi {
+    // sysfs root path to start scanning for files
+    path: String,
+}
+
+impl Default for UniPi {
+    fn default() -> Self {
+        UniPi::new("/run/unipi")
+    }
+}
+
+impl UniPi {
+    pub fn new(path: &str) -> Self {
+        UniPi {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl crate::backend::IoBackend for UniPi {
+    fn discover(
+        &self,
+        module_name: &str,
+        devices: &mut std::vec::Vec<crate::device::Device>,
+    ) -> Result<(), crate::errors::MausError> {
+        log::debug!("Start crawling path {:?}", self.path);
+        crate::device::devices_from_path(&self.path, module_name, devices)?;
+        log::info!("Finished crawling path {:?}", self.path);
+        Ok(())
+    }
+
+    fn read(&self, device: &crate::device::Device) -> Result<bool, crate::errors::MausError> {
+        let value = std::fs::File::open(&device.path)
+            .and_then(|mut file| crate::sysfs::read::read_value(&mut file))
+            .map_err(|e| {
+                crate::errors::MausError::new(format!("Could not read {}: {}", device.path, e))
+            })?;
+        value.ok_or_else(|| {
+            crate::errors::MausError::new(format!("Invalid contents in {}", device.path))
+        })
+    }
+
+    fn write(
+        &self,
+        device: &crate::device::Device,
+        value: bool,
+    ) -> Result<(), crate::errors::MausError> {
+        let path = &device.path;
+        let content = match value {
+            true => "1",
+            false => "0",
+        };
+        let mut file = std::fs::File::create(path).map_err(|e| {
+            crate::errors::MausError::new(format!("Could not open {} for writing: {}", path, e))
+        })?;
+        file.write_all(content.as_bytes()).map_err(|e| {
+            crate::errors::MausError::new(format!("Could not write to {}: {}", path, e))
+        })
+    }
+
+    fn read_analog(&self, device: &crate::device::Device) -> Result<f64, crate::errors::MausError> {
+        crate::analog::read_raw(&device.path)
+    }
+
+    fn write_analog(
+        &self,
+        device: &crate::device::Device,
+        value: u32,
+    ) -> Result<(), crate::errors::MausError> {
+        crate::sysfs::write::write_analog(&device.path, value)
+    }
+
+    fn watch(
+        &self,
+        devices: std::vec::Vec<crate::device::Device>,
+        tx: std::sync::mpsc::Sender<crate::sysfs::FileEvent>,
+    ) -> Result<(), crate::errors::MausError> {
+        crate::sysfs::read::Watcher::new(devices)
+            .and_then(|mut watcher| watcher.run(tx))
+            .map_err(|e| crate::errors::MausError::new(format!("Could not watch inputs: {}", e)))
+    }
+}
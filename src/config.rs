@@ -0,0 +1,2330 @@
+//! config contains the TOML configuration file layout, along with its validation
+//!
+//! Every setting has a default, so an empty file (or no file at all) is a valid configuration.
+//! Durations are given in milliseconds.
+
+// Log levels accepted in the logging section
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// Top-level configuration
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // sysfs root path to start scanning for files
+    pub sysfs: String,
+    // Boards to drive the digital devices of; a single UniPi board at `sysfs` when empty
+    pub boards: std::vec::Vec<BoardSection>,
+    // Name used for the root MQTT topic. Defaults to the host name
+    pub device_name: Option<String>,
+    pub logging: LoggingSection,
+    pub mqtt: MQTTSection,
+    pub debounce: DebounceSection,
+    pub analog: AnalogSection,
+    pub counters: CounterSection,
+    pub onewire: OneWireSection,
+    pub modbus_server: ModbusServerSection,
+    pub http: HttpSection,
+    pub stream: StreamSection,
+    pub gestures: GestureSection,
+    pub devices: std::vec::Vec<DeviceSection>,
+    pub covers: std::vec::Vec<CoverSection>,
+    pub interlocks: std::vec::Vec<InterlockSection>,
+    pub lights: std::vec::Vec<LightSection>,
+    pub dimmers: std::vec::Vec<DimmerSection>,
+    pub rules: std::vec::Vec<RuleSection>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            sysfs: "/run/unipi".to_string(),
+            boards: std::vec::Vec::new(),
+            device_name: None,
+            logging: LoggingSection::default(),
+            mqtt: MQTTSection::default(),
+            debounce: DebounceSection::default(),
+            analog: AnalogSection::default(),
+            counters: CounterSection::default(),
+            onewire: OneWireSection::default(),
+            modbus_server: ModbusServerSection::default(),
+            http: HttpSection::default(),
+            stream: StreamSection::default(),
+            gestures: GestureSection::default(),
+            devices: std::vec::Vec::new(),
+            covers: std::vec::Vec::new(),
+            interlocks: std::vec::Vec::new(),
+            lights: std::vec::Vec::new(),
+            dimmers: std::vec::Vec::new(),
+            rules: std::vec::Vec::new(),
+        }
+    }
+}
+
+/// Logging settings
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    // Default log level, overridden by `RUST_LOG`
+    pub level: String,
+}
+
+impl Default for LoggingSection {
+    fn default() -> Self {
+        LoggingSection {
+            level: "info".to_string(),
+        }
+    }
+}
+
+/// QoS and retain flag for a class of published messages
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PublishSection {
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// MQTT broker settings
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MQTTSection {
+    pub host: Option<String>,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    // At most one of the password sources can be given
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+    pub password_env: Option<String>,
+    // CA bundle to verify the broker against; enables TLS
+    pub ca_file: Option<String>,
+    // Client certificate and key for mutual TLS
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub clean_session: bool,
+    pub keep_alive_ms: u64,
+    // Bounds for the exponential backoff between reconnect attempts
+    pub reconnect_min_ms: u64,
+    pub reconnect_max_ms: u64,
+    // Defaults to `<device_name>/status`
+    pub availability_topic: Option<String>,
+    pub payload_online: String,
+    pub payload_offline: String,
+    // Home Assistant MQTT discovery
+    pub discovery: bool,
+    pub discovery_prefix: String,
+    pub inputs: PublishSection,
+    pub outputs: PublishSection,
+    pub relays: PublishSection,
+    pub events: PublishSection,
+    pub command_qos: u8,
+}
+
+impl Default for MQTTSection {
+    fn default() -> Self {
+        MQTTSection {
+            host: None,
+            port: 1883,
+            client_id: "hausmaus".to_string(),
+            username: None,
+            password: None,
+            password_file: None,
+            password_env: None,
+            ca_file: None,
+            client_cert: None,
+            client_key: None,
+            clean_session: true,
+            keep_alive_ms: 20_000,
+            reconnect_min_ms: 1000,
+            reconnect_max_ms: 60_000,
+            availability_topic: None,
+            payload_online: "online".to_string(),
+            payload_offline: "offline".to_string(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+            inputs: PublishSection {
+                qos: 1,
+                retain: true,
+            },
+            outputs: PublishSection {
+                qos: 1,
+                retain: true,
+            },
+            relays: PublishSection {
+                qos: 1,
+                retain: true,
+            },
+            events: PublishSection {
+                qos: 1,
+                retain: false,
+            },
+            command_qos: 1,
+        }
+    }
+}
+
+/// Global debounce settings; per input overrides go in the device table
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebounceSection {
+    pub stable_time_ms: u64,
+}
+
+impl Default for DebounceSection {
+    fn default() -> Self {
+        DebounceSection { stable_time_ms: 50 }
+    }
+}
+
+/// Global analog input settings; per device overrides go in the device table
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalogSection {
+    pub sample_interval_ms: u64,
+    // Minimal change before a new value is published, in the unit of the device
+    pub deadband: f64,
+}
+
+impl Default for AnalogSection {
+    fn default() -> Self {
+        AnalogSection {
+            sample_interval_ms: crate::analog::SAMPLE_INTERVAL,
+            deadband: 0.0,
+        }
+    }
+}
+
+/// Global counter settings
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CounterSection {
+    pub sample_interval_ms: u64,
+    // File the counter offsets are kept in across restarts; totals start from the raw hardware
+    // counts without one
+    pub state_file: Option<String>,
+}
+
+impl Default for CounterSection {
+    fn default() -> Self {
+        CounterSection {
+            sample_interval_ms: crate::counter::SAMPLE_INTERVAL,
+            state_file: None,
+        }
+    }
+}
+
+/// 1-Wire temperature sensor settings
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OneWireSection {
+    // Root of the 1-Wire devices in sysfs
+    pub path: String,
+    pub sample_interval_ms: u64,
+    // Names for and sensors to leave out, by 1-Wire ID; all other sensors found use their ID
+    pub sensors: std::vec::Vec<SensorSection>,
+}
+
+impl Default for OneWireSection {
+    fn default() -> Self {
+        OneWireSection {
+            path: crate::onewire::W1_PATH.to_string(),
+            sample_interval_ms: crate::onewire::SAMPLE_INTERVAL,
+            sensors: std::vec::Vec::new(),
+        }
+    }
+}
+
+/// Modbus TCP server settings
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModbusServerSection {
+    pub enabled: bool,
+    // Address and port to listen on; local only by default, as there is no authentication
+    pub bind: String,
+}
+
+impl Default for ModbusServerSection {
+    fn default() -> Self {
+        ModbusServerSection {
+            enabled: false,
+            bind: "127.0.0.1:502".to_string(),
+        }
+    }
+}
+
+/// HTTP API settings
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSection {
+    pub enabled: bool,
+    // Address and port to listen on; local only by default, as there is no authentication
+    pub bind: String,
+}
+
+impl Default for HttpSection {
+    fn default() -> Self {
+        HttpSection {
+            enabled: false,
+            bind: "127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+/// WebSocket live state stream settings
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamSection {
+    pub enabled: bool,
+    // Address and port to listen on; local only by default, as there is no authentication
+    pub bind: String,
+}
+
+impl Default for StreamSection {
+    fn default() -> Self {
+        StreamSection {
+            enabled: false,
+            bind: "127.0.0.1:8081".to_string(),
+        }
+    }
+}
+
+/// Settings for a single 1-Wire sensor, identified by its ID
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorSection {
+    pub id: String,
+    // Friendly name used in topics instead of the ID
+    pub name: Option<String>,
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+}
+
+/// Board with digital devices, along with the backend that drives it
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BoardSection {
+    // UniPi sysfs layout below `path`
+    Unipi {
+        path: String,
+    },
+    // Lines of a GPIO character device, numbered in the order given within `io_group`
+    GpioCdev {
+        chip: String,
+        io_group: i8,
+        #[serde(default)]
+        inputs: std::vec::Vec<u32>,
+        #[serde(default)]
+        outputs: std::vec::Vec<u32>,
+    },
+    // Coils, discrete inputs and registers of a unit of a Modbus TCP server at `address`
+    ModbusTcp {
+        address: String,
+        #[serde(default = "unit_id_default")]
+        unit_id: u8,
+        io_group: i8,
+        #[serde(default = "poll_interval_default")]
+        poll_interval_ms: u64,
+        #[serde(default = "timeout_default")]
+        timeout_ms: u64,
+        #[serde(default)]
+        coils: std::vec::Vec<u16>,
+        #[serde(default)]
+        discrete_inputs: std::vec::Vec<u16>,
+        #[serde(default)]
+        holding_registers: std::vec::Vec<u16>,
+        #[serde(default)]
+        input_registers: std::vec::Vec<u16>,
+    },
+}
+
+fn unit_id_default() -> u8 {
+    1
+}
+
+fn poll_interval_default() -> u64 {
+    100
+}
+
+fn timeout_default() -> u64 {
+    1000
+}
+
+/// Linear mapping from raw analog values to engineering units
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScaleSection {
+    pub raw_min: f64,
+    pub raw_max: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+/// Push button gesture thresholds
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GestureSection {
+    pub long_press_ms: u64,
+    pub multi_click_ms: u64,
+    pub hold_repeat_ms: u64,
+}
+
+impl Default for GestureSection {
+    fn default() -> Self {
+        GestureSection {
+            long_press_ms: 800,
+            multi_click_ms: 300,
+            hold_repeat_ms: 500,
+        }
+    }
+}
+
+/// Settings for a single device, identified by its hardware coordinates
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSection {
+    pub device_type: crate::device::DeviceType,
+    pub io_group: i8,
+    pub number: i8,
+    // Friendly name used in topics instead of the hardware coordinates
+    pub name: Option<String>,
+    pub state_topic: Option<String>,
+    pub command_topic: Option<String>,
+    #[serde(default)]
+    pub invert: bool,
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    pub model: Option<crate::device::Model>,
+    // Per input debounce stable time
+    pub debounce_ms: Option<u64>,
+    // Switch the output off again this long after every plain `ON`
+    pub staircase_ms: Option<u64>,
+    // Analog devices only: overrides of the global analog settings and scale
+    pub sample_interval_ms: Option<u64>,
+    pub deadband: Option<f64>,
+    pub scale: Option<ScaleSection>,
+    // Unit of analog values or of counter rates
+    pub unit: Option<String>,
+    // Counters only: rate published for one pulse per minute
+    pub rate_factor: Option<f64>,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+impl DeviceSection {
+    // Human readable reference for error messages, e.g. `relay 2_07`
+    fn label(&self) -> String {
+        format!(
+            "{} {}_{:02}",
+            self.device_type.as_str(),
+            self.io_group,
+            self.number
+        )
+    }
+
+    fn matches(&self, device: &crate::device::Device) -> bool {
+        self.device_type == device.device_type
+            && self.io_group == device.io_group
+            && self.number == device.number
+    }
+}
+
+/// Reference to a single device by its hardware coordinates
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceRef {
+    pub device_type: crate::device::DeviceType,
+    pub io_group: i8,
+    pub number: i8,
+}
+
+impl DeviceRef {
+    // Human readable reference for error messages, e.g. `relay 2_07`
+    fn label(&self) -> String {
+        format!(
+            "{} {}_{:02}",
+            self.device_type.as_str(),
+            self.io_group,
+            self.number
+        )
+    }
+
+    // Look up the referenced device
+    fn device<'a>(
+        &self,
+        devices: &'a [crate::device::Device],
+    ) -> Result<&'a crate::device::Device, crate::errors::MausError> {
+        devices
+            .iter()
+            .find(|device| {
+                self.device_type == device.device_type
+                    && self.io_group == device.io_group
+                    && self.number == device.number
+            })
+            .ok_or_else(|| {
+                crate::errors::MausError::new(format!(
+                    "Referenced device {} was not found",
+                    self.label()
+                ))
+            })
+    }
+
+    // Look up the ID of the referenced device
+    fn find(&self, devices: &[crate::device::Device]) -> Result<u8, crate::errors::MausError> {
+        self.device(devices).map(|device| device.id)
+    }
+}
+
+/// Roller shutter driven by an up and a down relay
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoverSection {
+    // Used in the cover topics
+    pub name: String,
+    pub up: DeviceRef,
+    pub down: DeviceRef,
+    // Time to travel all the way up or down
+    pub travel_up_ms: u64,
+    pub travel_down_ms: u64,
+    // The up and down relay always form an interlock group with this dead time
+    #[serde(default = "dead_time_default")]
+    pub dead_time_ms: u64,
+}
+
+/// Outputs of which at most one can be on at any time
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterlockSection {
+    pub devices: std::vec::Vec<DeviceRef>,
+    // Time an output needs to be off before another one in the group can be switched on
+    #[serde(default = "dead_time_default")]
+    pub dead_time_ms: u64,
+}
+
+fn dead_time_default() -> u64 {
+    500
+}
+
+/// Light on an output, toggled locally by its push buttons
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightSection {
+    pub output: DeviceRef,
+    pub buttons: std::vec::Vec<DeviceRef>,
+}
+
+/// Light dimmed through an analog output, driven locally by its push buttons
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DimmerSection {
+    // Used in the light topics
+    pub name: String,
+    // Analog output driving the dimmer
+    pub output: DeviceRef,
+    // Raw output value at full brightness, e.g. 10000 mV for a 0-10 V dimmer
+    #[serde(default = "max_value_default")]
+    pub max_value: u32,
+    #[serde(default)]
+    pub buttons: std::vec::Vec<DeviceRef>,
+    // Time to dim from off to full brightness while holding a button
+    #[serde(default = "dim_time_default")]
+    pub dim_time_ms: u64,
+}
+
+fn max_value_default() -> u32 {
+    10000
+}
+
+fn dim_time_default() -> u64 {
+    4000
+}
+
+/// Local automation rule: when the trigger fires and all conditions hold, run the actions
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSection {
+    pub name: String,
+    pub trigger: TriggerSection,
+    #[serde(default)]
+    pub conditions: std::vec::Vec<ConditionSection>,
+    pub actions: std::vec::Vec<ActionSection>,
+}
+
+/// What makes a rule fire
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TriggerSection {
+    // Any change of an input, or only changes to `state`
+    Input {
+        device: DeviceRef,
+        state: Option<bool>,
+    },
+    Gesture {
+        device: DeviceRef,
+        gesture: crate::gesture::Gesture,
+    },
+    // Any message on a topic, or only messages with `payload`
+    Message {
+        topic: String,
+        payload: Option<String>,
+    },
+    Timer {
+        name: String,
+    },
+}
+
+/// What needs to hold for a rule to fire
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ConditionSection {
+    State { device: DeviceRef, state: bool },
+    // Local time window in "HH:MM", wrapping around midnight when `after` is later than `before`
+    Time { after: String, before: String },
+}
+
+/// What a rule does
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ActionSection {
+    Set {
+        device: DeviceRef,
+        state: bool,
+    },
+    Toggle {
+        device: DeviceRef,
+    },
+    // Switch on and off again after the duration, restarting a running timer
+    Timed {
+        device: DeviceRef,
+        duration_ms: u64,
+    },
+    // Switch on once for the duration, without extending a running pulse
+    Pulse {
+        device: DeviceRef,
+        duration_ms: u64,
+    },
+    Publish {
+        topic: String,
+        payload: String,
+        #[serde(default)]
+        retain: bool,
+    },
+    StartTimer {
+        name: String,
+        duration_ms: u64,
+    },
+}
+
+// Parse a local time of day in "HH:MM" to minutes since midnight
+fn parse_time_of_day(time: &str) -> Result<u32, crate::errors::MausError> {
+    let invalid =
+        || crate::errors::MausError::new(format!("Invalid time {:?}: expected HH:MM", time));
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+// Check the address a server listens on, if it is enabled
+fn validate_bind(bind: &str, enabled: bool, what: &str) -> Result<(), crate::errors::MausError> {
+    if enabled && bind.parse::<std::net::SocketAddr>().is_err() {
+        return Err(crate::errors::MausError::new(format!(
+            "Invalid {} address {:?}: expected ip:port",
+            what, bind
+        )));
+    }
+    Ok(())
+}
+
+// Check the io_group of the devices of a board
+fn validate_board_io_group(io_group: i8, label: &str) -> Result<(), crate::errors::MausError> {
+    if !(1..=3).contains(&io_group) {
+        return Err(crate::errors::MausError::new(format!(
+            "Invalid io_group {} for {}: must be 1 to 3",
+            io_group, label
+        )));
+    }
+    Ok(())
+}
+
+// Check a QoS level
+fn validate_qos(qos: u8, what: &str) -> Result<(), crate::errors::MausError> {
+    if qos > 2 {
+        return Err(crate::errors::MausError::new(format!(
+            "Invalid QoS {} for {}: expected 0, 1 or 2",
+            qos, what
+        )));
+    }
+    Ok(())
+}
+
+// Check a topic or name segment does not contain MQTT wildcards
+fn validate_topic(topic: &str, what: &str) -> Result<(), crate::errors::MausError> {
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        return Err(crate::errors::MausError::new(format!(
+            "Invalid topic {:?} for {}: must be non-empty and must not contain wildcards",
+            topic, what
+        )));
+    }
+    Ok(())
+}
+
+// Look up the IDs of all referenced devices
+fn find_all(
+    device_refs: &[DeviceRef],
+    devices: &[crate::device::Device],
+) -> Result<std::vec::Vec<u8>, crate::errors::MausError> {
+    device_refs
+        .iter()
+        .map(|device_ref| device_ref.find(devices))
+        .collect()
+}
+
+// Check the counter settings of a device
+fn validate_counter(
+    device_section: &DeviceSection,
+    label: &str,
+) -> Result<(), crate::errors::MausError> {
+    if device_section.device_type != crate::device::DeviceType::Counter {
+        if device_section.rate_factor.is_some() {
+            return Err(crate::errors::MausError::new(format!(
+                "Rate factor can only be configured for counters, not for device {}",
+                label
+            )));
+        }
+        return Ok(());
+    }
+    if device_section.invert {
+        return Err(crate::errors::MausError::new(format!(
+            "Counter {} can not be inverted",
+            label
+        )));
+    }
+    if device_section
+        .rate_factor
+        .is_some_and(|rate_factor| !rate_factor.is_finite() || rate_factor <= 0.0)
+    {
+        return Err(crate::errors::MausError::new(format!(
+            "Counter {} needs a positive rate factor",
+            label
+        )));
+    }
+    Ok(())
+}
+
+// Check the buttons bound to a light are all inputs
+fn validate_buttons(buttons: &[DeviceRef], what: &str) -> Result<(), crate::errors::MausError> {
+    for button in buttons {
+        if button.device_type != crate::device::DeviceType::DigitalInput {
+            return Err(crate::errors::MausError::new(format!(
+                "Light {} can not use {} as a button",
+                what,
+                button.label()
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Build publish options from a validated publish config
+fn publish_options(publish_section: &PublishSection) -> crate::mqtt::PublishOptions {
+    crate::mqtt::PublishOptions::new(
+        rumqttc::qos(publish_section.qos).unwrap(),
+        publish_section.retain,
+    )
+}
+
+impl Config {
+    /// Parse a configuration from a TOML string
+    pub fn from_toml(contents: &str) -> Result<Self, crate::errors::MausError> {
+        toml::from_str(contents).map_err(|e| {
+            crate::errors::MausError::new(format!("Could not parse configuration: {}", e))
+        })
+    }
+
+    /// Read and parse a configuration file
+    pub fn from_file(path: &str) -> Result<Self, crate::errors::MausError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            crate::errors::MausError::new(format!(
+                "Could not read configuration file {}: {}",
+                path, e
+            ))
+        })?;
+        toml::from_str(&contents).map_err(|e| {
+            crate::errors::MausError::new(format!(
+                "Could not parse configuration file {}: {}",
+                path, e
+            ))
+        })
+    }
+
+    /// Check the configuration for settings that can not work
+    pub fn validate(&self) -> Result<(), crate::errors::MausError> {
+        if self.mqtt.host.is_none() {
+            return Err(crate::errors::MausError::new(
+                "No MQTT broker host given".to_string(),
+            ));
+        }
+        if !LOG_LEVELS.contains(&self.logging.level.as_str()) {
+            return Err(crate::errors::MausError::new(format!(
+                "Invalid log level {:?}: expected one of {}",
+                self.logging.level,
+                LOG_LEVELS.join(", ")
+            )));
+        }
+
+        self.validate_connection()?;
+        self.validate_boards()?;
+        validate_qos(self.mqtt.inputs.qos, "inputs")?;
+        validate_qos(self.mqtt.outputs.qos, "outputs")?;
+        validate_qos(self.mqtt.relays.qos, "relays")?;
+        validate_qos(self.mqtt.events.qos, "events")?;
+        validate_qos(self.mqtt.command_qos, "commands")?;
+        if let Some(availability_topic) = &self.mqtt.availability_topic {
+            validate_topic(availability_topic, "availability")?;
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        let mut names = std::collections::HashSet::new();
+        for device_section in &self.devices {
+            let label = device_section.label();
+            if !(1..=3).contains(&device_section.io_group)
+                || !(1..=99).contains(&device_section.number)
+            {
+                return Err(crate::errors::MausError::new(format!(
+                    "Invalid device {}: io_group must be 1 to 3 and number 1 to 99",
+                    label
+                )));
+            }
+            if !seen.insert(label.clone()) {
+                return Err(crate::errors::MausError::new(format!(
+                    "Device {} is configured more than once",
+                    label
+                )));
+            }
+            if let Some(name) = &device_section.name {
+                if name.is_empty() || name.contains(['/', '+', '#', ' ']) {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Invalid name {:?} for device {}: must be non-empty without '/', '+', '#' or spaces",
+                        name, label
+                    )));
+                }
+                if !names.insert((device_section.device_type.clone(), name.clone())) {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Name {:?} is used for more than one {}",
+                        name,
+                        device_section.device_type.as_str()
+                    )));
+                }
+            }
+            if let Some(state_topic) = &device_section.state_topic {
+                validate_topic(state_topic, &label)?;
+            }
+            if let Some(command_topic) = &device_section.command_topic {
+                validate_topic(command_topic, &label)?;
+            }
+            if let Some(model) = &device_section.model {
+                if !model.supports(&device_section.device_type) {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Model {:?} can not be used for device {}",
+                        model, label
+                    )));
+                }
+            }
+            if device_section.debounce_ms.is_some()
+                && device_section.device_type != crate::device::DeviceType::DigitalInput
+            {
+                return Err(crate::errors::MausError::new(format!(
+                    "Debounce can only be configured for inputs, not for device {}",
+                    label
+                )));
+            }
+            match device_section.staircase_ms {
+                Some(_)
+                    if device_section.device_type == crate::device::DeviceType::DigitalInput =>
+                {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Staircase can only be configured for outputs, not for device {}",
+                        label
+                    )));
+                }
+                Some(0) => {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Staircase time of device {} needs to be positive",
+                        label
+                    )));
+                }
+                _ => {}
+            }
+            self.validate_analog(device_section, &label)?;
+            validate_counter(device_section, &label)?;
+        }
+        if self.counters.sample_interval_ms == 0 {
+            return Err(crate::errors::MausError::new(
+                "Counters need a positive sample interval".to_string(),
+            ));
+        }
+        self.validate_sensors()?;
+        validate_bind(
+            &self.modbus_server.bind,
+            self.modbus_server.enabled,
+            "Modbus server",
+        )?;
+        validate_bind(&self.http.bind, self.http.enabled, "HTTP API")?;
+        validate_bind(&self.stream.bind, self.stream.enabled, "stream")?;
+        self.validate_covers()?;
+        self.validate_interlocks()?;
+        self.validate_lights()?;
+        self.validate_rules()
+    }
+
+    // Check the rule table
+    fn validate_rules(&self) -> Result<(), crate::errors::MausError> {
+        let started_timers: std::collections::HashSet<&String> = self
+            .rules
+            .iter()
+            .flat_map(|rule_section| &rule_section.actions)
+            .filter_map(|action_section| match action_section {
+                ActionSection::StartTimer { name, .. } => Some(name),
+                _ => None,
+            })
+            .collect();
+        let mut names = std::collections::HashSet::new();
+        for rule_section in &self.rules {
+            let name = &rule_section.name;
+            if name.is_empty() {
+                return Err(crate::errors::MausError::new(
+                    "Rules need a non-empty name".to_string(),
+                ));
+            }
+            if !names.insert(name.clone()) {
+                return Err(crate::errors::MausError::new(format!(
+                    "Rule name {:?} is used more than once",
+                    name
+                )));
+            }
+            let invalid = |reason: String| {
+                crate::errors::MausError::new(format!("Invalid rule {:?}: {}", name, reason))
+            };
+            match &rule_section.trigger {
+                TriggerSection::Input { device, .. } | TriggerSection::Gesture { device, .. }
+                    if device.device_type != crate::device::DeviceType::DigitalInput =>
+                {
+                    return Err(invalid(format!(
+                        "{} can not be used as a trigger",
+                        device.label()
+                    )));
+                }
+                TriggerSection::Message { topic, .. } => {
+                    validate_topic(topic, &format!("rule {:?}", name))?
+                }
+                TriggerSection::Timer { name: timer } if !started_timers.contains(timer) => {
+                    return Err(invalid(format!("timer {:?} is never started", timer)));
+                }
+                _ => {}
+            }
+            for condition_section in &rule_section.conditions {
+                match condition_section {
+                    ConditionSection::State { device, .. } if !device.device_type.is_digital() => {
+                        return Err(invalid(format!(
+                            "{} has no on or off state",
+                            device.label()
+                        )));
+                    }
+                    ConditionSection::Time { after, before } => {
+                        parse_time_of_day(after)?;
+                        parse_time_of_day(before)?;
+                    }
+                    _ => {}
+                }
+            }
+            if rule_section.actions.is_empty() {
+                return Err(invalid("needs at least one action".to_string()));
+            }
+            for action_section in &rule_section.actions {
+                match action_section {
+                    ActionSection::Set { device, .. }
+                    | ActionSection::Toggle { device }
+                    | ActionSection::Timed { device, .. }
+                    | ActionSection::Pulse { device, .. }
+                        if !device.device_type.is_switch() =>
+                    {
+                        return Err(invalid(format!("{} can not be switched", device.label())));
+                    }
+                    ActionSection::Publish { topic, .. } => {
+                        validate_topic(topic, &format!("rule {:?}", name))?
+                    }
+                    ActionSection::Timed { duration_ms: 0, .. }
+                    | ActionSection::Pulse { duration_ms: 0, .. }
+                    | ActionSection::StartTimer { duration_ms: 0, .. } => {
+                        return Err(invalid("durations need to be positive".to_string()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Check the board table
+    fn validate_boards(&self) -> Result<(), crate::errors::MausError> {
+        let mut lines = std::collections::HashSet::new();
+        let mut units = std::collections::HashSet::new();
+        for board_section in &self.boards {
+            match board_section {
+                BoardSection::Unipi { .. } => {}
+                BoardSection::GpioCdev {
+                    chip,
+                    io_group,
+                    inputs,
+                    outputs,
+                } => {
+                    let label = format!("GPIO chip {}", chip);
+                    validate_board_io_group(*io_group, &label)?;
+                    if inputs.len() > 99 || outputs.len() > 99 {
+                        return Err(crate::errors::MausError::new(format!(
+                            "{} can have at most 99 inputs and 99 outputs per board",
+                            label
+                        )));
+                    }
+                    for offset in inputs.iter().chain(outputs) {
+                        if !lines.insert((chip, offset)) {
+                            return Err(crate::errors::MausError::new(format!(
+                                "Line {} of {} is configured more than once",
+                                offset, label
+                            )));
+                        }
+                    }
+                }
+                BoardSection::ModbusTcp {
+                    address,
+                    unit_id,
+                    io_group,
+                    poll_interval_ms,
+                    timeout_ms,
+                    coils,
+                    discrete_inputs,
+                    holding_registers,
+                    input_registers,
+                } => {
+                    let label = format!("Modbus server {} unit {}", address, unit_id);
+                    if !address
+                        .rsplit_once(':')
+                        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+                    {
+                        return Err(crate::errors::MausError::new(format!(
+                            "Invalid Modbus address {:?}: expected host:port",
+                            address
+                        )));
+                    }
+                    if !units.insert((address, unit_id)) {
+                        return Err(crate::errors::MausError::new(format!(
+                            "{} is configured more than once",
+                            label
+                        )));
+                    }
+                    validate_board_io_group(*io_group, &label)?;
+                    if *poll_interval_ms == 0 || *timeout_ms == 0 {
+                        return Err(crate::errors::MausError::new(format!(
+                            "{} needs a positive poll interval and timeout",
+                            label
+                        )));
+                    }
+                    for (table, addresses) in [
+                        ("coil", coils),
+                        ("discrete input", discrete_inputs),
+                        ("holding register", holding_registers),
+                        ("input register", input_registers),
+                    ] {
+                        if addresses.len() > 99 {
+                            return Err(crate::errors::MausError::new(format!(
+                                "{} can have at most 99 of every table",
+                                label
+                            )));
+                        }
+                        let mut seen = std::collections::HashSet::new();
+                        if let Some(address) =
+                            addresses.iter().find(|&address| !seen.insert(address))
+                        {
+                            return Err(crate::errors::MausError::new(format!(
+                                "{} {} of {} is configured more than once",
+                                table, address, label
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Check the 1-Wire settings
+    fn validate_sensors(&self) -> Result<(), crate::errors::MausError> {
+        if self.onewire.sample_interval_ms == 0 {
+            return Err(crate::errors::MausError::new(
+                "1-Wire sensors need a positive sample interval".to_string(),
+            ));
+        }
+        let mut ids = std::collections::HashSet::new();
+        let mut names = std::collections::HashSet::new();
+        for sensor_section in &self.onewire.sensors {
+            if !ids.insert(&sensor_section.id) {
+                return Err(crate::errors::MausError::new(format!(
+                    "Sensor {} is configured more than once",
+                    sensor_section.id
+                )));
+            }
+            if let Some(name) = &sensor_section.name {
+                if name.is_empty() || name.contains(['/', '+', '#', ' ']) {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Invalid name {:?} for sensor {}: must be non-empty without '/', '+', '#' or spaces",
+                        name, sensor_section.id
+                    )));
+                }
+                if !names.insert(name) {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Name {:?} is used for more than one sensor",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Check the analog settings of a device and the global ones they override
+    fn validate_analog(
+        &self,
+        device_section: &DeviceSection,
+        label: &str,
+    ) -> Result<(), crate::errors::MausError> {
+        let has_analog_settings = device_section.sample_interval_ms.is_some()
+            || device_section.deadband.is_some()
+            || device_section.scale.is_some()
+            || (device_section.unit.is_some()
+                && device_section.device_type != crate::device::DeviceType::Counter);
+        if !device_section.device_type.is_analog() {
+            if has_analog_settings {
+                return Err(crate::errors::MausError::new(format!(
+                    "Analog settings can only be configured for analog devices, not for device {}",
+                    label
+                )));
+            }
+            return Ok(());
+        }
+        if device_section.invert {
+            return Err(crate::errors::MausError::new(format!(
+                "Analog device {} can not be inverted",
+                label
+            )));
+        }
+        let sample_interval_ms = device_section
+            .sample_interval_ms
+            .unwrap_or(self.analog.sample_interval_ms);
+        let deadband = device_section.deadband.unwrap_or(self.analog.deadband);
+        if sample_interval_ms == 0 || deadband.is_nan() || deadband < 0.0 {
+            return Err(crate::errors::MausError::new(format!(
+                "Analog device {} needs a positive sample interval and deadband",
+                label
+            )));
+        }
+        if let Some(scale) = &device_section.scale {
+            if scale.raw_min == scale.raw_max || scale.min == scale.max {
+                return Err(crate::errors::MausError::new(format!(
+                    "Invalid scale for device {}: the ranges can not be empty",
+                    label
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Check the light and dimmer tables
+    fn validate_lights(&self) -> Result<(), crate::errors::MausError> {
+        for light_section in &self.lights {
+            if !light_section.output.device_type.is_switch() {
+                return Err(crate::errors::MausError::new(format!(
+                    "Light can not use {} as an output",
+                    light_section.output.label()
+                )));
+            }
+            validate_buttons(&light_section.buttons, &light_section.output.label())?;
+        }
+        let mut names = std::collections::HashSet::new();
+        for dimmer_section in &self.dimmers {
+            let name = &dimmer_section.name;
+            if name.is_empty() || name.contains(['/', '+', '#', ' ']) {
+                return Err(crate::errors::MausError::new(format!(
+                    "Invalid dimmer name {:?}: must be non-empty without '/', '+', '#' or spaces",
+                    name
+                )));
+            }
+            if !names.insert(name.clone()) {
+                return Err(crate::errors::MausError::new(format!(
+                    "Dimmer name {:?} is used more than once",
+                    name
+                )));
+            }
+            if dimmer_section.output.device_type != crate::device::DeviceType::AnalogOutput {
+                return Err(crate::errors::MausError::new(format!(
+                    "Dimmer {:?} can not use {} as an output",
+                    name,
+                    dimmer_section.output.label()
+                )));
+            }
+            if dimmer_section.max_value == 0 {
+                return Err(crate::errors::MausError::new(format!(
+                    "Dimmer {:?} needs a positive max_value",
+                    name
+                )));
+            }
+            validate_buttons(&dimmer_section.buttons, name)?;
+        }
+        Ok(())
+    }
+
+    // Check the interlock table
+    fn validate_interlocks(&self) -> Result<(), crate::errors::MausError> {
+        for interlock_section in &self.interlocks {
+            let labels: std::vec::Vec<String> = interlock_section
+                .devices
+                .iter()
+                .map(|device_ref| device_ref.label())
+                .collect();
+            if labels.len() < 2 {
+                return Err(crate::errors::MausError::new(format!(
+                    "Interlock group [{}] needs at least two devices",
+                    labels.join(", ")
+                )));
+            }
+            for (index, device_ref) in interlock_section.devices.iter().enumerate() {
+                if !device_ref.device_type.is_switch() {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Interlock group [{}] can not contain {}",
+                        labels.join(", "),
+                        labels[index]
+                    )));
+                }
+                if labels[..index].contains(&labels[index]) {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Interlock group [{}] contains {} more than once",
+                        labels.join(", "),
+                        labels[index]
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Check the MQTT connection settings
+    fn validate_connection(&self) -> Result<(), crate::errors::MausError> {
+        if self.mqtt.port == 0 {
+            return Err(crate::errors::MausError::new(
+                "Invalid MQTT port 0".to_string(),
+            ));
+        }
+        // The keep alive is sent in whole seconds and rumqttc requires at least 5
+        if self.mqtt.keep_alive_ms < 5000 {
+            return Err(crate::errors::MausError::new(format!(
+                "Invalid MQTT keep alive {} ms: must be at least 5000 ms",
+                self.mqtt.keep_alive_ms
+            )));
+        }
+        if self.mqtt.reconnect_min_ms == 0
+            || self.mqtt.reconnect_min_ms > self.mqtt.reconnect_max_ms
+        {
+            return Err(crate::errors::MausError::new(format!(
+                "Invalid MQTT reconnect delays {} to {} ms: minimum must be positive and at most the maximum",
+                self.mqtt.reconnect_min_ms, self.mqtt.reconnect_max_ms
+            )));
+        }
+        let password_sources = [
+            &self.mqtt.password,
+            &self.mqtt.password_file,
+            &self.mqtt.password_env,
+        ]
+        .iter()
+        .filter(|source| source.is_some())
+        .count();
+        if password_sources > 1 {
+            return Err(crate::errors::MausError::new(
+                "Only one of the MQTT password, password_file and password_env can be given"
+                    .to_string(),
+            ));
+        }
+        if password_sources > 0 && self.mqtt.username.is_none() {
+            return Err(crate::errors::MausError::new(
+                "An MQTT password needs a username".to_string(),
+            ));
+        }
+        if self.mqtt.client_cert.is_some() != self.mqtt.client_key.is_some() {
+            return Err(crate::errors::MausError::new(
+                "The MQTT client certificate and key need to be given together".to_string(),
+            ));
+        }
+        if self.mqtt.client_cert.is_some() && self.mqtt.ca_file.is_none() {
+            return Err(crate::errors::MausError::new(
+                "An MQTT client certificate needs a CA file to enable TLS".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Check the cover table
+    fn validate_covers(&self) -> Result<(), crate::errors::MausError> {
+        let mut names = std::collections::HashSet::new();
+        let mut motors = std::collections::HashSet::new();
+        for cover_section in &self.covers {
+            let name = &cover_section.name;
+            if name.is_empty() || name.contains(['/', '+', '#', ' ']) {
+                return Err(crate::errors::MausError::new(format!(
+                    "Invalid cover name {:?}: must be non-empty without '/', '+', '#' or spaces",
+                    name
+                )));
+            }
+            if !names.insert(name.clone()) {
+                return Err(crate::errors::MausError::new(format!(
+                    "Cover name {:?} is used more than once",
+                    name
+                )));
+            }
+            for motor in [&cover_section.up, &cover_section.down] {
+                if !motor.device_type.is_switch() {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Cover {:?} can not use {} as a motor",
+                        name,
+                        motor.label()
+                    )));
+                }
+                if !motors.insert(motor.label()) {
+                    return Err(crate::errors::MausError::new(format!(
+                        "Device {} is used for more than one cover motor",
+                        motor.label()
+                    )));
+                }
+            }
+            if cover_section.travel_up_ms == 0 || cover_section.travel_down_ms == 0 {
+                return Err(crate::errors::MausError::new(format!(
+                    "Cover {:?} needs a travel time for both directions",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the device table to a list of crawled devices
+    ///
+    /// Disabled devices are dropped from the list. Configured devices that were not found are
+    /// reported as an error, since they most likely point to a typo.
+    pub fn apply_devices(
+        &self,
+        devices: &mut std::vec::Vec<crate::device::Device>,
+    ) -> Result<(), crate::errors::MausError> {
+        for device_section in &self.devices {
+            let device = devices
+                .iter_mut()
+                .find(|device| device_section.matches(device))
+                .ok_or_else(|| {
+                    crate::errors::MausError::new(format!(
+                        "Configured device {} was not found",
+                        device_section.label()
+                    ))
+                })?;
+            device.settings = crate::device::DeviceSettings {
+                name: device_section.name.clone(),
+                state_topic: device_section.state_topic.clone(),
+                command_topic: device_section.command_topic.clone(),
+                invert: device_section.invert,
+                model: device_section.model.clone(),
+            };
+        }
+        // Counters are only used when configured, every digital input has one
+        devices.retain(|device| {
+            match self
+                .devices
+                .iter()
+                .find(|device_section| device_section.matches(device))
+            {
+                Some(device_section) => device_section.enabled,
+                None => device.device_type != crate::device::DeviceType::Counter,
+            }
+        });
+        Ok(())
+    }
+
+    /// Covers from the cover table, linked to the (enabled) devices driving them
+    pub fn covers(
+        &self,
+        devices: &[crate::device::Device],
+    ) -> Result<std::vec::Vec<crate::models::Cover>, crate::errors::MausError> {
+        self.covers
+            .iter()
+            .map(|cover_section| {
+                Ok(crate::models::Cover::new(
+                    &cover_section.name,
+                    cover_section.up.find(devices)?,
+                    cover_section.down.find(devices)?,
+                    crate::models::CoverSettings {
+                        travel_up: std::time::Duration::from_millis(cover_section.travel_up_ms),
+                        travel_down: std::time::Duration::from_millis(cover_section.travel_down_ms),
+                        dead_time: std::time::Duration::from_millis(cover_section.dead_time_ms),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// Interlock groups from the interlock table and the covers, by device ID
+    pub fn interlock_groups(
+        &self,
+        devices: &[crate::device::Device],
+    ) -> Result<std::vec::Vec<crate::interlock::InterlockGroup>, crate::errors::MausError> {
+        let mut groups = std::vec::Vec::new();
+        for interlock_section in &self.interlocks {
+            groups.push(crate::interlock::InterlockGroup {
+                devices: interlock_section
+                    .devices
+                    .iter()
+                    .map(|device_ref| device_ref.find(devices))
+                    .collect::<Result<_, _>>()?,
+                dead_time: std::time::Duration::from_millis(interlock_section.dead_time_ms),
+            });
+        }
+        for cover_section in &self.covers {
+            groups.push(crate::interlock::InterlockGroup {
+                devices: vec![
+                    cover_section.up.find(devices)?,
+                    cover_section.down.find(devices)?,
+                ],
+                dead_time: std::time::Duration::from_millis(cover_section.dead_time_ms),
+            });
+        }
+        Ok(groups)
+    }
+
+    /// Lights from the light table, bound to their buttons
+    pub fn lights(
+        &self,
+        devices: &[crate::device::Device],
+    ) -> Result<std::vec::Vec<crate::models::Light>, crate::errors::MausError> {
+        self.lights
+            .iter()
+            .map(|light_section| {
+                Ok(crate::models::Light {
+                    output: light_section.output.device(devices)?.clone(),
+                    buttons: find_all(&light_section.buttons, devices)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Dimmable lights from the dimmer table, bound to their buttons
+    pub fn dimmers(
+        &self,
+        devices: &[crate::device::Device],
+    ) -> Result<std::vec::Vec<crate::models::DimmableLight>, crate::errors::MausError> {
+        self.dimmers
+            .iter()
+            .map(|dimmer_section| {
+                Ok(crate::models::DimmableLight::new(
+                    &dimmer_section.name,
+                    dimmer_section.output.device(devices)?.clone(),
+                    dimmer_section.max_value,
+                    find_all(&dimmer_section.buttons, devices)?,
+                    std::time::Duration::from_millis(dimmer_section.dim_time_ms),
+                ))
+            })
+            .collect()
+    }
+
+    /// Settings of the (enabled) analog devices, by device ID
+    pub fn analog_settings(
+        &self,
+        devices: &[crate::device::Device],
+    ) -> std::collections::HashMap<u8, crate::analog::AnalogSettings> {
+        devices
+            .iter()
+            .filter(|device| device.device_type.is_analog())
+            .map(|device| {
+                let device_section = self
+                    .devices
+                    .iter()
+                    .find(|device_section| device_section.matches(device));
+                let settings = crate::analog::AnalogSettings {
+                    interval: std::time::Duration::from_millis(
+                        device_section
+                            .and_then(|device_section| device_section.sample_interval_ms)
+                            .unwrap_or(self.analog.sample_interval_ms),
+                    ),
+                    deadband: device_section
+                        .and_then(|device_section| device_section.deadband)
+                        .unwrap_or(self.analog.deadband),
+                    scale: device_section
+                        .and_then(|device_section| device_section.scale.as_ref())
+                        .map(|scale| crate::analog::Scale {
+                            raw_min: scale.raw_min,
+                            raw_max: scale.raw_max,
+                            min: scale.min,
+                            max: scale.max,
+                        }),
+                    unit: device_section.and_then(|device_section| device_section.unit.clone()),
+                };
+                (device.id, settings)
+            })
+            .collect()
+    }
+
+    /// Settings of the (configured) counters, by device ID
+    pub fn counter_settings(
+        &self,
+        devices: &[crate::device::Device],
+    ) -> std::collections::HashMap<u8, crate::counter::CounterSettings> {
+        devices
+            .iter()
+            .filter(|device| device.device_type == crate::device::DeviceType::Counter)
+            .filter_map(|device| {
+                let device_section = self
+                    .devices
+                    .iter()
+                    .find(|device_section| device_section.matches(device))?;
+                let settings = crate::counter::CounterSettings {
+                    rate_factor: device_section.rate_factor.unwrap_or(1.0),
+                    unit: device_section.unit.clone(),
+                };
+                Some((device.id, settings))
+            })
+            .collect()
+    }
+
+    /// Backends for the board table, in order
+    pub fn backends(&self) -> std::vec::Vec<std::sync::Arc<dyn crate::backend::IoBackend>> {
+        if self.boards.is_empty() {
+            return vec![std::sync::Arc::new(crate::backend::unipi::UniPi::new(
+                &self.sysfs,
+            ))];
+        }
+        self.boards
+            .iter()
+            .map(
+                |board_section| -> std::sync::Arc<dyn crate::backend::IoBackend> {
+                    match board_section {
+                        BoardSection::Unipi { path } => {
+                            std::sync::Arc::new(crate::backend::unipi::UniPi::new(path))
+                        }
+                        BoardSection::GpioCdev {
+                            chip,
+                            io_group,
+                            inputs,
+                            outputs,
+                        } => std::sync::Arc::new(crate::backend::gpio::Gpio::new(
+                            chip, *io_group, inputs, outputs,
+                        )),
+                        BoardSection::ModbusTcp {
+                            address,
+                            unit_id,
+                            io_group,
+                            poll_interval_ms,
+                            timeout_ms,
+                            coils,
+                            discrete_inputs,
+                            holding_registers,
+                            input_registers,
+                        } => std::sync::Arc::new(crate::backend::modbus::Modbus::new(
+                            address,
+                            crate::backend::modbus::ModbusSettings {
+                                unit_id: *unit_id,
+                                io_group: *io_group,
+                                poll_interval: std::time::Duration::from_millis(*poll_interval_ms),
+                                timeout: std::time::Duration::from_millis(*timeout_ms),
+                                coils: coils.clone(),
+                                discrete_inputs: discrete_inputs.clone(),
+                                holding_registers: holding_registers.clone(),
+                                input_registers: input_registers.clone(),
+                            },
+                        )),
+                    }
+                },
+            )
+            .collect()
+    }
+
+    /// Sensors for the 1-Wire IDs found, named and filtered by the sensor table
+    ///
+    /// Sensors come and go with the bus, so configured sensors which were not found are only
+    /// logged.
+    pub fn sensors(&self, ids: &[String]) -> std::vec::Vec<crate::onewire::Sensor> {
+        for sensor_section in &self.onewire.sensors {
+            if !ids.contains(&sensor_section.id) {
+                log::warn!("Configured sensor {} was not found", sensor_section.id);
+            }
+        }
+        ids.iter()
+            .filter_map(|id| {
+                let sensor_section = self
+                    .onewire
+                    .sensors
+                    .iter()
+                    .find(|sensor_section| sensor_section.id == *id);
+                if sensor_section.is_some_and(|sensor_section| !sensor_section.enabled) {
+                    return None;
+                }
+                Some(crate::onewire::Sensor {
+                    id: id.clone(),
+                    name: sensor_section
+                        .and_then(|sensor_section| sensor_section.name.clone())
+                        .unwrap_or_else(|| id.clone()),
+                    path: format!("{}/{}", self.onewire.path, id),
+                })
+            })
+            .collect()
+    }
+
+    /// Staircase times of the (enabled) outputs from the device table, by device ID
+    pub fn staircases(
+        &self,
+        devices: &[crate::device::Device],
+    ) -> std::collections::HashMap<u8, std::time::Duration> {
+        self.devices
+            .iter()
+            .filter_map(|device_section| {
+                let millis = device_section.staircase_ms?;
+                let device = devices
+                    .iter()
+                    .find(|device| device_section.matches(device))?;
+                Some((device.id, std::time::Duration::from_millis(millis)))
+            })
+            .collect()
+    }
+
+    /// Rules from the rule table, linked to the (enabled) devices they use
+    pub fn rules(
+        &self,
+        devices: &[crate::device::Device],
+    ) -> Result<std::vec::Vec<crate::auto::Rule>, crate::errors::MausError> {
+        self.rules
+            .iter()
+            .map(|rule_section| {
+                let trigger = match &rule_section.trigger {
+                    TriggerSection::Input { device, state } => {
+                        crate::auto::Trigger::Input(device.find(devices)?, *state)
+                    }
+                    TriggerSection::Gesture { device, gesture } => {
+                        crate::auto::Trigger::Gesture(device.find(devices)?, *gesture)
+                    }
+                    TriggerSection::Message { topic, payload } => {
+                        crate::auto::Trigger::Message(topic.clone(), payload.clone())
+                    }
+                    TriggerSection::Timer { name } => crate::auto::Trigger::Timer(name.clone()),
+                };
+                let conditions = rule_section
+                    .conditions
+                    .iter()
+                    .map(|condition_section| {
+                        Ok(match condition_section {
+                            ConditionSection::State { device, state } => {
+                                crate::auto::Condition::State(
+                                    device.device(devices)?.clone(),
+                                    *state,
+                                )
+                            }
+                            ConditionSection::Time { after, before } => {
+                                crate::auto::Condition::Time(
+                                    parse_time_of_day(after)?,
+                                    parse_time_of_day(before)?,
+                                )
+                            }
+                        })
+                    })
+                    .collect::<Result<_, crate::errors::MausError>>()?;
+                let actions = rule_section
+                    .actions
+                    .iter()
+                    .map(|action_section| {
+                        Ok(match action_section {
+                            ActionSection::Set { device, state } => {
+                                crate::auto::Action::Set(device.find(devices)?, *state)
+                            }
+                            ActionSection::Toggle { device } => {
+                                crate::auto::Action::Toggle(device.device(devices)?.clone())
+                            }
+                            ActionSection::Timed {
+                                device,
+                                duration_ms,
+                            } => crate::auto::Action::Timed(
+                                device.find(devices)?,
+                                std::time::Duration::from_millis(*duration_ms),
+                            ),
+                            ActionSection::Pulse {
+                                device,
+                                duration_ms,
+                            } => crate::auto::Action::Pulse(
+                                device.find(devices)?,
+                                std::time::Duration::from_millis(*duration_ms),
+                            ),
+                            ActionSection::Publish {
+                                topic,
+                                payload,
+                                retain,
+                            } => crate::auto::Action::Publish(
+                                topic.clone(),
+                                payload.clone(),
+                                *retain,
+                            ),
+                            ActionSection::StartTimer { name, duration_ms } => {
+                                crate::auto::Action::StartTimer(
+                                    name.clone(),
+                                    std::time::Duration::from_millis(*duration_ms),
+                                )
+                            }
+                        })
+                    })
+                    .collect::<Result<_, crate::errors::MausError>>()?;
+                Ok(crate::auto::Rule {
+                    name: rule_section.name.clone(),
+                    trigger,
+                    conditions,
+                    actions,
+                })
+            })
+            .collect()
+    }
+
+    /// Name used for the root MQTT topic
+    pub fn device_name(&self) -> &str {
+        self.device_name.as_deref().unwrap_or("hausmaus")
+    }
+
+    /// Runtime MQTT settings
+    pub fn mqtt_config(&self) -> crate::mqtt::MQTTConfig {
+        let mut availability = crate::mqtt::Availability::new(self.device_name());
+        if let Some(availability_topic) = &self.mqtt.availability_topic {
+            availability.topic = availability_topic.clone();
+        }
+        availability.payload_online = self.mqtt.payload_online.clone();
+        availability.payload_offline = self.mqtt.payload_offline.clone();
+
+        let mut mqtt_config = crate::mqtt::MQTTConfig::new(
+            self.mqtt.host.as_deref().unwrap_or_default(),
+            &self.mqtt.client_id,
+            availability,
+        );
+        mqtt_config.port = self.mqtt.port;
+        mqtt_config.credentials =
+            self.mqtt
+                .username
+                .as_ref()
+                .map(|username| crate::mqtt::Credentials {
+                    username: username.clone(),
+                    password: self.password(),
+                });
+        mqtt_config.tls = self
+            .mqtt
+            .ca_file
+            .as_ref()
+            .map(|ca_file| crate::mqtt::TlsFiles {
+                ca_file: ca_file.clone(),
+                client_auth: self
+                    .mqtt
+                    .client_cert
+                    .clone()
+                    .zip(self.mqtt.client_key.clone()),
+            });
+        mqtt_config.clean_session = self.mqtt.clean_session;
+        mqtt_config.keep_alive = std::time::Duration::from_millis(self.mqtt.keep_alive_ms);
+        mqtt_config.reconnect_min = std::time::Duration::from_millis(self.mqtt.reconnect_min_ms);
+        mqtt_config.reconnect_max = std::time::Duration::from_millis(self.mqtt.reconnect_max_ms);
+        mqtt_config.inputs = publish_options(&self.mqtt.inputs);
+        mqtt_config.outputs = publish_options(&self.mqtt.outputs);
+        mqtt_config.relays = publish_options(&self.mqtt.relays);
+        mqtt_config.events = publish_options(&self.mqtt.events);
+        mqtt_config.command_qos = rumqttc::qos(self.mqtt.command_qos).unwrap();
+        mqtt_config
+    }
+
+    // Source of the MQTT password, if any
+    fn password(&self) -> Option<crate::mqtt::Password> {
+        if let Some(password) = &self.mqtt.password {
+            return Some(crate::mqtt::Password::Plain(password.clone()));
+        }
+        if let Some(path) = &self.mqtt.password_file {
+            return Some(crate::mqtt::Password::File(path.clone()));
+        }
+        self.mqtt
+            .password_env
+            .as_ref()
+            .map(|name| crate::mqtt::Password::Env(name.clone()))
+    }
+
+    /// Topic prefix for Home Assistant discovery, if enabled
+    pub fn discovery_prefix(&self) -> Option<&str> {
+        match self.mqtt.discovery {
+            true => Some(self.mqtt.discovery_prefix.as_str()),
+            false => None,
+        }
+    }
+
+    /// Debounce settings, including the per input overrides from the device table
+    pub fn debounce_config(&self) -> crate::debounce::DebounceConfig {
+        crate::debounce::DebounceConfig {
+            stable_time: std::time::Duration::from_millis(self.debounce.stable_time_ms),
+            inputs: self
+                .devices
+                .iter()
+                .filter_map(|device_section| {
+                    device_section.debounce_ms.map(|millis| {
+                        (
+                            format!("{}_{:02}", device_section.io_group, device_section.number),
+                            std::time::Duration::from_millis(millis),
+                        )
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Push button gesture thresholds
+    pub fn gesture_settings(&self) -> crate::gesture::GestureSettings {
+        crate::gesture::GestureSettings {
+            long_press: std::time::Duration::from_millis(self.gestures.long_press_ms),
+            multi_click: std::time::Duration::from_millis(self.gestures.multi_click_ms),
+            hold_repeat: std::time::Duration::from_millis(self.gestures.hold_repeat_ms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+        sysfs = "/sys/devices/platform/unipi_plc"
+        device_name = "plc"
+
+        [logging]
+        level = "debug"
+
+        [mqtt]
+        host = "broker.local"
+        port = 8883
+        username = "maus"
+        password_env = "MQTT_PASSWORD"
+        ca_file = "/etc/ssl/certs/ca.pem"
+        command_qos = 2
+        relays = { qos = 0, retain = false }
+
+        [debounce]
+        stable_time_ms = 30
+
+        [[boards]]
+        type = "unipi"
+        path = "/sys/devices/platform/unipi_plc"
+
+        [[boards]]
+        type = "gpio_cdev"
+        chip = "/dev/gpiochip0"
+        io_group = 3
+        inputs = [17, 27]
+        outputs = [22]
+
+        [[boards]]
+        type = "modbus_tcp"
+        address = "192.168.1.50:502"
+        io_group = 3
+        coils = [0, 1]
+        discrete_inputs = [0, 1, 2, 3]
+        holding_registers = [10]
+
+        [[devices]]
+        device_type = "relay"
+        io_group = 2
+        number = 1
+        name = "kitchen_ceiling"
+        model = "light"
+
+        [[devices]]
+        device_type = "input"
+        io_group = 1
+        number = 3
+        invert = true
+        debounce_ms = 100
+
+        [[devices]]
+        device_type = "output"
+        io_group = 1
+        number = 1
+        enabled = false
+
+        [[devices]]
+        device_type = "relay"
+        io_group = 2
+        number = 2
+        staircase_ms = 180000
+
+        [[devices]]
+        device_type = "analog_input"
+        io_group = 1
+        number = 2
+        deadband = 0.5
+        scale = { raw_min = 0, raw_max = 10000, min = 0, max = 100 }
+        unit = "%"
+
+        [counters]
+        state_file = "/var/lib/hausmaus/counters.json"
+
+        [[devices]]
+        device_type = "counter"
+        io_group = 1
+        number = 4
+        name = "electricity"
+        rate_factor = 60.0
+        unit = "W"
+
+        [onewire]
+        sample_interval_ms = 30000
+        sensors = [
+            { id = "28-0316a2795aff", name = "living_room" },
+            { id = "28-0416a2795aff", enabled = false },
+        ]
+
+        [modbus_server]
+        enabled = true
+        bind = "0.0.0.0:1502"
+
+        [http]
+        enabled = true
+        bind = "127.0.0.1:8080"
+
+        [stream]
+        enabled = true
+        bind = "0.0.0.0:8081"
+
+        [[covers]]
+        name = "living_room"
+        up = { device_type = "relay", io_group = 2, number = 3 }
+        down = { device_type = "relay", io_group = 2, number = 4 }
+        travel_up_ms = 20000
+        travel_down_ms = 18000
+
+        [[interlocks]]
+        devices = [
+            { device_type = "relay", io_group = 2, number = 5 },
+            { device_type = "relay", io_group = 2, number = 6 },
+        ]
+        dead_time_ms = 1000
+
+        [[lights]]
+        output = { device_type = "relay", io_group = 2, number = 1 }
+        buttons = [{ device_type = "input", io_group = 1, number = 3 }]
+
+        [[dimmers]]
+        name = "dining"
+        output = { device_type = "analog_output", io_group = 1, number = 1 }
+        buttons = [{ device_type = "input", io_group = 1, number = 3 }]
+
+        [[rules]]
+        name = "hallway_night"
+        trigger = { type = "input", device = { device_type = "input", io_group = 1, number = 3 }, state = true }
+        conditions = [{ type = "time", after = "22:30", before = "06:00" }]
+        actions = [
+            { type = "pulse", device = { device_type = "relay", io_group = 2, number = 1 }, duration_ms = 60000 },
+            { type = "start_timer", name = "hallway", duration_ms = 60000 },
+        ]
+
+        [[rules]]
+        name = "hallway_done"
+        trigger = { type = "timer", name = "hallway" }
+        actions = [{ type = "publish", topic = "plc/hallway", payload = "done" }]
+    "#;
+
+    fn device(
+        id: u8,
+        device_type: crate::device::DeviceType,
+        io_group: i8,
+        number: i8,
+    ) -> crate::device::Device {
+        crate::device::Device {
+            id,
+            path: "/foo/bar".to_string(),
+            module_name: String::from("plc"),
+            device_type,
+            io_group,
+            number,
+            settings: crate::device::DeviceSettings::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_example() {
+        let config = Config::from_toml(EXAMPLE).expect("Expect example to parse");
+        config.validate().expect("Expect example to be valid");
+
+        assert_eq!(config.sysfs, "/sys/devices/platform/unipi_plc");
+        assert_eq!(config.mqtt.host.as_deref(), Some("broker.local"));
+        assert_eq!(config.mqtt.client_id, "hausmaus");
+        assert_eq!(config.devices.len(), 6);
+        assert_eq!(config.devices[0].model, Some(crate::device::Model::Light));
+        assert!(config.devices[1].enabled);
+
+        let mqtt_config = config.mqtt_config();
+        assert_eq!(mqtt_config.availability.topic, "plc/status");
+        assert!(!mqtt_config.relays.retain);
+        assert_eq!(mqtt_config.command_qos, rumqttc::QoS::ExactlyOnce);
+        assert_eq!(mqtt_config.port, 8883);
+        assert_eq!(
+            mqtt_config.credentials.unwrap().password,
+            Some(crate::mqtt::Password::Env("MQTT_PASSWORD".to_string()))
+        );
+        assert_eq!(mqtt_config.tls.unwrap().client_auth, None);
+        assert_eq!(mqtt_config.keep_alive, std::time::Duration::from_secs(20));
+
+        let debounce_config = config.debounce_config();
+        assert_eq!(
+            debounce_config.inputs.get("1_03"),
+            Some(&std::time::Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn test_local_binds_by_default() {
+        let config = Config::from_toml(
+            "[modbus_server]\nenabled = true\n[http]\nenabled = true\n[stream]\nenabled = true",
+        )
+        .unwrap();
+        assert_eq!(config.modbus_server.bind, "127.0.0.1:502");
+        assert_eq!(config.http.bind, "127.0.0.1:8080");
+        assert_eq!(config.stream.bind, "127.0.0.1:8081");
+    }
+
+    #[test]
+    fn test_example_file_is_valid() {
+        let config = Config::from_toml(include_str!("../hausmaus.example.toml"))
+            .expect("Expect example file to parse");
+        config.validate().expect("Expect example file to be valid");
+    }
+
+    #[test]
+    fn test_apply_devices() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let mut devices = vec![
+            device(0, crate::device::DeviceType::RelayOutput, 2, 1),
+            device(1, crate::device::DeviceType::DigitalInput, 1, 3),
+            device(2, crate::device::DeviceType::DigitalOutput, 1, 1),
+            device(3, crate::device::DeviceType::DigitalOutput, 1, 2),
+            device(4, crate::device::DeviceType::RelayOutput, 2, 2),
+            device(5, crate::device::DeviceType::AnalogInput, 1, 2),
+            device(6, crate::device::DeviceType::Counter, 1, 4),
+            device(7, crate::device::DeviceType::Counter, 1, 3),
+        ];
+        config
+            .apply_devices(&mut devices)
+            .expect("Expect devices to apply");
+
+        // Disabled devices and counters which are not configured are left out
+        assert_eq!(devices.len(), 6);
+        assert_eq!(devices[0].settings.name.as_deref(), Some("kitchen_ceiling"));
+        assert!(devices[1].settings.invert);
+        assert_eq!(devices[2].id, 3);
+        assert_eq!(
+            config.staircases(&devices),
+            [(4, std::time::Duration::from_secs(180))]
+                .into_iter()
+                .collect()
+        );
+
+        let analog_settings = config.analog_settings(&devices);
+        assert_eq!(analog_settings.len(), 1);
+        let settings = &analog_settings[&5];
+        assert_eq!(settings.interval, std::time::Duration::from_millis(1000));
+        assert_eq!(settings.deadband, 0.5);
+        assert_eq!(settings.to_value(5000.0), 50.0);
+        assert_eq!(settings.unit.as_deref(), Some("%"));
+
+        let counter_settings = config.counter_settings(&devices);
+        assert_eq!(counter_settings.len(), 1);
+        assert_eq!(counter_settings[&6].rate_factor, 60.0);
+        assert_eq!(counter_settings[&6].unit.as_deref(), Some("W"));
+    }
+
+    #[test]
+    fn test_apply_devices_not_found() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let mut devices = vec![device(0, crate::device::DeviceType::RelayOutput, 2, 1)];
+        let error = config.apply_devices(&mut devices).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "MausError: Configured device input 1_03 was not found"
+        );
+    }
+
+    #[test]
+    fn test_backends() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let backends = config.backends();
+        assert_eq!(backends.len(), 3);
+        assert!(format!("{:?}", backends[1]).starts_with("Gpio { chip: \"/dev/gpiochip0\""));
+        assert!(format!("{:?}", backends[2])
+            .starts_with("Modbus { address: \"192.168.1.50:502\", unit_id: 1"));
+
+        // Without boards, everything is on the UniPi at the sysfs path
+        let backends = Config::default().backends();
+        assert_eq!(backends.len(), 1);
+        assert_eq!(
+            format!("{:?}", backends[0]),
+            "UniPi { path: \"/run/unipi\" }"
+        );
+    }
+
+    #[test]
+    fn test_sensors() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let ids: std::vec::Vec<String> = ["28-0316a2795aff", "28-0416a2795aff", "28-0516a2795aff"]
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        let sensors = config.sensors(&ids);
+        assert_eq!(sensors.len(), 2);
+        assert_eq!(sensors[0].name, "living_room");
+        assert_eq!(sensors[0].path, "/sys/bus/w1/devices/28-0316a2795aff");
+        assert_eq!(sensors[1].name, "28-0516a2795aff");
+    }
+
+    #[test]
+    fn test_covers() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let devices = vec![
+            device(0, crate::device::DeviceType::RelayOutput, 2, 3),
+            device(1, crate::device::DeviceType::RelayOutput, 2, 4),
+        ];
+        let covers = config.covers(&devices).expect("Expect covers to resolve");
+        assert_eq!(covers.len(), 1);
+        assert_eq!(covers[0].name, "living_room");
+
+        let error = config.covers(&devices[..1]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "MausError: Referenced device relay 2_04 was not found"
+        );
+    }
+
+    #[test]
+    fn test_interlock_groups() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let devices = vec![
+            device(0, crate::device::DeviceType::RelayOutput, 2, 3),
+            device(1, crate::device::DeviceType::RelayOutput, 2, 4),
+            device(2, crate::device::DeviceType::RelayOutput, 2, 5),
+            device(3, crate::device::DeviceType::RelayOutput, 2, 6),
+        ];
+        let groups = config.interlock_groups(&devices).unwrap();
+        assert_eq!(
+            groups,
+            vec![
+                crate::interlock::InterlockGroup {
+                    devices: vec![2, 3],
+                    dead_time: std::time::Duration::from_millis(1000),
+                },
+                crate::interlock::InterlockGroup {
+                    devices: vec![0, 1],
+                    dead_time: std::time::Duration::from_millis(500),
+                },
+            ]
+        );
+
+        let error = config.interlock_groups(&devices[..3]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "MausError: Referenced device relay 2_06 was not found"
+        );
+    }
+
+    #[test]
+    fn test_lights() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let devices = vec![
+            device(0, crate::device::DeviceType::RelayOutput, 2, 1),
+            device(1, crate::device::DeviceType::DigitalInput, 1, 3),
+        ];
+        let lights = config.lights(&devices).expect("Expect lights to resolve");
+        assert_eq!(lights[0].output.id, 0);
+        assert_eq!(lights[0].buttons, vec![1]);
+
+        let error = config.dimmers(&devices).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "MausError: Referenced device analog_output 1_01 was not found"
+        );
+        let mut devices = devices;
+        devices.push(crate::device::Device {
+            path: "/foo/ao_1_01/out_voltage_raw".to_string(),
+            ..device(2, crate::device::DeviceType::AnalogOutput, 1, 1)
+        });
+        let dimmers = config.dimmers(&devices).expect("Expect dimmers to resolve");
+        assert_eq!(dimmers[0].name, "dining");
+        assert_eq!(dimmers[0].output.path, "/foo/ao_1_01/out_voltage_raw");
+        assert_eq!(dimmers[0].max_value, 10000);
+        assert_eq!(dimmers[0].buttons, vec![1]);
+    }
+
+    #[test]
+    fn test_rules() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let devices = vec![
+            device(0, crate::device::DeviceType::RelayOutput, 2, 1),
+            device(1, crate::device::DeviceType::DigitalInput, 1, 3),
+        ];
+        let rules = config.rules(&devices).expect("Expect rules to resolve");
+        assert_eq!(rules[0].trigger, crate::auto::Trigger::Input(1, Some(true)));
+        assert_eq!(
+            rules[0].conditions,
+            vec![crate::auto::Condition::Time(22 * 60 + 30, 6 * 60)]
+        );
+        assert_eq!(
+            rules[0].actions[0],
+            crate::auto::Action::Pulse(0, std::time::Duration::from_secs(60))
+        );
+        assert_eq!(
+            rules[1].trigger,
+            crate::auto::Trigger::Timer("hallway".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let error = Config::from_toml("[mqtt]\nhots = \"broker\"").unwrap_err();
+        assert!(error.to_string().contains("unknown field `hots`"));
+    }
+
+    #[test]
+    fn test_validate_errors() {
+        let cases = [
+            ("", "No MQTT broker host given"),
+            (
+                "[mqtt]\nhost = \"b\"\ncommand_qos = 3",
+                "Invalid QoS 3 for commands",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\nusername = \"u\"\npassword = \"p\"\npassword_env = \"P\"",
+                "Only one of the MQTT password",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\nca_file = \"ca.pem\"\nclient_cert = \"cert.pem\"",
+                "client certificate and key need to be given together",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\nreconnect_min_ms = 5000\nreconnect_max_ms = 1000",
+                "Invalid MQTT reconnect delays 5000 to 1000 ms",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[covers]]\nname = \"c\"\nup = { device_type = \"relay\", io_group = 1, number = 1 }\ndown = { device_type = \"relay\", io_group = 1, number = 1 }\ntravel_up_ms = 1\ntravel_down_ms = 1",
+                "Device relay 1_01 is used for more than one cover motor",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[interlocks]]\ndevices = [{ device_type = \"relay\", io_group = 1, number = 1 }]",
+                "Interlock group [relay 1_01] needs at least two devices",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[lights]]\noutput = { device_type = \"relay\", io_group = 1, number = 1 }\nbuttons = [{ device_type = \"relay\", io_group = 1, number = 2 }]",
+                "Light relay 1_01 can not use relay 1_02 as a button",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[dimmers]]\nname = \"d\"\noutput = { device_type = \"relay\", io_group = 1, number = 1 }",
+                "Dimmer \"d\" can not use relay 1_01 as an output",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[boards]]\ntype = \"gpio_cdev\"\nchip = \"/dev/gpiochip0\"\nio_group = 0",
+                "Invalid io_group 0 for GPIO chip /dev/gpiochip0",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[boards]]\ntype = \"gpio_cdev\"\nchip = \"/dev/gpiochip0\"\nio_group = 1\ninputs = [4, 5]\noutputs = [5]",
+                "Line 5 of GPIO chip /dev/gpiochip0 is configured more than once",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[boards]]\ntype = \"modbus_tcp\"\naddress = \"plc.local\"\nio_group = 1",
+                "Invalid Modbus address \"plc.local\": expected host:port",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[modbus_server]\nenabled = true\nbind = \"localhost\"",
+                "Invalid Modbus server address \"localhost\": expected ip:port",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[http]\nenabled = true\nbind = \":8080\"",
+                "Invalid HTTP API address \":8080\": expected ip:port",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[stream]\nenabled = true\nbind = \"0.0.0.0\"",
+                "Invalid stream address \"0.0.0.0\": expected ip:port",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[boards]]\ntype = \"modbus_tcp\"\naddress = \"plc.local:502\"\nio_group = 1\ncoils = [3, 3]",
+                "coil 3 of Modbus server plc.local:502 unit 1 is configured more than once",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\nkeep_alive_ms = 1000",
+                "Invalid MQTT keep alive 1000 ms",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[devices]]\ndevice_type = \"relay\"\nio_group = 4\nnumber = 1",
+                "Invalid device relay 4_01",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[devices]]\ndevice_type = \"input\"\nio_group = 1\nnumber = 1\nmodel = \"light\"",
+                "Model Light can not be used for device input 1_01",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[devices]]\ndevice_type = \"relay\"\nio_group = 1\nnumber = 1\nname = \"a\"\n[[devices]]\ndevice_type = \"relay\"\nio_group = 1\nnumber = 2\nname = \"a\"",
+                "Name \"a\" is used for more than one relay",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[devices]]\ndevice_type = \"relay\"\nio_group = 1\nnumber = 1\ndebounce_ms = 10",
+                "Debounce can only be configured for inputs",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[devices]]\ndevice_type = \"input\"\nio_group = 1\nnumber = 1\nstaircase_ms = 10",
+                "Staircase can only be configured for outputs",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[devices]]\ndevice_type = \"relay\"\nio_group = 1\nnumber = 1\nunit = \"V\"",
+                "Analog settings can only be configured for analog devices",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[devices]]\ndevice_type = \"analog_input\"\nio_group = 1\nnumber = 1\nscale = { raw_min = 0, raw_max = 0, min = 0, max = 1 }",
+                "Invalid scale for device analog_input 1_01",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[devices]]\ndevice_type = \"input\"\nio_group = 1\nnumber = 1\nrate_factor = 2.0",
+                "Rate factor can only be configured for counters",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[devices]]\ndevice_type = \"counter\"\nio_group = 1\nnumber = 1\nrate_factor = 0.0",
+                "Counter counter 1_01 needs a positive rate factor",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[onewire]\nsensors = [{ id = \"28-01\", name = \"a\" }, { id = \"28-02\", name = \"a\" }]",
+                "Name \"a\" is used for more than one sensor",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[rules]]\nname = \"r\"\ntrigger = { type = \"timer\", name = \"t\" }\nactions = [{ type = \"publish\", topic = \"a/b\", payload = \"x\" }]",
+                "Invalid rule \"r\": timer \"t\" is never started",
+            ),
+            (
+                "[mqtt]\nhost = \"b\"\n[[rules]]\nname = \"r\"\ntrigger = { type = \"message\", topic = \"a/b\" }\nconditions = [{ type = \"time\", after = \"25:00\", before = \"06:00\" }]\nactions = [{ type = \"publish\", topic = \"a/c\", payload = \"x\" }]",
+                "Invalid time \"25:00\"",
+            ),
+        ];
+        for (contents, message) in cases {
+            let error = Config::from_toml(contents).unwrap().validate().unwrap_err();
+            assert!(
+                error.to_string().contains(message),
+                "{:?} does not contain {:?}",
+                error.to_string(),
+                message
+            );
+        }
+    }
+}
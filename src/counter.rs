@@ -0,0 +1,499 @@
+//! counter samples the pulse counters of digital inputs, computing totals and rates
+//!
+//! Totals survive restarts of both hausmaus and the hardware: the offset on top of the raw
+//! hardware count is kept in a state file, and a raw count going backwards is taken as a restart
+//! of the hardware counter.
+
+// Default interval at which counters are sampled, in ms
+pub const SAMPLE_INTERVAL: u64 = 10000;
+// Minimal time in between writes of the state file while only the counts change
+const SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Settings for a single counter
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterSettings {
+    // Rate published for one pulse per minute, e.g. 60 W for a meter with 1000 pulses per kWh
+    pub rate_factor: f64,
+    // Unit of the rate
+    pub unit: Option<String>,
+}
+
+impl Default for CounterSettings {
+    fn default() -> Self {
+        CounterSettings {
+            rate_factor: 1.0,
+            unit: None,
+        }
+    }
+}
+
+/// Persisted state of a counter
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CounterState {
+    // Added to the raw hardware count
+    pub offset: i64,
+    // Last raw hardware count seen, to detect a restart of the hardware counter
+    pub last_raw: Option<u64>,
+}
+
+/// Total count and rate of a counter; the rate is unknown until the second sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CounterStatus {
+    pub total: u64,
+    pub rate: Option<f64>,
+}
+
+/// Command for a counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterCommand {
+    // Set the total to a new value, `RESET` sets it to 0
+    Set(u64),
+}
+
+impl CounterCommand {
+    /// Parse a command payload: `RESET` or a new total
+    pub fn parse(payload: &str) -> Result<Self, String> {
+        let payload = payload.trim();
+        if payload.eq_ignore_ascii_case("reset") {
+            return Ok(CounterCommand::Set(0));
+        }
+        payload.parse().map(CounterCommand::Set).map_err(|_| {
+            format!(
+                "Invalid payload {:?}: expected RESET or a new total",
+                payload
+            )
+        })
+    }
+}
+
+/// Command for a counter by device ID
+pub type CounterEvent = (u8, CounterCommand);
+
+/// Key of a counter in the state file, independent of its configured name
+pub fn state_key(device: &crate::device::Device) -> String {
+    format!("{}_{:02}", device.io_group, device.number)
+}
+
+// A single counter being sampled
+#[derive(Debug)]
+struct TrackedCounter {
+    device: crate::device::Device,
+    settings: CounterSettings,
+    state: CounterState,
+    // Time of the previous sample, to compute the rate
+    last_sample: Option<std::time::Instant>,
+    last_status: Option<CounterStatus>,
+}
+
+impl TrackedCounter {
+    fn total(&self, raw: u64) -> u64 {
+        (raw as i64 + self.state.offset).max(0) as u64
+    }
+}
+
+/// Counters keeps track of the totals and rates of all counters and when to sample them
+#[derive(Debug)]
+pub struct Counters {
+    counters: std::vec::Vec<TrackedCounter>,
+    interval: std::time::Duration,
+    next_sample: std::time::Instant,
+}
+
+impl Counters {
+    /// Set up the counters from their saved states, all of them to be sampled right away
+    pub fn new(
+        counters: std::vec::Vec<(crate::device::Device, CounterSettings)>,
+        saved: &std::collections::HashMap<String, CounterState>,
+        interval: std::time::Duration,
+        now: std::time::Instant,
+    ) -> Self {
+        Counters {
+            counters: counters
+                .into_iter()
+                .map(|(device, settings)| TrackedCounter {
+                    state: saved.get(&state_key(&device)).copied().unwrap_or_default(),
+                    device,
+                    settings,
+                    last_sample: None,
+                    last_status: None,
+                })
+                .collect(),
+            interval,
+            next_sample: now,
+        }
+    }
+
+    /// Whether there are any counters at all
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    /// Devices of all counters, by index
+    pub fn devices(&self) -> std::vec::Vec<&crate::device::Device> {
+        self.counters
+            .iter()
+            .map(|counter| &counter.device)
+            .collect()
+    }
+
+    /// Index of the counter for a device ID
+    pub fn index(&self, device_id: u8) -> Option<usize> {
+        self.counters
+            .iter()
+            .position(|counter| counter.device.id == device_id)
+    }
+
+    /// Handle a raw count sampled at `now`, returning the status to publish if it changed
+    pub fn feed(
+        &mut self,
+        index: usize,
+        raw: u64,
+        now: std::time::Instant,
+    ) -> Option<(u8, CounterStatus)> {
+        let counter = &mut self.counters[index];
+        let pulses = match counter.state.last_raw {
+            // The hardware counter restarted from 0, keep counting on top of the last total
+            Some(last_raw) if raw < last_raw => {
+                log::info!(
+                    "Counter #{} restarted at {}, was {}",
+                    counter.device.id,
+                    raw,
+                    last_raw
+                );
+                counter.state.offset += last_raw as i64;
+                raw
+            }
+            Some(last_raw) => raw - last_raw,
+            None => 0,
+        };
+        let rate = counter.last_sample.and_then(|last_sample| {
+            let minutes = now.saturating_duration_since(last_sample).as_secs_f64() / 60.0;
+            (minutes > 0.0).then(|| pulses as f64 / minutes * counter.settings.rate_factor)
+        });
+        counter.state.last_raw = Some(raw);
+        counter.last_sample = Some(now);
+        let status = CounterStatus {
+            total: counter.total(raw),
+            rate,
+        };
+        if counter.last_status == Some(status) {
+            return None;
+        }
+        counter.last_status = Some(status);
+        Some((counter.device.id, status))
+    }
+
+    /// Apply a command to a counter with the current raw count, returning the new status
+    pub fn command(
+        &mut self,
+        index: usize,
+        command: CounterCommand,
+        raw: u64,
+    ) -> (u8, CounterStatus) {
+        let counter = &mut self.counters[index];
+        let CounterCommand::Set(total) = command;
+        counter.state.offset = total as i64 - raw as i64;
+        // Pulses since the last sample still count towards the rate, unless the hardware
+        // counter restarted in between
+        if counter.state.last_raw.is_none_or(|last_raw| raw < last_raw) {
+            counter.state.last_raw = Some(raw);
+        }
+        let status = CounterStatus {
+            total,
+            rate: counter.last_status.and_then(|status| status.rate),
+        };
+        counter.last_status = Some(status);
+        (counter.device.id, status)
+    }
+
+    /// Move on to the next sample after sampling all counters
+    pub fn sampled(&mut self, now: std::time::Instant) {
+        self.next_sample = now + self.interval;
+    }
+
+    /// The next moment the counters need to be sampled
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        (!self.is_empty()).then_some(self.next_sample)
+    }
+
+    /// States to persist, by state key
+    pub fn states(&self) -> std::collections::BTreeMap<String, CounterState> {
+        self.counters
+            .iter()
+            .map(|counter| (state_key(&counter.device), counter.state))
+            .collect()
+    }
+
+    // Offsets of all counters, to tell when the states need to be saved right away
+    fn offsets(&self) -> std::vec::Vec<i64> {
+        self.counters
+            .iter()
+            .map(|counter| counter.state.offset)
+            .collect()
+    }
+}
+
+/// Read the raw count of a counter
+pub fn read_raw(path: &str) -> Result<u64, crate::errors::MausError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| crate::errors::MausError::new(format!("Could not read {}: {}", path, e)))?;
+    contents.trim().parse().map_err(|_| {
+        crate::errors::MausError::new(format!(
+            "Invalid contents {:?} in {}",
+            contents.trim(),
+            path
+        ))
+    })
+}
+
+/// Load the saved counter states; a missing or unreadable file starts all counters from scratch
+pub fn load_states(path: &str) -> std::collections::HashMap<String, CounterState> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return std::collections::HashMap::new()
+        }
+        Err(e) => {
+            log::warn!("Could not read counter states from {}: {}", path, e);
+            return std::collections::HashMap::new();
+        }
+    };
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        log::warn!("Invalid counter states in {}: {}", path, e);
+        std::collections::HashMap::new()
+    })
+}
+
+/// Save the counter states, replacing the file at once so a crash can not leave half of it
+pub fn save_states(
+    path: &str,
+    states: &std::collections::BTreeMap<String, CounterState>,
+) -> Result<(), crate::errors::MausError> {
+    let error = |e: std::io::Error| {
+        crate::errors::MausError::new(format!("Could not save counter states to {}: {}", path, e))
+    };
+    let contents = serde_json::to_string_pretty(states)
+        .map_err(|e| crate::errors::MausError::new(e.to_string()))?;
+    let temporary_path = format!("{}.tmp", path);
+    std::fs::write(&temporary_path, contents).map_err(error)?;
+    std::fs::rename(&temporary_path, path).map_err(error)
+}
+
+// Save the states if there is a state file, logging failures
+fn save(state_file: Option<&str>, counters: &Counters) {
+    if let Some(path) = state_file {
+        if let Err(e) = save_states(path, &counters.states()) {
+            log::error!("{}", e);
+        }
+    }
+}
+
+/// Sample all counters, apply commands and publish totals and rates that changed
+///
+/// The states are saved right away when an offset changes, otherwise at most once a minute.
+pub fn run_counters(
+    rx: std::sync::mpsc::Receiver<CounterEvent>,
+    mut counters: Counters,
+    state_file: Option<String>,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+) {
+    let state_file = state_file.as_deref();
+    let mut last_save = std::time::Instant::now();
+    let mut saved_states = counters.states();
+    loop {
+        let offsets = counters.offsets();
+        let event = match counters.next_deadline() {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+                match rx.recv_timeout(timeout) {
+                    Ok(event) => Some(event),
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match rx.recv() {
+                Ok(event) => Some(event),
+                Err(_) => break,
+            },
+        };
+        let now = std::time::Instant::now();
+
+        let mut messages = std::vec::Vec::new();
+        if let Some((device_id, command)) = event {
+            let index = match counters.index(device_id) {
+                Some(index) => index,
+                None => continue,
+            };
+            let path = counters.devices()[index].path.clone();
+            match read_raw(&path) {
+                Ok(raw) => {
+                    let (device_id, status) = counters.command(index, command, raw);
+                    messages.push(crate::mqtt::MQTTMessage::Counter(device_id, status));
+                }
+                Err(e) => messages.push(crate::mqtt::MQTTMessage::Error(device_id, e.to_string())),
+            }
+        }
+        if counters
+            .next_deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            let samples: std::vec::Vec<(usize, u64)> = counters
+                .devices()
+                .into_iter()
+                .enumerate()
+                .filter_map(|(index, device)| match read_raw(&device.path) {
+                    Ok(raw) => Some((index, raw)),
+                    Err(e) => {
+                        log::debug!("{}", e);
+                        None
+                    }
+                })
+                .collect();
+            for (index, raw) in samples {
+                if let Some((device_id, status)) = counters.feed(index, raw, now) {
+                    messages.push(crate::mqtt::MQTTMessage::Counter(device_id, status));
+                }
+            }
+            counters.sampled(now);
+        }
+        for message in messages {
+            if mqtt_publish_tx.send(message).is_err() {
+                return;
+            }
+        }
+
+        let states = counters.states();
+        if states != saved_states
+            && (counters.offsets() != offsets || now.duration_since(last_save) >= SAVE_INTERVAL)
+        {
+            save(state_file, &counters);
+            saved_states = states;
+            last_save = now;
+        }
+    }
+    save(state_file, &counters);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: u8) -> crate::device::Device {
+        crate::device::Device {
+            id,
+            path: "/foo/bar".to_string(),
+            module_name: String::from("foo"),
+            device_type: crate::device::DeviceType::Counter,
+            io_group: 1,
+            number: id as i8,
+            settings: crate::device::DeviceSettings::default(),
+        }
+    }
+
+    fn counters(saved: &std::collections::HashMap<String, CounterState>) -> Counters {
+        let settings = CounterSettings {
+            rate_factor: 60.0,
+            unit: Some("W".to_string()),
+        };
+        Counters::new(
+            vec![(device(1), settings)],
+            saved,
+            std::time::Duration::from_millis(SAMPLE_INTERVAL),
+            std::time::Instant::now(),
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(CounterCommand::parse("RESET"), Ok(CounterCommand::Set(0)));
+        assert_eq!(CounterCommand::parse("reset\n"), Ok(CounterCommand::Set(0)));
+        assert_eq!(CounterCommand::parse("1234"), Ok(CounterCommand::Set(1234)));
+        assert!(CounterCommand::parse("-1").is_err());
+        assert!(CounterCommand::parse("ON").is_err());
+    }
+
+    #[test]
+    fn test_total_and_rate() {
+        let start = std::time::Instant::now();
+        let minute = std::time::Duration::from_secs(60);
+        let mut counters = counters(&std::collections::HashMap::new());
+        let status = |total, rate| Some((1, CounterStatus { total, rate }));
+
+        assert_eq!(counters.feed(0, 100, start), status(100, None));
+        // 10 pulses in a minute at 60 W per pulse per minute
+        assert_eq!(
+            counters.feed(0, 110, start + minute),
+            status(110, Some(600.0))
+        );
+        assert_eq!(
+            counters.feed(0, 110, start + minute * 2),
+            status(110, Some(0.0))
+        );
+        // Nothing changed
+        assert_eq!(counters.feed(0, 110, start + minute * 3), None);
+
+        // The hardware counter restarted: the total keeps counting on
+        assert_eq!(
+            counters.feed(0, 5, start + minute * 4),
+            status(115, Some(300.0))
+        );
+        assert_eq!(counters.states()["1_01"].offset, 110);
+
+        // Reset through a command
+        assert_eq!(
+            counters.command(0, CounterCommand::Set(0), 5),
+            (
+                1,
+                CounterStatus {
+                    total: 0,
+                    rate: Some(300.0)
+                }
+            )
+        );
+        assert_eq!(
+            counters.feed(0, 8, start + minute * 5),
+            status(3, Some(180.0))
+        );
+    }
+
+    #[test]
+    fn test_saved_states() {
+        let dir = tempdir::TempDir::new("counters").unwrap();
+        let path = dir.path().join("counters.json");
+        let path = path.to_str().unwrap();
+        assert!(load_states(path).is_empty());
+
+        let mut counters = counters(&std::collections::HashMap::new());
+        counters.command(0, CounterCommand::Set(1000), 10);
+        save_states(path, &counters.states()).unwrap();
+
+        // After a restart of hausmaus, the total is restored from the saved offset
+        let mut counters = counters_from(path);
+        assert_eq!(
+            counters.feed(0, 12, std::time::Instant::now()),
+            Some((
+                1,
+                CounterStatus {
+                    total: 1002,
+                    rate: None
+                }
+            ))
+        );
+        // Also after a restart of the hardware counter in between
+        let mut counters = counters_from(path);
+        assert_eq!(
+            counters.feed(0, 3, std::time::Instant::now()),
+            Some((
+                1,
+                CounterStatus {
+                    total: 1003,
+                    rate: None
+                }
+            ))
+        );
+    }
+
+    fn counters_from(path: &str) -> Counters {
+        counters(&load_states(path))
+    }
+}
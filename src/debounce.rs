@@ -0,0 +1,233 @@
+//! debounce filters contact bounce and short glitches out of the raw file events
+use crate::sysfs::FileEvent;
+
+/// Debounce configuration: a global stable time and per input overrides
+///
+/// Per input overrides are keyed on the `<io_group>_<number>` of the digital input, e.g. `1_03`.
+#[derive(Debug, Clone, Default)]
+pub struct DebounceConfig {
+    pub stable_time: std::time::Duration,
+    pub inputs: std::collections::HashMap<String, std::time::Duration>,
+}
+
+impl DebounceConfig {
+    /// Resolve the stable time per device ID for a list of devices
+    pub fn stable_times(
+        &self,
+        devices: &[crate::device::Device],
+    ) -> std::collections::HashMap<u8, std::time::Duration> {
+        let mut stable_times = std::collections::HashMap::new();
+        for device in devices {
+            if device.device_type != crate::device::DeviceType::DigitalInput {
+                continue;
+            }
+            let key = format!("{}_{:02}", device.io_group, device.number);
+            if let Some(&stable_time) = self.inputs.get(&key) {
+                stable_times.insert(device.id, stable_time);
+            }
+        }
+        stable_times
+    }
+}
+
+/// Debouncer only forwards a new value once it has been stable for long enough
+///
+/// A value that flips back before its stable time expired is dropped as a glitch.
+pub struct Debouncer {
+    default_stable_time: std::time::Duration,
+    stable_times: std::collections::HashMap<u8, std::time::Duration>,
+    // Last forwarded value per device, along with the moment it was first seen
+    stable: std::collections::HashMap<u8, (bool, std::time::Instant)>,
+    // Change waiting for its stable time to expire
+    pending: std::collections::HashMap<u8, (bool, std::time::Instant)>,
+}
+
+impl Debouncer {
+    pub fn new(
+        default_stable_time: std::time::Duration,
+        stable_times: std::collections::HashMap<u8, std::time::Duration>,
+    ) -> Self {
+        Debouncer {
+            default_stable_time,
+            stable_times,
+            stable: std::collections::HashMap::new(),
+            pending: std::collections::HashMap::new(),
+        }
+    }
+
+    fn stable_time(&self, device_id: u8) -> std::time::Duration {
+        *self
+            .stable_times
+            .get(&device_id)
+            .unwrap_or(&self.default_stable_time)
+    }
+
+    /// Feed a raw event observed at `now`
+    pub fn feed(&mut self, event: FileEvent, now: std::time::Instant) {
+        let (device_id, value, duration) = event;
+
+        // Raw events only come in on a change, so before the first one the value was the opposite
+        let (stable_value, _) = *self
+            .stable
+            .entry(device_id)
+            .or_insert((!value, now.checked_sub(duration).unwrap_or(now)));
+
+        if value == stable_value {
+            if self.pending.remove(&device_id).is_some() {
+                log::debug!("Dropped glitch for device #{}", device_id);
+            }
+        } else {
+            self.pending.entry(device_id).or_insert((value, now));
+        }
+    }
+
+    /// Collect all changes that have been stable long enough at `now`
+    pub fn poll(&mut self, now: std::time::Instant) -> std::vec::Vec<FileEvent> {
+        let mut ready: std::vec::Vec<(u8, bool, std::time::Instant)> = std::vec::Vec::new();
+        for (&device_id, &(value, since)) in &self.pending {
+            if now.saturating_duration_since(since) >= self.stable_time(device_id) {
+                ready.push((device_id, value, since));
+            }
+        }
+        ready.sort_by_key(|&(device_id, _, since)| (since, device_id));
+
+        let mut events = std::vec::Vec::with_capacity(ready.len());
+        for (device_id, value, since) in ready {
+            self.pending.remove(&device_id);
+            let duration = match self.stable.insert(device_id, (value, since)) {
+                Some((_, last_since)) => since.saturating_duration_since(last_since),
+                None => std::time::Duration::from_secs(0),
+            };
+            events.push((device_id, value, duration));
+        }
+        events
+    }
+
+    /// The next moment a pending change could become stable
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        self.pending
+            .iter()
+            .map(|(&device_id, &(_, since))| since + self.stable_time(device_id))
+            .min()
+    }
+}
+
+/// Debounce raw file events before passing them on
+pub fn run_debounce(
+    rx: std::sync::mpsc::Receiver<FileEvent>,
+    tx: std::sync::mpsc::Sender<FileEvent>,
+    mut debouncer: Debouncer,
+) {
+    loop {
+        let received = match debouncer.next_deadline() {
+            Some(deadline) => {
+                rx.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+            }
+            None => rx
+                .recv()
+                .map_err(|_| std::sync::mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(event) => debouncer.feed(event, std::time::Instant::now()),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+
+        for event in debouncer.poll(std::time::Instant::now()) {
+            if tx.send(event).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> std::time::Duration {
+        std::time::Duration::from_millis(millis)
+    }
+
+    // Feed a sequence of (offset in ms, value) for a single device and poll every millisecond
+    fn filter(debouncer: &mut Debouncer, sequence: &[(u64, bool)], until: u64) -> Vec<(bool, u64)> {
+        let start = std::time::Instant::now();
+        let mut output = Vec::new();
+        let mut sequence = sequence.iter().peekable();
+        for t in 0..=until {
+            while let Some(&&(offset, value)) = sequence.peek() {
+                if offset != t {
+                    break;
+                }
+                debouncer.feed((0, value, ms(0)), start + ms(t));
+                sequence.next();
+            }
+            for (device_id, value, _) in debouncer.poll(start + ms(t)) {
+                assert_eq!(device_id, 0);
+                output.push((value, t));
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn test_bounce_is_collapsed() {
+        let mut debouncer = Debouncer::new(ms(20), std::collections::HashMap::new());
+        let sequence = [(0, true), (2, false), (3, true), (5, false), (6, true)];
+        assert_eq!(filter(&mut debouncer, &sequence, 100), vec![(true, 26)]);
+    }
+
+    #[test]
+    fn test_glitch_is_dropped() {
+        let mut debouncer = Debouncer::new(ms(20), std::collections::HashMap::new());
+        let sequence = [(0, true), (5, false)];
+        assert_eq!(filter(&mut debouncer, &sequence, 100), vec![]);
+    }
+
+    #[test]
+    fn test_press_and_release() {
+        let mut debouncer = Debouncer::new(ms(20), std::collections::HashMap::new());
+        let sequence = [
+            (0, true),
+            (1, false),
+            (2, true),
+            (50, false),
+            (51, true),
+            (52, false),
+        ];
+        assert_eq!(
+            filter(&mut debouncer, &sequence, 100),
+            vec![(true, 22), (false, 72)]
+        );
+    }
+
+    #[test]
+    fn test_zero_stable_time_passes_through() {
+        let mut debouncer = Debouncer::new(ms(0), std::collections::HashMap::new());
+        let sequence = [(0, true), (1, false), (2, true)];
+        assert_eq!(
+            filter(&mut debouncer, &sequence, 10),
+            vec![(true, 0), (false, 1), (true, 2)]
+        );
+    }
+
+    #[test]
+    fn test_per_device_stable_time() {
+        let mut stable_times = std::collections::HashMap::new();
+        stable_times.insert(0, ms(50));
+        let mut debouncer = Debouncer::new(ms(10), stable_times);
+        let sequence = [(0, true), (30, false)];
+        assert_eq!(filter(&mut debouncer, &sequence, 100), vec![]);
+    }
+
+    #[test]
+    fn test_duration_between_stable_toggles() {
+        let mut debouncer = Debouncer::new(ms(10), std::collections::HashMap::new());
+        let start = std::time::Instant::now();
+        debouncer.feed((3, true, ms(0)), start);
+        assert_eq!(debouncer.poll(start + ms(10)), vec![(3, true, ms(0))]);
+        debouncer.feed((3, false, ms(0)), start + ms(40));
+        assert_eq!(debouncer.next_deadline(), Some(start + ms(50)));
+        assert_eq!(debouncer.poll(start + ms(50)), vec![(3, false, ms(40))]);
+    }
+}
@@ -1,8 +1,81 @@
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Eq, PartialEq, Hash, Debug, Clone, serde::Deserialize)]
 pub enum DeviceType {
+    #[serde(rename = "input")]
     DigitalInput,
+    #[serde(rename = "output")]
     DigitalOutput,
+    #[serde(rename = "relay")]
     RelayOutput,
+    #[serde(rename = "analog_input")]
+    AnalogInput,
+    #[serde(rename = "analog_output")]
+    AnalogOutput,
+    // Pulse counter of a digital input
+    #[serde(rename = "counter")]
+    Counter,
+}
+
+impl DeviceType {
+    /// Name of the device type as used in topics, IDs and the configuration file
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceType::DigitalInput => "input",
+            DeviceType::DigitalOutput => "output",
+            DeviceType::RelayOutput => "relay",
+            DeviceType::AnalogInput => "analog_input",
+            DeviceType::AnalogOutput => "analog_output",
+            DeviceType::Counter => "counter",
+        }
+    }
+
+    /// Whether the device is an output that is switched on and off
+    pub fn is_switch(&self) -> bool {
+        matches!(self, DeviceType::DigitalOutput | DeviceType::RelayOutput)
+    }
+
+    /// Whether the device is either on or off
+    pub fn is_digital(&self) -> bool {
+        matches!(
+            self,
+            DeviceType::DigitalInput | DeviceType::DigitalOutput | DeviceType::RelayOutput
+        )
+    }
+
+    /// Whether the device holds a numeric value instead of on or off
+    pub fn is_analog(&self) -> bool {
+        matches!(self, DeviceType::AnalogInput | DeviceType::AnalogOutput)
+    }
+}
+
+/// What is physically connected to a device
+#[derive(Eq, PartialEq, Debug, Clone, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Model {
+    PushButton,
+    Light,
+}
+
+impl Model {
+    /// Whether the model can be connected to a given device type
+    pub fn supports(&self, device_type: &DeviceType) -> bool {
+        match self {
+            Model::PushButton => *device_type == DeviceType::DigitalInput,
+            Model::Light => device_type.is_switch(),
+        }
+    }
+}
+
+/// Per device settings from the configuration file
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct DeviceSettings {
+    // Friendly name, used in topics instead of the io_group and number
+    pub name: Option<String>,
+    // Full topic overrides
+    pub state_topic: Option<String>,
+    pub command_topic: Option<String>,
+    // Invert the value read from and written to the file
+    pub invert: bool,
+    pub model: Option<Model>,
 }
 
 #[derive(Eq, PartialEq, Debug, Clone)]
@@ -15,9 +88,25 @@ pub struct Device {
     pub io_group: i8,
     pub number: i8,
     pub path: String,
+    pub settings: DeviceSettings,
 }
 
-const FILENAME_PATTERN: &str = r"/io_group(1|2|3)/(?P<device_fmt>di|do|ro)_(?P<io_group>1|2|3)_(?P<number>\d{2})/(di|do|ro)_value$";
+/// Devices are identified by a u8 ID, so there can be no more than this across all boards
+pub const MAX_DEVICES: usize = u8::MAX as usize + 1;
+
+/// ID for the device at `index` in the list of all devices
+pub fn device_id(index: usize) -> Result<u8, crate::errors::MausError> {
+    u8::try_from(index).map_err(|_| {
+        crate::errors::MausError::new(format!(
+            "Too many devices: at most {} are supported",
+            MAX_DEVICES
+        ))
+    })
+}
+
+// Analog inputs and outputs are crawled through their voltage value, in mV; digital inputs also
+// show up as a counter through their pulse counter
+const FILENAME_PATTERN: &str = r"/io_group(1|2|3)/(?P<device_fmt>di|do|ro|ai|ao)_(?P<io_group>1|2|3)_(?P<number>\d{2})/(?P<file>(di|do|ro)_value|di_counter|in_voltage_raw|out_voltage_raw)$";
 
 // Construct a device from a regex captures
 fn device_from_captures(
@@ -26,16 +115,20 @@ fn device_from_captures(
     path_str: &str,
     module_name: &str,
 ) -> Result<crate::device::Device, crate::errors::MausError> {
-    if let (Some(device_fmt), Some(io_group_str), Some(number_str)) = (
+    if let (Some(device_fmt), Some(io_group_str), Some(number_str), Some(file)) = (
         captures.name("device_fmt"),
         captures.name("io_group"),
         captures.name("number"),
+        captures.name("file"),
     ) {
         // Map against device type from capture
         let device_type = match device_fmt.as_str() {
+            "di" if file.as_str() == "di_counter" => DeviceType::Counter,
             "di" => DeviceType::DigitalInput,
             "do" => DeviceType::DigitalOutput,
             "ro" => DeviceType::RelayOutput,
+            "ai" => DeviceType::AnalogInput,
+            "ao" => DeviceType::AnalogOutput,
             _ => {
                 return Err(crate::errors::MausError::new(
                     "Could not determine device type from path".to_string(),
@@ -57,6 +150,7 @@ fn device_from_captures(
                 io_group,
                 number,
                 path,
+                settings: DeviceSettings::default(),
             });
         }
     }
@@ -90,9 +184,10 @@ fn crawl(
             if path.is_dir() {
                 crawl(&path, module_name, re, devices)?;
             } else if let Some(path_str) = path.to_str() {
-                // The id we use here is just the current length of the list
-                let id: u8 = devices.len().try_into().unwrap();
                 if let Some(captures) = re.captures(path_str) {
+                    // The id we use here is just the current length of the list
+                    let id = device_id(devices.len())
+                        .map_err(|e| std::io::Error::other(e.to_string()))?;
                     if let Ok(device) = device_from_captures(&captures, id, path_str, module_name) {
                         devices.push(device);
                     }
@@ -110,43 +205,76 @@ pub fn devices_from_path(
     devices: &mut std::vec::Vec<crate::device::Device>,
 ) -> Result<(), crate::errors::MausError> {
     if let Ok(re) = regex::Regex::new(FILENAME_PATTERN) {
-        crawl(std::path::Path::new(&dir), module_name, &re, devices).unwrap();
-        return Ok(());
+        return crawl(std::path::Path::new(&dir), module_name, &re, devices)
+            .map_err(|e| crate::errors::MausError::new(format!("Could not crawl {}: {}", dir, e)));
     }
     Err(crate::errors::MausError::new(
         "Could not build list of devices".to_string(),
     ))
 }
 
-// Map a device to an MQTT state topic
-fn state_topic_for_device(device: &crate::device::Device) -> String {
+/// Name of the device within its type: either the configured name or the hardware coordinates
+pub fn device_key(device: &crate::device::Device) -> String {
+    match &device.settings.name {
+        Some(name) => name.clone(),
+        None => format!(
+            "{io_group:1}_{number:02}",
+            io_group = device.io_group,
+            number = device.number
+        ),
+    }
+}
+
+// Map a device to the MQTT topic all its other topics are derived from
+fn base_topic_for_device(device: &crate::device::Device) -> String {
     format!(
-        "{name}/{device_type}/{io_group:1}_{number:02}/state",
+        "{name}/{device_type}/{key}",
         name = device.module_name,
-        device_type = match device.device_type {
-            crate::device::DeviceType::DigitalInput => "input",
-            crate::device::DeviceType::DigitalOutput => "output",
-            crate::device::DeviceType::RelayOutput => "relay",
-        },
-        io_group = device.io_group,
-        number = device.number
+        device_type = device.device_type.as_str(),
+        key = device_key(device),
     )
 }
 
-fn command_topic_for_device(device: &crate::device::Device) -> String {
+/// Identifier of a device, unique within its module, e.g. `relay_2_07`
+pub fn object_id_for_device(device: &crate::device::Device) -> String {
     format!(
-        "{name}/{device_type}/{io_group:1}_{number:02}/set",
-        name = device.module_name,
-        device_type = match device.device_type {
-            crate::device::DeviceType::DigitalInput => "input",
-            crate::device::DeviceType::DigitalOutput => "output",
-            crate::device::DeviceType::RelayOutput => "relay",
-        },
-        io_group = device.io_group,
-        number = device.number
+        "{device_type}_{key}",
+        device_type = device.device_type.as_str(),
+        key = device_key(device),
     )
 }
 
+/// Map a device to an MQTT state topic
+pub fn state_topic_for_device(device: &crate::device::Device) -> String {
+    match &device.settings.state_topic {
+        Some(state_topic) => state_topic.clone(),
+        None => format!("{}/state", base_topic_for_device(device)),
+    }
+}
+
+/// Map a device to an MQTT command topic
+pub fn command_topic_for_device(device: &crate::device::Device) -> String {
+    match &device.settings.command_topic {
+        Some(command_topic) => command_topic.clone(),
+        None => format!("{}/set", base_topic_for_device(device)),
+    }
+}
+
+/// Map a counter to the MQTT topic its rate is published on
+pub fn rate_topic_for_device(device: &crate::device::Device) -> String {
+    format!("{}/rate", base_topic_for_device(device))
+}
+
+// Map a device to the MQTT topic recognized gestures are published on
+fn action_topic_for_device(device: &crate::device::Device) -> String {
+    format!("{}/action", base_topic_for_device(device))
+}
+
+/// Map a device to the MQTT topic errors for the device are reported on
+pub fn error_topic_for_device(device: &crate::device::Device) -> String {
+    format!("{}/error", base_topic_for_device(device))
+}
+
 /// Set up mapping device -> state topic
 pub fn device_state_topics(
     devices: &std::vec::Vec<Device>,
@@ -167,6 +295,50 @@ pub fn device_command_topics(
     }
 }
 
+/// Mapping device ID -> action topic, for digital inputs only
+pub fn device_action_topics(
+    devices: &std::vec::Vec<Device>,
+    cache: &mut std::collections::HashMap<u8, String>,
+) {
+    for device in devices {
+        if device.device_type == DeviceType::DigitalInput {
+            cache.insert(device.id, action_topic_for_device(device));
+        }
+    }
+}
+
+/// Mapping device ID -> rate topic, for counters only
+pub fn device_rate_topics(
+    devices: &std::vec::Vec<Device>,
+    cache: &mut std::collections::HashMap<u8, String>,
+) {
+    for device in devices {
+        if device.device_type == DeviceType::Counter {
+            cache.insert(device.id, rate_topic_for_device(device));
+        }
+    }
+}
+
+/// Mapping device ID -> error topic
+pub fn device_error_topics(
+    devices: &std::vec::Vec<Device>,
+    cache: &mut std::collections::HashMap<u8, String>,
+) {
+    for device in devices {
+        cache.insert(device.id, error_topic_for_device(device));
+    }
+}
+
+/// Devices watched for changes: the inputs, and the outputs as these can be switched from
+/// elsewhere too; see `crate::sysfs::write::route_watched`
+pub fn watched_devices(devices: &[Device]) -> std::vec::Vec<Device> {
+    devices
+        .iter()
+        .filter(|device| device.device_type.is_digital())
+        .cloned()
+        .collect()
+}
+
 /// Mapping device ID -> path
 pub fn device_paths(
     devices: &std::vec::Vec<Device>,
@@ -178,10 +350,30 @@ pub fn device_paths(
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
     use std::io::Write;
 
+    /// Temporary UniPi sysfs tree with the given value files, e.g. `("di_1_01/di_value", "1")`
+    pub fn sysfs_fixture(files: &[(&str, &str)]) -> tempdir::TempDir {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        for (file, contents) in files {
+            // Files are grouped by the io_group in their name, e.g. di_1_01 is in io_group1
+            let io_group = &file[3..4];
+            let path = tmp_dir
+                .path()
+                .join(format!(
+                    "sys/devices/platform/unipi_plc/io_group{}",
+                    io_group
+                ))
+                .join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).expect("Could not create folder");
+            std::fs::write(&path, contents).expect("Could not write contents to temp file");
+        }
+        tmp_dir
+    }
+
     #[test]
     fn test_state_topic_for_device() {
         let device = crate::device::Device {
@@ -191,6 +383,7 @@ mod tests {
             device_type: crate::device::DeviceType::DigitalOutput,
             io_group: 1,
             number: 3,
+            settings: DeviceSettings::default(),
         };
 
         assert_eq!(state_topic_for_device(&device), "foo/output/1_03/state");
@@ -205,11 +398,49 @@ mod tests {
             device_type: crate::device::DeviceType::DigitalOutput,
             io_group: 1,
             number: 3,
+            settings: DeviceSettings::default(),
         };
 
         assert_eq!(command_topic_for_device(&device), "foo/output/1_03/set");
     }
 
+    #[test]
+    fn test_action_topic_for_device() {
+        let device = crate::device::Device {
+            id: 0,
+            path: "/foo/bar".to_string(),
+            module_name: String::from("foo"),
+            device_type: crate::device::DeviceType::DigitalInput,
+            io_group: 1,
+            number: 3,
+            settings: DeviceSettings::default(),
+        };
+
+        assert_eq!(action_topic_for_device(&device), "foo/input/1_03/action");
+    }
+
+    #[test]
+    fn test_topics_for_named_device() {
+        let mut device = crate::device::Device {
+            id: 0,
+            path: "/foo/bar".to_string(),
+            module_name: String::from("foo"),
+            device_type: crate::device::DeviceType::RelayOutput,
+            io_group: 2,
+            number: 1,
+            settings: DeviceSettings::default(),
+        };
+        device.settings.name = Some("kitchen_ceiling".to_string());
+        device.settings.command_topic = Some("kitchen/ceiling/set".to_string());
+
+        assert_eq!(
+            state_topic_for_device(&device),
+            "foo/relay/kitchen_ceiling/state"
+        );
+        assert_eq!(command_topic_for_device(&device), "kitchen/ceiling/set");
+        assert_eq!(object_id_for_device(&device), "relay_kitchen_ceiling");
+    }
+
     #[test]
     fn test_device_from_captures() {
         let id = 1;
@@ -217,7 +448,7 @@ mod tests {
         let module_name = "foo";
         let re = regex::Regex::new(crate::device::FILENAME_PATTERN).unwrap();
         let captures = re.captures(path).unwrap();
-        if let Ok(device) = device_from_captures(&captures, id, &path, &module_name) {
+        if let Ok(device) = device_from_captures(&captures, id, path, module_name) {
             assert_eq!(device.module_name, "foo");
             assert_eq!(device.number, 7);
             assert_eq!(device.io_group, 2);
@@ -228,11 +459,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_device_from_captures_analog_and_counter() {
+        let re = regex::Regex::new(crate::device::FILENAME_PATTERN).unwrap();
+        for (path, device_type) in [
+            (
+                "sys/devices/platform/unipi_plc/io_group1/ai_1_02/in_voltage_raw",
+                DeviceType::AnalogInput,
+            ),
+            (
+                "sys/devices/platform/unipi_plc/io_group1/ao_1_01/out_voltage_raw",
+                DeviceType::AnalogOutput,
+            ),
+            (
+                "sys/devices/platform/unipi_plc/io_group1/di_1_04/di_counter",
+                DeviceType::Counter,
+            ),
+        ] {
+            let captures = re.captures(path).unwrap();
+            let device =
+                device_from_captures(&captures, 0, path, "foo").expect("Expect a device from path");
+            assert_eq!(device.device_type, device_type);
+        }
+    }
+
+    #[test]
+    fn test_device_id() {
+        assert_eq!(device_id(255).unwrap(), 255);
+        assert_eq!(
+            device_id(256).unwrap_err().to_string(),
+            "MausError: Too many devices: at most 256 are supported"
+        );
+    }
+
     #[test]
     fn test_device_from_captures_not_found() {
         let path = "sys/devices/platform/unipi_plc/io_group2/di_2_07/foo";
         let re = regex::Regex::new(crate::device::FILENAME_PATTERN).unwrap();
-        if let Some(_) = re.captures(path) {
+        if re.captures(path).is_some() {
             panic!("Found a device, should not be the case");
         }
     }
@@ -259,7 +523,7 @@ mod tests {
 
         let mut devices = std::vec::Vec::new();
 
-        crawl(tmp_dir.path(), &module_name, &re, &mut devices).expect("Expect crawl to work");
+        crawl(tmp_dir.path(), module_name, &re, &mut devices).expect("Expect crawl to work");
 
         assert_eq!(devices.len(), 1);
 
@@ -297,10 +561,69 @@ mod tests {
 
         let mut devices = std::vec::Vec::new();
 
-        crawl(tmp_dir.path(), &module_name, &re, &mut devices).expect("Expect crawl to work");
+        crawl(tmp_dir.path(), module_name, &re, &mut devices).expect("Expect crawl to work");
 
         assert_eq!(devices.len(), 0);
 
+        tmp_dir.close().unwrap();
+    }
+    #[test]
+    fn test_watch_regular_file_falls_back_to_inotify() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let folder_structure = "sys/devices/platform/unipi_plc/io_group1/di_1_03/";
+        let full_path = tmp_dir.path().join(folder_structure);
+        std::fs::create_dir_all(&full_path).expect("Could not create folder");
+        let path = full_path.join("di_value");
+        std::fs::write(&path, "0\n").expect("Could not write contents to temp file");
+
+        let re = regex::Regex::new(crate::device::FILENAME_PATTERN).unwrap();
+        let mut devices = std::vec::Vec::new();
+        crawl(tmp_dir.path(), "foo", &re, &mut devices).expect("Expect crawl to work");
+
+        // Regular files can not be added to epoll, so they need the fallback
+        let watcher = crate::sysfs::read::Watcher::new(devices).expect("Expect watcher to work");
+        assert_eq!(
+            watcher.modes(),
+            vec![(0, crate::sysfs::read::WatchMode::Inotify)]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_watch_fallback_emits_toggles() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let folder_structure = "sys/devices/platform/unipi_plc/io_group1/di_1_03/";
+        let full_path = tmp_dir.path().join(folder_structure);
+        std::fs::create_dir_all(&full_path).expect("Could not create folder");
+        let path = full_path.join("di_value");
+        std::fs::write(&path, "0\n").expect("Could not write contents to temp file");
+
+        let re = regex::Regex::new(crate::device::FILENAME_PATTERN).unwrap();
+        let mut devices = std::vec::Vec::new();
+        crawl(tmp_dir.path(), "foo", &re, &mut devices).expect("Expect crawl to work");
+
+        let mut watcher =
+            crate::sysfs::read::Watcher::new(devices).expect("Expect watcher to work");
+        let (tx, rx) = std::sync::mpsc::channel();
+        // The initial value is read when setting up the watcher, so the write below is a toggle
+        std::thread::spawn(move || watcher.run(tx));
+
+        std::fs::write(&path, "1\n").expect("Could not write contents to temp file");
+        let (device_id, state, _) = rx
+            .recv_timeout(std::time::Duration::from_secs(1))
+            .expect("Expect a toggle event");
+        assert_eq!((device_id, state), (0, true));
+
+        std::fs::write(&path, "0\n").expect("Could not write contents to temp file");
+        let (device_id, state, duration) = rx
+            .recv_timeout(std::time::Duration::from_secs(1))
+            .expect("Expect a toggle event");
+        assert_eq!((device_id, state), (0, false));
+        assert!(duration > std::time::Duration::from_secs(0));
+
         tmp_dir.close().unwrap();
     }
 }
@@ -0,0 +1,290 @@
+//! gesture turns raw push button toggles into semantic events like clicks and long presses
+use crate::sysfs::FileEvent;
+
+/// A gesture recognized on a push button
+#[derive(Eq, PartialEq, Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gesture {
+    Single,
+    Double,
+    Triple,
+    // Button is held down longer than the long press time
+    LongPress,
+    // Button is released after a long press
+    LongRelease,
+    // Repeated for as long as the button stays held after a long press
+    Hold,
+}
+
+impl Gesture {
+    /// Payload used when publishing the gesture
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Gesture::Single => "single",
+            Gesture::Double => "double",
+            Gesture::Triple => "triple",
+            Gesture::LongPress => "long_press",
+            Gesture::LongRelease => "long_release",
+            Gesture::Hold => "hold",
+        }
+    }
+}
+
+/// Gesture for a given device ID
+pub type GestureEvent = (u8, Gesture);
+
+/// Thresholds for recognizing gestures
+#[derive(Debug, Clone)]
+pub struct GestureSettings {
+    // Time a button needs to be held before it counts as a long press
+    pub long_press: std::time::Duration,
+    // Maximum time between a release and the next press to count as a multi click
+    pub multi_click: std::time::Duration,
+    // Interval between hold events while the button stays held
+    pub hold_repeat: std::time::Duration,
+}
+
+impl Default for GestureSettings {
+    fn default() -> Self {
+        GestureSettings {
+            long_press: std::time::Duration::from_millis(800),
+            multi_click: std::time::Duration::from_millis(300),
+            hold_repeat: std::time::Duration::from_millis(500),
+        }
+    }
+}
+
+// Tracking state for a single button
+#[derive(Debug, Default)]
+struct ButtonState {
+    pressed_at: Option<std::time::Instant>,
+    released_at: Option<std::time::Instant>,
+    // Number of short clicks seen so far in the current sequence
+    clicks: u8,
+    // Set once the current press turned into a long press
+    next_hold: Option<std::time::Instant>,
+}
+
+impl ButtonState {
+    // Finish the click sequence once no further press came within the multi click time
+    fn finish_clicks(
+        &mut self,
+        now: std::time::Instant,
+        multi_click: std::time::Duration,
+    ) -> Option<Gesture> {
+        let released_at = self.released_at?;
+        if self.pressed_at.is_some()
+            || self.clicks == 0
+            || now.saturating_duration_since(released_at) < multi_click
+        {
+            return None;
+        }
+        let gesture = match self.clicks {
+            1 => Gesture::Single,
+            _ => Gesture::Double,
+        };
+        self.clicks = 0;
+        Some(gesture)
+    }
+}
+
+/// GestureRecognizer keeps track of all push buttons and emits gestures
+pub struct GestureRecognizer {
+    settings: GestureSettings,
+    buttons: std::collections::HashMap<u8, ButtonState>,
+}
+
+impl GestureRecognizer {
+    /// Set up a recognizer for the given device IDs; events for other devices are ignored
+    pub fn new(settings: GestureSettings, device_ids: &[u8]) -> Self {
+        GestureRecognizer {
+            settings,
+            buttons: device_ids
+                .iter()
+                .map(|&device_id| (device_id, ButtonState::default()))
+                .collect(),
+        }
+    }
+
+    /// Feed a (debounced) file event observed at `now`
+    pub fn feed(
+        &mut self,
+        event: FileEvent,
+        now: std::time::Instant,
+    ) -> std::vec::Vec<GestureEvent> {
+        let (device_id, pressed, _) = event;
+        let mut gestures = std::vec::Vec::new();
+        let button = match self.buttons.get_mut(&device_id) {
+            Some(button) => button,
+            None => return gestures,
+        };
+
+        // A press after the multi click time starts a new sequence, even if not polled in between
+        if let Some(gesture) = button.finish_clicks(now, self.settings.multi_click) {
+            gestures.push((device_id, gesture));
+        }
+        if pressed {
+            button.pressed_at = Some(now);
+            button.next_hold = None;
+        } else if button.pressed_at.take().is_some() {
+            if button.next_hold.take().is_some() {
+                gestures.push((device_id, Gesture::LongRelease));
+                button.clicks = 0;
+            } else {
+                button.clicks += 1;
+                button.released_at = Some(now);
+                // No need to wait for more clicks after the third one
+                if button.clicks == 3 {
+                    gestures.push((device_id, Gesture::Triple));
+                    button.clicks = 0;
+                }
+            }
+        }
+        gestures
+    }
+
+    /// Collect the gestures for which a threshold passed at `now`
+    pub fn poll(&mut self, now: std::time::Instant) -> std::vec::Vec<GestureEvent> {
+        let mut gestures = std::vec::Vec::new();
+        for (&device_id, button) in self.buttons.iter_mut() {
+            if let Some(pressed_at) = button.pressed_at {
+                match button.next_hold {
+                    None if now.saturating_duration_since(pressed_at)
+                        >= self.settings.long_press =>
+                    {
+                        gestures.push((device_id, Gesture::LongPress));
+                        button.clicks = 0;
+                        button.next_hold = Some(now + self.settings.hold_repeat);
+                    }
+                    Some(next_hold) if now >= next_hold => {
+                        gestures.push((device_id, Gesture::Hold));
+                        button.next_hold = Some(next_hold + self.settings.hold_repeat);
+                    }
+                    _ => {}
+                }
+            } else if let Some(gesture) = button.finish_clicks(now, self.settings.multi_click) {
+                gestures.push((device_id, gesture));
+            }
+        }
+        gestures.sort_by_key(|&(device_id, _)| device_id);
+        gestures
+    }
+
+    /// The next moment a threshold could pass for any of the buttons
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        self.buttons
+            .values()
+            .filter_map(|button| match (button.pressed_at, button.next_hold) {
+                (Some(_), Some(next_hold)) => Some(next_hold),
+                (Some(pressed_at), None) => Some(pressed_at + self.settings.long_press),
+                (None, _) if button.clicks > 0 => button
+                    .released_at
+                    .map(|released_at| released_at + self.settings.multi_click),
+                _ => None,
+            })
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> std::time::Duration {
+        std::time::Duration::from_millis(millis)
+    }
+
+    // Feed a sequence of (offset in ms, pressed) for device 0 and poll every millisecond
+    fn recognize(sequence: &[(u64, bool)], until: u64) -> Vec<(Gesture, u64)> {
+        let mut recognizer = GestureRecognizer::new(GestureSettings::default(), &[0]);
+        let start = std::time::Instant::now();
+        let mut output = Vec::new();
+        let mut sequence = sequence.iter().peekable();
+        for t in 0..=until {
+            let mut gestures = Vec::new();
+            if let Some(&&(offset, pressed)) = sequence.peek() {
+                if offset == t {
+                    gestures.extend(recognizer.feed((0, pressed, ms(0)), start + ms(t)));
+                    sequence.next();
+                }
+            }
+            gestures.extend(recognizer.poll(start + ms(t)));
+            output.extend(gestures.into_iter().map(|(_, gesture)| (gesture, t)));
+        }
+        output
+    }
+
+    #[test]
+    fn test_single_click() {
+        let gestures = recognize(&[(0, true), (100, false)], 1000);
+        assert_eq!(gestures, vec![(Gesture::Single, 400)]);
+    }
+
+    #[test]
+    fn test_double_click() {
+        let gestures = recognize(&[(0, true), (100, false), (200, true), (300, false)], 1000);
+        assert_eq!(gestures, vec![(Gesture::Double, 600)]);
+    }
+
+    #[test]
+    fn test_triple_click() {
+        let sequence = [
+            (0, true),
+            (100, false),
+            (200, true),
+            (300, false),
+            (400, true),
+            (500, false),
+        ];
+        assert_eq!(recognize(&sequence, 1000), vec![(Gesture::Triple, 500)]);
+    }
+
+    #[test]
+    fn test_long_press_with_hold_repeat() {
+        let gestures = recognize(&[(0, true), (2000, false)], 3000);
+        assert_eq!(
+            gestures,
+            vec![
+                (Gesture::LongPress, 800),
+                (Gesture::Hold, 1300),
+                (Gesture::Hold, 1800),
+                (Gesture::LongRelease, 2000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_click_then_long_press() {
+        let gestures = recognize(&[(0, true), (100, false), (200, true), (1100, false)], 2000);
+        assert_eq!(
+            gestures,
+            vec![(Gesture::LongPress, 1000), (Gesture::LongRelease, 1100)]
+        );
+    }
+
+    #[test]
+    fn test_late_press_without_poll_starts_new_sequence() {
+        let mut recognizer = GestureRecognizer::new(GestureSettings::default(), &[0]);
+        let start = std::time::Instant::now();
+        assert_eq!(recognizer.feed((0, true, ms(0)), start), vec![]);
+        assert_eq!(recognizer.feed((0, false, ms(0)), start + ms(100)), vec![]);
+        // Pressed again after the multi click time, before any poll came in
+        assert_eq!(
+            recognizer.feed((0, true, ms(0)), start + ms(500)),
+            vec![(0, Gesture::Single)]
+        );
+        assert_eq!(recognizer.feed((0, false, ms(0)), start + ms(600)), vec![]);
+        assert_eq!(
+            recognizer.poll(start + ms(1000)),
+            vec![(0, Gesture::Single)]
+        );
+    }
+
+    #[test]
+    fn test_unknown_device_is_ignored() {
+        let mut recognizer = GestureRecognizer::new(GestureSettings::default(), &[0]);
+        let now = std::time::Instant::now();
+        assert_eq!(recognizer.feed((1, true, ms(0)), now), vec![]);
+        assert_eq!(recognizer.next_deadline(), None);
+    }
+}
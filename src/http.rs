@@ -0,0 +1,280 @@
+//! http serves a small JSON API to list, read and command the devices without an MQTT broker
+//!
+//! - `GET /devices` lists all devices with their hardware coordinates, path and topics
+//! - `GET /devices/<type>/<key>` gives a single device along with its current state
+//! - `POST /devices/<type>/<key>` takes the same payloads as the command topic, e.g. `TOGGLE` or
+//!   `{"pulse": 0.5}` for outputs and a number for analog outputs
+//!
+//! Devices are identified as in their topics, e.g. `relay/2_01`, or `relay/kitchen_ceiling` once
+//! named. Commands go through the same channels as the ones from MQTT and are answered right away
+//! with `202 Accepted`; errors while executing them are published on the error topic as usual.
+
+// Largest request body read, in bytes
+const MAX_BODY: u64 = 4096;
+
+/// Api answers requests for the devices
+#[derive(Debug)]
+pub struct Api {
+    devices: std::vec::Vec<crate::device::Device>,
+    analog_settings: std::collections::HashMap<u8, crate::analog::AnalogSettings>,
+    io: crate::backend::Io,
+    output_tx: std::sync::mpsc::Sender<crate::output::OutputEvent>,
+    analog_tx: std::sync::mpsc::Sender<crate::analog::AnalogEvent>,
+}
+
+// Body for an error response
+fn error(message: &str) -> serde_json::Value {
+    serde_json::json!({ "error": message })
+}
+
+// Description of a device, without its state
+fn describe(device: &crate::device::Device) -> serde_json::Value {
+    serde_json::json!({
+        "id": device.id,
+        "type": device.device_type.as_str(),
+        "io_group": device.io_group,
+        "number": device.number,
+        "key": crate::device::device_key(device),
+        "path": device.path,
+        "topics": {
+            "state": crate::device::state_topic_for_device(device),
+            "command": crate::device::command_topic_for_device(device),
+            "error": crate::device::error_topic_for_device(device),
+        },
+    })
+}
+
+impl Api {
+    pub fn new(
+        devices: &[crate::device::Device],
+        analog_settings: std::collections::HashMap<u8, crate::analog::AnalogSettings>,
+        io: crate::backend::Io,
+        output_tx: std::sync::mpsc::Sender<crate::output::OutputEvent>,
+        analog_tx: std::sync::mpsc::Sender<crate::analog::AnalogEvent>,
+    ) -> Self {
+        Api {
+            devices: devices.to_vec(),
+            analog_settings,
+            io,
+            output_tx,
+            analog_tx,
+        }
+    }
+
+    /// Device by its type and key
+    pub fn find(&self, device_type: &str, key: &str) -> Option<&crate::device::Device> {
+        self.devices.iter().find(|device| {
+            device.device_type.as_str() == device_type && crate::device::device_key(device) == key
+        })
+    }
+
+    // Current state of a device: on or off, a value in engineering units, or null if unknown
+    fn state(&self, device: &crate::device::Device) -> serde_json::Value {
+        if device.device_type.is_digital() {
+            return match self.io.read_states(std::slice::from_ref(device)).first() {
+                Some(&(_, state)) => serde_json::Value::Bool(state),
+                None => serde_json::Value::Null,
+            };
+        }
+        let settings = match self.analog_settings.get(&device.id) {
+            Some(settings) => settings,
+            None => return serde_json::Value::Null,
+        };
+        match self.io.read_analog(device) {
+            Ok(raw) => serde_json::json!(settings.to_value(raw)),
+            Err(e) => {
+                log::debug!("{}", e);
+                serde_json::Value::Null
+            }
+        }
+    }
+
+    /// Send a command for a device, giving the status code and JSON body to answer with
+    pub fn command(&self, device: &crate::device::Device, body: &str) -> (u16, serde_json::Value) {
+        let sent = match device.device_type {
+            crate::device::DeviceType::DigitalOutput | crate::device::DeviceType::RelayOutput => {
+                match crate::output::OutputCommand::parse(body) {
+                    Ok(command) => self.output_tx.send((device.id, command)).is_ok(),
+                    Err(e) => return (400, error(&e)),
+                }
+            }
+            crate::device::DeviceType::AnalogOutput => match crate::analog::parse_value(body) {
+                Ok(value) => self.analog_tx.send((device.id, value)).is_ok(),
+                Err(e) => return (400, error(&e)),
+            },
+            _ => {
+                return (
+                    405,
+                    error(&format!(
+                        "Device {} {} takes no commands",
+                        device.device_type.as_str(),
+                        crate::device::device_key(device)
+                    )),
+                )
+            }
+        };
+        match sent {
+            true => (202, serde_json::json!({ "accepted": true })),
+            false => (503, error("Outputs are not running")),
+        }
+    }
+
+    /// Answer a request, giving the status code and JSON body
+    pub fn handle(
+        &self,
+        method: &tiny_http::Method,
+        url: &str,
+        body: &str,
+    ) -> (u16, serde_json::Value) {
+        let path = url.split('?').next().unwrap_or_default();
+        let segments: std::vec::Vec<&str> = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        match (method, segments.as_slice()) {
+            (tiny_http::Method::Get, ["devices"]) => (
+                200,
+                serde_json::Value::Array(self.devices.iter().map(describe).collect()),
+            ),
+            (method, ["devices", device_type, key]) => {
+                let device = match self.find(device_type, key) {
+                    Some(device) => device,
+                    None => return (404, error(&format!("No device {} {}", device_type, key))),
+                };
+                match method {
+                    tiny_http::Method::Get => {
+                        let mut description = describe(device);
+                        description["state"] = self.state(device);
+                        (200, description)
+                    }
+                    tiny_http::Method::Post => self.command(device, body),
+                    _ => (405, error(&format!("Method {} is not allowed", method))),
+                }
+            }
+            _ => (404, error(&format!("No resource {}", path))),
+        }
+    }
+}
+
+// Answer a single request
+fn respond(api: &Api, mut request: tiny_http::Request) {
+    let mut body = String::new();
+    let (status, json) = match std::io::Read::read_to_string(
+        &mut std::io::Read::take(request.as_reader(), MAX_BODY),
+        &mut body,
+    ) {
+        Ok(_) => api.handle(request.method(), request.url(), &body),
+        Err(e) => (400, error(&format!("Could not read body: {}", e))),
+    };
+    log::debug!("{} {} -> {}", request.method(), request.url(), status);
+    let header = tiny_http::Header::from_bytes("Content-Type", "application/json").unwrap();
+    let response = tiny_http::Response::from_string(json.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        log::debug!("Could not answer HTTP request: {}", e);
+    }
+}
+
+/// Answer all requests, one at a time
+pub fn serve(server: tiny_http::Server, api: Api) {
+    for request in server.incoming_requests() {
+        respond(&api, request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Api for an input, a named relay and an analog input, along with the command channel
+    fn api() -> (Api, std::sync::mpsc::Receiver<crate::output::OutputEvent>) {
+        let backend = std::sync::Arc::new(crate::backend::tests::MockBackend {
+            devices: vec![
+                (crate::device::DeviceType::DigitalInput, 1, 1),
+                (crate::device::DeviceType::RelayOutput, 2, 1),
+                (crate::device::DeviceType::AnalogInput, 1, 1),
+            ],
+            values: std::sync::Mutex::new(std::collections::HashMap::from([(1, true)])),
+            ..Default::default()
+        });
+        let (io, mut devices) = crate::backend::Io::discover(vec![backend], "plc").unwrap();
+        devices[1].settings.name = Some("kitchen".to_string());
+        let (output_tx, output_rx) = std::sync::mpsc::channel();
+        let (analog_tx, _) = std::sync::mpsc::channel();
+        let analog_settings =
+            std::collections::HashMap::from([(2, crate::analog::AnalogSettings::default())]);
+        let api = Api::new(&devices, analog_settings, io, output_tx, analog_tx);
+        (api, output_rx)
+    }
+
+    #[test]
+    fn test_handle() {
+        let (api, output_rx) = api();
+        let (status, json) = api.handle(&tiny_http::Method::Get, "/devices", "");
+        assert_eq!(status, 200);
+        assert_eq!(json.as_array().unwrap().len(), 3);
+        assert_eq!(json[1]["key"], "kitchen");
+        assert_eq!(json[1]["topics"]["command"], "plc/relay/kitchen/set");
+
+        let (status, json) = api.handle(&tiny_http::Method::Get, "/devices/relay/kitchen", "");
+        assert_eq!(status, 200);
+        assert_eq!(json["state"], true);
+        assert_eq!(json["path"], "mock:1");
+        // Analog values can not be read from the mock
+        let (_, json) = api.handle(&tiny_http::Method::Get, "/devices/analog_input/1_01", "");
+        assert_eq!(json["state"], serde_json::Value::Null);
+
+        let (status, _) = api.handle(
+            &tiny_http::Method::Post,
+            "/devices/relay/kitchen",
+            "{\"pulse\": 0.5}",
+        );
+        assert_eq!(status, 202);
+        assert_eq!(
+            output_rx.try_recv(),
+            Ok((
+                1,
+                crate::output::OutputCommand::Pulse(std::time::Duration::from_millis(500))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_handle_errors() {
+        let (api, output_rx) = api();
+        let cases = [
+            (tiny_http::Method::Get, "/devices/relay/2_01", 404),
+            (tiny_http::Method::Get, "/outputs", 404),
+            (tiny_http::Method::Delete, "/devices/relay/kitchen", 405),
+            (tiny_http::Method::Post, "/devices/input/1_01", 405),
+            (tiny_http::Method::Post, "/devices/relay/kitchen?x=1", 400),
+        ];
+        for (method, url, expected) in cases {
+            let (status, json) = api.handle(&method, url, "maybe");
+            assert_eq!(status, expected, "{} {}: {}", method, url, json);
+            assert!(json["error"].is_string());
+        }
+        assert!(output_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_serve() {
+        let (api, _output_rx) = api();
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        std::thread::spawn(move || serve(server, api));
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        std::io::Write::write_all(
+            &mut stream,
+            b"GET /devices/input/1_01 HTTP/1.0\r\nHost: localhost\r\n\r\n",
+        )
+        .unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200"), "{}", response);
+        assert!(response.contains("application/json"));
+        assert!(response.contains("\"state\":false"));
+    }
+}
@@ -0,0 +1,129 @@
+//! interlock keeps mutually exclusive outputs, like the up and down relay of a motor, from being
+//! switched on together
+
+/// Outputs of which at most one can be on at any time
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterlockGroup {
+    pub devices: std::vec::Vec<u8>,
+    // Time an output needs to be off before another one in the group can be switched on
+    pub dead_time: std::time::Duration,
+}
+
+/// Outcome of checking a command against the interlock groups
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Decision {
+    Allow,
+    // Allowed once the dead time passed at the given moment
+    Wait(std::time::Instant),
+    Reject(String),
+}
+
+/// Interlock tracks the state of all outputs in an interlock group
+#[derive(Debug, Clone, Default)]
+pub struct Interlock {
+    groups: std::vec::Vec<InterlockGroup>,
+    states: std::collections::HashMap<u8, bool>,
+    // Moment an output was last switched off
+    switched_off: std::collections::HashMap<u8, std::time::Instant>,
+}
+
+impl Interlock {
+    /// Set up the groups, starting from the current output states
+    pub fn new(groups: std::vec::Vec<InterlockGroup>, states: &[(u8, bool)]) -> Self {
+        Interlock {
+            groups,
+            states: states.iter().copied().collect(),
+            switched_off: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Check whether an output can be switched to the given state at `now`
+    ///
+    /// Switching off is always allowed.
+    pub fn check(&self, device_id: u8, state: bool, now: std::time::Instant) -> Decision {
+        if !state {
+            return Decision::Allow;
+        }
+        let mut wait_until: Option<std::time::Instant> = None;
+        for group in self
+            .groups
+            .iter()
+            .filter(|group| group.devices.contains(&device_id))
+        {
+            for &other in group.devices.iter().filter(|&&other| other != device_id) {
+                if self.states.get(&other) == Some(&true) {
+                    return Decision::Reject(format!(
+                        "Interlocked with device #{}, which is still on",
+                        other
+                    ));
+                }
+                if let Some(&switched_off) = self.switched_off.get(&other) {
+                    let allowed_at = switched_off + group.dead_time;
+                    if allowed_at > now {
+                        wait_until = wait_until.max(Some(allowed_at));
+                    }
+                }
+            }
+        }
+        match wait_until {
+            Some(allowed_at) => Decision::Wait(allowed_at),
+            None => Decision::Allow,
+        }
+    }
+
+    /// Whether an output is part of any interlock group
+    pub fn is_interlocked(&self, device_id: u8) -> bool {
+        self.groups
+            .iter()
+            .any(|group| group.devices.contains(&device_id))
+    }
+
+    /// Keep track of the state an output was switched to at `now`
+    pub fn set_state(&mut self, device_id: u8, state: bool, now: std::time::Instant) {
+        if self.states.insert(device_id, state) == Some(true) && !state {
+            self.switched_off.insert(device_id, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interlock() -> Interlock {
+        Interlock::new(
+            vec![InterlockGroup {
+                devices: vec![1, 2],
+                dead_time: std::time::Duration::from_millis(500),
+            }],
+            &[(1, false), (2, false)],
+        )
+    }
+
+    #[test]
+    fn test_reject_while_other_is_on() {
+        let mut interlock = interlock();
+        let now = std::time::Instant::now();
+        assert_eq!(interlock.check(1, true, now), Decision::Allow);
+        interlock.set_state(1, true, now);
+
+        assert!(matches!(interlock.check(2, true, now), Decision::Reject(_)));
+        // Switching off and devices outside the group are never held back
+        assert_eq!(interlock.check(2, false, now), Decision::Allow);
+        assert_eq!(interlock.check(3, true, now), Decision::Allow);
+    }
+
+    #[test]
+    fn test_dead_time_before_reversal() {
+        let mut interlock = interlock();
+        let now = std::time::Instant::now();
+        interlock.set_state(1, true, now);
+        interlock.set_state(1, false, now);
+
+        let allowed_at = now + std::time::Duration::from_millis(500);
+        assert_eq!(interlock.check(2, true, now), Decision::Wait(allowed_at));
+        assert_eq!(interlock.check(2, true, allowed_at), Decision::Allow);
+        // The same output can be switched on again right away
+        assert_eq!(interlock.check(1, true, now), Decision::Allow);
+    }
+}
@@ -1,7 +1,20 @@
+pub mod analog;
 pub mod auto;
+pub mod backend;
+pub mod config;
+pub mod counter;
+pub mod debounce;
 pub mod device;
 pub mod dummy;
 pub mod errors;
+pub mod gesture;
+pub mod http;
+pub mod interlock;
 pub mod maus;
+pub mod modbus;
+pub mod models;
 pub mod mqtt;
+pub mod onewire;
+pub mod output;
+pub mod stream;
 pub mod sysfs;
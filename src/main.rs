@@ -1,10 +1,15 @@
 use clap::Parser;
 
+// Command line arguments override the settings from the configuration file
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
     #[arg(help = "MQTT broker host to connect to")]
-    mqtt_host: String,
+    mqtt_host: Option<String>,
+
+    // Optional TOML configuration file
+    #[arg(long)]
+    config: Option<String>,
 
     // Optional sysfs root path to start scanning for files
     #[arg(long)]
@@ -21,43 +26,312 @@ struct Cli {
     // Optional arg to set the MQTT client ID string. Defaults to `hausmaus`
     #[arg(long)]
     mqtt_client_id: Option<String>,
+
+    // MQTT broker port. Defaults to 1883
+    #[arg(long)]
+    mqtt_port: Option<u16>,
+
+    // Username for authenticating with the MQTT broker
+    #[arg(long)]
+    mqtt_username: Option<String>,
+
+    // File to read the MQTT password from
+    #[arg(long, conflicts_with = "mqtt_password_env")]
+    mqtt_password_file: Option<String>,
+
+    // Environment variable to read the MQTT password from
+    #[arg(long)]
+    mqtt_password_env: Option<String>,
+
+    // CA bundle to verify the MQTT broker against; enables TLS
+    #[arg(long)]
+    mqtt_ca_file: Option<String>,
+
+    // Client certificate for mutual TLS
+    #[arg(long, requires = "mqtt_client_key")]
+    mqtt_client_cert: Option<String>,
+
+    // Private key for the client certificate
+    #[arg(long, requires = "mqtt_client_cert")]
+    mqtt_client_key: Option<String>,
+
+    // Whether the broker should drop the session when disconnected
+    #[arg(long, action = clap::ArgAction::Set)]
+    mqtt_clean_session: Option<bool>,
+
+    // MQTT keep alive interval in milliseconds, at least 5000
+    #[arg(long)]
+    mqtt_keep_alive: Option<u64>,
+
+    // Time in milliseconds an input needs to be stable before a change is passed on
+    #[arg(long)]
+    debounce: Option<u64>,
+
+    // Per input debounce time as `<io_group>_<number>=<milliseconds>`, e.g. `1_03=100`
+    #[arg(long, value_parser = parse_input_debounce)]
+    input_debounce: Vec<(String, u64)>,
+
+    // Time in milliseconds a push button needs to be held to count as a long press
+    #[arg(long)]
+    long_press: Option<u64>,
+
+    // Maximum time in milliseconds between clicks to count as a double or triple click
+    #[arg(long)]
+    multi_click: Option<u64>,
+
+    // Interval in milliseconds between hold events while a push button stays held
+    #[arg(long)]
+    hold_repeat: Option<u64>,
+
+    // Topic prefix for Home Assistant MQTT discovery
+    #[arg(long)]
+    discovery_prefix: Option<String>,
+
+    // Optional arg to disable Home Assistant MQTT discovery
+    #[arg(long)]
+    no_discovery: bool,
+
+    // Optional availability topic for the birth and last will messages. Defaults to
+    // `<device_name>/status`
+    #[arg(long)]
+    availability_topic: Option<String>,
+
+    // Payload published on the availability topic while connected
+    #[arg(long)]
+    payload_online: Option<String>,
+
+    // Payload published on the availability topic by the broker when the connection is lost
+    #[arg(long)]
+    payload_offline: Option<String>,
+
+    // QoS for publishing input states
+    #[arg(long, value_parser = parse_qos)]
+    input_qos: Option<u8>,
+
+    // Retain flag for publishing input states
+    #[arg(long, action = clap::ArgAction::Set)]
+    input_retain: Option<bool>,
+
+    // QoS for publishing digital output states
+    #[arg(long, value_parser = parse_qos)]
+    output_qos: Option<u8>,
+
+    // Retain flag for publishing digital output states
+    #[arg(long, action = clap::ArgAction::Set)]
+    output_retain: Option<bool>,
+
+    // QoS for publishing relay states
+    #[arg(long, value_parser = parse_qos)]
+    relay_qos: Option<u8>,
+
+    // Retain flag for publishing relay states
+    #[arg(long, action = clap::ArgAction::Set)]
+    relay_retain: Option<bool>,
+
+    // QoS for publishing events like push button gestures
+    #[arg(long, value_parser = parse_qos)]
+    event_qos: Option<u8>,
+
+    // Retain flag for publishing events like push button gestures
+    #[arg(long, action = clap::ArgAction::Set)]
+    event_retain: Option<bool>,
+
+    // QoS for subscribing to command topics
+    #[arg(long, value_parser = parse_qos)]
+    command_qos: Option<u8>,
+}
+
+// Parse a QoS level argument
+fn parse_qos(arg: &str) -> Result<u8, String> {
+    match arg.parse::<u8>() {
+        Ok(qos) if qos <= 2 => Ok(qos),
+        _ => Err(format!("expected a QoS level 0, 1 or 2, got {:?}", arg)),
+    }
+}
+
+// Parse a per input debounce argument
+fn parse_input_debounce(arg: &str) -> Result<(String, u64), String> {
+    match arg.split_once('=') {
+        Some((input, millis)) => match millis.parse::<u64>() {
+            Ok(millis) => Ok((input.to_string(), millis)),
+            Err(e) => Err(format!("invalid debounce time {:?}: {}", millis, e)),
+        },
+        None => Err(format!(
+            "expected <io_group>_<number>=<milliseconds>, got {:?}",
+            arg
+        )),
+    }
 }
 
 // device name from hostname
 fn device_name() -> Option<String> {
     match hostname::get() {
-        Ok(os_string) => match os_string.into_string() {
-            Ok(str_ref) => Some(str_ref),
-            Err(_) => None,
-        },
+        Ok(os_string) => os_string.into_string().ok(),
         Err(_) => None,
     }
 }
 
+// Override the configuration with all arguments given on the command line
+fn apply_cli(cli: Cli, config: &mut hausmaus::config::Config) -> Result<(), String> {
+    if let Some(mqtt_host) = cli.mqtt_host {
+        config.mqtt.host = Some(mqtt_host);
+    }
+    if let Some(sysfs) = cli.sysfs {
+        config.sysfs = sysfs;
+    }
+    if let Some(device_name) = cli.device_name {
+        config.device_name = Some(device_name);
+    }
+    if cli.debug {
+        config.logging.level = "debug".to_string();
+    }
+    if let Some(mqtt_client_id) = cli.mqtt_client_id {
+        config.mqtt.client_id = mqtt_client_id;
+    }
+    if let Some(mqtt_port) = cli.mqtt_port {
+        config.mqtt.port = mqtt_port;
+    }
+    if let Some(mqtt_username) = cli.mqtt_username {
+        config.mqtt.username = Some(mqtt_username);
+    }
+    // A password source on the command line replaces the one from the configuration file
+    if cli.mqtt_password_file.is_some() || cli.mqtt_password_env.is_some() {
+        config.mqtt.password = None;
+        config.mqtt.password_file = cli.mqtt_password_file;
+        config.mqtt.password_env = cli.mqtt_password_env;
+    }
+    if let Some(mqtt_ca_file) = cli.mqtt_ca_file {
+        config.mqtt.ca_file = Some(mqtt_ca_file);
+    }
+    if let Some(mqtt_client_cert) = cli.mqtt_client_cert {
+        config.mqtt.client_cert = Some(mqtt_client_cert);
+    }
+    if let Some(mqtt_client_key) = cli.mqtt_client_key {
+        config.mqtt.client_key = Some(mqtt_client_key);
+    }
+    if let Some(mqtt_clean_session) = cli.mqtt_clean_session {
+        config.mqtt.clean_session = mqtt_clean_session;
+    }
+    if let Some(mqtt_keep_alive) = cli.mqtt_keep_alive {
+        config.mqtt.keep_alive_ms = mqtt_keep_alive;
+    }
+    if let Some(debounce) = cli.debounce {
+        config.debounce.stable_time_ms = debounce;
+    }
+    for (input, millis) in cli.input_debounce {
+        let device_section = config.devices.iter_mut().find(|device_section| {
+            device_section.device_type == hausmaus::device::DeviceType::DigitalInput
+                && format!("{}_{:02}", device_section.io_group, device_section.number) == input
+        });
+        match device_section {
+            Some(device_section) => device_section.debounce_ms = Some(millis),
+            None => {
+                let (io_group, number) = input
+                    .split_once('_')
+                    .and_then(|(io_group, number)| {
+                        Some((io_group.parse().ok()?, number.parse().ok()?))
+                    })
+                    .ok_or_else(|| format!("invalid input {:?} for debounce", input))?;
+                config.devices.push(hausmaus::config::DeviceSection {
+                    device_type: hausmaus::device::DeviceType::DigitalInput,
+                    io_group,
+                    number,
+                    name: None,
+                    state_topic: None,
+                    command_topic: None,
+                    invert: false,
+                    enabled: true,
+                    model: None,
+                    debounce_ms: Some(millis),
+                    staircase_ms: None,
+                    sample_interval_ms: None,
+                    deadband: None,
+                    scale: None,
+                    unit: None,
+                    rate_factor: None,
+                });
+            }
+        }
+    }
+    if let Some(long_press) = cli.long_press {
+        config.gestures.long_press_ms = long_press;
+    }
+    if let Some(multi_click) = cli.multi_click {
+        config.gestures.multi_click_ms = multi_click;
+    }
+    if let Some(hold_repeat) = cli.hold_repeat {
+        config.gestures.hold_repeat_ms = hold_repeat;
+    }
+    if let Some(discovery_prefix) = cli.discovery_prefix {
+        config.mqtt.discovery_prefix = discovery_prefix;
+    }
+    if cli.no_discovery {
+        config.mqtt.discovery = false;
+    }
+    if let Some(availability_topic) = cli.availability_topic {
+        config.mqtt.availability_topic = Some(availability_topic);
+    }
+    if let Some(payload_online) = cli.payload_online {
+        config.mqtt.payload_online = payload_online;
+    }
+    if let Some(payload_offline) = cli.payload_offline {
+        config.mqtt.payload_offline = payload_offline;
+    }
+    for (qos, retain, publish_section) in [
+        (cli.input_qos, cli.input_retain, &mut config.mqtt.inputs),
+        (cli.output_qos, cli.output_retain, &mut config.mqtt.outputs),
+        (cli.relay_qos, cli.relay_retain, &mut config.mqtt.relays),
+        (cli.event_qos, cli.event_retain, &mut config.mqtt.events),
+    ] {
+        if let Some(qos) = qos {
+            publish_section.qos = qos;
+        }
+        if let Some(retain) = retain {
+            publish_section.retain = retain;
+        }
+    }
+    if let Some(command_qos) = cli.command_qos {
+        config.mqtt.command_qos = command_qos;
+    }
+    Ok(())
+}
+
 fn main() {
     let cli = Cli::parse();
 
-    let sysfs_path = cli.sysfs.as_deref().unwrap_or("/run/unipi");
+    let mut config = match cli.config.as_deref() {
+        Some(path) => hausmaus::config::Config::from_file(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        None => hausmaus::config::Config::default(),
+    };
+    if let Err(e) = apply_cli(cli, &mut config) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    let device_name: String = match cli.device_name.as_deref() {
-        // from input arg
+    let device_name: String = match config.device_name.as_deref() {
+        // from input arg or configuration file
         Some(device_name) => device_name.to_string(),
         // from hostname
         None => device_name().unwrap(),
     };
-    let device_name = slug::slugify(device_name);
-    let device_name = device_name.as_str();
-
-    let debug = cli.debug;
+    config.device_name = Some(slug::slugify(device_name));
 
-    let mqtt_client_id = cli.mqtt_client_id.as_deref().unwrap_or("hausmaus");
+    if let Err(e) = config.validate() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
     // log config
-    let log_level = match debug {
-        true => "debug",
-        false => "info",
-    };
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level)).init();
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(config.logging.level.as_str()),
+    )
+    .init();
 
-    hausmaus::maus::run(&cli.mqtt_host, sysfs_path, device_name, mqtt_client_id);
+    if let Err(e) = hausmaus::maus::run(&config) {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
 }
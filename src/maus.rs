@@ -1,62 +1,444 @@
-const MQTT_KEEP_ALIVE: u64 = 20;
 const MQTT_CLIENT_CHANNEL_CAP: usize = 10;
 
 /// run is the main entry point to start the maus
 ///
 /// It spawns:
-/// - all input reader threads
+/// - all input and output watcher threads, and the thread handing output changes to the writer
+/// - the debounce thread filtering contact bounce
 /// - all output write threads
 /// - the main automation engine thread to link input events to output events
-pub fn run(mqtt_host: &str, sysfs_path: &str, device_name: &str, mqtt_client_id: &str) {
+/// - the rule engine thread running the configured rules
+/// - the analog input sampling and analog output write threads
+/// - the counter sampling thread
+/// - the 1-Wire sensor sampling thread
+/// - the Modbus TCP server thread, when enabled
+/// - the HTTP API thread, when enabled
+/// - the WebSocket stream thread, when enabled
+///
+/// The configuration is expected to be validated already; devices from the configuration that
+/// can not be found are reported as an error.
+pub fn run(config: &crate::config::Config) -> Result<(), crate::errors::MausError> {
     log::debug!("Start hausmaus");
+    let device_name = config.device_name();
+    let mqtt_config = config.mqtt_config();
+    let debounce_config = config.debounce_config();
+    let discovery_prefix = config.discovery_prefix();
 
-    // Crawl a folder for paths to watch based on a regex
-    log::debug!("Start crawling path {:?}", sysfs_path);
-    let mut devices: std::vec::Vec<crate::device::Device> = std::vec::Vec::new();
-    crate::device::devices_from_path(sysfs_path, device_name, &mut devices).unwrap();
-    log::info!("Finished crawling path {:?}", sysfs_path);
+    // Discover the devices of every board through its backend
+    let (io, mut devices) = crate::backend::Io::discover(config.backends(), device_name)?;
+    config.apply_devices(&mut devices)?;
     for device in &devices {
         log::debug!("Found device with id {} {:?}", device.id, device.path);
     }
     log::debug!("Number of devices: {}", devices.len());
 
-    log::debug!("Build mapping of state topics for devices");
-    let mut state_topic_map: std::collections::HashMap<u8, String> =
-        std::collections::HashMap::new();
-    crate::device::device_state_topics(&devices, &mut state_topic_map);
+    log::debug!("Build mapping of state, action and error topics for devices");
+    let mut device_topics = crate::mqtt::publish::DeviceTopics::default();
+    crate::device::device_state_topics(&devices, &mut device_topics.state);
+    crate::device::device_action_topics(&devices, &mut device_topics.action);
+    crate::device::device_error_topics(&devices, &mut device_topics.error);
+    crate::device::device_rate_topics(&devices, &mut device_topics.rate);
 
     log::debug!("Build mapping of command topics for devices");
     let mut command_topic_map: std::collections::HashMap<String, u8> =
         std::collections::HashMap::new();
     crate::device::device_command_topics(&devices, &mut command_topic_map);
+    // Analog outputs take numbers instead, counters a new total, analog inputs no commands at all
+    let mut analog_settings = config.analog_settings(&devices);
+    let counter_settings = config.counter_settings(&devices);
+    command_topic_map.retain(|_, device_id| {
+        !analog_settings.contains_key(device_id) && !counter_settings.contains_key(device_id)
+    });
+    // The analog output of a dimmer is written by the dimmer only, so it gets no command topic,
+    // discovery or writer of its own
+    let dimmers = config.dimmers(&devices)?;
+    analog_settings
+        .retain(|device_id, _| !dimmers.iter().any(|dimmer| dimmer.output.id == *device_id));
+    let analog_commands: std::collections::HashMap<String, u8> = devices
+        .iter()
+        .filter(|device| device.device_type == crate::device::DeviceType::AnalogOutput)
+        .filter(|device| analog_settings.contains_key(&device.id))
+        .map(|device| (crate::device::command_topic_for_device(device), device.id))
+        .collect();
+    let counter_commands: std::collections::HashMap<String, u8> = devices
+        .iter()
+        .filter(|device| counter_settings.contains_key(&device.id))
+        .map(|device| (crate::device::command_topic_for_device(device), device.id))
+        .collect();
+
+    // Interlock groups start from the current output states
+    let output_devices: std::vec::Vec<crate::device::Device> = devices
+        .iter()
+        .filter(|device| device.device_type.is_switch())
+        .cloned()
+        .collect();
+    let interlock = crate::interlock::Interlock::new(
+        config.interlock_groups(&devices)?,
+        &io.read_states(&output_devices),
+    );
 
-    log::debug!("Build mapping of paths for devices");
-    let mut path_map: std::collections::HashMap<u8, String> = std::collections::HashMap::new();
-    crate::device::device_paths(&devices, &mut path_map);
+    let debouncer = crate::debounce::Debouncer::new(
+        debounce_config.stable_time,
+        debounce_config.stable_times(&devices),
+    );
 
     // MQTT setup
-    let mut mqtt_options = rumqttc::MqttOptions::new(mqtt_client_id, mqtt_host, 1883);
-    mqtt_options.set_keep_alive(std::time::Duration::from_secs(MQTT_KEEP_ALIVE));
+    let mqtt_options = mqtt_config.mqtt_options()?;
 
-    let (mut mqtt_client, mut mqtt_loop): (rumqttc::Client, rumqttc::Connection) =
+    let (mqtt_client, mut mqtt_loop): (rumqttc::Client, rumqttc::Connection) =
         rumqttc::Client::new(mqtt_options, MQTT_CLIENT_CHANNEL_CAP);
     //let mqtt_client = std::sync::Arc::new(mqtt_client);
 
-    // Subscribe
-    crate::mqtt::subscribe::subscribe_topics(&mut mqtt_client, &command_topic_map);
+    // Covers
+    let covers = config.covers(&devices)?;
+    device_topics.covers = covers
+        .iter()
+        .map(|cover| crate::models::CoverTopics::new(device_name, &cover.name))
+        .collect();
+
+    // 1-Wire sensors
+    let sensors = config.sensors(&crate::onewire::discover(&config.onewire.path));
+    log::info!("Found {} 1-Wire sensors", sensors.len());
+    device_topics.sensors = sensors
+        .iter()
+        .map(|sensor| crate::onewire::SensorTopics::new(device_name, &sensor.name))
+        .collect();
+
+    // Lights
+    let lights = config.lights(&devices)?;
+    let rules = config.rules(&devices)?;
+    device_topics.dimmers = dimmers
+        .iter()
+        .map(|dimmer| crate::models::DimmerTopics::new(device_name, &dimmer.name))
+        .collect();
+    let mut dimmer_buttons: std::collections::HashMap<u8, std::vec::Vec<usize>> =
+        std::collections::HashMap::new();
+    for (index, dimmer) in dimmers.iter().enumerate() {
+        for &button in &dimmer.buttons {
+            dimmer_buttons.entry(button).or_default().push(index);
+        }
+    }
+
+    // Gestures are only recognized for push buttons: the inputs configured as such, the buttons
+    // of the dimmable lights and the inputs of gesture triggers
+    let mut button_ids: std::vec::Vec<u8> = devices
+        .iter()
+        .filter(|device| device.settings.model == Some(crate::device::Model::PushButton))
+        .map(|device| device.id)
+        .chain(dimmer_buttons.keys().copied())
+        .chain(rules.iter().filter_map(|rule| match rule.trigger {
+            crate::auto::Trigger::Gesture(device_id, _) => Some(device_id),
+            _ => None,
+        }))
+        .collect();
+    button_ids.sort();
+    button_ids.dedup();
+    let gesture_recognizer =
+        crate::gesture::GestureRecognizer::new(config.gesture_settings(), &button_ids);
+
+    // Home Assistant discovery
+    let mut discovery_configs = std::vec::Vec::new();
+    if let Some(prefix) = discovery_prefix {
+        discovery_configs.extend(crate::mqtt::discovery::discovery_configs(
+            &devices,
+            prefix,
+            &mqtt_config.availability.topic,
+        ));
+        for device in &devices {
+            if let Some(settings) = analog_settings.get(&device.id) {
+                discovery_configs.push(crate::mqtt::discovery::config_for_analog(
+                    device,
+                    settings,
+                    prefix,
+                    &mqtt_config.availability.topic,
+                ));
+            }
+        }
+        for device in &devices {
+            if let Some(settings) = counter_settings.get(&device.id) {
+                discovery_configs.extend(crate::mqtt::discovery::configs_for_counter(
+                    device,
+                    settings,
+                    prefix,
+                    &mqtt_config.availability.topic,
+                ));
+            }
+        }
+        for (sensor, sensor_topics) in sensors.iter().zip(&device_topics.sensors) {
+            discovery_configs.push(crate::mqtt::discovery::config_for_sensor(
+                sensor,
+                sensor_topics,
+                device_name,
+                prefix,
+                &mqtt_config.availability.topic,
+            ));
+        }
+        for (cover, cover_topics) in covers.iter().zip(&device_topics.covers) {
+            discovery_configs.push(crate::mqtt::discovery::config_for_cover(
+                &cover.name,
+                cover_topics,
+                device_name,
+                prefix,
+                &mqtt_config.availability.topic,
+            ));
+        }
+        for (dimmer, dimmer_topics) in dimmers.iter().zip(&device_topics.dimmers) {
+            discovery_configs.push(crate::mqtt::discovery::config_for_dimmer(
+                &dimmer.name,
+                dimmer_topics,
+                device_name,
+                prefix,
+                &mqtt_config.availability.topic,
+            ));
+        }
+    }
+
+    // Subscriptions, restored by the publish thread after every ConnAck
+    let incoming_topics = crate::mqtt::subscribe::IncomingTopics {
+        commands: command_topic_map,
+        analog_commands,
+        counter_commands,
+        cover_commands: device_topics
+            .covers
+            .iter()
+            .enumerate()
+            .map(|(index, cover_topics)| (cover_topics.command.clone(), index))
+            .collect(),
+        cover_positions: device_topics
+            .covers
+            .iter()
+            .enumerate()
+            .map(|(index, cover_topics)| (cover_topics.set_position.clone(), index))
+            .collect(),
+        dimmer_commands: device_topics
+            .dimmers
+            .iter()
+            .enumerate()
+            .map(|(index, dimmer_topics)| (dimmer_topics.command.clone(), index))
+            .collect(),
+        dimmer_brightness: device_topics
+            .dimmers
+            .iter()
+            .enumerate()
+            .map(|(index, dimmer_topics)| (dimmer_topics.set_brightness.clone(), index))
+            .collect(),
+        discovery: discovery_prefix.map(|prefix| {
+            crate::mqtt::discovery::DiscoveryTopics::new(prefix, device_name, &discovery_configs)
+        }),
+        refresh: format!("{}/refresh", device_name),
+        rules: rules
+            .iter()
+            .filter_map(|rule| match &rule.trigger {
+                crate::auto::Trigger::Message(topic, _) => Some(topic.clone()),
+                _ => None,
+            })
+            .collect(),
+    };
+    let subscriptions = incoming_topics.filters(mqtt_config.command_qos);
+    let rule_engine = crate::auto::RuleEngine::new(rules, io.clone());
+    let output_timers =
+        crate::output::OutputTimers::new(config.staircases(&devices), &devices, io.clone());
+
+    // Analog inputs are sampled, analog outputs written on command
+    let mut analog_inputs = std::vec::Vec::new();
+    let mut analog_outputs = std::collections::HashMap::new();
+    for device in &devices {
+        let settings = match analog_settings.get(&device.id) {
+            Some(settings) => settings.clone(),
+            None => continue,
+        };
+        match device.device_type {
+            crate::device::DeviceType::AnalogInput => {
+                analog_inputs.push((device.clone(), settings));
+            }
+            _ => {
+                analog_outputs.insert(device.id, (device.clone(), settings));
+            }
+        }
+    }
+    let analog_sampler =
+        crate::analog::AnalogSampler::new(analog_inputs, std::time::Instant::now());
+
+    // Counters continue from their saved states
+    let counter_state_file = config.counters.state_file.clone();
+    let saved_counter_states = match &counter_state_file {
+        Some(path) => crate::counter::load_states(path),
+        None => std::collections::HashMap::new(),
+    };
+    let counters = crate::counter::Counters::new(
+        devices
+            .iter()
+            .filter_map(|device| {
+                let settings = counter_settings.get(&device.id)?;
+                Some((device.clone(), settings.clone()))
+            })
+            .collect(),
+        &saved_counter_states,
+        std::time::Duration::from_millis(config.counters.sample_interval_ms),
+        std::time::Instant::now(),
+    );
+    let sensor_sampler = crate::onewire::SensorSampler::new(
+        sensors,
+        std::time::Duration::from_millis(config.onewire.sample_interval_ms),
+        std::time::Instant::now(),
+    );
 
     // Channels
+    let (watch_tx, watch_rx) = std::sync::mpsc::channel();
     let (file_read_tx, file_read_rx) = std::sync::mpsc::channel();
+    let (debounce_tx, debounce_rx) = std::sync::mpsc::channel();
     let (mqtt_publish_tx, mqtt_publish_rx) = std::sync::mpsc::channel();
+    let connection_publish_tx = mqtt_publish_tx.clone();
+    let feedback_publish_tx = mqtt_publish_tx.clone();
     let (log_write_tx, log_write_rx) = std::sync::mpsc::channel();
-    let (mqtt_subscribe_tx, mqtt_subscribe_rx) = std::sync::mpsc::channel();
+    let (output_tx, output_rx) = std::sync::mpsc::channel();
     let (file_write_tx, file_write_rx) = std::sync::mpsc::channel();
+    let cover_write_tx = file_write_tx.clone();
+    let observed_write_tx = file_write_tx.clone();
+    let cover_publish_tx = mqtt_publish_tx.clone();
+    let (cover_tx, cover_rx) = std::sync::mpsc::channel();
+    let dimmer_publish_tx = mqtt_publish_tx.clone();
+    let (dimmer_tx, dimmer_rx) = std::sync::mpsc::channel();
+    let rule_output_tx = output_tx.clone();
+    let rule_publish_tx = mqtt_publish_tx.clone();
+    let (rule_tx, rule_rx) = std::sync::mpsc::channel();
+    let analog_input_publish_tx = mqtt_publish_tx.clone();
+    let analog_output_publish_tx = mqtt_publish_tx.clone();
+    let analog_io = io.clone();
+    let analog_output_io = io.clone();
+    let dimmer_io = io.clone();
+    let (analog_tx, analog_rx) = std::sync::mpsc::channel();
+    let counter_publish_tx = mqtt_publish_tx.clone();
+    let (counter_tx, counter_rx) = std::sync::mpsc::channel();
+    let sensor_publish_tx = mqtt_publish_tx.clone();
+
+    // Modbus clients command the outputs through the same channels as MQTT
+    let modbus_server = match config.modbus_server.enabled {
+        true => {
+            let bind = &config.modbus_server.bind;
+            let listener = std::net::TcpListener::bind(bind).map_err(|e| {
+                crate::errors::MausError::new(format!("Could not listen on {}: {}", bind, e))
+            })?;
+            log::info!("Modbus server listening on {}", bind);
+            if listener
+                .local_addr()
+                .is_ok_and(|addr| !addr.ip().is_loopback())
+            {
+                log::warn!(
+                    "The Modbus server has no authentication: anyone who can reach {} can switch the outputs",
+                    bind
+                );
+            }
+            Some((
+                listener,
+                crate::modbus::server::Server::new(
+                    &devices,
+                    analog_settings.clone(),
+                    io.clone(),
+                    output_tx.clone(),
+                    analog_tx.clone(),
+                ),
+            ))
+        }
+        false => None,
+    };
+
+    // The HTTP API commands the outputs through the same channels as MQTT as well
+    let http_server = match config.http.enabled {
+        true => {
+            let bind = &config.http.bind;
+            let server = tiny_http::Server::http(bind).map_err(|e| {
+                crate::errors::MausError::new(format!("Could not listen on {}: {}", bind, e))
+            })?;
+            log::info!("HTTP API listening on {}", bind);
+            Some((
+                server,
+                crate::http::Api::new(
+                    &devices,
+                    analog_settings.clone(),
+                    io.clone(),
+                    output_tx.clone(),
+                    analog_tx.clone(),
+                ),
+            ))
+        }
+        false => None,
+    };
+
+    // Stream clients get the same events as MQTT and command the outputs through the same channels
+    let stream = crate::stream::Broadcast::default();
+    let stream_server = match config.stream.enabled {
+        true => {
+            let bind = &config.stream.bind;
+            let listener = std::net::TcpListener::bind(bind).map_err(|e| {
+                crate::errors::MausError::new(format!("Could not listen on {}: {}", bind, e))
+            })?;
+            log::info!("WebSocket stream listening on {}", bind);
+            let api = crate::http::Api::new(
+                &devices,
+                analog_settings.clone(),
+                io.clone(),
+                output_tx.clone(),
+                analog_tx.clone(),
+            );
+            Some((
+                listener,
+                crate::stream::Stream::new(&devices, io.clone(), api, stream.clone()),
+            ))
+        }
+        false => None,
+    };
+    let feedback_stream = stream.clone();
+
+    let dispatch = crate::mqtt::subscribe::Dispatch {
+        commands: output_tx.clone(),
+        covers: cover_tx,
+        dimmers: dimmer_tx.clone(),
+        analog: analog_tx,
+        counters: counter_tx,
+        rules: rule_tx.clone(),
+    };
+    let bindings = crate::auto::Bindings {
+        lights,
+        dimmers: dimmer_buttons,
+        output_tx,
+        dimmer_tx,
+        io: io.clone(),
+    };
+
+    let publish_mqtt_config = mqtt_config.clone();
+    let backoff = mqtt_config.backoff();
+    let snapshot = crate::mqtt::publish::Snapshot {
+        devices: devices.clone(),
+        io: io.clone(),
+    };
+    let written_devices: std::collections::HashMap<u8, crate::device::Device> = devices
+        .iter()
+        .map(|device| (device.id, device.clone()))
+        .collect();
 
     let mut handles = std::vec::Vec::new();
 
-    log::debug!("Start main file event watcher thread");
+    log::debug!("Start input and output watcher threads, one per board");
+    handles.extend(io.watch(crate::device::watched_devices(&devices), watch_tx));
+
+    // Outputs are published by the writer thread only, which also feeds them to the interlock
+    let watched_outputs: std::collections::HashSet<u8> =
+        output_devices.iter().map(|device| device.id).collect();
+    log::debug!("Start thread to route output changes to the writer");
     let handle = std::thread::spawn(move || {
-        crate::sysfs::read::watch_input_file_events(devices.clone(), file_read_tx);
+        crate::sysfs::write::route_watched(
+            watch_rx,
+            file_read_tx,
+            observed_write_tx,
+            &watched_outputs,
+        );
+    });
+    handles.push(handle);
+
+    log::debug!("Start thread to debounce file events");
+    let handle = std::thread::spawn(move || {
+        crate::debounce::run_debounce(file_read_rx, debounce_tx, debouncer);
     });
     handles.push(handle);
 
@@ -68,35 +450,131 @@ pub fn run(mqtt_host: &str, sysfs_path: &str, device_name: &str, mqtt_client_id:
 
     log::debug!("Start thread to connect path sysfs read -> mqtt publish");
     let handle = std::thread::spawn(move || {
-        crate::auto::run_sysfs_to_mqtt(file_read_rx, log_write_tx, mqtt_publish_tx);
+        crate::auto::run_sysfs_to_mqtt(
+            debounce_rx,
+            log_write_tx,
+            mqtt_publish_tx,
+            gesture_recognizer,
+            bindings,
+            rule_tx,
+            stream,
+        );
     });
     handles.push(handle);
 
     log::debug!("Start thread to connect to handle MQTT publishing");
     let handle = std::thread::spawn(move || {
-        crate::mqtt::publish::publish_messages(mqtt_publish_rx, mqtt_client, &state_topic_map);
+        crate::mqtt::publish::publish_messages(
+            mqtt_publish_rx,
+            mqtt_client,
+            &device_topics,
+            &publish_mqtt_config,
+            &subscriptions,
+            &discovery_configs,
+            &snapshot,
+        );
     });
     handles.push(handle);
 
-    log::debug!("Start thread to connect MQTT subscribe -> sys write");
+    log::debug!("Start thread to run output commands and their timers");
     let handle = std::thread::spawn(move || {
-        crate::auto::run_mqtt_to_sysfs(mqtt_subscribe_rx, file_write_tx);
+        crate::output::run_outputs(output_rx, file_write_tx, output_timers);
     });
     handles.push(handle);
 
     log::debug!("Start thread to subscribe to and handle MQTT command topics");
     let handle = std::thread::spawn(move || {
         crate::mqtt::subscribe::handle_incoming_messages(
-            mqtt_subscribe_tx,
+            dispatch,
             &mut mqtt_loop,
-            &command_topic_map,
+            &incoming_topics,
+            connection_publish_tx,
+            backoff,
         )
     });
     handles.push(handle);
 
-    log::debug!("Start thread to subscribe to and handle MQTT command topics");
+    log::debug!("Start thread to write commands to sysfs and report back the state");
+    let handle = std::thread::spawn(move || {
+        crate::sysfs::write::handle_file_command(
+            file_write_rx,
+            &written_devices,
+            &io,
+            interlock,
+            feedback_publish_tx,
+            feedback_stream,
+        );
+    });
+    handles.push(handle);
+
+    if let Some((listener, server)) = modbus_server {
+        log::debug!("Start thread to serve Modbus clients");
+        let handle = std::thread::spawn(move || {
+            crate::modbus::server::serve(listener, server);
+        });
+        handles.push(handle);
+    }
+
+    if let Some((server, api)) = http_server {
+        log::debug!("Start thread to serve the HTTP API");
+        let handle = std::thread::spawn(move || {
+            crate::http::serve(server, api);
+        });
+        handles.push(handle);
+    }
+
+    if let Some((listener, stream)) = stream_server {
+        log::debug!("Start thread to stream to WebSocket clients");
+        let handle = std::thread::spawn(move || {
+            crate::stream::serve(listener, stream);
+        });
+        handles.push(handle);
+    }
+
+    log::debug!("Start thread to drive the covers");
+    let handle = std::thread::spawn(move || {
+        crate::models::run_covers(cover_rx, cover_write_tx, cover_publish_tx, covers);
+    });
+    handles.push(handle);
+
+    log::debug!("Start thread to drive the dimmable lights");
+    let handle = std::thread::spawn(move || {
+        crate::models::run_dimmers(dimmer_rx, dimmer_io, dimmer_publish_tx, dimmers);
+    });
+    handles.push(handle);
+
+    log::debug!("Start thread to run the rules");
+    let handle = std::thread::spawn(move || {
+        crate::auto::run_rules(rule_rx, rule_output_tx, rule_publish_tx, rule_engine);
+    });
+    handles.push(handle);
+
+    log::debug!("Start thread to sample the analog inputs");
+    let handle = std::thread::spawn(move || {
+        crate::analog::run_analog_inputs(analog_sampler, analog_io, analog_input_publish_tx);
+    });
+    handles.push(handle);
+
+    log::debug!("Start thread to write the analog outputs");
+    let handle = std::thread::spawn(move || {
+        crate::analog::run_analog_outputs(
+            analog_rx,
+            analog_outputs,
+            analog_output_io,
+            analog_output_publish_tx,
+        );
+    });
+    handles.push(handle);
+
+    log::debug!("Start thread to sample the counters");
+    let handle = std::thread::spawn(move || {
+        crate::counter::run_counters(counter_rx, counters, counter_state_file, counter_publish_tx);
+    });
+    handles.push(handle);
+
+    log::debug!("Start thread to sample the 1-Wire sensors");
     let handle = std::thread::spawn(move || {
-        crate::sysfs::write::handle_file_command(file_write_rx, &path_map);
+        crate::onewire::run_sensors(sensor_sampler, sensor_publish_tx);
     });
     handles.push(handle);
 
@@ -104,4 +582,5 @@ pub fn run(mqtt_host: &str, sysfs_path: &str, device_name: &str, mqtt_client_id:
     for handle in handles {
         handle.join().unwrap();
     }
+    Ok(())
 }
@@ -0,0 +1,510 @@
+//! modbus speaks the Modbus TCP protocol: framing, the request and response PDUs, a client and a
+//! server for the devices of hausmaus
+//!
+//! Only the functions needed to read and write single devices are supported: reading coils,
+//! discrete inputs, holding and input registers, and writing a single coil or register.
+pub mod server;
+
+// Largest number of bits and registers that fit in a single read response
+pub const MAX_BITS: u16 = 2000;
+const MAX_REGISTERS: u16 = 125;
+// Largest PDU, which together with the unit ID fits the length field of the MBAP header
+const MAX_PDU: usize = 253;
+// Value of a coil being on in a write single coil request
+const COIL_ON: u16 = 0xff00;
+
+/// Exception codes sent back for requests that can not be served
+pub const ILLEGAL_FUNCTION: u8 = 0x01;
+pub const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+pub const ILLEGAL_DATA_VALUE: u8 = 0x03;
+pub const SERVER_DEVICE_FAILURE: u8 = 0x04;
+
+/// The four data tables of a Modbus device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Table {
+    Coils,
+    DiscreteInputs,
+    HoldingRegisters,
+    InputRegisters,
+}
+
+impl Table {
+    /// Name of the table as used in device paths
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Table::Coils => "coil",
+            Table::DiscreteInputs => "discrete_input",
+            Table::HoldingRegisters => "holding_register",
+            Table::InputRegisters => "input_register",
+        }
+    }
+
+    // Function code to read the table
+    fn read_function(&self) -> u8 {
+        match self {
+            Table::Coils => 0x01,
+            Table::DiscreteInputs => 0x02,
+            Table::HoldingRegisters => 0x03,
+            Table::InputRegisters => 0x04,
+        }
+    }
+
+    /// Whether the table holds single bits rather than registers
+    pub fn is_bits(&self) -> bool {
+        matches!(self, Table::Coils | Table::DiscreteInputs)
+    }
+}
+
+/// Request PDU
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    // Table, start address and number of bits or registers
+    Read(Table, u16, u16),
+    WriteCoil(u16, bool),
+    WriteRegister(u16, u16),
+}
+
+/// Response PDU to a request that succeeded
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Bits(std::vec::Vec<bool>),
+    Registers(std::vec::Vec<u16>),
+    // Echo of a write request
+    Written,
+}
+
+// Big-endian u16 at `offset` of a PDU
+fn word(pdu: &[u8], offset: usize) -> Option<u16> {
+    pdu.get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+// Bits packed 8 to a byte, the first one in the least significant bit
+fn pack_bits(bits: &[bool]) -> std::vec::Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |byte, (index, &bit)| byte | ((bit as u8) << index))
+        })
+        .collect()
+}
+
+fn unpack_bits(bytes: &[u8], count: usize) -> std::vec::Vec<bool> {
+    (0..count)
+        .map(|index| bytes[index / 8] & (1 << (index % 8)) != 0)
+        .collect()
+}
+
+impl Request {
+    /// Function code of the request
+    pub fn function(&self) -> u8 {
+        match self {
+            Request::Read(table, _, _) => table.read_function(),
+            Request::WriteCoil(_, _) => 0x05,
+            Request::WriteRegister(_, _) => 0x06,
+        }
+    }
+
+    /// Encode the request PDU
+    pub fn encode(&self) -> std::vec::Vec<u8> {
+        let (address, value) = match *self {
+            Request::Read(_, address, count) => (address, count),
+            Request::WriteCoil(address, value) => (address, if value { COIL_ON } else { 0 }),
+            Request::WriteRegister(address, value) => (address, value),
+        };
+        let mut pdu = vec![self.function()];
+        pdu.extend(address.to_be_bytes());
+        pdu.extend(value.to_be_bytes());
+        pdu
+    }
+
+    /// Decode a request PDU, or the exception code to answer it with
+    pub fn parse(pdu: &[u8]) -> Result<Request, u8> {
+        let function = *pdu.first().ok_or(ILLEGAL_FUNCTION)?;
+        let table = match function {
+            0x01 => Some(Table::Coils),
+            0x02 => Some(Table::DiscreteInputs),
+            0x03 => Some(Table::HoldingRegisters),
+            0x04 => Some(Table::InputRegisters),
+            0x05 | 0x06 => None,
+            _ => return Err(ILLEGAL_FUNCTION),
+        };
+        let (address, value) = match (word(pdu, 1), word(pdu, 3)) {
+            (Some(address), Some(value)) if pdu.len() == 5 => (address, value),
+            _ => return Err(ILLEGAL_DATA_VALUE),
+        };
+        match (table, function) {
+            (Some(table), _) => {
+                let max = if table.is_bits() {
+                    MAX_BITS
+                } else {
+                    MAX_REGISTERS
+                };
+                if !(1..=max).contains(&value) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                if address.checked_add(value - 1).is_none() {
+                    return Err(ILLEGAL_DATA_ADDRESS);
+                }
+                Ok(Request::Read(table, address, value))
+            }
+            (None, 0x05) => match value {
+                COIL_ON => Ok(Request::WriteCoil(address, true)),
+                0 => Ok(Request::WriteCoil(address, false)),
+                _ => Err(ILLEGAL_DATA_VALUE),
+            },
+            (None, _) => Ok(Request::WriteRegister(address, value)),
+        }
+    }
+
+    /// Encode the response PDU to this request, or the exception for it
+    pub fn encode_response(&self, response: Result<Response, u8>) -> std::vec::Vec<u8> {
+        let function = self.function();
+        match response {
+            Ok(Response::Bits(bits)) => {
+                let bytes = pack_bits(&bits);
+                let mut pdu = vec![function, bytes.len() as u8];
+                pdu.extend(bytes);
+                pdu
+            }
+            Ok(Response::Registers(registers)) => {
+                let mut pdu = vec![function, (registers.len() * 2) as u8];
+                pdu.extend(registers.iter().flat_map(|register| register.to_be_bytes()));
+                pdu
+            }
+            Ok(Response::Written) => self.encode(),
+            Err(code) => vec![function | 0x80, code],
+        }
+    }
+
+    /// Decode the response PDU to this request
+    pub fn parse_response(&self, pdu: &[u8]) -> Result<Response, String> {
+        let function = self.function();
+        match pdu {
+            [code, exception] if *code == function | 0x80 => {
+                return Err(format!(
+                    "Exception {:#04x} for function {}",
+                    exception, function
+                ))
+            }
+            [code, ..] if *code == function => {}
+            _ => return Err(format!("Invalid response {:02x?}", pdu)),
+        }
+        match *self {
+            Request::Read(table, _, count) => {
+                let count = count as usize;
+                let expected = match table.is_bits() {
+                    true => count.div_ceil(8),
+                    false => count * 2,
+                };
+                let data = match pdu.get(1) {
+                    Some(&length) if length as usize == expected && pdu.len() == 2 + expected => {
+                        &pdu[2..]
+                    }
+                    _ => return Err(format!("Invalid response length {}", pdu.len())),
+                };
+                Ok(match table.is_bits() {
+                    true => Response::Bits(unpack_bits(data, count)),
+                    false => Response::Registers(
+                        (0..count)
+                            .filter_map(|index| word(data, index * 2))
+                            .collect(),
+                    ),
+                })
+            }
+            _ if pdu == self.encode() => Ok(Response::Written),
+            _ => Err(format!("Write was not echoed: {:02x?}", pdu)),
+        }
+    }
+}
+
+/// Read a Modbus TCP frame: the transaction ID, unit ID and PDU
+pub fn read_frame(
+    reader: &mut impl std::io::Read,
+) -> std::io::Result<(u16, u8, std::vec::Vec<u8>)> {
+    let mut header = [0; 7];
+    reader.read_exact(&mut header)?;
+    let transaction = u16::from_be_bytes([header[0], header[1]]);
+    let protocol = u16::from_be_bytes([header[2], header[3]]);
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if protocol != 0 || !(2..=MAX_PDU + 1).contains(&length) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid MBAP header {:02x?}", header),
+        ));
+    }
+    let mut pdu = vec![0; length - 1];
+    reader.read_exact(&mut pdu)?;
+    Ok((transaction, header[6], pdu))
+}
+
+/// Write a Modbus TCP frame
+pub fn write_frame(
+    writer: &mut impl std::io::Write,
+    transaction: u16,
+    unit_id: u8,
+    pdu: &[u8],
+) -> std::io::Result<()> {
+    let mut frame = std::vec::Vec::with_capacity(7 + pdu.len());
+    frame.extend(transaction.to_be_bytes());
+    frame.extend(0u16.to_be_bytes());
+    frame.extend((pdu.len() as u16 + 1).to_be_bytes());
+    frame.push(unit_id);
+    frame.extend(pdu);
+    writer.write_all(&frame)
+}
+
+/// Client for a single unit of a Modbus TCP server, e.g. an extension module or a gateway
+///
+/// The connection is made on the first request, and made again on the next request after it
+/// failed.
+#[derive(Debug)]
+pub struct Client {
+    // Host and port of the server
+    address: String,
+    unit_id: u8,
+    timeout: std::time::Duration,
+    stream: Option<std::net::TcpStream>,
+    transaction: u16,
+}
+
+impl Client {
+    pub fn new(address: &str, unit_id: u8, timeout: std::time::Duration) -> Self {
+        Client {
+            address: address.to_string(),
+            unit_id,
+            timeout,
+            stream: None,
+            transaction: 0,
+        }
+    }
+
+    // Connect to the server, unless still connected
+    fn connect(&mut self) -> std::io::Result<&mut std::net::TcpStream> {
+        if let Some(ref mut stream) = self.stream {
+            return Ok(stream);
+        }
+        let socket_address = std::net::ToSocketAddrs::to_socket_addrs(&self.address)?
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "Address does not resolve")
+            })?;
+        let stream = std::net::TcpStream::connect_timeout(&socket_address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        log::info!("Connected to Modbus server {}", self.address);
+        Ok(self.stream.insert(stream))
+    }
+
+    // Send a request and wait for the PDU answering it, skipping late answers to earlier ones
+    fn exchange(&mut self, pdu: &[u8]) -> std::io::Result<std::vec::Vec<u8>> {
+        self.transaction = self.transaction.wrapping_add(1);
+        let (transaction, unit_id) = (self.transaction, self.unit_id);
+        let stream = self.connect()?;
+        write_frame(stream, transaction, unit_id, pdu)?;
+        loop {
+            let (received, _, response) = read_frame(stream)?;
+            if received == transaction {
+                return Ok(response);
+            }
+            log::debug!("Skipping response to Modbus transaction {}", received);
+        }
+    }
+
+    /// Send a request and wait for its response
+    pub fn request(&mut self, request: &Request) -> Result<Response, crate::errors::MausError> {
+        let pdu = self.exchange(&request.encode()).map_err(|e| {
+            // Start over with a new connection on the next request
+            self.stream = None;
+            crate::errors::MausError::new(format!("Modbus server {}: {}", self.address, e))
+        })?;
+        request.parse_response(&pdu).map_err(|e| {
+            crate::errors::MausError::new(format!("Modbus server {}: {}", self.address, e))
+        })
+    }
+
+    /// Read `count` bits from the coils or discrete inputs, starting at `address`
+    pub fn read_bits(
+        &mut self,
+        table: Table,
+        address: u16,
+        count: u16,
+    ) -> Result<std::vec::Vec<bool>, crate::errors::MausError> {
+        match self.request(&Request::Read(table, address, count))? {
+            Response::Bits(bits) => Ok(bits),
+            response => Err(crate::errors::MausError::new(format!(
+                "Unexpected Modbus response {:?}",
+                response
+            ))),
+        }
+    }
+
+    /// Read `count` holding or input registers, starting at `address`
+    pub fn read_registers(
+        &mut self,
+        table: Table,
+        address: u16,
+        count: u16,
+    ) -> Result<std::vec::Vec<u16>, crate::errors::MausError> {
+        match self.request(&Request::Read(table, address, count))? {
+            Response::Registers(registers) => Ok(registers),
+            response => Err(crate::errors::MausError::new(format!(
+                "Unexpected Modbus response {:?}",
+                response
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Modbus TCP simulator, serving all four tables from memory
+    #[derive(Debug, Default)]
+    pub struct Simulator {
+        pub tables: std::sync::Mutex<std::collections::HashMap<(Table, u16), u16>>,
+    }
+
+    impl Simulator {
+        // Answer a single request PDU
+        fn handle(&self, pdu: &[u8]) -> std::vec::Vec<u8> {
+            let request = match Request::parse(pdu) {
+                Ok(request) => request,
+                Err(code) => return vec![pdu.first().copied().unwrap_or(0) | 0x80, code],
+            };
+            let mut tables = self.tables.lock().unwrap();
+            let response = match request {
+                Request::Read(table, address, count) => {
+                    let values = (address..address + count)
+                        .map(|address| tables.get(&(table, address)).copied().unwrap_or(0));
+                    match table.is_bits() {
+                        true => Response::Bits(values.map(|value| value != 0).collect()),
+                        false => Response::Registers(values.collect()),
+                    }
+                }
+                Request::WriteCoil(address, value) => {
+                    tables.insert((Table::Coils, address), value as u16);
+                    Response::Written
+                }
+                Request::WriteRegister(address, value) => {
+                    tables.insert((Table::HoldingRegisters, address), value);
+                    Response::Written
+                }
+            };
+            request.encode_response(Ok(response))
+        }
+
+        /// Listen on a free local port, serving every connection in a thread of its own
+        pub fn start(self: &std::sync::Arc<Self>) -> String {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let simulator = self.clone();
+            std::thread::spawn(move || {
+                for mut stream in listener.incoming().flatten() {
+                    let simulator = simulator.clone();
+                    std::thread::spawn(move || {
+                        while let Ok((transaction, unit_id, pdu)) = read_frame(&mut stream) {
+                            let response = simulator.handle(&pdu);
+                            if write_frame(&mut stream, transaction, unit_id, &response).is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+            });
+            address
+        }
+    }
+
+    #[test]
+    fn test_pdus() {
+        let request = Request::Read(Table::Coils, 19, 10);
+        assert_eq!(request.encode(), vec![0x01, 0x00, 0x13, 0x00, 0x0a]);
+        assert_eq!(Request::parse(&request.encode()), Ok(request.clone()));
+        let mut bits = vec![false; 10];
+        bits[0] = true;
+        bits[9] = true;
+        let pdu = request.encode_response(Ok(Response::Bits(bits.clone())));
+        assert_eq!(pdu, vec![0x01, 0x02, 0x01, 0x02]);
+        assert_eq!(request.parse_response(&pdu), Ok(Response::Bits(bits)));
+
+        let request = Request::Read(Table::InputRegisters, 8, 2);
+        let pdu = request.encode_response(Ok(Response::Registers(vec![10, 0x1234])));
+        assert_eq!(pdu, vec![0x04, 0x04, 0x00, 0x0a, 0x12, 0x34]);
+        assert_eq!(
+            request.parse_response(&pdu),
+            Ok(Response::Registers(vec![10, 0x1234]))
+        );
+
+        let request = Request::WriteCoil(172, true);
+        assert_eq!(request.encode(), vec![0x05, 0x00, 0xac, 0xff, 0x00]);
+        assert_eq!(
+            request.parse_response(&request.encode()),
+            Ok(Response::Written)
+        );
+        assert_eq!(
+            request.parse_response(&request.encode_response(Err(ILLEGAL_DATA_ADDRESS))),
+            Err("Exception 0x02 for function 5".to_string())
+        );
+
+        assert_eq!(
+            Request::parse(&[0x2b, 0x0e, 0x01, 0x00]),
+            Err(ILLEGAL_FUNCTION)
+        );
+        assert_eq!(
+            Request::parse(&[0x03, 0x00, 0x00, 0x00, 0x7e]),
+            Err(ILLEGAL_DATA_VALUE)
+        );
+        assert_eq!(
+            Request::parse(&[0x05, 0x00, 0x01, 0x12, 0x34]),
+            Err(ILLEGAL_DATA_VALUE)
+        );
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut frame = std::vec::Vec::new();
+        write_frame(&mut frame, 0x0102, 7, &[0x03, 0x00, 0x00, 0x00, 0x01]).unwrap();
+        assert_eq!(
+            frame,
+            vec![0x01, 0x02, 0x00, 0x00, 0x00, 0x06, 0x07, 0x03, 0x00, 0x00, 0x00, 0x01]
+        );
+        assert_eq!(
+            read_frame(&mut frame.as_slice()).unwrap(),
+            (0x0102, 7, vec![0x03, 0x00, 0x00, 0x00, 0x01])
+        );
+        frame[2] = 1;
+        assert!(read_frame(&mut frame.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_client() {
+        let simulator = std::sync::Arc::new(Simulator::default());
+        simulator
+            .tables
+            .lock()
+            .unwrap()
+            .insert((Table::DiscreteInputs, 3), 1);
+        let address = simulator.start();
+
+        let mut client = Client::new(&address, 1, std::time::Duration::from_secs(1));
+        assert_eq!(
+            client.read_bits(Table::DiscreteInputs, 2, 3).unwrap(),
+            vec![false, true, false]
+        );
+        assert_eq!(
+            client.request(&Request::WriteRegister(5, 4711)).unwrap(),
+            Response::Written
+        );
+        assert_eq!(
+            client
+                .read_registers(Table::HoldingRegisters, 5, 1)
+                .unwrap(),
+            vec![4711]
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! server answers Modbus TCP requests for the devices of hausmaus
+//!
+//! Devices are found at `io_group * 100 + number`: inputs as discrete inputs, outputs as coils,
+//! analog inputs as input registers and analog outputs as holding registers. Relays are coils as
+//! well, from address 1000 on, so relay 2_01 is coil 1201. Registers hold the raw values.
+//!
+//! Writes are sent as commands, just like the ones from MQTT, so they go through the same output
+//! timers and interlocks. They are acknowledged right away; the state that results is published
+//! over MQTT as usual. Clients that stay silent for `CLIENT_TIMEOUT` are disconnected.
+
+// Coil addresses of the relays start here, to keep them apart from the digital outputs
+const RELAY_OFFSET: i32 = 1000;
+// Time a client gets between requests and for taking every response
+const CLIENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Table and address of a device, if it is exposed
+pub fn address(device: &crate::device::Device) -> Option<(crate::modbus::Table, u16)> {
+    let (table, offset) = match device.device_type {
+        crate::device::DeviceType::DigitalInput => (crate::modbus::Table::DiscreteInputs, 0),
+        crate::device::DeviceType::DigitalOutput => (crate::modbus::Table::Coils, 0),
+        crate::device::DeviceType::RelayOutput => (crate::modbus::Table::Coils, RELAY_OFFSET),
+        crate::device::DeviceType::AnalogInput => (crate::modbus::Table::InputRegisters, 0),
+        crate::device::DeviceType::AnalogOutput => (crate::modbus::Table::HoldingRegisters, 0),
+        crate::device::DeviceType::Counter => return None,
+    };
+    let address = offset + device.io_group as i32 * 100 + device.number as i32;
+    u16::try_from(address).ok().map(|address| (table, address))
+}
+
+/// Server maps Modbus requests to reads of and commands for the devices
+#[derive(Debug)]
+pub struct Server {
+    // Device by table and address
+    devices: std::collections::HashMap<(crate::modbus::Table, u16), crate::device::Device>,
+    // Settings of the analog outputs, to turn raw values into commands
+    analog_settings: std::collections::HashMap<u8, crate::analog::AnalogSettings>,
+    io: crate::backend::Io,
+    output_tx: std::sync::mpsc::Sender<crate::output::OutputEvent>,
+    analog_tx: std::sync::mpsc::Sender<crate::analog::AnalogEvent>,
+    // Idle clients are dropped after this, so they do not hold on to a thread forever
+    timeout: std::time::Duration,
+}
+
+impl Server {
+    pub fn new(
+        devices: &[crate::device::Device],
+        analog_settings: std::collections::HashMap<u8, crate::analog::AnalogSettings>,
+        io: crate::backend::Io,
+        output_tx: std::sync::mpsc::Sender<crate::output::OutputEvent>,
+        analog_tx: std::sync::mpsc::Sender<crate::analog::AnalogEvent>,
+    ) -> Self {
+        Server {
+            devices: devices
+                .iter()
+                .filter_map(|device| address(device).map(|address| (address, device.clone())))
+                .collect(),
+            analog_settings,
+            io,
+            output_tx,
+            analog_tx,
+            timeout: CLIENT_TIMEOUT,
+        }
+    }
+
+    // Read the value of a device as a bit or raw register value
+    fn read(&self, device: &crate::device::Device) -> Result<u16, crate::errors::MausError> {
+        if device.device_type.is_digital() {
+            return match self.io.read_states(std::slice::from_ref(device)).first() {
+                Some(&(_, state)) => Ok(state as u16),
+                None => Err(crate::errors::MausError::new(format!(
+                    "Could not read device #{}",
+                    device.id
+                ))),
+            };
+        }
+        let raw = self.io.read_analog(device)?;
+        Ok(raw.round().clamp(0.0, u16::MAX as f64) as u16)
+    }
+
+    /// Answer a request, or give the exception code to answer it with
+    ///
+    /// Addresses without a device read as 0, as long as at least one address read has one.
+    pub fn handle(&self, request: &crate::modbus::Request) -> Result<crate::modbus::Response, u8> {
+        match *request {
+            crate::modbus::Request::Read(table, first, count) => {
+                let devices: std::vec::Vec<Option<&crate::device::Device>> = (first
+                    ..=first + (count - 1))
+                    .map(|address| self.devices.get(&(table, address)))
+                    .collect();
+                if devices.iter().all(|device| device.is_none()) {
+                    return Err(crate::modbus::ILLEGAL_DATA_ADDRESS);
+                }
+                let values = devices
+                    .into_iter()
+                    .map(|device| match device {
+                        Some(device) => self.read(device),
+                        None => Ok(0),
+                    })
+                    .collect::<Result<std::vec::Vec<u16>, crate::errors::MausError>>()
+                    .map_err(|e| {
+                        log::error!("{}", e);
+                        crate::modbus::SERVER_DEVICE_FAILURE
+                    })?;
+                Ok(match table.is_bits() {
+                    true => crate::modbus::Response::Bits(
+                        values.into_iter().map(|value| value != 0).collect(),
+                    ),
+                    false => crate::modbus::Response::Registers(values),
+                })
+            }
+            crate::modbus::Request::WriteCoil(address, state) => {
+                let device = self
+                    .devices
+                    .get(&(crate::modbus::Table::Coils, address))
+                    .ok_or(crate::modbus::ILLEGAL_DATA_ADDRESS)?;
+                log::debug!("Modbus command {:?} for device #{}", state, device.id);
+                self.output_tx
+                    .send((device.id, crate::output::OutputCommand::Set(state)))
+                    .map_err(|_| crate::modbus::SERVER_DEVICE_FAILURE)?;
+                Ok(crate::modbus::Response::Written)
+            }
+            crate::modbus::Request::WriteRegister(address, raw) => {
+                let device = self
+                    .devices
+                    .get(&(crate::modbus::Table::HoldingRegisters, address))
+                    .ok_or(crate::modbus::ILLEGAL_DATA_ADDRESS)?;
+                let value = match self.analog_settings.get(&device.id) {
+                    Some(settings) => settings.to_value(raw as f64),
+                    None => raw as f64,
+                };
+                log::debug!("Modbus value {} for device #{}", value, device.id);
+                self.analog_tx
+                    .send((device.id, value))
+                    .map_err(|_| crate::modbus::SERVER_DEVICE_FAILURE)?;
+                Ok(crate::modbus::Response::Written)
+            }
+        }
+    }
+}
+
+// Answer all requests of a single client until it disconnects
+fn handle_connection(mut stream: std::net::TcpStream, server: &Server) {
+    let peer = stream
+        .peer_addr()
+        .map(|peer| peer.to_string())
+        .unwrap_or_default();
+    log::info!("Modbus client {} connected", peer);
+    // Clients that go silent or stop reading are dropped
+    if let Err(e) = stream
+        .set_read_timeout(Some(server.timeout))
+        .and_then(|_| stream.set_write_timeout(Some(server.timeout)))
+    {
+        log::error!("Could not set timeouts for Modbus client {}: {}", peer, e);
+        return;
+    }
+    while let Ok((transaction, unit_id, pdu)) = crate::modbus::read_frame(&mut stream) {
+        let response = match crate::modbus::Request::parse(&pdu) {
+            Ok(request) => request.encode_response(server.handle(&request)),
+            Err(code) => vec![pdu.first().copied().unwrap_or(0) | 0x80, code],
+        };
+        if let Err(e) = crate::modbus::write_frame(&mut stream, transaction, unit_id, &response) {
+            log::debug!("Could not answer Modbus client {}: {}", peer, e);
+            break;
+        }
+    }
+    log::info!("Modbus client {} disconnected", peer);
+}
+
+/// Accept Modbus TCP clients, answering each in a thread of its own
+pub fn serve(listener: std::net::TcpListener, server: Server) {
+    let server = std::sync::Arc::new(server);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let server = server.clone();
+                std::thread::spawn(move || handle_connection(stream, &server));
+            }
+            Err(e) => log::error!("Could not accept Modbus client: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Server for an input, an output and a relay, along with the command channels
+    fn server() -> (
+        Server,
+        std::sync::mpsc::Receiver<crate::output::OutputEvent>,
+        std::sync::mpsc::Receiver<crate::analog::AnalogEvent>,
+    ) {
+        let backend = std::sync::Arc::new(crate::backend::tests::MockBackend {
+            devices: vec![
+                (crate::device::DeviceType::DigitalInput, 1, 1),
+                (crate::device::DeviceType::DigitalInput, 1, 3),
+                (crate::device::DeviceType::DigitalOutput, 1, 1),
+                (crate::device::DeviceType::RelayOutput, 2, 1),
+                (crate::device::DeviceType::AnalogOutput, 1, 1),
+            ],
+            values: std::sync::Mutex::new(std::collections::HashMap::from([(1, true)])),
+            ..Default::default()
+        });
+        let (io, devices) = crate::backend::Io::discover(vec![backend], "plc").unwrap();
+        let settings = crate::analog::AnalogSettings {
+            scale: Some(crate::analog::Scale {
+                raw_min: 0.0,
+                raw_max: 10000.0,
+                min: 0.0,
+                max: 100.0,
+            }),
+            ..Default::default()
+        };
+        let (output_tx, output_rx) = std::sync::mpsc::channel();
+        let (analog_tx, analog_rx) = std::sync::mpsc::channel();
+        let server = Server::new(
+            &devices,
+            std::collections::HashMap::from([(4, settings)]),
+            io,
+            output_tx,
+            analog_tx,
+        );
+        (server, output_rx, analog_rx)
+    }
+
+    #[test]
+    fn test_handle() {
+        let (server, output_rx, analog_rx) = server();
+        assert_eq!(
+            server.handle(&crate::modbus::Request::Read(
+                crate::modbus::Table::DiscreteInputs,
+                101,
+                3
+            )),
+            Ok(crate::modbus::Response::Bits(vec![false, false, true]))
+        );
+        assert_eq!(
+            server.handle(&crate::modbus::Request::Read(
+                crate::modbus::Table::Coils,
+                0,
+                10
+            )),
+            Err(crate::modbus::ILLEGAL_DATA_ADDRESS)
+        );
+        // Analog values can not be read from the mock
+        assert_eq!(
+            server.handle(&crate::modbus::Request::Read(
+                crate::modbus::Table::HoldingRegisters,
+                101,
+                1
+            )),
+            Err(crate::modbus::SERVER_DEVICE_FAILURE)
+        );
+
+        assert_eq!(
+            server.handle(&crate::modbus::Request::WriteCoil(1201, true)),
+            Ok(crate::modbus::Response::Written)
+        );
+        assert_eq!(
+            output_rx.try_recv(),
+            Ok((3, crate::output::OutputCommand::Set(true)))
+        );
+        assert_eq!(
+            server.handle(&crate::modbus::Request::WriteCoil(201, true)),
+            Err(crate::modbus::ILLEGAL_DATA_ADDRESS)
+        );
+        assert_eq!(
+            server.handle(&crate::modbus::Request::WriteRegister(101, 2500)),
+            Ok(crate::modbus::Response::Written)
+        );
+        assert_eq!(analog_rx.try_recv(), Ok((4, 25.0)));
+    }
+
+    #[test]
+    fn test_serve() {
+        let (server, output_rx, _analog_rx) = server();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || serve(listener, server));
+
+        let mut client = crate::modbus::Client::new(&address, 1, std::time::Duration::from_secs(1));
+        assert_eq!(
+            client
+                .read_bits(crate::modbus::Table::Coils, 101, 1)
+                .unwrap(),
+            vec![false]
+        );
+        client
+            .request(&crate::modbus::Request::WriteCoil(101, true))
+            .unwrap();
+        assert_eq!(
+            output_rx.recv_timeout(std::time::Duration::from_secs(1)),
+            Ok((2, crate::output::OutputCommand::Set(true)))
+        );
+        assert!(client
+            .read_registers(crate::modbus::Table::InputRegisters, 101, 1)
+            .is_err());
+    }
+
+    #[test]
+    fn test_idle_client_is_dropped() {
+        use std::io::Read;
+
+        let (mut server, _output_rx, _analog_rx) = server();
+        server.timeout = std::time::Duration::from_millis(100);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve(listener, server));
+
+        // Connect without ever sending a request
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(std::time::Duration::from_secs(2)))
+            .unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+}
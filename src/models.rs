@@ -1,53 +1,947 @@
-/// Represents an Normally Open push button
-struct PushButton {
-    // Link to underlying device
-    device: *const Device,
-    // Current state of the button; either pushed (true) or not (false)
-    state: bool,
-    // Instant to keep track of last update TODO: to be checked how of to push out updates
-    t: std::time::Instant,
-}
-
-/// Represents a light control
-struct Light {
-    // Link to the underlying device
-    device: *const Device,
-    // LIght can be on or off
-    state: bool,
-}
-
-/// Represents a dimmable light control
-struct DimmableLight {
-    device: *const Device,
-    state: bool,
-    // simple 256 level brightness control TODO: to be checked if this is enough (corresponds to
-    // DALI, so probably OK enough
-    brightness: u8,
-}
+//! models contains the higher level devices built on top of plain inputs and outputs
+
+// Extra run time when running into an end stop, as a fraction of the full travel time. Makes sure
+// the end stop is actually reached, so the estimated position can be recalibrated.
+const END_STOP_OVERRUN: f64 = 0.1;
+// Interval at which the position of a moving cover is published
+const POSITION_REPORT_INTERVAL: u64 = 1000;
+// Interval between brightness steps while dimming
+const DIM_STEP_INTERVAL: u64 = 50;
 
-enum CoverDirection {
+/// Direction a cover is moving in
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum CoverDirection {
     Up,
     Down,
     Stopped,
 }
 
-struct Cover {
-    motor_up: *const Device,
-    motor_down: *const Device,
-    cover_direction: CoverDirection,
-    position: u8,
+/// Command for a cover, as received on its command or position topic
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum CoverCommand {
+    Open,
+    Close,
+    Stop,
+    // Position in percent, 0 is closed and 100 is open
+    Position(u8),
+}
+
+impl CoverCommand {
+    /// Parse a payload from the command topic: `OPEN`, `CLOSE` or `STOP`
+    pub fn parse(payload: &str) -> Option<Self> {
+        match payload {
+            "OPEN" => Some(CoverCommand::Open),
+            "CLOSE" => Some(CoverCommand::Close),
+            "STOP" => Some(CoverCommand::Stop),
+            _ => None,
+        }
+    }
+
+    /// Parse a payload from the position topic: a percentage from 0 to 100
+    pub fn parse_position(payload: &str) -> Option<Self> {
+        match payload.trim().parse::<u8>() {
+            Ok(position) if position <= 100 => Some(CoverCommand::Position(position)),
+            _ => None,
+        }
+    }
+}
+
+/// Cover command for a given cover index
+pub type CoverEvent = (usize, CoverCommand);
+
+/// Published state of a cover
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct CoverStatus {
+    pub direction: CoverDirection,
+    // Unknown until the cover ran into one of its end stops
+    pub position: Option<u8>,
+}
+
+impl CoverStatus {
+    /// State payload, following the Home Assistant cover states
+    pub fn state(&self) -> &'static str {
+        match (self.direction, self.position) {
+            (CoverDirection::Up, _) => "opening",
+            (CoverDirection::Down, _) => "closing",
+            (CoverDirection::Stopped, Some(100)) => "open",
+            (CoverDirection::Stopped, Some(0)) => "closed",
+            (CoverDirection::Stopped, _) => "stopped",
+        }
+    }
+}
+
+/// Time it takes the cover to travel all the way, per direction
+#[derive(Debug, Clone)]
+pub struct CoverSettings {
+    pub travel_up: std::time::Duration,
+    pub travel_down: std::time::Duration,
+    // Dead time of the interlock between the up and down relay
+    pub dead_time: std::time::Duration,
+}
+
+/// MQTT topics for a single cover
+#[derive(Debug, Clone, Default)]
+pub struct CoverTopics {
+    pub command: String,
+    pub set_position: String,
+    pub state: String,
+    pub position: String,
+    pub error: String,
+}
+
+impl CoverTopics {
+    /// Topics below `<device_name>/cover/<name>`
+    pub fn new(device_name: &str, name: &str) -> Self {
+        let base = format!("{}/cover/{}", device_name, name);
+        CoverTopics {
+            command: format!("{}/set", base),
+            set_position: format!("{}/position/set", base),
+            state: format!("{}/state", base),
+            position: format!("{}/position", base),
+            error: format!("{}/error", base),
+        }
+    }
+}
+
+// A movement in progress
+#[derive(Debug, Clone)]
+struct Movement {
+    direction: CoverDirection,
+    started_at: std::time::Instant,
+    stop_at: std::time::Instant,
+    // Position to stop at; runs into the end stop when not set
+    target: Option<f64>,
+}
+
+/// Roller shutter driven by two relays, one per direction
+///
+/// The position is estimated from the time the motor has been running, in percent with 0 being
+/// closed and 100 open. Every run into an end stop recalibrates the position.
+#[derive(Debug, Clone)]
+pub struct Cover {
+    pub name: String,
+    motor_up: u8,
+    motor_down: u8,
+    settings: CoverSettings,
+    movement: Option<Movement>,
+    // Position at the start of the current movement, if known
+    position: Option<f64>,
+    // Direction of the motor switched off last, and when
+    switched_off: Option<(CoverDirection, std::time::Instant)>,
 }
 
 impl Cover {
-    fn up(&mut self) {
+    pub fn new(name: &str, motor_up: u8, motor_down: u8, settings: CoverSettings) -> Self {
+        Cover {
+            name: name.to_string(),
+            motor_up,
+            motor_down,
+            settings,
+            movement: None,
+            position: None,
+            switched_off: None,
+        }
+    }
+
+    // Full travel time in a given direction
+    fn travel_time(&self, direction: CoverDirection) -> std::time::Duration {
+        match direction {
+            CoverDirection::Down => self.settings.travel_down,
+            _ => self.settings.travel_up,
+        }
+    }
+
+    /// Estimated position at `now`, in percent
+    pub fn position(&self, now: std::time::Instant) -> Option<f64> {
+        let position = self.position?;
+        let movement = match &self.movement {
+            Some(movement) => movement,
+            None => return Some(position),
+        };
+        let elapsed = now.saturating_duration_since(movement.started_at);
+        let travelled =
+            100.0 * elapsed.as_secs_f64() / self.travel_time(movement.direction).as_secs_f64();
+        let position = match movement.direction {
+            CoverDirection::Up => position + travelled,
+            CoverDirection::Down => position - travelled,
+            CoverDirection::Stopped => position,
+        };
+        Some(position.clamp(0.0, 100.0))
+    }
+
+    /// Current state for publishing
+    pub fn status(&self, now: std::time::Instant) -> CoverStatus {
+        CoverStatus {
+            direction: self
+                .movement
+                .as_ref()
+                .map_or(CoverDirection::Stopped, |movement| movement.direction),
+            position: self.position(now).map(|position| position.round() as u8),
+        }
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.movement.is_some()
+    }
+
+    /// Handle a command at `now`, returning the relay states to write in order
+    ///
+    /// The relay for the opposite direction is always switched off before switching on a motor.
+    /// Reversing only starts the motor once the interlock dead time has passed, so the position is
+    /// estimated from that moment on.
+    pub fn command(
+        &mut self,
+        command: CoverCommand,
+        now: std::time::Instant,
+    ) -> Result<std::vec::Vec<crate::mqtt::MQTTEvent>, String> {
+        match command {
+            CoverCommand::Open => Ok(self.start(CoverDirection::Up, None, now)),
+            CoverCommand::Close => Ok(self.start(CoverDirection::Down, None, now)),
+            CoverCommand::Stop => Ok(self.stop(now)),
+            CoverCommand::Position(100) => Ok(self.start(CoverDirection::Up, None, now)),
+            CoverCommand::Position(0) => Ok(self.start(CoverDirection::Down, None, now)),
+            CoverCommand::Position(target) => {
+                let position = self.position(now).ok_or_else(|| {
+                    format!(
+                        "Position of cover {} is unknown, open or close it fully first",
+                        self.name
+                    )
+                })?;
+                let target = target as f64;
+                if (target - position).abs() < f64::EPSILON {
+                    return Ok(self.stop(now));
+                }
+                let direction = match target > position {
+                    true => CoverDirection::Up,
+                    false => CoverDirection::Down,
+                };
+                Ok(self.start(direction, Some(target), now))
+            }
+        }
+    }
+
+    // Start moving in a direction, either to a target position or into the end stop
+    fn start(
+        &mut self,
+        direction: CoverDirection,
+        target: Option<f64>,
+        now: std::time::Instant,
+    ) -> std::vec::Vec<crate::mqtt::MQTTEvent> {
+        self.position = self.position(now);
+        if let Some(movement) = &self.movement {
+            if movement.direction != direction {
+                self.switched_off = Some((movement.direction, now));
+            }
+        }
+        // The interlock holds the motor back until the dead time after switching off the other one
+        let started_at = match self.switched_off {
+            Some((switched_off, at)) if switched_off != direction => {
+                (at + self.settings.dead_time).max(now)
+            }
+            _ => now,
+        };
+        let travel_time = self.travel_time(direction).as_secs_f64();
+        let distance = match (target, self.position) {
+            (Some(target), Some(position)) => (target - position).abs() / 100.0,
+            (None, Some(position)) => {
+                let remaining = match direction {
+                    CoverDirection::Up => 100.0 - position,
+                    _ => position,
+                };
+                remaining / 100.0 + END_STOP_OVERRUN
+            }
+            // Unknown position: run the full travel time to be sure the end stop is reached
+            _ => 1.0 + END_STOP_OVERRUN,
+        };
+        self.movement = Some(Movement {
+            direction,
+            started_at,
+            stop_at: started_at + std::time::Duration::from_secs_f64(travel_time * distance),
+            target,
+        });
+        let (on, off) = match direction {
+            CoverDirection::Up => (self.motor_up, self.motor_down),
+            _ => (self.motor_down, self.motor_up),
+        };
+        vec![(off, false), (on, true)]
+    }
+
+    // Stop moving, keeping the estimated position
+    fn stop(&mut self, now: std::time::Instant) -> std::vec::Vec<crate::mqtt::MQTTEvent> {
+        self.position = self.position(now);
+        if let Some(movement) = self.movement.take() {
+            self.switched_off = Some((movement.direction, now));
+        }
+        vec![(self.motor_up, false), (self.motor_down, false)]
+    }
+
+    /// Stop the motor once the target or end stop is reached at `now`
+    pub fn poll(&mut self, now: std::time::Instant) -> std::vec::Vec<crate::mqtt::MQTTEvent> {
+        let movement = match &self.movement {
+            Some(movement) if now >= movement.stop_at => movement.clone(),
+            _ => return std::vec::Vec::new(),
+        };
+        self.movement = None;
+        self.switched_off = Some((movement.direction, now));
+        self.position = match (movement.target, movement.direction) {
+            (Some(target), _) => Some(target),
+            // Recalibrate at the end stop
+            (None, CoverDirection::Up) => Some(100.0),
+            (None, _) => Some(0.0),
+        };
+        let motor = match movement.direction {
+            CoverDirection::Up => self.motor_up,
+            _ => self.motor_down,
+        };
+        vec![(motor, false)]
+    }
+
+    /// The moment the current movement ends, if moving
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        self.movement.as_ref().map(|movement| movement.stop_at)
+    }
+}
+
+/// Drive all covers: handle their commands, stop them in time and publish their status
+///
+/// Relay states are written through the regular output writer, so they get read back and
+/// published like any other output.
+pub fn run_covers(
+    rx: std::sync::mpsc::Receiver<CoverEvent>,
+    file_write_tx: std::sync::mpsc::Sender<crate::sysfs::write::WriteEvent>,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+    mut covers: std::vec::Vec<Cover>,
+) {
+    let report_interval = std::time::Duration::from_millis(POSITION_REPORT_INTERVAL);
+    let mut next_report = std::time::Instant::now() + report_interval;
+    loop {
+        let moving = covers.iter().any(|cover| cover.is_moving());
+        let deadline = covers
+            .iter()
+            .filter_map(|cover| cover.next_deadline())
+            .chain(moving.then_some(next_report))
+            .min();
+        let received = match deadline {
+            Some(deadline) => {
+                rx.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+            }
+            None => rx
+                .recv()
+                .map_err(|_| std::sync::mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        let now = std::time::Instant::now();
+        let mut changed = std::collections::BTreeSet::new();
+        match received {
+            Ok((index, command)) => {
+                let cover = match covers.get_mut(index) {
+                    Some(cover) => cover,
+                    None => continue,
+                };
+                log::debug!("Command {:?} for cover {}", command, cover.name);
+                match cover.command(command, now) {
+                    Ok(writes) => {
+                        for write in writes {
+                            file_write_tx
+                                .send(crate::sysfs::write::WriteEvent::Command(write))
+                                .unwrap();
+                        }
+                        changed.insert(index);
+                        if !moving {
+                            next_report = now + report_interval;
+                        }
+                    }
+                    Err(error) => mqtt_publish_tx
+                        .send(crate::mqtt::MQTTMessage::CoverError(index, error))
+                        .unwrap(),
+                }
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+
+        for (index, cover) in covers.iter_mut().enumerate() {
+            let writes = cover.poll(now);
+            if !writes.is_empty() {
+                log::debug!("Cover {} reached its position", cover.name);
+                changed.insert(index);
+            }
+            for write in writes {
+                file_write_tx
+                    .send(crate::sysfs::write::WriteEvent::Command(write))
+                    .unwrap();
+            }
+        }
+        if now >= next_report {
+            changed.extend(
+                covers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, cover)| cover.is_moving())
+                    .map(|(index, _)| index),
+            );
+            next_report = now + report_interval;
+        }
+
+        for index in changed {
+            mqtt_publish_tx
+                .send(crate::mqtt::MQTTMessage::Cover(
+                    index,
+                    covers[index].status(now),
+                ))
+                .unwrap();
+        }
+    }
+}
+
+/// Light on a relay or digital output, toggled locally by its push buttons
+///
+/// Bindings are handled inside hausmaus, so the light keeps working when the broker is down.
+#[derive(Debug, Clone)]
+pub struct Light {
+    pub output: crate::device::Device,
+    // IDs of the inputs toggling the light on every press
+    pub buttons: std::vec::Vec<u8>,
+}
+
+impl Light {
+    /// Command toggling the output for a (debounced) input event, if bound to this light
+    ///
+    /// The current state is read from the output itself, so changes made over MQTT are taken into
+    /// account.
+    pub fn feed(
+        &self,
+        event: crate::sysfs::FileEvent,
+        io: &crate::backend::Io,
+    ) -> Option<crate::mqtt::MQTTEvent> {
+        let (device_id, pressed, _) = event;
+        if !pressed || !self.buttons.contains(&device_id) {
+            return None;
+        }
+        Some((self.output.id, !io.state(&self.output)))
     }
+}
 
-    fn down(&mut self) {
+/// Command for a dimmable light
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum DimmerCommand {
+    On,
+    Off,
+    Toggle,
+    // Brightness from 0 to 255; 0 switches the light off
+    Brightness(u8),
+    // Start and stop dimming, alternating between up and down
+    DimStart,
+    DimStop,
+}
+
+impl DimmerCommand {
+    /// Parse a payload from the command topic: `ON`, `OFF` or `TOGGLE`, in any case
+    pub fn parse(payload: &str) -> Option<Self> {
+        match payload.trim().to_ascii_lowercase().as_str() {
+            "on" => Some(DimmerCommand::On),
+            "off" => Some(DimmerCommand::Off),
+            "toggle" => Some(DimmerCommand::Toggle),
+            _ => None,
+        }
     }
 
-    fn go_to_position(&mut self, pos: u8) {
+    /// Parse a payload from the brightness topic: a value from 0 to 255
+    pub fn parse_brightness(payload: &str) -> Option<Self> {
+        payload
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .map(DimmerCommand::Brightness)
     }
+}
+
+/// Dimmer command for a given dimmable light index
+pub type DimmerEvent = (usize, DimmerCommand);
+
+/// Published state of a dimmable light
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct DimmerStatus {
+    pub on: bool,
+    // Last brightness, also remembered while off
+    pub brightness: u8,
+}
+
+/// MQTT topics for a single dimmable light
+#[derive(Debug, Clone, Default)]
+pub struct DimmerTopics {
+    pub command: String,
+    pub set_brightness: String,
+    pub state: String,
+    pub brightness: String,
+    pub error: String,
+}
+
+impl DimmerTopics {
+    /// Topics below `<device_name>/light/<name>`
+    pub fn new(device_name: &str, name: &str) -> Self {
+        let base = format!("{}/light/{}", device_name, name);
+        DimmerTopics {
+            command: format!("{}/set", base),
+            set_brightness: format!("{}/brightness/set", base),
+            state: format!("{}/state", base),
+            brightness: format!("{}/brightness", base),
+            error: format!("{}/error", base),
+        }
+    }
+}
+
+// Ongoing dimming
+#[derive(Debug, Clone)]
+struct Dimming {
+    up: bool,
+    next_step: std::time::Instant,
+}
+
+/// Light dimmed through an analog output, e.g. a 0-10 V dimmer
+///
+/// A short press toggles the light, restoring the last brightness. Holding the button dims up or
+/// down, alternating direction on every hold.
+#[derive(Debug, Clone)]
+pub struct DimmableLight {
+    pub name: String,
+    // Analog output driving the light
+    pub output: crate::device::Device,
+    // Raw output value at full brightness
+    pub max_value: u32,
+    // IDs of the inputs driving the light
+    pub buttons: std::vec::Vec<u8>,
+    // Brightness change per dimming step
+    step: u8,
+    on: bool,
+    brightness: u8,
+    dimming: Option<Dimming>,
+    // Direction of the next dimming
+    dim_up: bool,
+}
+
+impl DimmableLight {
+    /// Set up a light which takes `dim_time` to dim from off to full brightness
+    pub fn new(
+        name: &str,
+        output: crate::device::Device,
+        max_value: u32,
+        buttons: std::vec::Vec<u8>,
+        dim_time: std::time::Duration,
+    ) -> Self {
+        let steps = (dim_time.as_millis() / DIM_STEP_INTERVAL as u128).max(1);
+        DimmableLight {
+            name: name.to_string(),
+            output,
+            max_value,
+            buttons,
+            step: (255 / steps).clamp(1, 255) as u8,
+            on: false,
+            brightness: 255,
+            dimming: None,
+            dim_up: true,
+        }
+    }
+
+    pub fn status(&self) -> DimmerStatus {
+        DimmerStatus {
+            on: self.on,
+            brightness: self.brightness,
+        }
+    }
+
+    /// Raw value to write to the analog output
+    pub fn output_value(&self) -> u32 {
+        match self.on {
+            true => self.brightness as u32 * self.max_value / 255,
+            false => 0,
+        }
+    }
+
+    /// Command translated from a push button gesture, if any
+    pub fn gesture_command(gesture: crate::gesture::Gesture) -> Option<DimmerCommand> {
+        match gesture {
+            crate::gesture::Gesture::Single => Some(DimmerCommand::Toggle),
+            crate::gesture::Gesture::LongPress => Some(DimmerCommand::DimStart),
+            crate::gesture::Gesture::LongRelease => Some(DimmerCommand::DimStop),
+            _ => None,
+        }
+    }
+
+    /// Handle a command at `now`; returns whether the output needs to be written
+    pub fn command(&mut self, command: DimmerCommand, now: std::time::Instant) -> bool {
+        let before = self.status();
+        match command {
+            DimmerCommand::On => self.on = true,
+            DimmerCommand::Off | DimmerCommand::Brightness(0) => self.on = false,
+            DimmerCommand::Toggle => self.on = !self.on,
+            DimmerCommand::Brightness(brightness) => {
+                self.on = true;
+                self.brightness = brightness;
+            }
+            DimmerCommand::DimStart => {
+                // Dimming a light that is off starts from the bottom
+                if !self.on {
+                    self.on = true;
+                    self.brightness = self.step;
+                    self.dim_up = true;
+                }
+                self.dimming = Some(Dimming {
+                    up: self.dim_up,
+                    next_step: now + std::time::Duration::from_millis(DIM_STEP_INTERVAL),
+                });
+            }
+            DimmerCommand::DimStop => {
+                if let Some(dimming) = self.dimming.take() {
+                    self.dim_up = !dimming.up;
+                }
+            }
+        }
+        self.status() != before
+    }
+
+    /// Take the dimming steps due at `now`; returns whether the output needs to be written
+    pub fn poll(&mut self, now: std::time::Instant) -> bool {
+        let before = self.brightness;
+        let interval = std::time::Duration::from_millis(DIM_STEP_INTERVAL);
+        if let Some(dimming) = &mut self.dimming {
+            while dimming.next_step <= now {
+                self.brightness = match dimming.up {
+                    true => self.brightness.saturating_add(self.step),
+                    // Never dim all the way off
+                    false => self.brightness.saturating_sub(self.step).max(1),
+                };
+                dimming.next_step += interval;
+            }
+        }
+        self.brightness != before
+    }
+
+    /// The moment of the next dimming step, if dimming
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        self.dimming.as_ref().map(|dimming| dimming.next_step)
+    }
+}
+
+/// Drive all dimmable lights: handle their commands, dim and publish their status
+///
+/// Output values are written through the board of the analog output.
+pub fn run_dimmers(
+    rx: std::sync::mpsc::Receiver<DimmerEvent>,
+    io: crate::backend::Io,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+    mut dimmers: std::vec::Vec<DimmableLight>,
+) {
+    loop {
+        let deadline = dimmers
+            .iter()
+            .filter_map(|dimmer| dimmer.next_deadline())
+            .min();
+        let received = match deadline {
+            Some(deadline) => {
+                rx.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+            }
+            None => rx
+                .recv()
+                .map_err(|_| std::sync::mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        let now = std::time::Instant::now();
+        let mut changed = std::collections::BTreeSet::new();
+        match received {
+            Ok((index, command)) => {
+                if let Some(dimmer) = dimmers.get_mut(index) {
+                    log::debug!("Command {:?} for dimmable light {}", command, dimmer.name);
+                    if dimmer.command(command, now) {
+                        changed.insert(index);
+                    }
+                }
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        for (index, dimmer) in dimmers.iter_mut().enumerate() {
+            if dimmer.poll(now) {
+                changed.insert(index);
+            }
+        }
+
+        for index in changed {
+            let dimmer = &dimmers[index];
+            let message = match io.write_analog(&dimmer.output, dimmer.output_value()) {
+                Ok(()) => crate::mqtt::MQTTMessage::Dimmer(index, dimmer.status()),
+                Err(e) => crate::mqtt::MQTTMessage::DimmerError(index, e.to_string()),
+            };
+            mqtt_publish_tx.send(message).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> std::time::Duration {
+        std::time::Duration::from_secs(secs)
+    }
+
+    fn cover() -> Cover {
+        cover_with_dead_time(std::time::Duration::ZERO)
+    }
+
+    fn cover_with_dead_time(dead_time: std::time::Duration) -> Cover {
+        Cover::new(
+            "living_room",
+            1,
+            2,
+            CoverSettings {
+                travel_up: secs(20),
+                travel_down: secs(10),
+                dead_time,
+            },
+        )
+    }
+
+    #[test]
+    fn test_open_calibrates_at_end_stop() {
+        let mut cover = cover();
+        let start = std::time::Instant::now();
+        assert_eq!(cover.status(start).position, None);
+
+        let writes = cover.command(CoverCommand::Open, start).unwrap();
+        assert_eq!(writes, vec![(2, false), (1, true)]);
+        assert_eq!(cover.status(start).state(), "opening");
+
+        // Full travel time plus the overrun, since the position is unknown
+        assert_eq!(cover.next_deadline(), Some(start + secs(22)));
+        assert!(cover.poll(start + secs(21)).is_empty());
+        assert_eq!(cover.poll(start + secs(22)), vec![(1, false)]);
+
+        let status = cover.status(start + secs(22));
+        assert_eq!(status.position, Some(100));
+        assert_eq!(status.state(), "open");
+    }
+
+    #[test]
+    fn test_go_to_position() {
+        let mut cover = cover();
+        let start = std::time::Instant::now();
+        cover.command(CoverCommand::Close, start).unwrap();
+        cover.poll(start + secs(11));
+        assert_eq!(cover.status(start + secs(11)).state(), "closed");
+
+        // 30% up takes 30% of the 20s travel up time
+        let start = start + secs(20);
+        let writes = cover.command(CoverCommand::Position(30), start).unwrap();
+        assert_eq!(writes, vec![(2, false), (1, true)]);
+        assert_eq!(cover.status(start + secs(3)).position, Some(15));
+        assert_eq!(cover.poll(start + secs(6)), vec![(1, false)]);
+        assert_eq!(cover.status(start + secs(6)).position, Some(30));
+        assert_eq!(cover.status(start + secs(6)).state(), "stopped");
+
+        // 20% down takes 20% of the 10s travel down time
+        let writes = cover
+            .command(CoverCommand::Position(10), start + secs(6))
+            .unwrap();
+        assert_eq!(writes, vec![(1, false), (2, true)]);
+        assert_eq!(cover.next_deadline(), Some(start + secs(8)));
+    }
+
+    #[test]
+    fn test_stop_keeps_estimated_position() {
+        let mut cover = cover();
+        let start = std::time::Instant::now();
+        cover.command(CoverCommand::Open, start).unwrap();
+        cover.poll(start + secs(22));
+
+        let writes = cover
+            .command(CoverCommand::Close, start + secs(30))
+            .unwrap();
+        assert_eq!(writes, vec![(1, false), (2, true)]);
+        let writes = cover.command(CoverCommand::Stop, start + secs(34)).unwrap();
+        assert_eq!(writes, vec![(1, false), (2, false)]);
+        assert_eq!(cover.status(start + secs(40)).position, Some(60));
+        assert_eq!(cover.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_reversal_starts_after_dead_time() {
+        let mut cover = cover_with_dead_time(secs(1));
+        let start = std::time::Instant::now();
+        cover.command(CoverCommand::Open, start).unwrap();
+        assert_eq!(cover.poll(start + secs(22)), vec![(1, false)]);
+
+        // The motor down only runs once the dead time after stopping the motor up has passed
+        let writes = cover
+            .command(CoverCommand::Close, start + secs(22))
+            .unwrap();
+        assert_eq!(writes, vec![(1, false), (2, true)]);
+        assert_eq!(cover.status(start + secs(23)).position, Some(100));
+        assert_eq!(cover.status(start + secs(28)).position, Some(50));
+        assert_eq!(cover.next_deadline(), Some(start + secs(34)));
+
+        // Reversing while moving holds the motor up back as well
+        let writes = cover.command(CoverCommand::Open, start + secs(28)).unwrap();
+        assert_eq!(writes, vec![(2, false), (1, true)]);
+        assert_eq!(cover.status(start + secs(29)).position, Some(50));
+        assert_eq!(cover.status(start + secs(33)).position, Some(70));
+
+        // Going on in the same direction is not held back
+        cover.command(CoverCommand::Stop, start + secs(33)).unwrap();
+        cover
+            .command(CoverCommand::Position(80), start + secs(40))
+            .unwrap();
+        assert_eq!(cover.next_deadline(), Some(start + secs(42)));
+    }
+
+    #[test]
+    fn test_position_unknown() {
+        let mut cover = cover();
+        let error = cover
+            .command(CoverCommand::Position(50), std::time::Instant::now())
+            .unwrap_err();
+        assert!(error.contains("unknown"));
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(CoverCommand::parse("OPEN"), Some(CoverCommand::Open));
+        assert_eq!(CoverCommand::parse("open"), None);
+        assert_eq!(
+            CoverCommand::parse_position("42"),
+            Some(CoverCommand::Position(42))
+        );
+        assert_eq!(CoverCommand::parse_position("101"), None);
+    }
+
+    fn dimmer() -> DimmableLight {
+        DimmableLight::new(
+            "dining",
+            crate::device::Device {
+                id: 0,
+                path: "/foo/bar".to_string(),
+                module_name: String::from("foo"),
+                device_type: crate::device::DeviceType::AnalogOutput,
+                io_group: 1,
+                number: 1,
+                settings: crate::device::DeviceSettings::default(),
+            },
+            10000,
+            vec![3],
+            std::time::Duration::from_millis(500),
+        )
+    }
+
+    #[test]
+    fn test_dimmer_remembers_brightness() {
+        let mut dimmer = dimmer();
+        let now = std::time::Instant::now();
+        assert!(dimmer.command(DimmerCommand::Brightness(51), now));
+        assert_eq!(dimmer.output_value(), 2000);
+
+        assert!(dimmer.command(DimmerCommand::Toggle, now));
+        assert_eq!(dimmer.output_value(), 0);
+        assert_eq!(
+            dimmer.status(),
+            DimmerStatus {
+                on: false,
+                brightness: 51
+            }
+        );
+
+        assert!(dimmer.command(DimmerCommand::On, now));
+        assert_eq!(dimmer.output_value(), 2000);
+        assert!(!dimmer.command(DimmerCommand::On, now));
+    }
+
+    #[test]
+    fn test_dimmer_writes_through_its_board() {
+        let backend = std::sync::Arc::new(crate::backend::tests::MockBackend {
+            devices: vec![(crate::device::DeviceType::AnalogOutput, 1, 1)],
+            ..Default::default()
+        });
+        let (io, devices) = crate::backend::Io::discover(vec![backend.clone()], "foo").unwrap();
+        let dimmer = DimmableLight::new(
+            "dining",
+            devices[0].clone(),
+            10000,
+            vec![],
+            std::time::Duration::from_millis(500),
+        );
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        tx.send((0, DimmerCommand::Brightness(51))).unwrap();
+        drop(tx);
+        run_dimmers(rx, io, publish_tx, vec![dimmer]);
+
+        assert_eq!(backend.analog.lock().unwrap().get(&0), Some(&2000));
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [crate::mqtt::MQTTMessage::Dimmer(
+                0,
+                DimmerStatus {
+                    on: true,
+                    brightness: 51
+                }
+            )]
+        ));
+    }
+
+    #[test]
+    fn test_press_and_hold_dimming() {
+        let mut dimmer = dimmer();
+        let start = std::time::Instant::now();
+        let ms = std::time::Duration::from_millis;
+
+        // 10 steps of 25 over 500ms, starting from the bottom when off
+        dimmer.command(DimmerCommand::DimStart, start);
+        assert_eq!(dimmer.status().brightness, 25);
+        assert_eq!(dimmer.next_deadline(), Some(start + ms(50)));
+        assert!(dimmer.poll(start + ms(200)));
+        assert_eq!(dimmer.status().brightness, 125);
+        dimmer.command(DimmerCommand::DimStop, start + ms(220));
+        assert_eq!(dimmer.next_deadline(), None);
+
+        // The next hold dims down again, never going all the way off
+        dimmer.command(DimmerCommand::DimStart, start + ms(1000));
+        dimmer.poll(start + ms(2000));
+        dimmer.command(DimmerCommand::DimStop, start + ms(2000));
+        assert_eq!(
+            dimmer.status(),
+            DimmerStatus {
+                on: true,
+                brightness: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_light_toggles_on_press() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let path = tmp_dir.path().join("ro_value");
+        std::fs::write(&path, "1\n").expect("Could not write contents to temp file");
+        let light = Light {
+            output: crate::device::Device {
+                id: 7,
+                path: path.to_str().unwrap().to_string(),
+                module_name: String::from("foo"),
+                device_type: crate::device::DeviceType::RelayOutput,
+                io_group: 2,
+                number: 1,
+                settings: crate::device::DeviceSettings::default(),
+            },
+            buttons: vec![3],
+        };
+        let duration = std::time::Duration::from_millis(0);
+        let io = crate::backend::Io::default();
+
+        assert_eq!(light.feed((3, true, duration), &io), Some((7, false)));
+        assert_eq!(light.feed((3, false, duration), &io), None);
+        assert_eq!(light.feed((4, true, duration), &io), None);
 
-    fn stop(&mut self) {
+        tmp_dir.close().unwrap();
     }
 }
@@ -0,0 +1,508 @@
+//! discovery publishes Home Assistant MQTT discovery configs for all devices
+
+/// Topic and retained config payload for a single entity
+pub type DiscoveryConfig = (String, String);
+
+// Home Assistant component used for a device
+fn component_for_device(device: &crate::device::Device) -> &'static str {
+    if device.settings.model == Some(crate::device::Model::Light) {
+        return "light";
+    }
+    match device.device_type {
+        crate::device::DeviceType::DigitalInput => "binary_sensor",
+        crate::device::DeviceType::DigitalOutput => "switch",
+        crate::device::DeviceType::RelayOutput => "switch",
+        crate::device::DeviceType::AnalogInput => "sensor",
+        crate::device::DeviceType::AnalogOutput => "number",
+        crate::device::DeviceType::Counter => "sensor",
+    }
+}
+
+// Display name of a device
+fn name_for_device(device: &crate::device::Device, object_id: &str) -> String {
+    match &device.settings.name {
+        Some(name) => name.replace('_', " "),
+        None => object_id.replace('_', " "),
+    }
+}
+
+// Discovery topic for a component and object within the node
+fn config_topic(prefix: &str, component: &str, node_id: &str, object_id: &str) -> String {
+    format!("{prefix}/{component}/{node_id}/{object_id}/config")
+}
+
+// Device info shared by all entities of this node
+fn device_info(node_id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "identifiers": [node_id],
+        "name": node_id,
+        "manufacturer": "hausmaus",
+        "model": "UniPi",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    })
+}
+
+/// Build the discovery config for a single device
+pub fn config_for_device(
+    device: &crate::device::Device,
+    prefix: &str,
+    availability_topic: &str,
+) -> DiscoveryConfig {
+    let node_id = device.module_name.as_str();
+    let object_id = crate::device::object_id_for_device(device);
+    let component = component_for_device(device);
+
+    let mut payload = serde_json::json!({
+        "name": name_for_device(device, &object_id),
+        "unique_id": format!("{node_id}_{object_id}"),
+        "object_id": format!("{node_id}_{object_id}"),
+        "state_topic": crate::device::state_topic_for_device(device),
+        "payload_on": "ON",
+        "payload_off": "OFF",
+        "availability_topic": availability_topic,
+        "device": device_info(node_id),
+    });
+    if component != "binary_sensor" {
+        payload["command_topic"] = crate::device::command_topic_for_device(device).into();
+    }
+
+    (
+        config_topic(prefix, component, node_id, &object_id),
+        payload.to_string(),
+    )
+}
+
+/// Build the discovery configs for all digital devices
+pub fn discovery_configs(
+    devices: &[crate::device::Device],
+    prefix: &str,
+    availability_topic: &str,
+) -> std::vec::Vec<DiscoveryConfig> {
+    devices
+        .iter()
+        .filter(|device| device.device_type.is_digital())
+        .map(|device| config_for_device(device, prefix, availability_topic))
+        .collect()
+}
+
+/// Build the discovery config for an analog device: a sensor for inputs, a number for outputs
+pub fn config_for_analog(
+    device: &crate::device::Device,
+    settings: &crate::analog::AnalogSettings,
+    prefix: &str,
+    availability_topic: &str,
+) -> DiscoveryConfig {
+    let node_id = device.module_name.as_str();
+    let object_id = crate::device::object_id_for_device(device);
+    let component = component_for_device(device);
+
+    let mut payload = serde_json::json!({
+        "name": name_for_device(device, &object_id),
+        "unique_id": format!("{node_id}_{object_id}"),
+        "object_id": format!("{node_id}_{object_id}"),
+        "state_topic": crate::device::state_topic_for_device(device),
+        "availability_topic": availability_topic,
+        "device": device_info(node_id),
+    });
+    if let Some(unit) = &settings.unit {
+        payload["unit_of_measurement"] = unit.as_str().into();
+    }
+    if device.device_type == crate::device::DeviceType::AnalogOutput {
+        let (min, max) = settings.range();
+        payload["command_topic"] = crate::device::command_topic_for_device(device).into();
+        payload["min"] = min.into();
+        payload["max"] = max.into();
+        payload["step"] = ((max - min) / 1000.0).into();
+    } else {
+        payload["state_class"] = "measurement".into();
+    }
+
+    (
+        config_topic(prefix, component, node_id, &object_id),
+        payload.to_string(),
+    )
+}
+
+/// Build the discovery configs for a counter: a sensor for the total and one for the rate
+pub fn configs_for_counter(
+    device: &crate::device::Device,
+    settings: &crate::counter::CounterSettings,
+    prefix: &str,
+    availability_topic: &str,
+) -> [DiscoveryConfig; 2] {
+    let node_id = device.module_name.as_str();
+    let object_id = crate::device::object_id_for_device(device);
+    let name = name_for_device(device, &object_id);
+
+    let total = serde_json::json!({
+        "name": name,
+        "unique_id": format!("{node_id}_{object_id}"),
+        "object_id": format!("{node_id}_{object_id}"),
+        "state_topic": crate::device::state_topic_for_device(device),
+        "state_class": "total_increasing",
+        "availability_topic": availability_topic,
+        "device": device_info(node_id),
+    });
+    let rate_id = format!("{object_id}_rate");
+    let mut rate = serde_json::json!({
+        "name": format!("{name} rate"),
+        "unique_id": format!("{node_id}_{rate_id}"),
+        "object_id": format!("{node_id}_{rate_id}"),
+        "state_topic": crate::device::rate_topic_for_device(device),
+        "state_class": "measurement",
+        "availability_topic": availability_topic,
+        "device": device_info(node_id),
+    });
+    if let Some(unit) = &settings.unit {
+        rate["unit_of_measurement"] = unit.as_str().into();
+    }
+
+    [
+        (
+            config_topic(prefix, "sensor", node_id, &object_id),
+            total.to_string(),
+        ),
+        (
+            config_topic(prefix, "sensor", node_id, &rate_id),
+            rate.to_string(),
+        ),
+    ]
+}
+
+/// Build the discovery config for a 1-Wire temperature sensor
+pub fn config_for_sensor(
+    sensor: &crate::onewire::Sensor,
+    sensor_topics: &crate::onewire::SensorTopics,
+    node_id: &str,
+    prefix: &str,
+    availability_topic: &str,
+) -> DiscoveryConfig {
+    let object_id = format!("temperature_{}", sensor.name.replace('-', "_"));
+    let payload = serde_json::json!({
+        "name": sensor.name.replace('_', " "),
+        "unique_id": format!("{node_id}_{}", sensor.id.replace('-', "_")),
+        "object_id": format!("{node_id}_{object_id}"),
+        "device_class": "temperature",
+        "state_class": "measurement",
+        "unit_of_measurement": "°C",
+        "state_topic": sensor_topics.state,
+        "availability_topic": availability_topic,
+        "device": device_info(node_id),
+    });
+    (
+        config_topic(prefix, "sensor", node_id, &object_id),
+        payload.to_string(),
+    )
+}
+
+/// Build the discovery config for a cover
+pub fn config_for_cover(
+    name: &str,
+    cover_topics: &crate::models::CoverTopics,
+    node_id: &str,
+    prefix: &str,
+    availability_topic: &str,
+) -> DiscoveryConfig {
+    let object_id = format!("cover_{name}");
+    let payload = serde_json::json!({
+        "name": name.replace('_', " "),
+        "unique_id": format!("{node_id}_{object_id}"),
+        "object_id": format!("{node_id}_{object_id}"),
+        "device_class": "shutter",
+        "command_topic": cover_topics.command,
+        "state_topic": cover_topics.state,
+        "position_topic": cover_topics.position,
+        "set_position_topic": cover_topics.set_position,
+        "payload_open": "OPEN",
+        "payload_close": "CLOSE",
+        "payload_stop": "STOP",
+        "availability_topic": availability_topic,
+        "device": device_info(node_id),
+    });
+    (
+        config_topic(prefix, "cover", node_id, &object_id),
+        payload.to_string(),
+    )
+}
+
+/// Build the discovery config for a dimmable light
+pub fn config_for_dimmer(
+    name: &str,
+    dimmer_topics: &crate::models::DimmerTopics,
+    node_id: &str,
+    prefix: &str,
+    availability_topic: &str,
+) -> DiscoveryConfig {
+    let object_id = format!("light_{name}");
+    let payload = serde_json::json!({
+        "name": name.replace('_', " "),
+        "unique_id": format!("{node_id}_{object_id}"),
+        "object_id": format!("{node_id}_{object_id}"),
+        "command_topic": dimmer_topics.command,
+        "state_topic": dimmer_topics.state,
+        "brightness_command_topic": dimmer_topics.set_brightness,
+        "brightness_state_topic": dimmer_topics.brightness,
+        "brightness_scale": 255,
+        "payload_on": "ON",
+        "payload_off": "OFF",
+        "availability_topic": availability_topic,
+        "device": device_info(node_id),
+    });
+    (
+        config_topic(prefix, "light", node_id, &object_id),
+        payload.to_string(),
+    )
+}
+
+/// Keeps track of the discovery topics published in this run, to clean up the stale ones
+///
+/// Retained configs of devices that are no longer found are received back after subscribing to
+/// the node's discovery topics, and need to be removed by publishing an empty retained payload.
+#[derive(Debug)]
+pub struct DiscoveryTopics {
+    prefix: String,
+    node_id: String,
+    topics: std::collections::HashSet<String>,
+}
+
+impl DiscoveryTopics {
+    pub fn new(prefix: &str, node_id: &str, configs: &[DiscoveryConfig]) -> Self {
+        DiscoveryTopics {
+            prefix: prefix.to_string(),
+            node_id: node_id.to_string(),
+            topics: configs.iter().map(|(topic, _)| topic.clone()).collect(),
+        }
+    }
+
+    /// Subscription filter matching all discovery configs for this node
+    pub fn filter(&self) -> String {
+        config_topic(&self.prefix, "+", &self.node_id, "+")
+    }
+
+    /// Whether a received message is a config for this node which is no longer published
+    pub fn is_stale(&self, topic: &str, payload: &[u8]) -> bool {
+        if payload.is_empty() || self.topics.contains(topic) {
+            return false;
+        }
+        let parts: std::vec::Vec<&str> = topic.split('/').collect();
+        matches!(
+            parts.as_slice(),
+            [prefix, _, node_id, _, "config"] if *prefix == self.prefix && *node_id == self.node_id
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(device_type: crate::device::DeviceType) -> crate::device::Device {
+        crate::device::Device {
+            id: 0,
+            path: "/foo/bar".to_string(),
+            module_name: String::from("foo"),
+            device_type,
+            io_group: 1,
+            number: 3,
+            settings: crate::device::DeviceSettings::default(),
+        }
+    }
+
+    #[test]
+    fn test_config_for_input() {
+        let (topic, payload) = config_for_device(
+            &device(crate::device::DeviceType::DigitalInput),
+            "homeassistant",
+            "foo/status",
+        );
+        assert_eq!(topic, "homeassistant/binary_sensor/foo/input_1_03/config");
+
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["unique_id"], "foo_input_1_03");
+        assert_eq!(payload["state_topic"], "foo/input/1_03/state");
+        assert_eq!(payload["availability_topic"], "foo/status");
+        assert_eq!(payload["device"]["identifiers"][0], "foo");
+        assert!(payload.get("command_topic").is_none());
+    }
+
+    #[test]
+    fn test_config_for_relay() {
+        let (topic, payload) = config_for_device(
+            &device(crate::device::DeviceType::RelayOutput),
+            "homeassistant",
+            "foo/status",
+        );
+        assert_eq!(topic, "homeassistant/switch/foo/relay_1_03/config");
+
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["command_topic"], "foo/relay/1_03/set");
+        assert_eq!(payload["state_topic"], "foo/relay/1_03/state");
+    }
+
+    #[test]
+    fn test_config_for_light() {
+        let mut device = device(crate::device::DeviceType::RelayOutput);
+        device.settings.name = Some("kitchen_ceiling".to_string());
+        device.settings.model = Some(crate::device::Model::Light);
+        let (topic, payload) = config_for_device(&device, "homeassistant", "foo/status");
+        assert_eq!(
+            topic,
+            "homeassistant/light/foo/relay_kitchen_ceiling/config"
+        );
+
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["name"], "kitchen ceiling");
+        assert_eq!(payload["command_topic"], "foo/relay/kitchen_ceiling/set");
+    }
+
+    #[test]
+    fn test_config_for_analog() {
+        let settings = crate::analog::AnalogSettings {
+            scale: Some(crate::analog::Scale {
+                raw_min: 0.0,
+                raw_max: 10000.0,
+                min: 0.0,
+                max: 100.0,
+            }),
+            unit: Some("%".to_string()),
+            ..Default::default()
+        };
+        let (topic, payload) = config_for_analog(
+            &device(crate::device::DeviceType::AnalogOutput),
+            &settings,
+            "homeassistant",
+            "foo/status",
+        );
+        assert_eq!(topic, "homeassistant/number/foo/analog_output_1_03/config");
+
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["command_topic"], "foo/analog_output/1_03/set");
+        assert_eq!(payload["unit_of_measurement"], "%");
+        assert_eq!(payload["max"], 100.0);
+
+        let (topic, payload) = config_for_analog(
+            &device(crate::device::DeviceType::AnalogInput),
+            &settings,
+            "homeassistant",
+            "foo/status",
+        );
+        assert_eq!(topic, "homeassistant/sensor/foo/analog_input_1_03/config");
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert!(payload.get("command_topic").is_none());
+    }
+
+    #[test]
+    fn test_configs_for_counter() {
+        let settings = crate::counter::CounterSettings {
+            rate_factor: 60.0,
+            unit: Some("W".to_string()),
+        };
+        let [(topic, payload), (rate_topic, rate_payload)] = configs_for_counter(
+            &device(crate::device::DeviceType::Counter),
+            &settings,
+            "homeassistant",
+            "foo/status",
+        );
+        assert_eq!(topic, "homeassistant/sensor/foo/counter_1_03/config");
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["state_topic"], "foo/counter/1_03/state");
+        assert_eq!(payload["state_class"], "total_increasing");
+
+        assert_eq!(
+            rate_topic,
+            "homeassistant/sensor/foo/counter_1_03_rate/config"
+        );
+        let rate_payload: serde_json::Value = serde_json::from_str(&rate_payload).unwrap();
+        assert_eq!(rate_payload["state_topic"], "foo/counter/1_03/rate");
+        assert_eq!(rate_payload["unit_of_measurement"], "W");
+    }
+
+    #[test]
+    fn test_config_for_sensor() {
+        let sensor = crate::onewire::Sensor {
+            id: "28-0316a2795aff".to_string(),
+            name: "living_room".to_string(),
+            path: "/foo/bar".to_string(),
+        };
+        let sensor_topics = crate::onewire::SensorTopics::new("foo", &sensor.name);
+        let (topic, payload) = config_for_sensor(
+            &sensor,
+            &sensor_topics,
+            "foo",
+            "homeassistant",
+            "foo/status",
+        );
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/foo/temperature_living_room/config"
+        );
+
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        // The unique ID sticks to the sensor when it is renamed
+        assert_eq!(payload["unique_id"], "foo_28_0316a2795aff");
+        assert_eq!(payload["state_topic"], "foo/temperature/living_room/state");
+        assert_eq!(payload["device_class"], "temperature");
+        assert_eq!(payload["unit_of_measurement"], "°C");
+    }
+
+    #[test]
+    fn test_config_for_cover() {
+        let cover_topics = crate::models::CoverTopics::new("foo", "living_room");
+        let (topic, payload) = config_for_cover(
+            "living_room",
+            &cover_topics,
+            "foo",
+            "homeassistant",
+            "foo/status",
+        );
+        assert_eq!(topic, "homeassistant/cover/foo/cover_living_room/config");
+
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["command_topic"], "foo/cover/living_room/set");
+        assert_eq!(
+            payload["set_position_topic"],
+            "foo/cover/living_room/position/set"
+        );
+        assert_eq!(payload["position_topic"], "foo/cover/living_room/position");
+    }
+
+    #[test]
+    fn test_config_for_dimmer() {
+        let dimmer_topics = crate::models::DimmerTopics::new("foo", "dining");
+        let (topic, payload) = config_for_dimmer(
+            "dining",
+            &dimmer_topics,
+            "foo",
+            "homeassistant",
+            "foo/status",
+        );
+        assert_eq!(topic, "homeassistant/light/foo/light_dining/config");
+
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload["brightness_command_topic"],
+            "foo/light/dining/brightness/set"
+        );
+        assert_eq!(payload["state_topic"], "foo/light/dining/state");
+    }
+
+    #[test]
+    fn test_stale_topics() {
+        let configs = discovery_configs(
+            &[device(crate::device::DeviceType::RelayOutput)],
+            "homeassistant",
+            "foo/status",
+        );
+        let discovery_topics = DiscoveryTopics::new("homeassistant", "foo", &configs);
+        assert_eq!(discovery_topics.filter(), "homeassistant/+/foo/+/config");
+
+        // Still published
+        assert!(!discovery_topics.is_stale("homeassistant/switch/foo/relay_1_03/config", b"{}"));
+        // Already removed
+        assert!(!discovery_topics.is_stale("homeassistant/switch/foo/relay_1_04/config", b""));
+        // Other node
+        assert!(!discovery_topics.is_stale("homeassistant/switch/bar/relay_1_04/config", b"{}"));
+        // Gone
+        assert!(discovery_topics.is_stale("homeassistant/switch/foo/relay_1_04/config", b"{}"));
+    }
+}
@@ -1,30 +1,824 @@
 /// publish module accepts all incoming events and publishes them to MQTT
-use crate::sysfs::FileEvent;
+use crate::mqtt::{MQTTMessage, PublishOptions};
+
+// Options for birth messages and discovery configs, which always need to be retained
+const RETAINED: PublishOptions = PublishOptions {
+    qos: rumqttc::QoS::AtLeastOnce,
+    retain: true,
+};
+
+/// Topics to publish on, per device ID
+#[derive(Debug, Clone, Default)]
+pub struct DeviceTopics {
+    pub state: std::collections::HashMap<u8, String>,
+    pub action: std::collections::HashMap<u8, String>,
+    pub error: std::collections::HashMap<u8, String>,
+    // Topics the rates of the counters are published on
+    pub rate: std::collections::HashMap<u8, String>,
+    // Topics per cover index
+    pub covers: std::vec::Vec<crate::models::CoverTopics>,
+    // Topics per dimmable light index
+    pub dimmers: std::vec::Vec<crate::models::DimmerTopics>,
+    // Topics per 1-Wire sensor index
+    pub sensors: std::vec::Vec<crate::onewire::SensorTopics>,
+}
+
+// Latest status of the covers, dimmable lights, analog devices, counters and sensors, published
+// again after every (re)connect
+#[derive(Debug, Default)]
+struct ModelStatus {
+    covers: std::collections::BTreeMap<usize, crate::models::CoverStatus>,
+    dimmers: std::collections::BTreeMap<usize, crate::models::DimmerStatus>,
+    analog: std::collections::BTreeMap<u8, f64>,
+    counters: std::collections::BTreeMap<u8, crate::counter::CounterStatus>,
+    sensors: std::collections::BTreeMap<usize, f64>,
+}
+
+/// All devices, along with the boards to read the state of the digital ones from for snapshots
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub devices: std::vec::Vec<crate::device::Device>,
+    pub io: crate::backend::Io,
+}
+
+impl Snapshot {
+    /// Current state of all digital devices that can be read
+    pub fn read(&self) -> std::vec::Vec<(u8, bool)> {
+        let digital_devices: std::vec::Vec<crate::device::Device> = self
+            .devices
+            .iter()
+            .filter(|device| device.device_type.is_digital())
+            .cloned()
+            .collect();
+        log::info!(
+            "Publishing state snapshot of {} devices",
+            digital_devices.len()
+        );
+        self.io.read_states(&digital_devices)
+    }
+}
+
+/// State updates held back while disconnected, keeping only the latest one per device
+#[derive(Debug, Default)]
+pub struct OfflineBuffer {
+    states: std::collections::BTreeMap<u8, crate::sysfs::FileEvent>,
+}
+
+impl OfflineBuffer {
+    /// Keep a state update, replacing an earlier one for the same device
+    pub fn push(&mut self, event: crate::sysfs::FileEvent) {
+        self.states.insert(event.0, event);
+    }
+
+    /// Take out all buffered updates, ordered by device ID
+    pub fn drain(&mut self) -> std::vec::Vec<crate::sysfs::FileEvent> {
+        std::mem::take(&mut self.states).into_values().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+}
 
 /// handle_messages receives any file events and sends them out over MQTT
+///
+/// After every (re)connect, all subscriptions are restored and the birth message, discovery
+/// configs, buffered states and a snapshot of the other device states are published again. While
+/// disconnected, only the latest state of every device and the latest status of every cover,
+/// dimmable light, analog device, counter and sensor is kept; actions, errors and messages
+/// published by rules are dropped. QoS and retain flags follow the MQTT config per topic class.
 pub fn publish_messages(
-    rx: std::sync::mpsc::Receiver<FileEvent>,
+    rx: std::sync::mpsc::Receiver<MQTTMessage>,
     mut mqtt_client: rumqttc::Client,
-    state_topic_map: &std::collections::HashMap<u8, String>,
+    device_topics: &DeviceTopics,
+    mqtt_config: &crate::mqtt::MQTTConfig,
+    subscriptions: &[rumqttc::SubscribeFilter],
+    discovery_configs: &[crate::mqtt::discovery::DiscoveryConfig],
+    snapshot: &Snapshot,
 ) {
-    for (device_id, state, duration) in rx {
-        let message_str: &str = match state {
-            true => "ON",
-            false => "OFF",
+    let state_options: std::collections::HashMap<u8, PublishOptions> = snapshot
+        .devices
+        .iter()
+        .map(|device| (device.id, mqtt_config.state_options(&device.device_type)))
+        .collect();
+    let availability = &mqtt_config.availability;
+    let state_topic_map = &device_topics.state;
+    // Nothing gets published before the first ConnAck
+    let mut connected = false;
+    let mut offline_buffer = OfflineBuffer::default();
+    let mut model_status = ModelStatus::default();
+
+    for message in rx {
+        if !connected {
+            match message {
+                MQTTMessage::State(event) => {
+                    offline_buffer.push(event);
+                    continue;
+                }
+                MQTTMessage::Cover(index, status) => {
+                    model_status.covers.insert(index, status);
+                    continue;
+                }
+                MQTTMessage::Dimmer(index, status) => {
+                    model_status.dimmers.insert(index, status);
+                    continue;
+                }
+                MQTTMessage::Analog((device_id, value)) => {
+                    model_status.analog.insert(device_id, value);
+                    continue;
+                }
+                MQTTMessage::Counter(device_id, status) => {
+                    model_status.counters.insert(device_id, status);
+                    continue;
+                }
+                MQTTMessage::Sensor(index, value) => {
+                    model_status.sensors.insert(index, value);
+                    continue;
+                }
+                MQTTMessage::Connected | MQTTMessage::Disconnected => {}
+                message => {
+                    log::debug!("Dropping message while disconnected: {:?}", message);
+                    continue;
+                }
+            }
+        }
+        let (topic, message_str, options) = match message {
+            MQTTMessage::State((device_id, state, duration)) => {
+                let message_str = state_payload(state);
+                log::debug!(
+                    "publishing message for device #{}: {:?}, {:?}",
+                    device_id,
+                    state,
+                    duration,
+                );
+                (
+                    state_topic_map.get(&device_id).cloned(),
+                    message_str.to_string(),
+                    state_options.get(&device_id).copied().unwrap_or(RETAINED),
+                )
+            }
+            MQTTMessage::Analog((device_id, value)) => {
+                model_status.analog.insert(device_id, value);
+                (
+                    state_topic_map.get(&device_id).cloned(),
+                    crate::analog::value_payload(value),
+                    state_options.get(&device_id).copied().unwrap_or(RETAINED),
+                )
+            }
+            MQTTMessage::Counter(device_id, status) => {
+                model_status.counters.insert(device_id, status);
+                let options = state_options.get(&device_id).copied().unwrap_or(RETAINED);
+                publish_counter(&mut mqtt_client, device_topics, device_id, &status, options);
+                continue;
+            }
+            MQTTMessage::Sensor(index, value) => {
+                model_status.sensors.insert(index, value);
+                (
+                    device_topics
+                        .sensors
+                        .get(index)
+                        .map(|sensor_topics| sensor_topics.state.clone()),
+                    crate::analog::value_payload(value),
+                    mqtt_config.inputs,
+                )
+            }
+            MQTTMessage::SensorError(index, error) => {
+                log::warn!("Error for sensor #{}: {}", index, error);
+                (
+                    device_topics
+                        .sensors
+                        .get(index)
+                        .map(|sensor_topics| sensor_topics.error.clone()),
+                    error,
+                    mqtt_config.events,
+                )
+            }
+            MQTTMessage::Action((device_id, gesture)) => {
+                log::debug!("publishing action for device #{}: {:?}", device_id, gesture);
+                (
+                    device_topics.action.get(&device_id).cloned(),
+                    gesture.as_str().to_string(),
+                    mqtt_config.events,
+                )
+            }
+            MQTTMessage::Error(device_id, error) => {
+                log::warn!("Error for device #{}: {}", device_id, error);
+                (
+                    device_topics.error.get(&device_id).cloned(),
+                    error,
+                    mqtt_config.events,
+                )
+            }
+            MQTTMessage::Cover(index, status) => {
+                model_status.covers.insert(index, status);
+                if let Some(cover_topics) = device_topics.covers.get(index) {
+                    publish_cover(&mut mqtt_client, cover_topics, &status);
+                }
+                continue;
+            }
+            MQTTMessage::Dimmer(index, status) => {
+                model_status.dimmers.insert(index, status);
+                if let Some(dimmer_topics) = device_topics.dimmers.get(index) {
+                    publish_dimmer(&mut mqtt_client, dimmer_topics, &status);
+                }
+                continue;
+            }
+            MQTTMessage::CoverError(index, error) => {
+                log::warn!("Error for cover #{}: {}", index, error);
+                (
+                    device_topics
+                        .covers
+                        .get(index)
+                        .map(|cover_topics| cover_topics.error.clone()),
+                    error,
+                    mqtt_config.events,
+                )
+            }
+            MQTTMessage::DimmerError(index, error) => {
+                log::warn!("Error for dimmable light #{}: {}", index, error);
+                (
+                    device_topics
+                        .dimmers
+                        .get(index)
+                        .map(|dimmer_topics| dimmer_topics.error.clone()),
+                    error,
+                    mqtt_config.events,
+                )
+            }
+            MQTTMessage::Retained(topic, payload) => (Some(topic), payload, RETAINED),
+            MQTTMessage::Publish(topic, payload, retain) => (
+                Some(topic),
+                payload,
+                PublishOptions::new(mqtt_config.events.qos, retain),
+            ),
+            MQTTMessage::Connected => {
+                connected = true;
+                crate::mqtt::subscribe::subscribe_topics(&mut mqtt_client, subscriptions);
+                log::info!(
+                    "Connected, publishing birth message on {}",
+                    availability.topic
+                );
+                publish(
+                    &mut mqtt_client,
+                    &availability.topic,
+                    &availability.payload_online,
+                    RETAINED,
+                );
+                for (topic, payload) in discovery_configs {
+                    publish(&mut mqtt_client, topic, payload, RETAINED);
+                }
+                if !offline_buffer.is_empty() {
+                    log::info!("Flushing {} buffered states", offline_buffer.len());
+                }
+                let mut flushed = std::collections::BTreeSet::new();
+                for (device_id, state, _) in offline_buffer.drain() {
+                    if let Some(topic) = state_topic_map.get(&device_id) {
+                        let options = state_options.get(&device_id).copied().unwrap_or(RETAINED);
+                        publish(&mut mqtt_client, topic, state_payload(state), options);
+                    }
+                    flushed.insert(device_id);
+                }
+                // The buffered states are not published a second time with the snapshot
+                publish_snapshot(
+                    &mut mqtt_client,
+                    state_topic_map,
+                    &state_options,
+                    snapshot,
+                    &flushed,
+                );
+                publish_models(
+                    &mut mqtt_client,
+                    device_topics,
+                    &model_status,
+                    &state_options,
+                    mqtt_config.inputs,
+                );
+                continue;
+            }
+            MQTTMessage::Disconnected => {
+                if connected {
+                    log::warn!("Disconnected, buffering states until connected again");
+                }
+                connected = false;
+                continue;
+            }
+            MQTTMessage::Refresh => {
+                publish_snapshot(
+                    &mut mqtt_client,
+                    state_topic_map,
+                    &state_options,
+                    snapshot,
+                    &std::collections::BTreeSet::new(),
+                );
+                publish_models(
+                    &mut mqtt_client,
+                    device_topics,
+                    &model_status,
+                    &state_options,
+                    mqtt_config.inputs,
+                );
+                continue;
+            }
         };
+        if let Some(topic) = topic {
+            publish(&mut mqtt_client, &topic, &message_str, options);
+        }
+    }
+}
+
+// Payload for a device state
+fn state_payload(state: bool) -> &'static str {
+    match state {
+        true => "ON",
+        false => "OFF",
+    }
+}
+
+// Publish the current state of all digital devices, except for the skipped ones
+fn publish_snapshot(
+    mqtt_client: &mut rumqttc::Client,
+    state_topic_map: &std::collections::HashMap<u8, String>,
+    state_options: &std::collections::HashMap<u8, PublishOptions>,
+    snapshot: &Snapshot,
+    skipped: &std::collections::BTreeSet<u8>,
+) {
+    for (device_id, state) in snapshot.read() {
+        if skipped.contains(&device_id) {
+            continue;
+        }
         if let Some(topic) = state_topic_map.get(&device_id) {
-            log::debug!(
-                "publishing message for device #{}: {:?}, {:?}, {}",
-                device_id,
-                state,
-                duration,
-                topic
+            let options = state_options.get(&device_id).copied().unwrap_or(RETAINED);
+            publish(mqtt_client, topic, state_payload(state), options);
+        }
+    }
+}
+
+// Publish the latest status of all covers, dimmable lights, analog devices, counters and sensors
+fn publish_models(
+    mqtt_client: &mut rumqttc::Client,
+    device_topics: &DeviceTopics,
+    model_status: &ModelStatus,
+    state_options: &std::collections::HashMap<u8, PublishOptions>,
+    sensor_options: PublishOptions,
+) {
+    for (device_id, &value) in &model_status.analog {
+        if let Some(topic) = device_topics.state.get(device_id) {
+            let options = state_options.get(device_id).copied().unwrap_or(RETAINED);
+            publish(
+                mqtt_client,
+                topic,
+                &crate::analog::value_payload(value),
+                options,
+            );
+        }
+    }
+    for (&device_id, status) in &model_status.counters {
+        let options = state_options.get(&device_id).copied().unwrap_or(RETAINED);
+        publish_counter(mqtt_client, device_topics, device_id, status, options);
+    }
+    for (&index, &value) in &model_status.sensors {
+        if let Some(sensor_topics) = device_topics.sensors.get(index) {
+            publish(
+                mqtt_client,
+                &sensor_topics.state,
+                &crate::analog::value_payload(value),
+                sensor_options,
+            );
+        }
+    }
+    for (&index, status) in &model_status.covers {
+        if let Some(cover_topics) = device_topics.covers.get(index) {
+            publish_cover(mqtt_client, cover_topics, status);
+        }
+    }
+    for (&index, status) in &model_status.dimmers {
+        if let Some(dimmer_topics) = device_topics.dimmers.get(index) {
+            publish_dimmer(mqtt_client, dimmer_topics, status);
+        }
+    }
+}
+
+// Publish the total of a counter, and its rate once known
+fn publish_counter(
+    mqtt_client: &mut rumqttc::Client,
+    device_topics: &DeviceTopics,
+    device_id: u8,
+    status: &crate::counter::CounterStatus,
+    options: PublishOptions,
+) {
+    if let Some(topic) = device_topics.state.get(&device_id) {
+        publish(mqtt_client, topic, &status.total.to_string(), options);
+    }
+    if let (Some(topic), Some(rate)) = (device_topics.rate.get(&device_id), status.rate) {
+        publish(
+            mqtt_client,
+            topic,
+            &crate::analog::value_payload(rate),
+            options,
+        );
+    }
+}
+
+// Publish the state and brightness of a dimmable light
+fn publish_dimmer(
+    mqtt_client: &mut rumqttc::Client,
+    dimmer_topics: &crate::models::DimmerTopics,
+    status: &crate::models::DimmerStatus,
+) {
+    publish(
+        mqtt_client,
+        &dimmer_topics.state,
+        state_payload(status.on),
+        RETAINED,
+    );
+    publish(
+        mqtt_client,
+        &dimmer_topics.brightness,
+        &status.brightness.to_string(),
+        RETAINED,
+    );
+}
+
+// Publish the state and position of a cover; the position is left out while unknown
+fn publish_cover(
+    mqtt_client: &mut rumqttc::Client,
+    cover_topics: &crate::models::CoverTopics,
+    status: &crate::models::CoverStatus,
+) {
+    publish(mqtt_client, &cover_topics.state, status.state(), RETAINED);
+    if let Some(position) = status.position {
+        publish(
+            mqtt_client,
+            &cover_topics.position,
+            &position.to_string(),
+            RETAINED,
+        );
+    }
+}
+
+// Publish a single message, logging the outcome
+fn publish(mqtt_client: &mut rumqttc::Client, topic: &str, payload: &str, options: PublishOptions) {
+    log::debug!("publishing {} on {}", payload, topic);
+    let result = mqtt_client.publish(topic, options.qos, options.retain, payload);
+    match result {
+        Ok(r) => log::debug!("Everything OK {:?}", r),
+        Err(e) => log::debug!("Error {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run the publisher against a test broker, returning the channel to feed it and the packets
+    // the broker receives
+    fn start(
+        mut mqtt_config: crate::mqtt::MQTTConfig,
+        device_topics: DeviceTopics,
+        subscriptions: std::vec::Vec<rumqttc::SubscribeFilter>,
+        snapshot: Snapshot,
+    ) -> (
+        std::sync::mpsc::Sender<MQTTMessage>,
+        std::sync::mpsc::Receiver<rumqttc::Packet>,
+    ) {
+        let (port, packets) = crate::mqtt::tests::start_broker(0);
+        mqtt_config.port = port;
+        let (mqtt_client, mut connection) =
+            rumqttc::Client::new(mqtt_config.mqtt_options().unwrap(), 100);
+        std::thread::spawn(move || for _ in connection.iter().take_while(|n| n.is_ok()) {});
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            publish_messages(
+                rx,
+                mqtt_client,
+                &device_topics,
+                &mqtt_config,
+                &subscriptions,
+                &[],
+                &snapshot,
+            )
+        });
+        (tx, packets)
+    }
+
+    // Wait for the next `count` messages published on the broker
+    fn published(
+        packets: &std::sync::mpsc::Receiver<rumqttc::Packet>,
+        count: usize,
+    ) -> std::vec::Vec<rumqttc::Publish> {
+        let mut publishes = std::vec::Vec::new();
+        while publishes.len() < count {
+            let packet = packets
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .expect("Expect a publish");
+            if let rumqttc::Packet::Publish(publish) = packet {
+                publishes.push(publish);
+            }
+        }
+        publishes
+    }
+
+    // Topic, payload, QoS and retain flag of a publish
+    fn summary(publish: &rumqttc::Publish) -> (String, String, rumqttc::QoS, bool) {
+        (
+            publish.topic.clone(),
+            String::from_utf8_lossy(&publish.payload).to_string(),
+            publish.qos,
+            publish.retain,
+        )
+    }
+
+    // Subscription to the refresh topic; rumqttc refuses to subscribe to nothing at all
+    fn refresh() -> std::vec::Vec<rumqttc::SubscribeFilter> {
+        vec![rumqttc::SubscribeFilter::new(
+            "plc/refresh".to_string(),
+            rumqttc::QoS::AtLeastOnce,
+        )]
+    }
+
+    // Devices below a temporary sysfs tree, along with their snapshot and topics
+    fn devices(files: &[(&str, &str)]) -> (tempdir::TempDir, Snapshot, DeviceTopics) {
+        let tmp_dir = crate::device::tests::sysfs_fixture(files);
+        let backend = std::sync::Arc::new(crate::backend::unipi::UniPi::new(
+            tmp_dir.path().to_str().unwrap(),
+        ));
+        let (io, devices) = crate::backend::Io::discover(vec![backend], "plc").unwrap();
+        let mut device_topics = DeviceTopics::default();
+        crate::device::device_state_topics(&devices, &mut device_topics.state);
+        crate::device::device_action_topics(&devices, &mut device_topics.action);
+        (tmp_dir, Snapshot { devices, io }, device_topics)
+    }
+
+    #[test]
+    fn test_birth_message_after_every_connect() {
+        let mut availability = crate::mqtt::Availability::new("plc");
+        availability.topic = "plc/availability".to_string();
+        availability.payload_online = "up".to_string();
+        let mqtt_config = crate::mqtt::MQTTConfig::new("127.0.0.1", "maus", availability);
+        let snapshot = Snapshot {
+            devices: std::vec::Vec::new(),
+            io: crate::backend::Io::default(),
+        };
+        let (tx, packets) = start(mqtt_config, DeviceTopics::default(), refresh(), snapshot);
+
+        for _ in 0..2 {
+            tx.send(MQTTMessage::Connected).unwrap();
+            assert_eq!(
+                summary(&published(&packets, 1)[0]),
+                (
+                    "plc/availability".to_string(),
+                    "up".to_string(),
+                    rumqttc::QoS::AtLeastOnce,
+                    true
+                )
             );
-            let result = mqtt_client.publish(topic, rumqttc::QoS::AtLeastOnce, false, message_str);
-            match result {
-                Ok(r) => log::debug!("Everything OK {:?}", r),
-                Err(e) => log::debug!("Error {:?}", e),
+            tx.send(MQTTMessage::Disconnected).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_offline_buffer_keeps_latest_state() {
+        let mut offline_buffer = OfflineBuffer::default();
+        let duration = std::time::Duration::from_millis(10);
+        offline_buffer.push((3, true, duration));
+        offline_buffer.push((1, true, duration));
+        offline_buffer.push((3, false, duration));
+        assert_eq!(offline_buffer.len(), 2);
+
+        assert_eq!(
+            offline_buffer.drain(),
+            vec![(1, true, duration), (3, false, duration)]
+        );
+        assert!(offline_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_buffered_states_published_once() {
+        let (_tmp_dir, snapshot, device_topics) =
+            devices(&[("di_1_01/di_value", "1\n"), ("ro_2_01/ro_value", "0\n")]);
+        let id = |device_type| {
+            snapshot
+                .devices
+                .iter()
+                .find(|device| device.device_type == device_type)
+                .unwrap()
+                .id
+        };
+        let input_id = id(crate::device::DeviceType::DigitalInput);
+        let relay_id = id(crate::device::DeviceType::RelayOutput);
+        let mqtt_config = crate::mqtt::MQTTConfig::new(
+            "127.0.0.1",
+            "maus",
+            crate::mqtt::Availability::new("plc"),
+        );
+        let (tx, packets) = start(mqtt_config, device_topics, refresh(), snapshot);
+
+        let duration = std::time::Duration::from_millis(10);
+        tx.send(MQTTMessage::State((relay_id, true, duration)))
+            .unwrap();
+        tx.send(MQTTMessage::Action((
+            input_id,
+            crate::gesture::Gesture::Single,
+        )))
+        .unwrap();
+        tx.send(MQTTMessage::Connected).unwrap();
+        tx.send(MQTTMessage::Publish(
+            "plc/marker".to_string(),
+            "done".to_string(),
+            false,
+        ))
+        .unwrap();
+
+        // Everything published up to the marker, except for the birth message
+        let mut states = std::vec::Vec::new();
+        loop {
+            let (topic, payload, _, _) = summary(&published(&packets, 1)[0]);
+            if topic == "plc/marker" {
+                break;
+            }
+            if topic != "plc/status" {
+                states.push((topic, payload));
             }
         }
+        states.sort();
+        // The buffered relay state, not the one read for the snapshot, and no action
+        assert_eq!(
+            states,
+            vec![
+                ("plc/input/1_01/state".to_string(), "ON".to_string()),
+                ("plc/relay/2_01/state".to_string(), "ON".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_on_connect_and_refresh() {
+        let (tmp_dir, snapshot, device_topics) =
+            devices(&[("di_1_01/di_value", "1\n"), ("ro_2_01/ro_value", "0\n")]);
+        let mqtt_config = crate::mqtt::MQTTConfig::new(
+            "127.0.0.1",
+            "maus",
+            crate::mqtt::Availability::new("plc"),
+        );
+        let (tx, packets) = start(mqtt_config, device_topics, refresh(), snapshot);
+        // State topics and payloads, sorted as devices are crawled in no particular order
+        let states = |publishes: std::vec::Vec<rumqttc::Publish>| {
+            let mut states: std::vec::Vec<(String, String)> = publishes
+                .iter()
+                .map(summary)
+                .filter(|(topic, _, _, _)| topic != "plc/status")
+                .map(|(topic, payload, _, _)| (topic, payload))
+                .collect();
+            states.sort();
+            states
+        };
+
+        // Birth message and both states
+        tx.send(MQTTMessage::Connected).unwrap();
+        assert_eq!(
+            states(published(&packets, 3)),
+            vec![
+                ("plc/input/1_01/state".to_string(), "ON".to_string()),
+                ("plc/relay/2_01/state".to_string(), "OFF".to_string()),
+            ]
+        );
+
+        // States are read again for every refresh
+        std::fs::write(
+            tmp_dir
+                .path()
+                .join("sys/devices/platform/unipi_plc/io_group1/di_1_01/di_value"),
+            "0\n",
+        )
+        .unwrap();
+        tx.send(MQTTMessage::Refresh).unwrap();
+        assert_eq!(
+            states(published(&packets, 2)),
+            vec![
+                ("plc/input/1_01/state".to_string(), "OFF".to_string()),
+                ("plc/relay/2_01/state".to_string(), "OFF".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_options_per_topic_class() {
+        let (_tmp_dir, snapshot, device_topics) = devices(&[
+            ("di_1_01/di_value", "1\n"),
+            ("do_1_01/do_value", "1\n"),
+            ("ro_2_01/ro_value", "0\n"),
+        ]);
+        let input_id = snapshot
+            .devices
+            .iter()
+            .find(|device| device.device_type == crate::device::DeviceType::DigitalInput)
+            .unwrap()
+            .id;
+        let mut incoming_topics = crate::mqtt::subscribe::IncomingTopics {
+            refresh: "plc/refresh".to_string(),
+            ..Default::default()
+        };
+        crate::device::device_command_topics(&snapshot.devices, &mut incoming_topics.commands);
+        let mut mqtt_config = crate::mqtt::MQTTConfig::new(
+            "127.0.0.1",
+            "maus",
+            crate::mqtt::Availability::new("plc"),
+        );
+        mqtt_config.inputs = PublishOptions::new(rumqttc::QoS::AtMostOnce, false);
+        mqtt_config.outputs = PublishOptions::new(rumqttc::QoS::ExactlyOnce, true);
+        mqtt_config.relays = PublishOptions::new(rumqttc::QoS::AtLeastOnce, false);
+        mqtt_config.events = PublishOptions::new(rumqttc::QoS::ExactlyOnce, true);
+        mqtt_config.command_qos = rumqttc::QoS::ExactlyOnce;
+        let subscriptions = incoming_topics.filters(mqtt_config.command_qos);
+        let (tx, packets) = start(mqtt_config, device_topics, subscriptions, snapshot);
+
+        tx.send(MQTTMessage::Connected).unwrap();
+        tx.send(MQTTMessage::Action((
+            input_id,
+            crate::gesture::Gesture::Single,
+        )))
+        .unwrap();
+        // Birth message, three states and the action
+        let mut publishes: std::vec::Vec<(String, String, rumqttc::QoS, bool)> =
+            published(&packets, 5).iter().map(summary).collect();
+        publishes.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            publishes,
+            vec![
+                (
+                    "plc/input/1_01/action".to_string(),
+                    "single".to_string(),
+                    rumqttc::QoS::ExactlyOnce,
+                    true
+                ),
+                (
+                    "plc/input/1_01/state".to_string(),
+                    "ON".to_string(),
+                    rumqttc::QoS::AtMostOnce,
+                    false
+                ),
+                (
+                    "plc/output/1_01/state".to_string(),
+                    "ON".to_string(),
+                    rumqttc::QoS::ExactlyOnce,
+                    true
+                ),
+                (
+                    "plc/relay/2_01/state".to_string(),
+                    "OFF".to_string(),
+                    rumqttc::QoS::AtLeastOnce,
+                    false
+                ),
+                (
+                    "plc/status".to_string(),
+                    "online".to_string(),
+                    rumqttc::QoS::AtLeastOnce,
+                    true
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_qos_for_subscriptions() {
+        let (_tmp_dir, snapshot, device_topics) = devices(&[("ro_2_01/ro_value", "0\n")]);
+        let mut incoming_topics = crate::mqtt::subscribe::IncomingTopics {
+            refresh: "plc/refresh".to_string(),
+            ..Default::default()
+        };
+        crate::device::device_command_topics(&snapshot.devices, &mut incoming_topics.commands);
+        let mut mqtt_config = crate::mqtt::MQTTConfig::new(
+            "127.0.0.1",
+            "maus",
+            crate::mqtt::Availability::new("plc"),
+        );
+        mqtt_config.command_qos = rumqttc::QoS::ExactlyOnce;
+        let subscriptions = incoming_topics.filters(mqtt_config.command_qos);
+        let (tx, packets) = start(mqtt_config, device_topics, subscriptions, snapshot);
+
+        tx.send(MQTTMessage::Connected).unwrap();
+        let subscribe = loop {
+            match packets.recv_timeout(std::time::Duration::from_secs(5)) {
+                Ok(rumqttc::Packet::Subscribe(subscribe)) => break subscribe,
+                Ok(_) => {}
+                Err(e) => panic!("Expect a subscription: {}", e),
+            }
+        };
+        let filters: std::vec::Vec<(String, rumqttc::QoS)> = subscribe
+            .filters
+            .into_iter()
+            .map(|filter| (filter.path, filter.qos))
+            .collect();
+        assert_eq!(
+            filters,
+            vec![
+                ("plc/relay/2_01/set".to_string(), rumqttc::QoS::ExactlyOnce),
+                ("plc/refresh".to_string(), rumqttc::QoS::ExactlyOnce),
+            ]
+        );
     }
 }
@@ -1,48 +1,317 @@
-/// subscribe module accepts incoming MQTT messages and forwards it back to the rest
+//! subscribe module accepts incoming MQTT messages and forwards it back to the rest
 
-/// Subscribe to the topics as available in the topics from the command topic map
-pub fn subscribe_topics(
-    mqtt_client: &mut rumqttc::Client,
+/// Subscription filters for all topics in the command topic map
+pub fn command_filters(
     command_topic_map: &std::collections::HashMap<String, u8>,
-) {
-    // COnvert to vector of (topic, QoS)
-    let mut topic_qos: std::vec::Vec<rumqttc::SubscribeFilter> = std::vec::Vec::new();
-    for topic in command_topic_map.keys() {
-        topic_qos.push(rumqttc::SubscribeFilter {
-            path: topic.clone(),
-            qos: rumqttc::QoS::AtLeastOnce,
-        });
+    qos: rumqttc::QoS,
+) -> std::vec::Vec<rumqttc::SubscribeFilter> {
+    let mut filters: std::vec::Vec<rumqttc::SubscribeFilter> = command_topic_map
+        .keys()
+        .map(|topic| rumqttc::SubscribeFilter::new(topic.clone(), qos))
+        .collect();
+    filters.sort_by(|a, b| a.path.cmp(&b.path));
+    filters
+}
+
+/// Subscribe to all given filters at once
+///
+/// Needs to be done again after every ConnAck, since a clean session drops all subscriptions.
+pub fn subscribe_topics(mqtt_client: &mut rumqttc::Client, filters: &[rumqttc::SubscribeFilter]) {
+    log::debug!("Subscribing to {} topics", filters.len());
+    if let Err(e) = mqtt_client.subscribe_many(filters.to_vec()) {
+        log::error!("Could not subscribe: {}", e);
     }
+}
+
+/// Topics incoming messages are dispatched on
+#[derive(Debug, Default)]
+pub struct IncomingTopics {
+    // Command topic -> device ID
+    pub commands: std::collections::HashMap<String, u8>,
+    // Command topic -> analog output ID
+    pub analog_commands: std::collections::HashMap<String, u8>,
+    // Command topic -> counter ID
+    pub counter_commands: std::collections::HashMap<String, u8>,
+    // Cover command and position topics -> cover index
+    pub cover_commands: std::collections::HashMap<String, usize>,
+    pub cover_positions: std::collections::HashMap<String, usize>,
+    // Dimmable light command and brightness topics -> dimmable light index
+    pub dimmer_commands: std::collections::HashMap<String, usize>,
+    pub dimmer_brightness: std::collections::HashMap<String, usize>,
+    pub discovery: Option<crate::mqtt::discovery::DiscoveryTopics>,
+    pub refresh: String,
+    // Topics rules are triggered by
+    pub rules: std::collections::HashSet<String>,
+}
+
+/// Channels incoming messages are forwarded to
+#[derive(Debug, Clone)]
+pub struct Dispatch {
+    pub commands: std::sync::mpsc::Sender<crate::output::OutputEvent>,
+    pub covers: std::sync::mpsc::Sender<crate::models::CoverEvent>,
+    pub dimmers: std::sync::mpsc::Sender<crate::models::DimmerEvent>,
+    pub analog: std::sync::mpsc::Sender<crate::analog::AnalogEvent>,
+    pub counters: std::sync::mpsc::Sender<crate::counter::CounterEvent>,
+    pub rules: std::sync::mpsc::Sender<crate::auto::RuleEvent>,
+}
 
-    mqtt_client.subscribe_many(topic_qos).unwrap();
+impl IncomingTopics {
+    /// Subscription filters for all topics
+    pub fn filters(&self, qos: rumqttc::QoS) -> std::vec::Vec<rumqttc::SubscribeFilter> {
+        let mut filters = command_filters(&self.commands, qos);
+        let mut model_topics: std::vec::Vec<&String> = self
+            .analog_commands
+            .keys()
+            .chain(self.counter_commands.keys())
+            .chain(self.cover_commands.keys())
+            .chain(self.cover_positions.keys())
+            .chain(self.dimmer_commands.keys())
+            .chain(self.dimmer_brightness.keys())
+            .chain(
+                self.rules
+                    .iter()
+                    .filter(|topic| !self.commands.contains_key(*topic)),
+            )
+            .collect();
+        model_topics.sort();
+        model_topics.dedup();
+        filters.extend(
+            model_topics
+                .into_iter()
+                .map(|topic| rumqttc::SubscribeFilter::new(topic.clone(), qos)),
+        );
+        filters.push(rumqttc::SubscribeFilter::new(self.refresh.clone(), qos));
+        if let Some(discovery_topics) = &self.discovery {
+            filters.push(rumqttc::SubscribeFilter::new(
+                discovery_topics.filter(),
+                rumqttc::QoS::AtLeastOnce,
+            ));
+        }
+        filters
+    }
 }
 
-/// handle incoming messages
+/// handle incoming messages, supervising the connection
+///
+/// Connection errors are retried with an exponential backoff; the publish thread is told about
+/// every disconnect and (re)connect, so it can buffer states and restore subscriptions.
 pub fn handle_incoming_messages(
-    tx: std::sync::mpsc::Sender<crate::mqtt::MQTTEvent>,
+    dispatch: Dispatch,
     mqtt_loop: &mut rumqttc::Connection,
-    command_topic_map: &std::collections::HashMap<String, u8>,
+    topics: &IncomingTopics,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+    mut backoff: crate::mqtt::Backoff,
 ) {
     // handle message
     for event in mqtt_loop.iter() {
         log::debug!("Received incoming event {:?}", event);
-        if let Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg))) = event {
+        let event = match event {
+            Ok(event) => event,
+            // The next iteration reconnects
+            Err(e) => {
+                let delay = backoff.next_delay();
+                log::warn!("MQTT connection error: {}, reconnecting in {:?}", e, delay);
+                mqtt_publish_tx
+                    .send(crate::mqtt::MQTTMessage::Disconnected)
+                    .unwrap();
+                std::thread::sleep(delay);
+                continue;
+            }
+        };
+        if let rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) = event {
+            backoff.reset();
+            mqtt_publish_tx
+                .send(crate::mqtt::MQTTMessage::Connected)
+                .unwrap();
+            continue;
+        }
+        if let rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg)) = event {
             log::debug!("Incoming event {:?} {:?}", msg.topic, msg.payload);
 
+            if msg.topic == topics.refresh {
+                log::debug!("Received refresh request");
+                mqtt_publish_tx
+                    .send(crate::mqtt::MQTTMessage::Refresh)
+                    .unwrap();
+                continue;
+            }
+
+            // Clean up discovery configs for devices that are gone
+            if let Some(discovery_topics) = &topics.discovery {
+                if discovery_topics.is_stale(&msg.topic, &msg.payload) {
+                    log::info!("Removing stale discovery config {}", msg.topic);
+                    mqtt_publish_tx
+                        .send(crate::mqtt::MQTTMessage::Retained(msg.topic, String::new()))
+                        .unwrap();
+                    continue;
+                }
+            }
+
             if let Ok(payload) = std::str::from_utf8(&msg.payload.to_owned()) {
-                let toggle: Option<bool> = match payload {
-                    "ON" => Some(true),
-                    "OFF" => Some(false),
+                // Rules see the message as well, on top of the regular handling
+                if topics.rules.contains(&msg.topic) {
+                    dispatch
+                        .rules
+                        .send(crate::auto::RuleEvent::Message(
+                            msg.topic.clone(),
+                            payload.to_string(),
+                        ))
+                        .unwrap();
+                }
+
+                if let Some(&device_id) = topics.analog_commands.get(&msg.topic) {
+                    log::debug!("Received value for analog output #{}", device_id);
+                    match crate::analog::parse_value(payload) {
+                        Ok(value) => dispatch.analog.send((device_id, value)).unwrap(),
+                        Err(e) => mqtt_publish_tx
+                            .send(crate::mqtt::MQTTMessage::Error(device_id, e))
+                            .unwrap(),
+                    }
+                    continue;
+                }
+
+                if let Some(&device_id) = topics.counter_commands.get(&msg.topic) {
+                    log::debug!("Received command for counter #{}", device_id);
+                    match crate::counter::CounterCommand::parse(payload) {
+                        Ok(command) => dispatch.counters.send((device_id, command)).unwrap(),
+                        Err(e) => mqtt_publish_tx
+                            .send(crate::mqtt::MQTTMessage::Error(device_id, e))
+                            .unwrap(),
+                    }
+                    continue;
+                }
+
+                let cover_command = match (
+                    topics.cover_commands.get(&msg.topic),
+                    topics.cover_positions.get(&msg.topic),
+                ) {
+                    (Some(&index), _) => Some((index, crate::models::CoverCommand::parse(payload))),
+                    (_, Some(&index)) => {
+                        Some((index, crate::models::CoverCommand::parse_position(payload)))
+                    }
+                    _ => None,
+                };
+                match cover_command {
+                    Some((index, Some(command))) => {
+                        log::debug!("Received command for cover #{}", index);
+                        dispatch.covers.send((index, command)).unwrap();
+                        continue;
+                    }
+                    Some((index, None)) => {
+                        mqtt_publish_tx
+                            .send(crate::mqtt::MQTTMessage::CoverError(
+                                index,
+                                format!("Invalid payload {:?}", payload),
+                            ))
+                            .unwrap();
+                        continue;
+                    }
+                    None => {}
+                }
+
+                let dimmer_command = match (
+                    topics.dimmer_commands.get(&msg.topic),
+                    topics.dimmer_brightness.get(&msg.topic),
+                ) {
+                    (Some(&index), _) => {
+                        Some((index, crate::models::DimmerCommand::parse(payload)))
+                    }
+                    (_, Some(&index)) => Some((
+                        index,
+                        crate::models::DimmerCommand::parse_brightness(payload),
+                    )),
                     _ => None,
                 };
+                match dimmer_command {
+                    Some((index, Some(command))) => {
+                        log::debug!("Received command for dimmable light #{}", index);
+                        dispatch.dimmers.send((index, command)).unwrap();
+                        continue;
+                    }
+                    Some((index, None)) => {
+                        mqtt_publish_tx
+                            .send(crate::mqtt::MQTTMessage::DimmerError(
+                                index,
+                                format!("Invalid payload {:?}", payload),
+                            ))
+                            .unwrap();
+                        continue;
+                    }
+                    None => {}
+                }
 
-                if let (Some(&device_id), Some(payload)) =
-                    (command_topic_map.get(&msg.topic), toggle)
-                {
+                if let Some(&device_id) = topics.commands.get(&msg.topic) {
                     log::debug!("Received message for device #{}", device_id);
-                    tx.send((device_id, payload)).unwrap();
+                    match crate::output::OutputCommand::parse(payload) {
+                        Ok(command) => dispatch.commands.send((device_id, command)).unwrap(),
+                        Err(e) => mqtt_publish_tx
+                            .send(crate::mqtt::MQTTMessage::Error(device_id, e))
+                            .unwrap(),
+                    }
                 }
+            } else if let Some(&device_id) = topics.commands.get(&msg.topic) {
+                mqtt_publish_tx
+                    .send(crate::mqtt::MQTTMessage::Error(
+                        device_id,
+                        "Payload is not valid UTF-8".to_string(),
+                    ))
+                    .unwrap();
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connected_after_every_connack() {
+        // The first connection is dropped right after its ConnAck
+        let (port, _packets) = crate::mqtt::tests::start_broker(1);
+        let mut mqtt_config = crate::mqtt::MQTTConfig::new(
+            "127.0.0.1",
+            "maus",
+            crate::mqtt::Availability::new("plc"),
+        );
+        mqtt_config.port = port;
+        let backoff = crate::mqtt::Backoff::new(
+            std::time::Duration::from_millis(1),
+            std::time::Duration::from_millis(10),
+        );
+        let (_client, mut connection) =
+            rumqttc::Client::new(mqtt_config.mqtt_options().unwrap(), 10);
+        let dispatch = Dispatch {
+            commands: std::sync::mpsc::channel().0,
+            covers: std::sync::mpsc::channel().0,
+            dimmers: std::sync::mpsc::channel().0,
+            analog: std::sync::mpsc::channel().0,
+            counters: std::sync::mpsc::channel().0,
+            rules: std::sync::mpsc::channel().0,
+        };
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            handle_incoming_messages(
+                dispatch,
+                &mut connection,
+                &IncomingTopics::default(),
+                publish_tx,
+                backoff,
+            )
+        });
+
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(matches!(
+            publish_rx.recv_timeout(timeout),
+            Ok(crate::mqtt::MQTTMessage::Connected)
+        ));
+        assert!(matches!(
+            publish_rx.recv_timeout(timeout),
+            Ok(crate::mqtt::MQTTMessage::Disconnected)
+        ));
+        assert!(matches!(
+            publish_rx.recv_timeout(timeout),
+            Ok(crate::mqtt::MQTTMessage::Connected)
+        ));
+    }
+}
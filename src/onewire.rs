@@ -0,0 +1,309 @@
+//! onewire discovers temperature sensors on the 1-Wire bus and samples them in the background
+//!
+//! Reading a sensor takes up to a second for the conversion, so all sensors are read by a thread
+//! of their own.
+
+/// Default root of the 1-Wire devices in sysfs
+pub const W1_PATH: &str = "/sys/bus/w1/devices";
+// Default interval at which sensors are sampled, in ms
+pub const SAMPLE_INTERVAL: u64 = 10000;
+// Family codes of the temperature sensors: DS18S20, DS1822, DS18B20, DS1825 and DS28EA00
+const FAMILIES: [&str; 5] = ["10", "22", "28", "3b", "42"];
+// Value of the temperature register after power-on, in m°C, read when a conversion did not run
+const POWER_ON_VALUE: i64 = 85000;
+// Temperatures above this are close enough to 85 °C to take the power-on value as real, in °C
+const POWER_ON_THRESHOLD: f64 = 80.0;
+
+/// A temperature sensor on the 1-Wire bus
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sensor {
+    // 1-Wire ID, e.g. `28-0316a2795aff`
+    pub id: String,
+    // Name used in topics, the ID unless configured
+    pub name: String,
+    // Directory of the sensor in sysfs
+    pub path: String,
+}
+
+/// MQTT topics for a single sensor
+#[derive(Debug, Clone, Default)]
+pub struct SensorTopics {
+    pub state: String,
+    pub error: String,
+}
+
+impl SensorTopics {
+    /// Topics below `<device_name>/temperature/<name>`
+    pub fn new(device_name: &str, name: &str) -> Self {
+        let base = format!("{}/temperature/{}", device_name, name);
+        SensorTopics {
+            state: format!("{}/state", base),
+            error: format!("{}/error", base),
+        }
+    }
+}
+
+/// IDs of all temperature sensors found below `path`, sorted
+pub fn discover(path: &str) -> std::vec::Vec<String> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::debug!("No 1-Wire devices in {}: {}", path, e);
+            return std::vec::Vec::new();
+        }
+    };
+    let mut ids: std::vec::Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|id| {
+            id.split_once('-')
+                .is_some_and(|(family, _)| FAMILIES.contains(&family))
+        })
+        .collect();
+    ids.sort();
+    ids
+}
+
+// Parse a temperature in m°C
+fn parse_millidegrees(value: &str) -> Result<i64, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid temperature {:?}", value.trim()))
+}
+
+/// Parse the contents of `w1_slave`: a line with the CRC check and one with the temperature
+pub fn parse_w1_slave(contents: &str) -> Result<i64, String> {
+    let mut lines = contents.lines();
+    match lines.next() {
+        Some(line) if line.trim_end().ends_with("YES") => {}
+        Some(_) => return Err("CRC error".to_string()),
+        None => return Err("No data".to_string()),
+    }
+    lines
+        .next()
+        .and_then(|line| line.split_once("t="))
+        .ok_or_else(|| "No temperature".to_string())
+        .and_then(|(_, value)| parse_millidegrees(value))
+}
+
+/// Read the temperature of a sensor in m°C, from `temperature` on newer kernels or `w1_slave`
+pub fn read_millidegrees(path: &str) -> Result<i64, String> {
+    let temperature_path = format!("{}/temperature", path);
+    if std::path::Path::new(&temperature_path).exists() {
+        let contents = std::fs::read_to_string(&temperature_path)
+            .map_err(|e| format!("Could not read {}: {}", temperature_path, e))?;
+        return parse_millidegrees(&contents);
+    }
+    let w1_slave_path = format!("{}/w1_slave", path);
+    let contents = std::fs::read_to_string(&w1_slave_path)
+        .map_err(|e| format!("Could not read {}: {}", w1_slave_path, e))?;
+    parse_w1_slave(&contents)
+}
+
+// A single sensor being sampled
+#[derive(Debug)]
+struct SampledSensor {
+    sensor: Sensor,
+    last_value: Option<f64>,
+    // Whether the last read failed, to report an error only once until it recovers
+    failing: bool,
+}
+
+/// Outcome of a sample worth publishing
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reading {
+    Temperature(usize, f64),
+    Error(usize, String),
+}
+
+/// SensorSampler keeps track of the last temperatures and when to sample all sensors
+#[derive(Debug)]
+pub struct SensorSampler {
+    sensors: std::vec::Vec<SampledSensor>,
+    interval: std::time::Duration,
+    next_sample: std::time::Instant,
+}
+
+impl SensorSampler {
+    /// Set up the sensors, all of them to be sampled right away
+    pub fn new(
+        sensors: std::vec::Vec<Sensor>,
+        interval: std::time::Duration,
+        now: std::time::Instant,
+    ) -> Self {
+        SensorSampler {
+            sensors: sensors
+                .into_iter()
+                .map(|sensor| SampledSensor {
+                    sensor,
+                    last_value: None,
+                    failing: false,
+                })
+                .collect(),
+            interval,
+            next_sample: now,
+        }
+    }
+
+    /// All sensors, by index
+    pub fn sensors(&self) -> std::vec::Vec<&Sensor> {
+        self.sensors.iter().map(|sampled| &sampled.sensor).collect()
+    }
+
+    /// Handle a sample in m°C, returning what to publish
+    ///
+    /// Values that did not change are left out, as are power-on values unless the last
+    /// temperature was close to them.
+    pub fn feed(&mut self, index: usize, sample: Result<i64, String>) -> Option<Reading> {
+        let sampled = &mut self.sensors[index];
+        let sample = sample.and_then(|millidegrees| {
+            let near_power_on = sampled
+                .last_value
+                .is_some_and(|last_value| last_value >= POWER_ON_THRESHOLD);
+            if millidegrees == POWER_ON_VALUE && !near_power_on {
+                return Err("Power-on value, the conversion did not run".to_string());
+            }
+            Ok(millidegrees as f64 / 1000.0)
+        });
+        match sample {
+            Ok(value) => {
+                sampled.failing = false;
+                if sampled.last_value == Some(value) {
+                    return None;
+                }
+                sampled.last_value = Some(value);
+                Some(Reading::Temperature(index, value))
+            }
+            Err(e) => {
+                log::debug!("Could not read sensor {}: {}", sampled.sensor.id, e);
+                if std::mem::replace(&mut sampled.failing, true) {
+                    return None;
+                }
+                Some(Reading::Error(index, e))
+            }
+        }
+    }
+
+    /// Move on to the next sample after sampling all sensors
+    pub fn sampled(&mut self, now: std::time::Instant) {
+        self.next_sample = now + self.interval;
+    }
+
+    /// The next moment the sensors need to be sampled
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        (!self.sensors.is_empty()).then_some(self.next_sample)
+    }
+}
+
+/// Sample all sensors and publish the temperatures that changed, and read errors
+pub fn run_sensors(
+    mut sampler: SensorSampler,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+) {
+    while let Some(deadline) = sampler.next_deadline() {
+        std::thread::sleep(deadline.saturating_duration_since(std::time::Instant::now()));
+        let samples: std::vec::Vec<Result<i64, String>> = sampler
+            .sensors()
+            .into_iter()
+            .map(|sensor| read_millidegrees(&sensor.path))
+            .collect();
+        for (index, sample) in samples.into_iter().enumerate() {
+            let message = match sampler.feed(index, sample) {
+                Some(Reading::Temperature(index, value)) => {
+                    crate::mqtt::MQTTMessage::Sensor(index, value)
+                }
+                Some(Reading::Error(index, e)) => crate::mqtt::MQTTMessage::SensorError(index, e),
+                None => continue,
+            };
+            if mqtt_publish_tx.send(message).is_err() {
+                return;
+            }
+        }
+        sampler.sampled(std::time::Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_w1_slave() {
+        let contents =
+            "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse_w1_slave(contents), Ok(23125));
+        let contents =
+            "72 01 4b 46 7f ff 0e 10 57 : crc=12 NO\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse_w1_slave(contents), Err("CRC error".to_string()));
+        assert!(parse_w1_slave("").is_err());
+        assert_eq!(parse_w1_slave("00 : crc=00 YES\n00 t=-1250\n"), Ok(-1250));
+    }
+
+    #[test]
+    fn test_discover_and_read() {
+        let dir = tempdir::TempDir::new("w1").unwrap();
+        let path = dir.path().to_str().unwrap();
+        for id in [
+            "28-0316a2795aff",
+            "10-000802b4b2e5",
+            "w1_bus_master1",
+            "01-00000d7a8b23",
+        ] {
+            std::fs::create_dir(dir.path().join(id)).unwrap();
+        }
+        std::fs::write(dir.path().join("28-0316a2795aff/temperature"), "21500\n").unwrap();
+        std::fs::write(
+            dir.path().join("10-000802b4b2e5/w1_slave"),
+            "2d 00 4b 46 ff ff 02 10 b6 : crc=b6 YES\n2d 00 4b 46 ff ff 02 10 b6 t=22375\n",
+        )
+        .unwrap();
+
+        let ids = discover(path);
+        assert_eq!(ids, vec!["10-000802b4b2e5", "28-0316a2795aff"]);
+        assert_eq!(
+            read_millidegrees(&format!("{}/{}", path, ids[0])),
+            Ok(22375)
+        );
+        assert_eq!(
+            read_millidegrees(&format!("{}/{}", path, ids[1])),
+            Ok(21500)
+        );
+    }
+
+    #[test]
+    fn test_sampler() {
+        let sensor = Sensor {
+            id: "28-0316a2795aff".to_string(),
+            name: "living_room".to_string(),
+            path: "/foo/bar".to_string(),
+        };
+        let mut sampler = SensorSampler::new(
+            vec![sensor],
+            std::time::Duration::from_millis(SAMPLE_INTERVAL),
+            std::time::Instant::now(),
+        );
+        // The power-on value is not a temperature
+        assert!(matches!(
+            sampler.feed(0, Ok(POWER_ON_VALUE)),
+            Some(Reading::Error(0, _))
+        ));
+        // Errors are reported once until the sensor recovers
+        assert_eq!(sampler.feed(0, Err("CRC error".to_string())), None);
+        assert_eq!(
+            sampler.feed(0, Ok(21500)),
+            Some(Reading::Temperature(0, 21.5))
+        );
+        assert_eq!(sampler.feed(0, Ok(21500)), None);
+
+        // Close to 85 °C, it may well be real
+        assert_eq!(
+            sampler.feed(0, Ok(84000)),
+            Some(Reading::Temperature(0, 84.0))
+        );
+        assert_eq!(
+            sampler.feed(0, Ok(POWER_ON_VALUE)),
+            Some(Reading::Temperature(0, 85.0))
+        );
+    }
+}
@@ -0,0 +1,436 @@
+//! output turns commands for outputs into writes, switching timed outputs off again
+//!
+//! Every command for an output passes through here, so a plain `ON` or `OFF` can cancel a
+//! running timer, no matter whether it came in over MQTT, from a rule or from a light binding.
+
+/// Command for a single output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputCommand {
+    // Switch on or off, cancelling a running timer
+    Set(bool),
+    // Switch on and off again after the duration; a new timed command restarts the timer
+    Timed(std::time::Duration),
+    // Switch on once for the duration; ignored while a pulse is running, and never cuts a longer
+    // running timer short or switches off an output that is on without a timer
+    Pulse(std::time::Duration),
+    // Switch to the opposite of the current state, cancelling a running timer
+    Toggle,
+}
+
+/// Output command by device ID
+pub type OutputEvent = (u8, OutputCommand);
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonCommand {
+    // String like the plain payloads, a boolean or 0/1
+    state: Option<serde_json::Value>,
+    // Seconds
+    duration: Option<f64>,
+    pulse: Option<f64>,
+}
+
+// Parse a plain payload, ignoring case
+fn parse_word(word: &str) -> Option<OutputCommand> {
+    match word.trim().to_ascii_lowercase().as_str() {
+        "on" | "1" | "true" => Some(OutputCommand::Set(true)),
+        "off" | "0" | "false" => Some(OutputCommand::Set(false)),
+        "toggle" => Some(OutputCommand::Toggle),
+        _ => None,
+    }
+}
+
+// Parse a JSON duration in seconds
+fn seconds(value: f64) -> Result<std::time::Duration, String> {
+    match value > 0.0 {
+        true => std::time::Duration::try_from_secs_f64(value).map_err(|e| e.to_string()),
+        false => Err(format!("Invalid duration {}: must be positive", value)),
+    }
+}
+
+impl OutputCommand {
+    /// Parse a command payload: a plain state or a JSON object
+    ///
+    /// Plain states are `ON`, `OFF` and `TOGGLE`, as well as `1`, `0`, `true` and `false`, in any
+    /// case. JSON objects hold a `state`, and either a `duration` in seconds for `ON`, or a
+    /// `pulse` in seconds, e.g. `{"state": "ON", "duration": 180}` or `{"pulse": 0.5}`.
+    pub fn parse(payload: &str) -> Result<Self, String> {
+        if let Some(command) = parse_word(payload) {
+            return Ok(command);
+        }
+        if !payload.trim_start().starts_with('{') {
+            return Err(format!(
+                "Invalid payload {:?}: expected ON, OFF, TOGGLE or a JSON object",
+                payload
+            ));
+        }
+        let json: JsonCommand = serde_json::from_str(payload)
+            .map_err(|e| format!("Invalid JSON payload {:?}: {}", payload, e))?;
+        let state = match &json.state {
+            None => None,
+            Some(serde_json::Value::String(word)) => parse_word(word),
+            Some(serde_json::Value::Bool(state)) => Some(OutputCommand::Set(*state)),
+            Some(serde_json::Value::Number(number)) => parse_word(&number.to_string()),
+            Some(_) => None,
+        };
+        if json.state.is_some() && state.is_none() {
+            return Err(format!("Invalid state in payload {:?}", payload));
+        }
+        match (state, json.duration, json.pulse) {
+            (Some(OutputCommand::Set(true)) | None, None, Some(pulse)) => {
+                seconds(pulse).map(OutputCommand::Pulse)
+            }
+            (Some(OutputCommand::Set(true)), Some(duration), None) => {
+                seconds(duration).map(OutputCommand::Timed)
+            }
+            (Some(command), None, None) => Ok(command),
+            (None, None, None) => Err(format!("No state in payload {:?}", payload)),
+            _ => Err(format!(
+                "Invalid payload {:?}: duration and pulse only go with ON",
+                payload
+            )),
+        }
+    }
+}
+
+// Running timer of an output
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    until: std::time::Instant,
+    pulse: bool,
+}
+
+/// OutputTimers keeps track of the outputs that need to be switched off later
+#[derive(Debug, Default)]
+pub struct OutputTimers {
+    // Staircase outputs: every plain `ON` switches them off again after this time
+    staircases: std::collections::HashMap<u8, std::time::Duration>,
+    // Outputs by ID, to read the current state from when toggling
+    outputs: std::collections::HashMap<u8, crate::device::Device>,
+    io: crate::backend::Io,
+    timers: std::collections::HashMap<u8, Timer>,
+}
+
+impl OutputTimers {
+    pub fn new(
+        staircases: std::collections::HashMap<u8, std::time::Duration>,
+        outputs: &[crate::device::Device],
+        io: crate::backend::Io,
+    ) -> Self {
+        OutputTimers {
+            staircases,
+            outputs: outputs
+                .iter()
+                .map(|device| (device.id, device.clone()))
+                .collect(),
+            io,
+            timers: std::collections::HashMap::new(),
+        }
+    }
+
+    // Current state of an output, as read from the hardware
+    fn state(&self, device_id: u8) -> bool {
+        self.outputs
+            .get(&device_id)
+            .is_some_and(|device| self.io.state(device))
+    }
+
+    /// Handle a command at `now`, returning the state to write, if any
+    pub fn command(
+        &mut self,
+        (device_id, command): OutputEvent,
+        now: std::time::Instant,
+    ) -> Option<crate::mqtt::MQTTEvent> {
+        let command = match command {
+            OutputCommand::Toggle => OutputCommand::Set(!self.state(device_id)),
+            command => command,
+        };
+        let command = match (command, self.staircases.get(&device_id)) {
+            (OutputCommand::Set(true), Some(&staircase)) => OutputCommand::Timed(staircase),
+            (command, _) => command,
+        };
+        match command {
+            OutputCommand::Set(_) | OutputCommand::Toggle => {
+                self.timers.remove(&device_id);
+            }
+            OutputCommand::Timed(duration) => {
+                let until = now + duration;
+                self.timers.insert(
+                    device_id,
+                    Timer {
+                        until,
+                        pulse: false,
+                    },
+                );
+            }
+            OutputCommand::Pulse(duration) => {
+                let until = now + duration;
+                match self.timers.get(&device_id) {
+                    Some(timer) if timer.pulse || timer.until >= until => {
+                        log::debug!("Output #{} is already on for longer", device_id);
+                        return None;
+                    }
+                    Some(_) => {}
+                    // On without a timer means on for good
+                    None if self.state(device_id) => {
+                        log::debug!("Output #{} is already on for good", device_id);
+                        return None;
+                    }
+                    None => {}
+                }
+                self.timers.insert(device_id, Timer { until, pulse: true });
+            }
+        }
+        let state = !matches!(command, OutputCommand::Set(false));
+        Some((device_id, state))
+    }
+
+    /// Switch off the outputs whose timer expired at `now`
+    pub fn poll(&mut self, now: std::time::Instant) -> std::vec::Vec<crate::mqtt::MQTTEvent> {
+        let mut expired: std::vec::Vec<u8> = self
+            .timers
+            .iter()
+            .filter(|(_, timer)| timer.until <= now)
+            .map(|(&device_id, _)| device_id)
+            .collect();
+        expired.sort();
+        for device_id in &expired {
+            self.timers.remove(device_id);
+            log::debug!("Timer of output #{} expired", device_id);
+        }
+        expired
+            .into_iter()
+            .map(|device_id| (device_id, false))
+            .collect()
+    }
+
+    /// The next moment a timer expires
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        self.timers.values().map(|timer| timer.until).min()
+    }
+}
+
+/// Run all output commands through the timers and pass the resulting writes on
+pub fn run_outputs(
+    rx: std::sync::mpsc::Receiver<OutputEvent>,
+    file_write_tx: std::sync::mpsc::Sender<crate::sysfs::write::WriteEvent>,
+    mut output_timers: OutputTimers,
+) {
+    loop {
+        let received = match output_timers.next_deadline() {
+            Some(deadline) => {
+                rx.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+            }
+            None => rx
+                .recv()
+                .map_err(|_| std::sync::mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        let now = std::time::Instant::now();
+        match received {
+            Ok(event) => {
+                log::debug!("Output command received {:?}", event);
+                if let Some(write) = output_timers.command(event, now) {
+                    file_write_tx
+                        .send(crate::sysfs::write::WriteEvent::Command(write))
+                        .unwrap();
+                }
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        for write in output_timers.poll(now) {
+            file_write_tx
+                .send(crate::sysfs::write::WriteEvent::Command(write))
+                .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> std::time::Duration {
+        std::time::Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_parse() {
+        for (payload, state) in [
+            ("ON", true),
+            ("off", false),
+            ("1", true),
+            ("False", false),
+            (" true\n", true),
+            (r#"{"state": "on"}"#, true),
+            (r#"{"state": false}"#, false),
+            (r#"{"state": 1}"#, true),
+        ] {
+            assert_eq!(
+                OutputCommand::parse(payload),
+                Ok(OutputCommand::Set(state)),
+                "{:?}",
+                payload
+            );
+        }
+        assert_eq!(OutputCommand::parse("Toggle"), Ok(OutputCommand::Toggle));
+        assert_eq!(
+            OutputCommand::parse(r#"{"state": "TOGGLE"}"#),
+            Ok(OutputCommand::Toggle)
+        );
+        assert_eq!(
+            OutputCommand::parse(r#"{"state": "ON", "duration": 180}"#),
+            Ok(OutputCommand::Timed(secs(180)))
+        );
+        assert_eq!(
+            OutputCommand::parse(r#"{"pulse": 0.5}"#),
+            Ok(OutputCommand::Pulse(std::time::Duration::from_millis(500)))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (payload, message) in [
+            ("on for a bit", "expected ON, OFF, TOGGLE or a JSON object"),
+            (r#"{"state": "ON""#, "Invalid JSON payload"),
+            (r#"{"state": "ON", "brightness": 3}"#, "unknown field"),
+            (r#"{"state": "maybe"}"#, "Invalid state"),
+            (r#"{}"#, "No state"),
+            (r#"{"state": "OFF", "duration": 10}"#, "only go with ON"),
+            (r#"{"state": "ON", "duration": -1}"#, "must be positive"),
+        ] {
+            let error = OutputCommand::parse(payload).unwrap_err();
+            assert!(error.contains(message), "{:?} for {:?}", error, payload);
+        }
+    }
+
+    #[test]
+    fn test_toggle_reads_state() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let path = tmp_dir.path().join("ro_1");
+        std::fs::write(&path, "1\n").expect("Could not write contents to temp file");
+        let output = crate::device::Device {
+            id: 1,
+            path: path.to_str().unwrap().to_string(),
+            module_name: String::from("foo"),
+            device_type: crate::device::DeviceType::RelayOutput,
+            io_group: 2,
+            number: 1,
+            settings: crate::device::DeviceSettings::default(),
+        };
+        let mut output_timers = OutputTimers::new(
+            std::collections::HashMap::new(),
+            &[output],
+            crate::backend::Io::default(),
+        );
+        let start = std::time::Instant::now();
+        output_timers.command((1, OutputCommand::Timed(secs(10))), start);
+        // Toggling off cancels the timer
+        assert_eq!(
+            output_timers.command((1, OutputCommand::Toggle), start),
+            Some((1, false))
+        );
+        assert_eq!(output_timers.next_deadline(), None);
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_timed_retrigger_and_cancel() {
+        let mut output_timers = OutputTimers::default();
+        let start = std::time::Instant::now();
+        assert_eq!(
+            output_timers.command((1, OutputCommand::Timed(secs(180))), start),
+            Some((1, true))
+        );
+        // Retriggering restarts the timer
+        output_timers.command((1, OutputCommand::Timed(secs(180))), start + secs(100));
+        assert_eq!(output_timers.poll(start + secs(180)), vec![]);
+        assert_eq!(output_timers.next_deadline(), Some(start + secs(280)));
+        assert_eq!(output_timers.poll(start + secs(280)), vec![(1, false)]);
+
+        // A plain command cancels the timer
+        output_timers.command((1, OutputCommand::Timed(secs(10))), start);
+        assert_eq!(
+            output_timers.command((1, OutputCommand::Set(true)), start),
+            Some((1, true))
+        );
+        assert_eq!(output_timers.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_pulse_overlap() {
+        let mut output_timers = OutputTimers::default();
+        let start = std::time::Instant::now();
+        assert_eq!(
+            output_timers.command((1, OutputCommand::Pulse(secs(1))), start),
+            Some((1, true))
+        );
+        // A pulse is not extended by another one
+        assert_eq!(
+            output_timers.command((1, OutputCommand::Pulse(secs(1))), start),
+            None
+        );
+        assert_eq!(output_timers.poll(start + secs(1)), vec![(1, false)]);
+
+        // Nor does it cut a longer timer short
+        output_timers.command((2, OutputCommand::Timed(secs(60))), start);
+        assert_eq!(
+            output_timers.command((2, OutputCommand::Pulse(secs(1))), start),
+            None
+        );
+        assert_eq!(output_timers.next_deadline(), Some(start + secs(60)));
+    }
+
+    #[test]
+    fn test_pulse_keeps_output_on_for_good() {
+        let backend = std::sync::Arc::new(crate::backend::tests::MockBackend {
+            devices: vec![
+                (crate::device::DeviceType::RelayOutput, 2, 1),
+                (crate::device::DeviceType::RelayOutput, 2, 2),
+            ],
+            values: std::sync::Mutex::new(std::collections::HashMap::from([(0, true)])),
+            ..Default::default()
+        });
+        let (io, devices) = crate::backend::Io::discover(vec![backend], "foo").unwrap();
+        let mut output_timers = OutputTimers::new(std::collections::HashMap::new(), &devices, io);
+        let start = std::time::Instant::now();
+
+        // Output 0 was switched on without a timer, so the pulse does not switch it off
+        assert_eq!(
+            output_timers.command((0, OutputCommand::Pulse(secs(1))), start),
+            None
+        );
+        assert_eq!(output_timers.next_deadline(), None);
+
+        // Output 1 is off, so it gets the pulse
+        assert_eq!(
+            output_timers.command((1, OutputCommand::Pulse(secs(1))), start),
+            Some((1, true))
+        );
+        assert_eq!(output_timers.poll(start + secs(1)), vec![(1, false)]);
+    }
+
+    #[test]
+    fn test_staircase() {
+        let mut output_timers = OutputTimers::new(
+            [(1, secs(180))].into_iter().collect(),
+            &[],
+            crate::backend::Io::default(),
+        );
+        let start = std::time::Instant::now();
+        assert_eq!(
+            output_timers.command((1, OutputCommand::Set(true)), start),
+            Some((1, true))
+        );
+        assert_eq!(output_timers.next_deadline(), Some(start + secs(180)));
+        output_timers.command((1, OutputCommand::Set(true)), start + secs(60));
+        assert_eq!(output_timers.poll(start + secs(240)), vec![(1, false)]);
+
+        // Other outputs stay on
+        output_timers.command((2, OutputCommand::Set(true)), start);
+        assert_eq!(output_timers.next_deadline(), None);
+    }
+}
@@ -0,0 +1,420 @@
+//! stream pushes state changes live to WebSocket clients, e.g. a dashboard on a wall-mounted tablet
+//!
+//! Every client first gets a snapshot of all digital devices, followed by every change of an input
+//! or output and every gesture as they happen:
+//!
+//! - `{"type": "snapshot", "devices": {"input/1_01": false, "relay/kitchen": true}}`
+//! - `{"type": "state", "device": "relay/kitchen", "state": true}`
+//! - `{"type": "action", "device": "input/1_01", "action": "single"}`
+//!
+//! Clients send commands on the same socket, with the same payloads as the command topic:
+//! `{"device": "relay/kitchen", "command": "TOGGLE"}` or `{"device": "relay/kitchen", "command":
+//! {"pulse": 0.5}}`. Commands that can not be sent are answered with `{"type": "error", "error":
+//! "..."}`; errors while executing them are published on the error topic as usual.
+
+// Time a client waits for a command before sending the changes that came in meanwhile
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+// Time a client gets for the handshake and for taking every message
+const CLIENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+// Number of events queued for a client before it is dropped for falling behind
+const QUEUE_SIZE: usize = 256;
+
+/// A change to be sent to the clients
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamEvent {
+    State(crate::sysfs::FileEvent),
+    Action(crate::gesture::GestureEvent),
+}
+
+/// Broadcast hands every event to all connected clients
+///
+/// Clones share the clients, so the threads producing events each hold one. Events sent while no
+/// client is connected are dropped. Every client has a queue of its own, so a slow client never
+/// holds up the others: it is dropped once its queue is full.
+#[derive(Debug, Clone, Default)]
+pub struct Broadcast {
+    clients:
+        std::sync::Arc<std::sync::Mutex<std::vec::Vec<std::sync::mpsc::SyncSender<StreamEvent>>>>,
+}
+
+impl Broadcast {
+    /// Receive all events from now on, until falling behind
+    pub fn subscribe(&self) -> std::sync::mpsc::Receiver<StreamEvent> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(QUEUE_SIZE);
+        self.clients.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Send an event to all clients, forgetting the ones that disconnected or fell behind
+    pub fn send(&self, event: StreamEvent) {
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|tx| match tx.try_send(event) {
+                Ok(()) => true,
+                Err(std::sync::mpsc::TrySendError::Full(_)) => {
+                    log::warn!("Dropping a stream client that fell behind");
+                    false
+                }
+                Err(std::sync::mpsc::TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+/// Stream turns events into messages for the clients and their messages into commands
+#[derive(Debug)]
+pub struct Stream {
+    // Type and key by device ID, as used in the messages
+    names: std::collections::HashMap<u8, String>,
+    snapshot: crate::mqtt::publish::Snapshot,
+    // Commands take the same route as the ones from the HTTP API
+    api: crate::http::Api,
+    broadcast: Broadcast,
+    // Time a client gets for the handshake and for taking every message
+    timeout: std::time::Duration,
+}
+
+// Message telling a client what went wrong
+fn error(message: &str) -> String {
+    serde_json::json!({ "type": "error", "error": message }).to_string()
+}
+
+impl Stream {
+    pub fn new(
+        devices: &[crate::device::Device],
+        io: crate::backend::Io,
+        api: crate::http::Api,
+        broadcast: Broadcast,
+    ) -> Self {
+        Stream {
+            names: devices
+                .iter()
+                .map(|device| {
+                    (
+                        device.id,
+                        format!(
+                            "{}/{}",
+                            device.device_type.as_str(),
+                            crate::device::device_key(device)
+                        ),
+                    )
+                })
+                .collect(),
+            snapshot: crate::mqtt::publish::Snapshot {
+                devices: devices.to_vec(),
+                io,
+            },
+            api,
+            broadcast,
+            timeout: CLIENT_TIMEOUT,
+        }
+    }
+
+    /// Message with the current state of all digital devices
+    pub fn snapshot_message(&self) -> String {
+        let states: serde_json::Map<String, serde_json::Value> = self
+            .snapshot
+            .read()
+            .into_iter()
+            .filter_map(|(device_id, state)| {
+                self.names
+                    .get(&device_id)
+                    .map(|name| (name.clone(), serde_json::Value::Bool(state)))
+            })
+            .collect();
+        serde_json::json!({ "type": "snapshot", "devices": states }).to_string()
+    }
+
+    /// Message for an event, if it is for a known device
+    pub fn event_message(&self, event: &StreamEvent) -> Option<String> {
+        let message = match *event {
+            StreamEvent::State((device_id, state, _)) => serde_json::json!({
+                "type": "state",
+                "device": self.names.get(&device_id)?,
+                "state": state,
+            }),
+            StreamEvent::Action((device_id, gesture)) => serde_json::json!({
+                "type": "action",
+                "device": self.names.get(&device_id)?,
+                "action": gesture.as_str(),
+            }),
+        };
+        Some(message.to_string())
+    }
+
+    /// Send the command in a message from a client, giving the message to answer with on errors
+    pub fn command(&self, text: &str) -> Option<String> {
+        let message: serde_json::Value = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => return Some(error(&format!("Invalid message {:?}: {}", text, e))),
+        };
+        let name = match message["device"].as_str() {
+            Some(name) => name,
+            None => return Some(error("Expected a device, e.g. \"relay/2_01\"")),
+        };
+        // Commands are either a payload as is or a JSON object like on the command topic
+        let payload = match &message["command"] {
+            serde_json::Value::String(payload) => payload.clone(),
+            serde_json::Value::Null => return Some(error("Expected a command")),
+            command => command.to_string(),
+        };
+        let device = match name
+            .split_once('/')
+            .and_then(|(device_type, key)| self.api.find(device_type, key))
+        {
+            Some(device) => device,
+            None => return Some(error(&format!("No device {}", name))),
+        };
+        log::debug!("Stream command {:?} for device #{}", payload, device.id);
+        match self.api.command(device, &payload) {
+            (202, _) => None,
+            (_, json) => Some(error(json["error"].as_str().unwrap_or_default())),
+        }
+    }
+}
+
+// Send a snapshot and all changes to a single client, and handle its commands until it disconnects
+fn handle_client(
+    stream: std::net::TcpStream,
+    shared: &Stream,
+) -> Result<(), crate::errors::MausError> {
+    let io_error = |e: std::io::Error| crate::errors::MausError::new(e.to_string());
+    // Clients that stall during the handshake or stop reading are dropped
+    stream
+        .set_read_timeout(Some(shared.timeout))
+        .map_err(io_error)?;
+    stream
+        .set_write_timeout(Some(shared.timeout))
+        .map_err(io_error)?;
+    // Subscribe before taking the snapshot, so no change gets lost in between
+    let rx = shared.broadcast.subscribe();
+    let mut websocket = tungstenite::accept(stream)
+        .map_err(|e| crate::errors::MausError::new(format!("WebSocket handshake failed: {}", e)))?;
+    websocket
+        .get_ref()
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(io_error)?;
+    let websocket_error = |e: tungstenite::Error| crate::errors::MausError::new(e.to_string());
+    websocket
+        .send(tungstenite::Message::Text(shared.snapshot_message()))
+        .map_err(websocket_error)?;
+
+    loop {
+        loop {
+            match rx.try_recv() {
+                Ok(event) => {
+                    if let Some(message) = shared.event_message(&event) {
+                        websocket
+                            .send(tungstenite::Message::Text(message))
+                            .map_err(websocket_error)?;
+                    }
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => break,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    return Err(crate::errors::MausError::new(
+                        "Fell behind on the changes".to_string(),
+                    ))
+                }
+            }
+        }
+        match websocket.read() {
+            Ok(tungstenite::Message::Text(text)) => {
+                if let Some(reply) = shared.command(&text) {
+                    websocket
+                        .send(tungstenite::Message::Text(reply))
+                        .map_err(websocket_error)?;
+                }
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(websocket_error(e)),
+        }
+    }
+}
+
+/// Accept WebSocket clients, streaming to each in a thread of its own
+pub fn serve(listener: std::net::TcpListener, stream: Stream) {
+    let stream = std::sync::Arc::new(stream);
+    for client in listener.incoming() {
+        match client {
+            Ok(client) => {
+                let stream = stream.clone();
+                std::thread::spawn(move || {
+                    let peer = client
+                        .peer_addr()
+                        .map(|peer| peer.to_string())
+                        .unwrap_or_default();
+                    log::info!("Stream client {} connected", peer);
+                    if let Err(e) = handle_client(client, &stream) {
+                        log::debug!("Stream client {}: {}", peer, e);
+                    }
+                    log::info!("Stream client {} disconnected", peer);
+                });
+            }
+            Err(e) => log::error!("Could not accept stream client: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stream for an input and a named relay, along with the command channel
+    fn stream() -> (
+        Stream,
+        Broadcast,
+        std::sync::mpsc::Receiver<crate::output::OutputEvent>,
+    ) {
+        let backend = std::sync::Arc::new(crate::backend::tests::MockBackend {
+            devices: vec![
+                (crate::device::DeviceType::DigitalInput, 1, 1),
+                (crate::device::DeviceType::RelayOutput, 2, 1),
+            ],
+            values: std::sync::Mutex::new(std::collections::HashMap::from([(1, true)])),
+            ..Default::default()
+        });
+        let (io, mut devices) = crate::backend::Io::discover(vec![backend], "plc").unwrap();
+        devices[1].settings.name = Some("kitchen".to_string());
+        let (output_tx, output_rx) = std::sync::mpsc::channel();
+        let (analog_tx, _) = std::sync::mpsc::channel();
+        let api = crate::http::Api::new(
+            &devices,
+            std::collections::HashMap::new(),
+            io.clone(),
+            output_tx,
+            analog_tx,
+        );
+        let broadcast = Broadcast::default();
+        let stream = Stream::new(&devices, io, api, broadcast.clone());
+        (stream, broadcast, output_rx)
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let broadcast = Broadcast::default();
+        // Sent to nobody
+        broadcast.send(StreamEvent::Action((1, crate::gesture::Gesture::Single)));
+        let rx = broadcast.subscribe();
+        let gone = broadcast.subscribe();
+        drop(gone);
+        let event = StreamEvent::State((1, true, std::time::Duration::from_secs(1)));
+        broadcast.send(event);
+        assert_eq!(rx.try_recv(), Ok(event));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(broadcast.clients.lock().unwrap().len(), 1);
+
+        // A client that does not keep up is dropped once its queue is full
+        for _ in 0..QUEUE_SIZE {
+            broadcast.send(event);
+        }
+        assert_eq!(broadcast.clients.lock().unwrap().len(), 1);
+        broadcast.send(event);
+        assert!(broadcast.clients.lock().unwrap().is_empty());
+        assert_eq!(rx.iter().count(), QUEUE_SIZE);
+    }
+
+    #[test]
+    fn test_messages() {
+        let (stream, _, output_rx) = stream();
+        let snapshot: serde_json::Value = serde_json::from_str(&stream.snapshot_message()).unwrap();
+        assert_eq!(
+            snapshot,
+            serde_json::json!({
+                "type": "snapshot",
+                "devices": {"input/1_01": false, "relay/kitchen": true},
+            })
+        );
+        assert_eq!(
+            stream.event_message(&StreamEvent::Action((0, crate::gesture::Gesture::Double))),
+            Some(r#"{"action":"double","device":"input/1_01","type":"action"}"#.to_string())
+        );
+        assert_eq!(
+            stream.event_message(&StreamEvent::State((
+                7,
+                true,
+                std::time::Duration::from_secs(1)
+            ))),
+            None
+        );
+
+        assert_eq!(
+            stream.command(r#"{"device": "relay/kitchen", "command": {"pulse": 0.5}}"#),
+            None
+        );
+        assert_eq!(
+            output_rx.try_recv(),
+            Ok((
+                1,
+                crate::output::OutputCommand::Pulse(std::time::Duration::from_millis(500))
+            ))
+        );
+        for text in [
+            "TOGGLE",
+            r#"{"command": "ON"}"#,
+            r#"{"device": "relay/kitchen"}"#,
+            r#"{"device": "relay/2_01", "command": "ON"}"#,
+            r#"{"device": "input/1_01", "command": "ON"}"#,
+            r#"{"device": "relay/kitchen", "command": "maybe"}"#,
+        ] {
+            let reply: serde_json::Value =
+                serde_json::from_str(&stream.command(text).unwrap()).unwrap();
+            assert_eq!(reply["type"], "error", "{}", text);
+        }
+        assert!(output_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_serve() {
+        let (stream, broadcast, output_rx) = stream();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve(listener, stream));
+
+        let (mut websocket, _) = tungstenite::client(
+            format!("ws://{}/", address),
+            std::net::TcpStream::connect(address).unwrap(),
+        )
+        .unwrap();
+        let snapshot = websocket.read().unwrap().into_text().unwrap();
+        assert!(snapshot.contains("\"relay/kitchen\":true"), "{}", snapshot);
+
+        broadcast.send(StreamEvent::State((0, true, std::time::Duration::ZERO)));
+        let change = websocket.read().unwrap().into_text().unwrap();
+        assert!(change.contains("\"device\":\"input/1_01\""), "{}", change);
+
+        websocket
+            .send(tungstenite::Message::Text(
+                r#"{"device": "relay/kitchen", "command": "OFF"}"#.to_string(),
+            ))
+            .unwrap();
+        assert_eq!(
+            output_rx.recv_timeout(std::time::Duration::from_secs(1)),
+            Ok((1, crate::output::OutputCommand::Set(false)))
+        );
+    }
+
+    #[test]
+    fn test_stalled_handshake_is_dropped() {
+        use std::io::Read;
+
+        let (mut stream, _, _) = stream();
+        stream.timeout = std::time::Duration::from_millis(100);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve(listener, stream));
+
+        // Connect without ever sending the handshake
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(std::time::Duration::from_secs(2)))
+            .unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(client.read(&mut buffer).unwrap(), 0);
+    }
+}
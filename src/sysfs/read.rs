@@ -1,76 +1,368 @@
 use std::io::{Read, Seek};
+use std::os::unix::io::AsRawFd;
 
 use crate::sysfs::FileEvent;
 
+// Interval at which files that do not rely on inotify are read again
 const POLL_INTERVAL: u64 = 200;
+// Maximum number of epoll events handled in one go
+const MAX_EVENTS: usize = 64;
+// epoll token used for the inotify file descriptor; all other tokens are indices in the file list
+const INOTIFY_TOKEN: u64 = u64::MAX;
 
-/// Wait for toggle on a specific path
-fn wait_for_toggle(
+/// How changes to a watched file are picked up
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum WatchMode {
+    // sysfs attribute notification through POLLPRI/POLLERR on epoll. epoll accepts any sysfs
+    // attribute, also when the driver never notifies, so these are polled as well as a safety net
+    Notify,
+    // inotify modify events, for regular files that can not be added to epoll
+    Inotify,
+    // periodic re-read every `POLL_INTERVAL`, when neither of the above is possible
+    Poll,
+}
+
+// A single file being watched, along with the last known value
+struct WatchedFile {
     device: crate::device::Device,
-    tx: std::sync::mpsc::Sender<FileEvent>,
-) -> std::io::Result<()> {
-    log::debug!("Start monitoring path {:?}", device.path);
-    let file = std::fs::File::open(&device.path)?;
-    let mut reader = std::io::BufReader::new(file);
+    file: std::fs::File,
+    mode: WatchMode,
+    last_value: Option<bool>,
+    last_toggle_time: Option<std::time::Instant>,
+}
+
+/// Watcher registers all input files with a single epoll instance
+///
+/// Files that support sysfs notification are woken up on POLLPRI/POLLERR, regular files fall back
+/// to inotify and files that support neither are read again every `POLL_INTERVAL`. As epoll can
+/// not tell whether a sysfs driver actually notifies, notified files are re-read every
+/// `POLL_INTERVAL` too. The initial values are read right away, so changes from then on are
+/// reported.
+pub struct Watcher {
+    epoll_fd: std::os::unix::io::RawFd,
+    inotify_fd: Option<std::os::unix::io::RawFd>,
+    files: std::vec::Vec<WatchedFile>,
+    // inotify watch descriptor -> index in files
+    watch_descriptors: std::collections::HashMap<i32, usize>,
+}
+
+// Map a libc return value to an io result
+fn cvt(result: libc::c_int) -> std::io::Result<libc::c_int> {
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Read the current value of a sysfs file, starting from the first byte
+///
+/// Returns `None` when the file does not start with either `0` or `1`.
+pub fn read_value(file: &mut std::fs::File) -> std::io::Result<Option<bool>> {
     let mut first_char = [0; 1];
+    file.seek(std::io::SeekFrom::Start(0))?;
+    file.read_exact(&mut first_char)?;
+    Ok(match first_char[0] as char {
+        '0' => Some(false),
+        '1' => Some(true),
+        _ => None,
+    })
+}
+
+impl Watcher {
+    /// Open all device files, read their current value and register them for change notification
+    pub fn new(devices: std::vec::Vec<crate::device::Device>) -> std::io::Result<Self> {
+        let epoll_fd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        let mut watcher = Watcher {
+            epoll_fd,
+            inotify_fd: None,
+            files: std::vec::Vec::with_capacity(devices.len()),
+            watch_descriptors: std::collections::HashMap::new(),
+        };
+        let (initial_tx, _) = std::sync::mpsc::channel();
+
+        for device in devices {
+            log::debug!("Start monitoring path {:?}", device.path);
+            let file = std::fs::File::open(&device.path)?;
+            let index = watcher.files.len();
+            let mode = watcher.register(&file, &device.path, index)?;
+            log::debug!("Watching device #{} with {:?}", device.id, mode);
+            watcher.files.push(WatchedFile {
+                device,
+                file,
+                mode,
+                last_value: None,
+                last_toggle_time: None,
+            });
+            // Only records the starting value, as there is nothing it toggled from
+            watcher.check(index, &initial_tx);
+        }
 
-    let mut last_value: Option<bool> = None;
-    let mut last_toggle_time: Option<std::time::Instant> = None;
+        Ok(watcher)
+    }
+
+    // Try epoll first, then inotify; fall back to plain polling if neither is possible
+    fn register(
+        &mut self,
+        file: &std::fs::File,
+        path: &str,
+        index: usize,
+    ) -> std::io::Result<WatchMode> {
+        let mut event = libc::epoll_event {
+            events: (libc::EPOLLPRI | libc::EPOLLERR) as u32,
+            u64: index as u64,
+        };
+        let result = unsafe {
+            libc::epoll_ctl(
+                self.epoll_fd,
+                libc::EPOLL_CTL_ADD,
+                file.as_raw_fd(),
+                &mut event,
+            )
+        };
+        if result == 0 {
+            return Ok(WatchMode::Notify);
+        }
+
+        // Regular files are refused by epoll with EPERM
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EPERM) {
+            return Err(error);
+        }
+
+        let inotify_fd = self.inotify_fd()?;
+        let path = std::ffi::CString::new(path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                inotify_fd,
+                path.as_ptr(),
+                libc::IN_MODIFY | libc::IN_CLOSE_WRITE,
+            )
+        };
+        if wd < 0 {
+            log::debug!(
+                "Could not add inotify watch for {:?}: {}",
+                path,
+                std::io::Error::last_os_error()
+            );
+            return Ok(WatchMode::Poll);
+        }
+        self.watch_descriptors.insert(wd, index);
+        Ok(WatchMode::Inotify)
+    }
+
+    // Lazily set up the inotify instance and add it to the epoll set
+    fn inotify_fd(&mut self) -> std::io::Result<std::os::unix::io::RawFd> {
+        if let Some(inotify_fd) = self.inotify_fd {
+            return Ok(inotify_fd);
+        }
+        let inotify_fd = cvt(unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) })?;
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: INOTIFY_TOKEN,
+        };
+        cvt(unsafe {
+            libc::epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_ADD, inotify_fd, &mut event)
+        })?;
+        self.inotify_fd = Some(inotify_fd);
+        Ok(inotify_fd)
+    }
 
-    loop {
-        // Go back to first line and read it again
-        reader.seek(std::io::SeekFrom::Start(0))?;
-        reader.read_exact(&mut first_char)?;
-        let first_char = first_char[0] as char;
+    /// The watch mode used per device ID
+    pub fn modes(&self) -> std::vec::Vec<(u8, WatchMode)> {
+        self.files
+            .iter()
+            .map(|watched| (watched.device.id, watched.mode))
+            .collect()
+    }
 
-        // Parse to bool
-        let value = match first_char {
-            '0' => false,
-            '1' => true,
-            _ => continue, // skip invalid lines
+    // Read a single file again and send out an event if it toggled.
+    // Returns false if the receiving end is gone.
+    fn check(&mut self, index: usize, tx: &std::sync::mpsc::Sender<FileEvent>) -> bool {
+        let watched = &mut self.files[index];
+        let value = match read_value(&mut watched.file) {
+            Ok(Some(value)) => value != watched.device.settings.invert,
+            // skip invalid contents
+            Ok(None) => return true,
+            Err(e) => {
+                log::debug!("Could not read path {:?}: {}", watched.device.path, e);
+                return true;
+            }
         };
 
         // Update last value and last toggle time
-        if let Some(last_value) = last_value {
+        if let Some(last_value) = watched.last_value {
             if last_value != value {
-                let toggle_time = last_toggle_time
+                let toggle_time = watched
+                    .last_toggle_time
                     .map(|t| t.elapsed())
                     .unwrap_or_else(|| std::time::Duration::from_secs(0));
                 log::debug!(
                     "Toggled for device #{} path {:?} ! {:?} / {:?}",
-                    device.id,
-                    device.path,
+                    watched.device.id,
+                    watched.device.path,
                     value,
                     toggle_time
                 );
-                tx.send((device.id, value, toggle_time)).unwrap();
-                last_toggle_time = Some(std::time::Instant::now());
+                if tx.send((watched.device.id, value, toggle_time)).is_err() {
+                    return false;
+                }
+                watched.last_toggle_time = Some(std::time::Instant::now());
             }
         } else {
-            last_toggle_time = Some(std::time::Instant::now());
+            watched.last_toggle_time = Some(std::time::Instant::now());
         }
-        last_value = Some(value);
+        watched.last_value = Some(value);
+        true
+    }
 
-        // Go to bed again!
-        std::thread::sleep(std::time::Duration::from_millis(POLL_INTERVAL));
+    // Drain the inotify buffer, returning the indices of the files that changed
+    fn inotify_events(&self) -> std::vec::Vec<usize> {
+        let mut indices = std::vec::Vec::new();
+        let inotify_fd = match self.inotify_fd {
+            Some(inotify_fd) => inotify_fd,
+            None => return indices,
+        };
+        let mut buffer = [0u8; 4096];
+        let header_size = std::mem::size_of::<libc::inotify_event>();
+        loop {
+            let n = unsafe {
+                libc::read(
+                    inotify_fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if n <= 0 {
+                break;
+            }
+            let n = n as usize;
+            let mut offset = 0;
+            while offset + header_size <= n {
+                let event: libc::inotify_event = unsafe {
+                    std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                if let Some(&index) = self.watch_descriptors.get(&event.wd) {
+                    if !indices.contains(&index) {
+                        indices.push(index);
+                    }
+                }
+                offset += header_size + event.len as usize;
+            }
+        }
+        indices
+    }
+
+    /// Block forever, sending out a file event for every toggle observed
+    ///
+    /// Only returns when the receiving end of the channel is dropped or on an unrecoverable error.
+    pub fn run(&mut self, tx: std::sync::mpsc::Sender<FileEvent>) -> std::io::Result<()> {
+        let polled: std::vec::Vec<usize> = (0..self.files.len())
+            .filter(|&index| self.files[index].mode != WatchMode::Inotify)
+            .collect();
+        let poll_interval = std::time::Duration::from_millis(POLL_INTERVAL);
+        let mut next_poll = std::time::Instant::now() + poll_interval;
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+
+        loop {
+            // Without files to poll, only inotify wakes the watcher up
+            let timeout = match polled.is_empty() {
+                true => -1,
+                false => next_poll
+                    .saturating_duration_since(std::time::Instant::now())
+                    .as_millis() as libc::c_int,
+            };
+            let n = unsafe {
+                libc::epoll_wait(
+                    self.epoll_fd,
+                    events.as_mut_ptr(),
+                    MAX_EVENTS as libc::c_int,
+                    timeout,
+                )
+            };
+            if n < 0 {
+                let error = std::io::Error::last_os_error();
+                if error.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+
+            for event in &events[..n as usize] {
+                let token = event.u64;
+                let indices = match token {
+                    INOTIFY_TOKEN => self.inotify_events(),
+                    index => vec![index as usize],
+                };
+                for index in indices {
+                    if !self.check(index, &tx) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            // Periodic re-read for the files that fell back to polling, and for notified files in
+            // case their driver never notifies
+            if !polled.is_empty() && std::time::Instant::now() >= next_poll {
+                for &index in &polled {
+                    if !self.check(index, &tx) {
+                        return Ok(());
+                    }
+                }
+                next_poll = std::time::Instant::now() + poll_interval;
+            }
+        }
     }
 }
 
-/// watch_input file events is the main block responsible for watching SysFS file events
-pub fn watch_input_file_events(
-    devices: std::vec::Vec<crate::device::Device>,
-    tx: std::sync::mpsc::Sender<FileEvent>,
-) {
-    let mut handles = std::vec::Vec::with_capacity(devices.len());
-    for device in devices {
-        let path_tx = tx.clone();
-        let handle = std::thread::spawn(move || {
-            wait_for_toggle(device, path_tx).unwrap();
-        });
-        handles.push(handle);
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(inotify_fd) = self.inotify_fd {
+                libc::close(inotify_fd);
+            }
+            libc::close(self.epoll_fd);
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_notify_without_notification_is_polled() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let path = tmp_dir.path().join("di_value");
+        std::fs::write(&path, "0\n").expect("Could not write contents to temp file");
+        let device = crate::device::Device {
+            id: 0,
+            path: path.to_str().unwrap().to_string(),
+            module_name: String::from("foo"),
+            device_type: crate::device::DeviceType::DigitalInput,
+            io_group: 1,
+            number: 1,
+            settings: crate::device::DeviceSettings::default(),
+        };
+
+        // Pretend epoll accepted the file, like it does for any sysfs attribute, while nothing
+        // ever wakes it up
+        let mut watcher = Watcher::new(vec![device]).expect("Expect watcher to work");
+        watcher.files[0].mode = WatchMode::Notify;
+        watcher.watch_descriptors.clear();
+        assert_eq!(watcher.modes(), vec![(0, WatchMode::Notify)]);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || watcher.run(tx));
+
+        std::fs::write(&path, "1\n").expect("Could not write contents to temp file");
+        let (device_id, state, _) = rx
+            .recv_timeout(std::time::Duration::from_secs(1))
+            .expect("Expect a toggle event");
+        assert_eq!((device_id, state), (0, true));
 
-    for handle in handles {
-        handle.join().unwrap();
+        tmp_dir.close().unwrap();
     }
 }
@@ -1,25 +1,569 @@
-/// Write incoming messages back by updating the related file system entry
-use std::io::Write;
+//! Write incoming messages back by updating the related file system entry
 
-pub fn handle_file_command(
-    rx: std::sync::mpsc::Receiver<crate::mqtt::MQTTEvent>,
-    path_map: &std::collections::HashMap<u8, String>,
+/// Events handled by the writer thread
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteEvent {
+    // Switch an output to the given state
+    Command(crate::mqtt::MQTTEvent),
+    // An output was seen toggling by the watcher, e.g. when switched by hand
+    Observed(crate::sysfs::FileEvent),
+}
+
+/// Write a raw value to an analog output
+pub fn write_analog(path: &str, value: u32) -> Result<(), crate::errors::MausError> {
+    std::fs::write(path, value.to_string())
+        .map_err(|e| crate::errors::MausError::new(format!("Could not write to {}: {}", path, e)))
+}
+
+// Publish and stream the state of an output, along with the time since its last change
+fn publish_state(
+    device_id: u8,
+    state: bool,
+    last_change: &mut std::collections::HashMap<u8, std::time::Instant>,
+    mqtt_publish_tx: &std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+    stream: &crate::stream::Broadcast,
 ) {
-    for (device_id, toggle) in rx {
-        if let Some(path) = path_map.get(&device_id) {
-            log::info!(
-                "Received message for device #{} {:?} new path {}",
+    let now = std::time::Instant::now();
+    let duration = last_change
+        .insert(device_id, now)
+        .map(|t| now.duration_since(t))
+        .unwrap_or_else(|| std::time::Duration::from_secs(0));
+    mqtt_publish_tx
+        .send(crate::mqtt::MQTTMessage::State((
+            device_id, state, duration,
+        )))
+        .unwrap();
+    stream.send(crate::stream::StreamEvent::State((
+        device_id, state, duration,
+    )));
+}
+
+// Write a single command; returns the state read back, or None when writing or reading back
+// failed and the error was published
+fn write_command(
+    device_id: u8,
+    toggle: bool,
+    device: &crate::device::Device,
+    io: &crate::backend::Io,
+    mqtt_publish_tx: &std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+) -> Option<bool> {
+    log::info!(
+        "Received message for device #{} {:?} new path {}",
+        device_id,
+        toggle,
+        device.path
+    );
+    let state = match io.write_state(device, toggle) {
+        Ok(state) => state,
+        Err(e) => {
+            mqtt_publish_tx
+                .send(crate::mqtt::MQTTMessage::Error(device_id, e.to_string()))
+                .unwrap();
+            return None;
+        }
+    };
+    if state != toggle {
+        let error = crate::errors::MausError::new(format!(
+            "Output did not follow: requested {}, read back {}",
+            toggle, state
+        ));
+        mqtt_publish_tx
+            .send(crate::mqtt::MQTTMessage::Error(
                 device_id,
-                toggle,
-                path
-            );
-            if let Ok(mut file) = std::fs::File::create(path) {
-                let content = match toggle {
-                    true => "1",
-                    false => "0",
-                };
-                file.write_all(content.as_bytes()).unwrap();
+                error.to_string(),
+            ))
+            .unwrap();
+    }
+    Some(state)
+}
+
+/// Pass on the watched input events, and hand those of the outputs to the writer thread
+pub fn route_watched(
+    rx: std::sync::mpsc::Receiver<crate::sysfs::FileEvent>,
+    input_tx: std::sync::mpsc::Sender<crate::sysfs::FileEvent>,
+    write_tx: std::sync::mpsc::Sender<WriteEvent>,
+    outputs: &std::collections::HashSet<u8>,
+) {
+    for event in rx {
+        match outputs.contains(&event.0) {
+            true => write_tx.send(WriteEvent::Observed(event)).unwrap(),
+            false => input_tx.send(event).unwrap(),
+        }
+    }
+}
+
+/// Write the requested state for every command and publish the state as read back
+///
+/// Whenever the hardware did not follow the command, an error is published for the device. All
+/// commands go through the interlock: outputs that would be switched on together with another one
+/// in their group are rejected with an error, and are held back until the dead time has passed
+/// after switching off another one. A new command for an output replaces one still held back.
+///
+/// This is the only place output states are published from: outputs the watcher sees toggling
+/// are read again, and published and handed to the interlock only when they differ from the state
+/// last published, so the writes made here are not reported twice.
+///
+/// An interlocked output that could not be written or read back is taken to be on, so it keeps
+/// blocking its group until it is switched off or seen off; an error saying so is published.
+/// States are streamed to the WebSocket clients as well.
+pub fn handle_file_command(
+    rx: std::sync::mpsc::Receiver<WriteEvent>,
+    devices: &std::collections::HashMap<u8, crate::device::Device>,
+    io: &crate::backend::Io,
+    mut interlock: crate::interlock::Interlock,
+    mqtt_publish_tx: std::sync::mpsc::Sender<crate::mqtt::MQTTMessage>,
+    stream: crate::stream::Broadcast,
+) {
+    let mut last_change: std::collections::HashMap<u8, std::time::Instant> =
+        std::collections::HashMap::new();
+    // State last published per device ID, absent while unknown
+    let mut states: std::collections::HashMap<u8, bool> = std::collections::HashMap::new();
+    // Commands waiting for the dead time to pass, per device ID
+    let mut pending: std::collections::HashMap<u8, (bool, std::time::Instant)> =
+        std::collections::HashMap::new();
+
+    loop {
+        let deadline = pending.values().map(|&(_, allowed_at)| allowed_at).min();
+        let received = match deadline {
+            Some(deadline) => {
+                rx.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
             }
+            None => rx
+                .recv()
+                .map_err(|_| std::sync::mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        let now = std::time::Instant::now();
+        let mut commands: std::vec::Vec<crate::mqtt::MQTTEvent> = std::vec::Vec::new();
+        match received {
+            Ok(WriteEvent::Command((device_id, toggle))) => {
+                pending.remove(&device_id);
+                commands.push((device_id, toggle));
+            }
+            Ok(WriteEvent::Observed((device_id, _, _))) => {
+                // Read again, as the event may be stale by the time it got here
+                if let Some(device) = devices.get(&device_id) {
+                    let read = io.read_states(std::slice::from_ref(device));
+                    if let Some(&(_, state)) = read.first() {
+                        if states.insert(device_id, state) != Some(state) {
+                            log::info!("Device #{} switched to {:?} elsewhere", device_id, state);
+                            publish_state(
+                                device_id,
+                                state,
+                                &mut last_change,
+                                &mqtt_publish_tx,
+                                &stream,
+                            );
+                            interlock.set_state(device_id, state, now);
+                        }
+                    }
+                }
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => match deadline {
+                // Still finish the commands held back
+                Some(deadline) => {
+                    std::thread::sleep(deadline.saturating_duration_since(now));
+                }
+                None => return,
+            },
+        }
+        let now = std::time::Instant::now();
+        let mut due: std::vec::Vec<u8> = pending
+            .iter()
+            .filter(|(_, &(_, allowed_at))| allowed_at <= now)
+            .map(|(&device_id, _)| device_id)
+            .collect();
+        due.sort();
+        for device_id in due {
+            if let Some((toggle, _)) = pending.remove(&device_id) {
+                commands.push((device_id, toggle));
+            }
+        }
+
+        for (device_id, toggle) in commands {
+            let device = match devices.get(&device_id) {
+                Some(device) => device,
+                None => continue,
+            };
+            match interlock.check(device_id, toggle, std::time::Instant::now()) {
+                crate::interlock::Decision::Allow => {}
+                crate::interlock::Decision::Wait(allowed_at) => {
+                    log::debug!("Holding back device #{} for the dead time", device_id);
+                    pending.insert(device_id, (toggle, allowed_at));
+                    continue;
+                }
+                crate::interlock::Decision::Reject(error) => {
+                    mqtt_publish_tx
+                        .send(crate::mqtt::MQTTMessage::Error(device_id, error))
+                        .unwrap();
+                    continue;
+                }
+            }
+            let state = match write_command(device_id, toggle, device, io, &mqtt_publish_tx) {
+                Some(state) => {
+                    states.insert(device_id, state);
+                    publish_state(
+                        device_id,
+                        state,
+                        &mut last_change,
+                        &mqtt_publish_tx,
+                        &stream,
+                    );
+                    state
+                }
+                None => {
+                    // The output may have switched anyway, so it keeps blocking the others in its
+                    // group until its state is known again
+                    states.remove(&device_id);
+                    if interlock.is_interlocked(device_id) {
+                        mqtt_publish_tx
+                            .send(crate::mqtt::MQTTMessage::Error(
+                                device_id,
+                                "State unknown, taken to be on by the interlock".to_string(),
+                            ))
+                            .unwrap();
+                    }
+                    true
+                }
+            };
+            interlock.set_state(device_id, state, std::time::Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Relay output writing to `path`
+    fn output(device_id: u8, path: &std::path::Path) -> crate::device::Device {
+        crate::device::Device {
+            id: device_id,
+            path: path.to_str().unwrap().to_string(),
+            module_name: String::from("foo"),
+            device_type: crate::device::DeviceType::RelayOutput,
+            io_group: 2,
+            number: device_id as i8,
+            settings: crate::device::DeviceSettings::default(),
+        }
+    }
+
+    #[test]
+    fn test_state_is_published_after_write() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let path = tmp_dir.path().join("ro_value");
+        std::fs::write(&path, "0\n").expect("Could not write contents to temp file");
+
+        let devices = std::collections::HashMap::from([(3, output(3, &path))]);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        let stream = crate::stream::Broadcast::default();
+        let stream_rx = stream.subscribe();
+        tx.send(WriteEvent::Command((3, true))).unwrap();
+        drop(tx);
+        handle_file_command(
+            rx,
+            &devices,
+            &crate::backend::Io::default(),
+            crate::interlock::Interlock::default(),
+            publish_tx,
+            stream,
+        );
+
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [crate::mqtt::MQTTMessage::State((3, true, _))]
+        ));
+        assert!(matches!(
+            stream_rx.try_recv(),
+            Ok(crate::stream::StreamEvent::State((3, true, _)))
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_inverted_output() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let path = tmp_dir.path().join("ro_value");
+        std::fs::write(&path, "0\n").expect("Could not write contents to temp file");
+
+        let mut device = output(3, &path);
+        device.settings.invert = true;
+        let devices = std::collections::HashMap::from([(3, device)]);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        tx.send(WriteEvent::Command((3, true))).unwrap();
+        drop(tx);
+        handle_file_command(
+            rx,
+            &devices,
+            &crate::backend::Io::default(),
+            crate::interlock::Interlock::default(),
+            publish_tx,
+            crate::stream::Broadcast::default(),
+        );
+
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [crate::mqtt::MQTTMessage::State((3, true, _))]
+        ));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_error_is_published_when_write_fails() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let path = tmp_dir.path().join("missing").join("ro_value");
+
+        let devices = std::collections::HashMap::from([(3, output(3, &path))]);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        tx.send(WriteEvent::Command((3, false))).unwrap();
+        drop(tx);
+        handle_file_command(
+            rx,
+            &devices,
+            &crate::backend::Io::default(),
+            crate::interlock::Interlock::default(),
+            publish_tx,
+            crate::stream::Broadcast::default(),
+        );
+
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [crate::mqtt::MQTTMessage::Error(3, _)]
+        ));
+
+        tmp_dir.close().unwrap();
+    }
+
+    // Set up files for two interlocked outputs, 1 being on
+    fn interlocked_outputs(
+        tmp_dir: &tempdir::TempDir,
+    ) -> (
+        std::collections::HashMap<u8, crate::device::Device>,
+        crate::interlock::Interlock,
+    ) {
+        let mut devices = std::collections::HashMap::new();
+        for (device_id, value) in [(1, "1\n"), (2, "0\n")] {
+            let path = tmp_dir.path().join(format!("ro_{}", device_id));
+            std::fs::write(&path, value).expect("Could not write contents to temp file");
+            devices.insert(device_id, output(device_id, &path));
         }
+        let interlock = crate::interlock::Interlock::new(
+            vec![crate::interlock::InterlockGroup {
+                devices: vec![1, 2],
+                dead_time: std::time::Duration::from_millis(50),
+            }],
+            &[(1, true), (2, false)],
+        );
+        (devices, interlock)
+    }
+
+    #[test]
+    fn test_interlocked_output_is_rejected() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let (devices, interlock) = interlocked_outputs(&tmp_dir);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        tx.send(WriteEvent::Command((2, true))).unwrap();
+        drop(tx);
+        handle_file_command(
+            rx,
+            &devices,
+            &crate::backend::Io::default(),
+            interlock,
+            publish_tx,
+            crate::stream::Broadcast::default(),
+        );
+
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [crate::mqtt::MQTTMessage::Error(2, _)]
+        ));
+        assert_eq!(std::fs::read_to_string(&devices[&2].path).unwrap(), "0\n");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_own_write_is_published_once() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let path = tmp_dir.path().join("ro_value");
+        std::fs::write(&path, "0\n").expect("Could not write contents to temp file");
+
+        let devices = std::collections::HashMap::from([(3, output(3, &path))]);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        tx.send(WriteEvent::Command((3, true))).unwrap();
+        // The watcher sees the write as well
+        tx.send(WriteEvent::Observed((
+            3,
+            true,
+            std::time::Duration::from_secs(0),
+        )))
+        .unwrap();
+        drop(tx);
+        handle_file_command(
+            rx,
+            &devices,
+            &crate::backend::Io::default(),
+            crate::interlock::Interlock::default(),
+            publish_tx,
+            crate::stream::Broadcast::default(),
+        );
+
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [crate::mqtt::MQTTMessage::State((3, true, _))]
+        ));
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_output_switched_elsewhere_is_published() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let (devices, interlock) = interlocked_outputs(&tmp_dir);
+
+        // Switched off by something else than a command, e.g. the relay's manual override
+        std::fs::write(&devices[&1].path, "0\n").expect("Could not write contents to temp file");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        tx.send(WriteEvent::Observed((
+            1,
+            false,
+            std::time::Duration::from_secs(0),
+        )))
+        .unwrap();
+        tx.send(WriteEvent::Command((2, true))).unwrap();
+        drop(tx);
+        handle_file_command(
+            rx,
+            &devices,
+            &crate::backend::Io::default(),
+            interlock,
+            publish_tx,
+            crate::stream::Broadcast::default(),
+        );
+
+        // The interlock knows output 1 is off, so output 2 is switched on after the dead time
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                crate::mqtt::MQTTMessage::State((1, false, _)),
+                crate::mqtt::MQTTMessage::State((2, true, _))
+            ]
+        ));
+        assert_eq!(std::fs::read_to_string(&devices[&2].path).unwrap(), "1");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_failed_read_back_keeps_blocking() {
+        let backend = std::sync::Arc::new(crate::backend::tests::MockBackend {
+            devices: vec![
+                (crate::device::DeviceType::RelayOutput, 2, 1),
+                (crate::device::DeviceType::RelayOutput, 2, 2),
+            ],
+            unreadable: std::collections::HashSet::from([0]),
+            ..Default::default()
+        });
+        let (io, devices) = crate::backend::Io::discover(vec![backend.clone()], "foo").unwrap();
+        let devices: std::collections::HashMap<u8, crate::device::Device> = devices
+            .into_iter()
+            .map(|device| (device.id, device))
+            .collect();
+        let interlock = crate::interlock::Interlock::new(
+            vec![crate::interlock::InterlockGroup {
+                devices: vec![0, 1],
+                dead_time: std::time::Duration::from_millis(50),
+            }],
+            &[(0, false), (1, false)],
+        );
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        tx.send(WriteEvent::Command((0, true))).unwrap();
+        tx.send(WriteEvent::Command((1, true))).unwrap();
+        drop(tx);
+        handle_file_command(
+            rx,
+            &devices,
+            &io,
+            interlock,
+            publish_tx,
+            crate::stream::Broadcast::default(),
+        );
+
+        // Output 0 was written but not read back, so output 1 is rejected
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                crate::mqtt::MQTTMessage::Error(0, _),
+                crate::mqtt::MQTTMessage::Error(0, _),
+                crate::mqtt::MQTTMessage::Error(1, _)
+            ]
+        ));
+        assert_eq!(backend.values.lock().unwrap().get(&1), None);
+    }
+
+    #[test]
+    fn test_reversal_waits_for_dead_time() {
+        let tmp_dir =
+            tempdir::TempDir::new("myfolder").expect("Could not create a temporary folder");
+        let (devices, interlock) = interlocked_outputs(&tmp_dir);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (publish_tx, publish_rx) = std::sync::mpsc::channel();
+        tx.send(WriteEvent::Command((1, false))).unwrap();
+        tx.send(WriteEvent::Command((2, true))).unwrap();
+        drop(tx);
+        let start = std::time::Instant::now();
+        handle_file_command(
+            rx,
+            &devices,
+            &crate::backend::Io::default(),
+            interlock,
+            publish_tx,
+            crate::stream::Broadcast::default(),
+        );
+        assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+
+        let messages: Vec<crate::mqtt::MQTTMessage> = publish_rx.iter().collect();
+        assert!(matches!(
+            messages.as_slice(),
+            [
+                crate::mqtt::MQTTMessage::State((1, false, _)),
+                crate::mqtt::MQTTMessage::State((2, true, _))
+            ]
+        ));
+        assert_eq!(std::fs::read_to_string(&devices[&2].path).unwrap(), "1");
+
+        tmp_dir.close().unwrap();
     }
 }